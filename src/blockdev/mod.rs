@@ -0,0 +1,4189 @@
+// Copyright 2019 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, bail, Context, Result};
+use gptman::{GPTHeader, GPTPartitionEntry, GPT};
+use nix::sys::stat::{major, minor};
+use nix::{errno::Errno, mount, sched};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{
+    canonicalize, metadata, read_dir, read_to_string, remove_dir, symlink_metadata, File,
+    OpenOptions,
+};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::num::{NonZeroU32, NonZeroU64};
+use std::os::linux::fs::MetadataExt;
+use std::os::raw::c_int;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::cmdline::PartitionFilter;
+use crate::util::*;
+
+use crate::{runcmd, runcmd_output};
+
+mod probe;
+
+pub use self::probe::*;
+
+#[derive(Debug)]
+pub struct Disk {
+    path: String,
+}
+
+impl Disk {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let canon_path = path
+            .canonicalize()
+            .with_context(|| format!("canonicalizing {}", path.display()))?;
+
+        let canon_path = canon_path
+            .to_str()
+            .with_context(|| {
+                format!(
+                    "path {} canonicalized from {} is not UTF-8",
+                    canon_path.display(),
+                    path.display()
+                )
+            })?
+            .to_string();
+
+        Ok(Disk { path: canon_path })
+    }
+
+    pub fn mount_partition_by_label(&self, label: &str, flags: mount::MsFlags) -> Result<Mount> {
+        // get partition list
+        let partitions = self.get_partitions()?;
+        if partitions.is_empty() {
+            bail!("couldn't find any partitions on {}", self.path);
+        }
+
+        // find the partition with the matching label
+        let matching_partitions = partitions
+            .iter()
+            .filter(|d| d.label.as_ref().unwrap_or(&"".to_string()) == label)
+            .collect::<Vec<&Partition>>();
+        let part = match matching_partitions.len() {
+            0 => bail!("couldn't find {} device for {}", label, self.path),
+            1 => matching_partitions[0],
+            _ => bail!(
+                "found multiple devices on {} with label \"{}\"",
+                self.path,
+                label
+            ),
+        };
+
+        // mount it
+        match &part.fstype {
+            Some(fstype) => Mount::try_mount(&part.path, fstype, flags),
+            None => bail!(
+                "couldn't get filesystem type of {} device for {}",
+                label,
+                self.path
+            ),
+        }
+    }
+
+    pub fn get_partitions(&self) -> Result<Vec<Partition>> {
+        // walk each device in the output
+        let mut result: Vec<Partition> = Vec::new();
+        for devinfo in lsblk(Path::new(&self.path), true)? {
+            if let Some(name) = devinfo.get("NAME") {
+                // Only return partitions.  Skip the whole-disk device, as well
+                // as holders like LVM or RAID devices using one of the partitions.
+                if devinfo.get("TYPE").map(|s| s.as_str()) != Some("part") {
+                    continue;
+                }
+                let (mountpoint, swap) = match devinfo.get("MOUNTPOINT") {
+                    Some(mp) if mp == "[SWAP]" => (None, true),
+                    Some(mp) => (Some(mp.to_string()), false),
+                    None => (None, false),
+                };
+                result.push(Partition {
+                    path: name.to_owned(),
+                    label: devinfo.get("LABEL").map(<_>::to_string),
+                    fstype: devinfo.get("FSTYPE").map(<_>::to_string),
+                    parent: self.path.to_owned(),
+                    mountpoint,
+                    swap,
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    /// Return the devices, such as device-mapper targets, holding the
+    /// whole disk.
+    pub fn get_holders(&self) -> Result<Vec<String>> {
+        let holders = Path::new("/sys/block")
+            .join(
+                Path::new(&self.path)
+                    .file_name()
+                    .with_context(|| format!("path {} has no filename", self.path))?,
+            )
+            .join("holders");
+        let mut ret: Vec<String> = Vec::new();
+        for ent in read_dir(&holders).with_context(|| format!("reading {}", &holders.display()))? {
+            let ent = ent.with_context(|| format!("reading {} entry", &holders.display()))?;
+            ret.push(format!("/dev/{}", ent.file_name().to_string_lossy()));
+        }
+        ret.sort();
+        Ok(ret)
+    }
+
+    /// Look for a CoreOS install on the disk, i.e. a filesystem labeled
+    /// "boot" containing BLS entries.  Boot filesystems that aren't
+    /// already mounted are temporarily mounted without writing to the
+    /// device.
+    pub fn get_coreos_install(&self) -> Result<Option<CoreosInstall>> {
+        for part in self.get_partitions()? {
+            if part.label.as_deref() != Some("boot") {
+                continue;
+            }
+            let found = match (&part.mountpoint, &part.fstype) {
+                (Some(mountpoint), _) => read_coreos_install(&part.path, Path::new(mountpoint))?,
+                (None, Some(fstype)) => {
+                    let mount = Mount::try_mount_inspect(&part.path, fstype)?;
+                    read_coreos_install(&part.path, mount.mountpoint())?
+                }
+                (None, None) => None,
+            };
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Return an empty list if we have exclusive access to the device, or
+    /// a list of partitions preventing us from gaining exclusive access.
+    pub fn get_busy_partitions(self) -> Result<Vec<Partition>> {
+        // Try rereading the partition table.  This is the most complete
+        // check, but it only works on partitionable devices.
+        let rereadpt_result = {
+            let mut f = OpenOptions::new()
+                .write(true)
+                .open(&self.path)
+                .with_context(|| format!("opening {}", &self.path))?;
+            reread_partition_table(&mut f, false).map(|_| Vec::new())
+        };
+        if rereadpt_result.is_ok() {
+            return rereadpt_result;
+        }
+
+        // Walk partitions, record the ones that are reported in use,
+        // and return the list if any
+        let mut busy: Vec<Partition> = Vec::new();
+        for d in self.get_partitions()? {
+            if d.mountpoint.is_some() || d.swap || !d.get_holders()?.is_empty() {
+                busy.push(d)
+            }
+        }
+        if !busy.is_empty() {
+            return Ok(busy);
+        }
+
+        // Our investigation found nothing.  If the device is expected to be
+        // partitionable but reread failed, we evidently missed something,
+        // so error out for safety
+        if !self.is_dm_device() {
+            return rereadpt_result;
+        }
+
+        Ok(Vec::new())
+    }
+
+    /// Erase filesystem, RAID, LVM, and partition table signatures from the
+    /// disk and from each of its partitions, except for signatures within
+    /// the saved partitions.
+    pub fn wipe_signatures(&self, saved: &SavedPartitions) -> Result<()> {
+        let saved_ranges = saved.get_ranges()?;
+        let is_saved = |start: u64, end: u64| {
+            saved_ranges
+                .iter()
+                .any(|(saved_start, saved_end)| start < *saved_end && *saved_start < end)
+        };
+
+        // Partitions of a disk image file don't have device nodes, so we
+        // can only wipe the top-level signatures.
+        if !self.is_image_file()? {
+            for part in self.get_partitions()? {
+                match Partition::get_offsets(&part.path) {
+                    Ok((start, end)) if is_saved(start, end) => continue,
+                    Ok(_) => (),
+                    // e.g. device-mapper partitions
+                    Err(e) if saved.is_saved() => {
+                        eprintln!("Not wiping {}: {:#}", part.path, e);
+                        continue;
+                    }
+                    Err(_) => (),
+                }
+                eprintln!("Wiping signatures from {}", part.path);
+                runcmd!("wipefs", "--all", "--force", &part.path)?;
+            }
+        }
+
+        // wipe whole-disk signatures outside the saved partitions
+        let mut offsets = Vec::new();
+        for line in
+            runcmd_output!("wipefs", "--noheadings", "--output", "OFFSET", &self.path)?.lines()
+        {
+            let text = line.trim();
+            let offset = u64::from_str_radix(text.trim_start_matches("0x"), 16)
+                .with_context(|| format!("parsing wipefs offset '{text}'"))?;
+            if !is_saved(offset, offset + 1) {
+                offsets.push(format!("--offset={text}"));
+            }
+        }
+        if !offsets.is_empty() {
+            eprintln!("Wiping signatures from {}", self.path);
+            let mut cmd = Command::new("wipefs");
+            cmd.arg("--force").args(&offsets).arg(&self.path);
+            cmd_output(&mut cmd)?;
+        }
+        Ok(())
+    }
+
+    /// Get a handle to the set of device nodes for individual partitions
+    /// of the device.
+    pub fn get_partition_table(&self) -> Result<Box<dyn PartTable>> {
+        if self.is_image_file()? {
+            Ok(Box::new(PartTableLoop::new(&self.path)))
+        } else if self.is_dm_device() {
+            Ok(Box::new(PartTableKpartx::new(&self.path)?))
+        } else {
+            Ok(Box::new(PartTableBlkpg::new(&self.path)?))
+        }
+    }
+
+    pub fn is_image_file(&self) -> Result<bool> {
+        Ok(metadata(&self.path)
+            .with_context(|| format!("getting metadata for {}", self.path))?
+            .is_file())
+    }
+
+    pub fn is_dm_device(&self) -> bool {
+        self.path.starts_with("/dev/dm-")
+    }
+
+    /// Check whether the disk is a dm-multipath map.
+    pub fn is_multipath(&self) -> Result<bool> {
+        if !self.is_dm_device() {
+            return Ok(false);
+        }
+        let name = Path::new(&self.path)
+            .file_name()
+            .with_context(|| format!("path {} has no filename", self.path))?
+            .to_string_lossy()
+            .to_string();
+        Ok(read_dm_info(&name)?
+            .map(|(uuid, _)| HolderKind::from_dm_uuid(&uuid) == HolderKind::Multipath)
+            .unwrap_or(false))
+    }
+
+    /// If the disk is one of the paths of a dm-multipath map, return the
+    /// map's device path.
+    pub fn get_multipath_map(&self) -> Result<Option<String>> {
+        for holder in get_blkdev_holders(Path::new(&self.path))? {
+            let name = holder.file_name().unwrap_or_default().to_string_lossy();
+            if let Some((uuid, dm_name)) = read_dm_info(&name)? {
+                if HolderKind::from_dm_uuid(&uuid) == HolderKind::Multipath {
+                    return Ok(Some(format!("/dev/mapper/{dm_name}")));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn is_luks_integrity(&self) -> Result<bool> {
+        if !self.is_dm_device() {
+            return Ok(false);
+        }
+        Ok(runcmd_output!(
+            "dmsetup",
+            "info",
+            "--columns",
+            "--noheadings",
+            "-o",
+            "uuid",
+            &self.path
+        )
+        .with_context(|| format!("checking if device {} is type LUKS integrity", self.path))?
+        .trim()
+        .starts_with("CRYPT-INTEGRITY-"))
+    }
+}
+
+/// A handle to the set of device nodes for individual partitions of a
+/// device.  Must be held as long as the device nodes are needed; they might
+/// be removed upon drop.
+pub trait PartTable {
+    /// Update device nodes for the current state of the partition table
+    fn reread(&mut self) -> Result<()>;
+
+    /// Get the disk whose device nodes can be used to access partitions
+    fn disk(&mut self) -> Result<Disk>;
+}
+
+/// Device nodes for partitionable kernel devices, managed by the kernel.
+/// Rather than asking the kernel to reread the whole partition table,
+/// which fails if any partition is in use, diff the on-disk GPT against
+/// the kernel's view and add, remove, or resize individual partitions
+/// with the BLKPG ioctl.  Disks with an MBR partition table fall back to
+/// BLKRRPART, which is retried since it can fail transiently; BLKPG only
+/// touches the partitions that changed, so it isn't retried.
+///
+/// This covers whole disks and loop devices, including the ones
+/// PartTableLoop attaches to regular image files.  Device-mapper devices
+/// are never partitionable by the kernel, so BLKPG fails on them and their
+/// partitions have to be separate device-mapper devices, which
+/// PartTableKpartx creates with kpartx.
+#[derive(Debug)]
+pub struct PartTableBlkpg {
+    path: String,
+    file: File,
+}
+
+/// A single BLKPG operation.  Offsets and lengths are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlkpgOp {
+    Delete(u32),
+    Resize(u32, u64, u64),
+    Add(u32, u64, u64),
+}
+
+impl PartTableBlkpg {
+    fn new(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("opening {path}"))?;
+        Ok(Self {
+            path: path.to_string(),
+            file,
+        })
+    }
+
+    /// Read the partitions the kernel should have, as a map from partition
+    /// number to start offset and length.  Return None if the disk has an
+    /// MBR partition table rather than a GPT.
+    fn get_disk_partitions(&mut self) -> Result<Option<BTreeMap<u32, (u64, u64)>>> {
+        let mut result = BTreeMap::new();
+        // like the kernel, ignore a GPT without a protective MBR
+        if !disk_has_mbr(&mut self.file)? {
+            return Ok(Some(result));
+        }
+        let gpt = match GPT::find_from(&mut self.file) {
+            Ok(gpt) => gpt,
+            Err(gptman::Error::InvalidSignature) => return Ok(None),
+            Err(e) => return Err(e).context("reading partition table"),
+        };
+        for (i, p) in gpt.iter().filter(|(_, p)| p.is_used()) {
+            result.insert(
+                i,
+                (
+                    p.starting_lba * gpt.sector_size,
+                    (p.ending_lba - p.starting_lba + 1) * gpt.sector_size,
+                ),
+            );
+        }
+        Ok(Some(result))
+    }
+
+    /// Read the kernel's current partitions from sysfs, as a map from
+    /// partition number to start offset and length.
+    fn get_kernel_partitions(&self) -> Result<BTreeMap<u32, (u64, u64)>> {
+        let dev = self
+            .file
+            .metadata()
+            .with_context(|| format!("getting metadata for {}", self.path))?
+            .st_rdev();
+        let dir = PathBuf::from(format!("/sys/dev/block/{}:{}", major(dev), minor(dev)));
+        let read = |path: PathBuf| -> Result<u64> {
+            read_to_string(&path)
+                .with_context(|| format!("reading {}", path.display()))?
+                .trim_end()
+                .parse()
+                .with_context(|| format!("parsing {}", path.display()))
+        };
+        let mut result = BTreeMap::new();
+        for ent in read_dir(&dir).with_context(|| format!("reading {}", dir.display()))? {
+            let ent = ent.with_context(|| format!("reading {} entry", dir.display()))?;
+            let partdir = ent.path();
+            if !partdir.join("partition").exists() {
+                continue;
+            }
+            // sysfs values are always in 512-byte units
+            let number: u32 = read(partdir.join("partition"))?
+                .try_into()
+                .context("partition number too large")?;
+            let start = read(partdir.join("start"))? * 512;
+            let size = read(partdir.join("size"))? * 512;
+            result.insert(number, (start, size));
+        }
+        Ok(result)
+    }
+
+    /// Compute the operations needed to make the kernel's partitions
+    /// match the disk's.  Partitions that moved are removed and re-added.
+    fn plan(kernel: &BTreeMap<u32, (u64, u64)>, disk: &BTreeMap<u32, (u64, u64)>) -> Vec<BlkpgOp> {
+        let mut deletes = Vec::new();
+        let mut resizes = Vec::new();
+        let mut adds = Vec::new();
+        for (&number, &(start, length)) in kernel {
+            match disk.get(&number) {
+                Some(&(new_start, new_length)) if new_start == start => {
+                    if new_length != length {
+                        resizes.push(BlkpgOp::Resize(number, start, new_length));
+                    }
+                }
+                _ => deletes.push(BlkpgOp::Delete(number)),
+            }
+        }
+        for (&number, &(start, length)) in disk {
+            match kernel.get(&number) {
+                Some(&(old_start, _)) if old_start == start => (),
+                _ => adds.push(BlkpgOp::Add(number, start, length)),
+            }
+        }
+        // free up space before reusing it
+        deletes.into_iter().chain(resizes).chain(adds).collect()
+    }
+
+    fn apply(&self, op: BlkpgOp) -> Result<()> {
+        let (op_code, number, start, length) = match op {
+            BlkpgOp::Delete(number) => (ioctl::BLKPG_DEL_PARTITION, number, 0, 0),
+            BlkpgOp::Resize(number, start, length) => {
+                (ioctl::BLKPG_RESIZE_PARTITION, number, start, length)
+            }
+            BlkpgOp::Add(number, start, length) => {
+                (ioctl::BLKPG_ADD_PARTITION, number, start, length)
+            }
+        };
+        let mut partition = ioctl::BlkpgPartition {
+            start: start
+                .try_into()
+                .context("partition offset doesn't fit in i64")?,
+            length: length
+                .try_into()
+                .context("partition length doesn't fit in i64")?,
+            pno: number.try_into().context("partition number too large")?,
+            devname: [0; 64],
+            volname: [0; 64],
+        };
+        let arg = ioctl::BlkpgIoctlArg {
+            op: op_code,
+            flags: 0,
+            datalen: std::mem::size_of::<ioctl::BlkpgPartition>() as c_int,
+            data: &mut partition as *mut _ as *mut libc::c_void,
+        };
+        match unsafe { ioctl::blkpg(self.file.as_raw_fd(), &arg) } {
+            Ok(_) => Ok(()),
+            Err(Errno::EBUSY) => Err(anyhow!("partition {} is in use", number)),
+            Err(e) => Err(anyhow!(e)),
+        }
+        .with_context(|| match op {
+            BlkpgOp::Delete(_) => format!("removing partition {number} of {}", self.path),
+            BlkpgOp::Resize(..) => format!("resizing partition {number} of {}", self.path),
+            BlkpgOp::Add(..) => format!("adding partition {number} of {}", self.path),
+        })
+    }
+}
+
+impl PartTable for PartTableBlkpg {
+    fn reread(&mut self) -> Result<()> {
+        match self.get_disk_partitions()? {
+            Some(disk) => {
+                for op in Self::plan(&self.get_kernel_partitions()?, &disk) {
+                    self.apply(op)?;
+                }
+            }
+            None => reread_partition_table(&mut self.file, true)?,
+        }
+        udev_settle()
+    }
+
+    fn disk(&mut self) -> Result<Disk> {
+        Disk::new(&self.path)
+    }
+}
+
+/// Device nodes for non-partitionable kernel devices, managed by running
+/// kpartx to parse the partition table and create device-mapper devices for
+/// each partition.
+#[derive(Debug)]
+pub struct PartTableKpartx {
+    path: String,
+    need_teardown: bool,
+}
+
+impl PartTableKpartx {
+    fn new(path: &str) -> Result<Self> {
+        let mut table = Self {
+            path: path.to_string(),
+            need_teardown: !Self::already_set_up(path)?,
+        };
+        // create/sync partition devices if missing
+        table.reread()?;
+        Ok(table)
+    }
+
+    // We only want to kpartx -d on drop if we're the one initially
+    // creating the partition devices.  There's no good way to detect
+    // this.
+    fn already_set_up(path: &str) -> Result<bool> {
+        let re = Regex::new(r"^p[0-9]+$").expect("compiling RE");
+        let expected = Path::new(path)
+            .file_name()
+            .with_context(|| format!("getting filename of {path}"))?
+            .to_os_string()
+            .into_string()
+            .map_err(|_| anyhow!("converting filename of {}", path))?;
+        for ent in read_dir("/dev/mapper").context("listing /dev/mapper")? {
+            let ent = ent.context("reading /dev/mapper entry")?;
+            let found = ent.file_name().into_string().map_err(|_| {
+                anyhow!(
+                    "converting filename of {}",
+                    Path::new(&ent.file_name()).display()
+                )
+            })?;
+            if found.starts_with(&expected) && re.is_match(&found[expected.len()..]) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn run_kpartx(&self, flag: &str) -> Result<()> {
+        // Swallow stderr on success.  Avoids spurious warnings:
+        //   GPT:Primary header thinks Alt. header is not at the end of the disk.
+        //   GPT:Alternate GPT header not at the end of the disk.
+        //   GPT: Use GNU Parted to correct GPT errors.
+        //
+        // By default, kpartx waits for udev to settle before returning,
+        // but this blocks indefinitely inside a container.  See e.g.
+        //   https://github.com/moby/moby/issues/22025
+        // Use -n to skip blocking on udev, and then manually settle.
+        runcmd_output!("kpartx", flag, "-n", &self.path)?;
+        udev_settle()?;
+        Ok(())
+    }
+}
+
+impl PartTable for PartTableKpartx {
+    fn reread(&mut self) -> Result<()> {
+        let delay = 1;
+        for _ in 0..4 {
+            match self.run_kpartx("-u") {
+                Ok(()) => return Ok(()),
+                Err(e) => eprintln!("Error: {e}"),
+            }
+            eprintln!("Retrying in {delay} second");
+            sleep(Duration::from_secs(delay));
+        }
+        self.run_kpartx("-u")
+    }
+
+    fn disk(&mut self) -> Result<Disk> {
+        Disk::new(&self.path)
+    }
+}
+
+impl Drop for PartTableKpartx {
+    /// If we created the partition devices (rather than finding them
+    /// already existing), delete them afterward so we don't leave DM
+    /// devices attached to the specified disk.
+    fn drop(&mut self) {
+        if self.need_teardown {
+            if let Err(e) = self.run_kpartx("-d") {
+                eprintln!("{e}")
+            }
+        }
+    }
+}
+
+/// Device nodes for partitions of a regular disk image file, managed by
+/// attaching the file to a loop device with partition scanning enabled and
+/// then updating its partitions with BLKPG, like PartTableBlkpg.  The loop
+/// device is only attached when partitions are needed, so plain image
+/// writes don't require loop device support.
+#[derive(Debug)]
+pub struct PartTableLoop {
+    path: String,
+    loop_device: Option<LoopDevice>,
+}
+
+impl PartTableLoop {
+    fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            loop_device: None,
+        }
+    }
+}
+
+impl PartTable for PartTableLoop {
+    fn reread(&mut self) -> Result<()> {
+        // If the loop device isn't attached yet, the kernel will scan the
+        // partitions when it is.
+        match self.loop_device.as_mut() {
+            Some(loop_device) => loop_device.reread(),
+            None => Ok(()),
+        }
+    }
+
+    fn disk(&mut self) -> Result<Disk> {
+        if self.loop_device.is_none() {
+            // don't rely on the kernel's partition scan having finished
+            let mut loop_device = LoopDevice::attach(&self.path)?;
+            loop_device.reread()?;
+            self.loop_device = Some(loop_device);
+        }
+        Disk::new(&self.loop_device.as_ref().expect("loop device missing").path)
+    }
+}
+
+/// A loop device attached to a file with partition scanning enabled.
+/// Detached on drop.
+#[derive(Debug)]
+struct LoopDevice {
+    path: String,
+    file: File,
+    table: PartTableBlkpg,
+}
+
+impl LoopDevice {
+    fn attach(backing_path: &str) -> Result<Self> {
+        let backing = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(backing_path)
+            .with_context(|| format!("opening {backing_path}"))?;
+        let control = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/loop-control")
+            .context("opening /dev/loop-control")?;
+        // Another process may grab the free loop device before we attach
+        // to it.  Retry a few times before giving up.
+        let mut tries = 0;
+        let (path, file) = loop {
+            let index = unsafe { ioctl::loop_ctl_get_free(control.as_raw_fd()) }
+                .context("finding free loop device")?;
+            let path = format!("/dev/loop{index}");
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("opening {path}"))?;
+            match unsafe { ioctl::loop_set_fd(file.as_raw_fd(), backing.as_raw_fd()) } {
+                Ok(_) => break (path, file),
+                Err(Errno::EBUSY) if tries < 10 => tries += 1,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("attaching {backing_path} to {path}"))
+                }
+            }
+        };
+
+        // enable partition scanning, and have the kernel detach the device
+        // once it's no longer in use even if we crash
+        let mut info = ioctl::LoopInfo64 {
+            lo_device: 0,
+            lo_inode: 0,
+            lo_rdevice: 0,
+            lo_offset: 0,
+            lo_sizelimit: 0,
+            lo_number: 0,
+            lo_encrypt_type: 0,
+            lo_encrypt_key_size: 0,
+            lo_flags: ioctl::LO_FLAGS_PARTSCAN | ioctl::LO_FLAGS_AUTOCLEAR,
+            lo_file_name: [0; 64],
+            lo_crypt_name: [0; 64],
+            lo_encrypt_key: [0; 32],
+            lo_init: [0; 2],
+        };
+        for (dst, src) in info.lo_file_name[..63]
+            .iter_mut()
+            .zip(backing_path.as_bytes())
+        {
+            *dst = *src;
+        }
+        if let Err(e) = unsafe { ioctl::loop_set_status64(file.as_raw_fd(), &info) } {
+            let _ = unsafe { ioctl::loop_clr_fd(file.as_raw_fd()) };
+            return Err(e).with_context(|| format!("configuring {path}"));
+        }
+        let table = PartTableBlkpg::new(&path)?;
+        Ok(Self { path, file, table })
+    }
+
+    /// Update the loop device for the current size and partition table of
+    /// the file.
+    fn reread(&mut self) -> Result<()> {
+        let fd = self.file.as_raw_fd();
+        // the file may have grown since the loop device was attached
+        unsafe { ioctl::loop_set_capacity(fd) }
+            .with_context(|| format!("updating size of {}", self.path))?;
+        // the file was written directly, so drop the loop device's cached
+        // copy of the partition table
+        unsafe { ioctl::blkflsbuf(fd) }
+            .with_context(|| format!("flushing buffers of {}", self.path))?;
+        self.table.reread()
+    }
+}
+
+impl Drop for LoopDevice {
+    /// Detach the loop device so we don't leave it attached to the image
+    /// file.  If it's still in use, the kernel will detach it once it
+    /// isn't.
+    fn drop(&mut self) {
+        if let Err(e) = unsafe { ioctl::loop_clr_fd(self.file.as_raw_fd()) } {
+            eprintln!("detaching {}: {e}", self.path)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Partition {
+    pub path: String,
+    pub label: Option<String>,
+    pub fstype: Option<String>,
+
+    pub parent: String,
+    pub mountpoint: Option<String>,
+    pub swap: bool,
+}
+
+impl Partition {
+    /// Return start and end offsets within the disk.
+    pub fn get_offsets(path: &str) -> Result<(u64, u64)> {
+        let dev = metadata(path)
+            .with_context(|| format!("getting metadata for {path}"))?
+            .st_rdev();
+        let maj: u64 = major(dev);
+        let min: u64 = minor(dev);
+
+        let start = read_sysfs_dev_block_value_u64(maj, min, "start")?;
+        let size = read_sysfs_dev_block_value_u64(maj, min, "size")?;
+
+        // We multiply by 512 here: the kernel values are always in 512 blocks, regardless of the
+        // actual sector size of the block device. We keep the values as bytes to make things
+        // easier.
+        let start_offset: u64 = start
+            .checked_mul(512)
+            .context("start offset mult overflow")?;
+        let end_offset: u64 = start_offset
+            .checked_add(size.checked_mul(512).context("end offset mult overflow")?)
+            .context("end offset add overflow")?;
+        Ok((start_offset, end_offset))
+    }
+
+    pub fn get_holders(&self) -> Result<Vec<String>> {
+        let holders = self.get_sysfs_dir()?.join("holders");
+        let mut ret: Vec<String> = Vec::new();
+        for ent in read_dir(&holders).with_context(|| format!("reading {}", &holders.display()))? {
+            let ent = ent.with_context(|| format!("reading {} entry", &holders.display()))?;
+            ret.push(format!("/dev/{}", ent.file_name().to_string_lossy()));
+        }
+        Ok(ret)
+    }
+
+    // Try to locate the device directory in sysfs.
+    fn get_sysfs_dir(&self) -> Result<PathBuf> {
+        let basedir = Path::new("/sys/block");
+
+        // First assume we have a regular partition.
+        // /sys/block/sda/sda1
+        let devdir = basedir
+            .join(
+                Path::new(&self.parent)
+                    .file_name()
+                    .with_context(|| format!("parent {} has no filename", self.parent))?,
+            )
+            .join(
+                Path::new(&self.path)
+                    .file_name()
+                    .with_context(|| format!("path {} has no filename", self.path))?,
+            );
+        if devdir.exists() {
+            return Ok(devdir);
+        }
+
+        // Now assume a kpartx "partition", where the path is a symlink to
+        // an unpartitioned DM device node.
+        // /sys/block/dm-1
+        let is_link = symlink_metadata(&self.path)
+            .with_context(|| format!("reading metadata for {}", self.path))?
+            .file_type()
+            .is_symlink();
+        if is_link {
+            let target = canonicalize(&self.path)
+                .with_context(|| format!("getting absolute path to {}", self.path))?;
+            let devdir = basedir.join(
+                target
+                    .file_name()
+                    .with_context(|| format!("target {} has no filename", target.display()))?,
+            );
+            if devdir.exists() {
+                return Ok(devdir);
+            }
+        }
+
+        // Give up
+        bail!(
+            "couldn't find /sys/block directory for partition {} of {}",
+            &self.path,
+            &self.parent
+        );
+    }
+}
+
+/// A device stacked on top of a partition or another holder, such as an
+/// LVM logical volume, dm-crypt mapping, or md array.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Holder {
+    pub path: String,
+    pub kind: HolderKind,
+    /// Device-mapper name, if any.
+    pub name: Option<String>,
+    pub mountpoints: Vec<String>,
+    pub swap: bool,
+    /// Devices this holder is built on, recursively.
+    pub deps: Vec<String>,
+    pub holders: Vec<Holder>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolderKind {
+    Lvm,
+    Crypt,
+    Multipath,
+    DeviceMapper,
+    Md,
+    Other,
+}
+
+impl HolderKind {
+    /// Classify a device-mapper device by the subsystem prefix of its
+    /// DM UUID.
+    fn from_dm_uuid(uuid: &str) -> Self {
+        if uuid.starts_with("LVM-") {
+            Self::Lvm
+        } else if uuid.starts_with("CRYPT-") {
+            Self::Crypt
+        } else if uuid.starts_with("mpath-") {
+            Self::Multipath
+        } else {
+            Self::DeviceMapper
+        }
+    }
+}
+
+impl Holder {
+    /// Recursively find the holders of a partition.
+    pub fn find_for_partition(part: &Partition) -> Result<Vec<Holder>> {
+        part.get_holders()?
+            .iter()
+            .map(|path| Self::new(Path::new(path)))
+            .collect()
+    }
+
+    fn new(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .with_context(|| format!("path {} has no filename", path.display()))?
+            .to_string_lossy()
+            .to_string();
+        let (kind, dm_name) = if let Some((uuid, dm_name)) = read_dm_info(&name)? {
+            (HolderKind::from_dm_uuid(&uuid), Some(dm_name))
+        } else if Path::new("/sys/block").join(&name).join("md").exists() {
+            (HolderKind::Md, None)
+        } else {
+            (HolderKind::Other, None)
+        };
+        Ok(Self {
+            path: path.display().to_string(),
+            kind,
+            name: dm_name,
+            mountpoints: get_mountpoints(path)?,
+            swap: is_swap(path)?,
+            deps: get_blkdev_deps_recursing(path)?
+                .iter()
+                .map(|dep| dep.display().to_string())
+                .collect(),
+            holders: get_blkdev_holders(path)?
+                .iter()
+                .map(|path| Self::new(path))
+                .collect::<Result<Vec<_>>>()?,
+        })
+    }
+
+    /// Return the devices this holder is built on, other than the
+    /// specified partitions and intermediate device-mapper devices.  For
+    /// example, an md array or LVM logical volume spanning multiple disks.
+    pub fn other_devices(&self, disk_parts: &[String]) -> Vec<String> {
+        self.deps
+            .iter()
+            .filter(|dep| !disk_parts.contains(dep) && !dep.starts_with("/dev/dm-"))
+            .cloned()
+            .collect()
+    }
+
+    /// Return the holders in the tree rooted at this holder that are also
+    /// built on devices other than the specified partitions.
+    pub fn find_spanning(&self, disk_parts: &[String]) -> Vec<&Holder> {
+        let mut ret = Vec::new();
+        if !self.other_devices(disk_parts).is_empty() {
+            ret.push(self);
+        }
+        for holder in &self.holders {
+            ret.extend(holder.find_spanning(disk_parts));
+        }
+        ret
+    }
+
+    /// Return all devices in the tree rooted at this holder.
+    pub fn all_paths(&self) -> Vec<String> {
+        let mut ret = vec![self.path.clone()];
+        for holder in &self.holders {
+            ret.extend(holder.all_paths());
+        }
+        ret
+    }
+
+    pub fn describe(&self) -> String {
+        let kind = match self.kind {
+            HolderKind::Lvm => "LVM volume",
+            HolderKind::Crypt => "dm-crypt mapping",
+            HolderKind::Multipath => "multipath map",
+            HolderKind::DeviceMapper => "device-mapper device",
+            HolderKind::Md => "md array",
+            HolderKind::Other => return self.path.clone(),
+        };
+        match &self.name {
+            Some(name) => format!("{} ({} {})", self.path, kind, name),
+            None => format!("{} ({})", self.path, kind),
+        }
+    }
+
+    /// Unmount and deactivate this holder, after first doing the same for
+    /// its own holders.
+    pub fn teardown(&self) -> Result<()> {
+        for holder in &self.holders {
+            holder.teardown()?;
+        }
+        release_device(Path::new(&self.path), &self.mountpoints, self.swap)?;
+        match (self.kind, &self.name) {
+            // only deactivate this LV, not the rest of its VG
+            (HolderKind::Lvm, _) => {
+                eprintln!("Deactivating LVM logical volume {}", self.path);
+                runcmd!("lvchange", "--activate", "n", &self.path)
+            }
+            (HolderKind::Crypt, Some(name)) => {
+                eprintln!("Closing dm-crypt mapping {name}");
+                runcmd!("cryptsetup", "close", name)
+            }
+            (HolderKind::Multipath | HolderKind::DeviceMapper, Some(name)) => {
+                eprintln!("Removing device-mapper device {name}");
+                runcmd!("dmsetup", "remove", name)
+            }
+            (HolderKind::Md, _) => {
+                eprintln!("Stopping md array {}", self.path);
+                runcmd!("mdadm", "--stop", &self.path)
+            }
+            _ => bail!("don't know how to release {}", self.path),
+        }
+        .with_context(|| format!("releasing {}", self.path))
+    }
+}
+
+/// Read the DM UUID and name of a device-mapper device, given its kernel
+/// name (e.g. "dm-0").  Return None if it isn't a device-mapper device.
+fn read_dm_info(name: &str) -> Result<Option<(String, String)>> {
+    let dir = Path::new("/sys/block").join(name).join("dm");
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let read = |field: &str| {
+        let path = dir.join(field);
+        read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))
+            .map(|s| s.trim_end().to_string())
+    };
+    Ok(Some((read("uuid")?, read("name")?)))
+}
+
+/// Unmount a device from all its mountpoints, innermost first, and
+/// disable it if it's swap.
+pub fn release_device(device: &Path, mountpoints: &[String], swap: bool) -> Result<()> {
+    let mut mountpoints = mountpoints.to_vec();
+    mountpoints.sort_by_key(|mp| std::cmp::Reverse(mp.len()));
+    for mountpoint in mountpoints {
+        eprintln!("Unmounting {} from {}", device.display(), mountpoint);
+        mount::umount(mountpoint.as_str())
+            .with_context(|| format!("unmounting {} from {}", device.display(), mountpoint))?;
+    }
+    if swap {
+        eprintln!("Disabling swap on {}", device.display());
+        runcmd!("swapoff", device)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct Mount {
+    device: String,
+    mountpoint: PathBuf,
+    /// Whether we own this mount.
+    owned: bool,
+    /// Read-only marking of the device, released after unmounting.
+    _read_only: Option<ReadOnlyDevice>,
+}
+
+impl Mount {
+    pub fn try_mount(device: &str, fstype: &str, flags: mount::MsFlags) -> Result<Mount> {
+        Self::mount_private(device, fstype, flags, None)
+    }
+
+    /// Mount a filesystem to read it without modifying the device.  A
+    /// read-only mount still replays the filesystem journal, so disable
+    /// journal recovery, and mark the device read-only in case the
+    /// filesystem doesn't honor that.
+    pub fn try_mount_inspect(device: &str, fstype: &str) -> Result<Mount> {
+        let data = match fstype {
+            "ext3" | "ext4" => Some("noload"),
+            "xfs" => Some("norecovery"),
+            "btrfs" => Some("nologreplay"),
+            _ => None,
+        };
+        let read_only = ReadOnlyDevice::new(device)?;
+        let mut mount = Self::mount_private(device, fstype, mount::MsFlags::MS_RDONLY, data)?;
+        mount._read_only = read_only;
+        Ok(mount)
+    }
+
+    fn mount_private(
+        device: &str,
+        fstype: &str,
+        flags: mount::MsFlags,
+        data: Option<&str>,
+    ) -> Result<Mount> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("coreos-installer-")
+            .tempdir()
+            .context("creating temporary directory")?;
+        // avoid auto-cleanup of tempdir, which could recursively remove
+        // the partition contents if umount failed
+        let mountpoint = tempdir.into_path();
+
+        // Ensure we're in a private mount namespace so the mount isn't
+        // visible to the rest of the system.  Multiple unshare calls
+        // should be safe.
+        sched::unshare(sched::CloneFlags::CLONE_NEWNS).context("unsharing mount namespace")?;
+
+        mount::mount::<str, Path, str, str>(Some(device), &mountpoint, Some(fstype), flags, data)
+            .with_context(|| format!("mounting device {} on {}", device, mountpoint.display()))?;
+
+        Ok(Mount {
+            device: device.to_string(),
+            mountpoint,
+            owned: true,
+            _read_only: None,
+        })
+    }
+
+    pub fn from_existing(path: &str) -> Result<Mount> {
+        let mounts = read_to_string("/proc/self/mounts").context("reading mount table")?;
+        for line in mounts.lines() {
+            let mount: Vec<&str> = line.split_whitespace().collect();
+            // see https://man7.org/linux/man-pages/man5/fstab.5.html
+            if mount.len() != 6 {
+                bail!("invalid line in /proc/self/mounts: {}", line);
+            }
+            if mount[1] == path {
+                return Ok(Mount {
+                    device: mount[0].to_string(),
+                    mountpoint: path.into(),
+                    owned: false,
+                    _read_only: None,
+                });
+            }
+        }
+        bail!("mountpoint {} not found", path);
+    }
+
+    pub fn device(&self) -> &str {
+        self.device.as_str()
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        self.mountpoint.as_path()
+    }
+
+    pub fn get_partition_offsets(&self) -> Result<(u64, u64)> {
+        Partition::get_offsets(&self.device)
+    }
+
+    pub fn get_filesystem_uuid(&self) -> Result<String> {
+        let devinfo = lsblk_single(Path::new(&self.device))?;
+        devinfo
+            .get("UUID")
+            .map(String::from)
+            .with_context(|| format!("filesystem {} has no UUID", self.device))
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+
+        // Unmount sometimes fails immediately after closing the last open
+        // file on the partition.  Retry several times before giving up.
+        for retries in (0..20).rev() {
+            match mount::umount(&self.mountpoint) {
+                Ok(_) => break,
+                Err(err) => {
+                    if retries == 0 {
+                        eprintln!("umounting {}: {}", self.device, err);
+                        return;
+                    } else {
+                        sleep(Duration::from_millis(100));
+                    }
+                }
+            }
+        }
+        if let Err(err) = remove_dir(&self.mountpoint) {
+            eprintln!("removing {}: {}", self.mountpoint.display(), err);
+        }
+    }
+}
+
+/// A block device marked read-only in the kernel until dropped.
+#[derive(Debug)]
+struct ReadOnlyDevice {
+    device: String,
+    file: File,
+}
+
+impl ReadOnlyDevice {
+    /// Mark the device read-only, or do nothing if it already is.
+    fn new(device: &str) -> Result<Option<Self>> {
+        let file = File::open(device).with_context(|| format!("opening {device}"))?;
+        let mut read_only: c_int = 0;
+        unsafe { ioctl::blkroget(file.as_raw_fd(), &mut read_only) }
+            .with_context(|| format!("getting read-only flag of {device}"))?;
+        if read_only != 0 {
+            return Ok(None);
+        }
+        Self::set(&file, 1).with_context(|| format!("marking {device} read-only"))?;
+        Ok(Some(Self {
+            device: device.to_string(),
+            file,
+        }))
+    }
+
+    fn set(file: &File, read_only: c_int) -> Result<()> {
+        unsafe { ioctl::blkroset(file.as_raw_fd(), &read_only) }?;
+        Ok(())
+    }
+}
+
+impl Drop for ReadOnlyDevice {
+    fn drop(&mut self) {
+        if let Err(e) = Self::set(&self.file, 0) {
+            eprintln!("marking {} writable: {e}", self.device);
+        }
+    }
+}
+
+/// Planned disposition of a saved partition, as reported by
+/// `install --dry-run`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SavedPartitionPlan {
+    pub index: u32,
+    pub new_index: u32,
+    pub label: String,
+    pub start_offset: u64,
+    pub size: u64,
+}
+
+/// A move of a saved partition's data to a new location on the disk.
+/// Offsets and length are in bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PartitionMove {
+    pub index: u32,
+    pub label: String,
+    pub from: u64,
+    pub to: u64,
+    pub length: u64,
+}
+
+/// A saved partition in the portable stash format written by
+/// `dev partitions stash`.  LBAs are in units of the stash's sector size.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StashedPartition {
+    pub index: u32,
+    pub label: String,
+    #[serde_as(as = "DisplayFromStr")]
+    pub type_guid: Uuid,
+    #[serde_as(as = "DisplayFromStr")]
+    pub guid: Uuid,
+    pub start_lba: u64,
+    pub end_lba: u64,
+    pub attributes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct PartitionStash {
+    sector_size: u64,
+    partitions: Vec<StashedPartition>,
+}
+
+#[derive(Debug)]
+pub struct SavedPartitions {
+    sector_size: u64,
+    partitions: Vec<(u32, GPTPartitionEntry)>,
+}
+
+impl SavedPartitions {
+    /// Create a SavedPartitions for a block device with a sector size.
+    pub fn new_from_disk(disk: &mut File, filters: &[PartitionFilter]) -> Result<Self> {
+        if !disk
+            .metadata()
+            .context("getting disk metadata")?
+            .file_type()
+            .is_block_device()
+        {
+            bail!("specified file is not a block device");
+        }
+        Self::new(disk, get_sector_size(disk)?.get() as u64, filters)
+    }
+
+    /// Create a SavedPartitions for a file with a specified imputed sector
+    /// size.  Useful for disk image files and unit tests, and fails on a
+    /// real disk.
+    pub fn new_from_file(
+        disk: &mut File,
+        sector_size: u64,
+        filters: &[PartitionFilter],
+    ) -> Result<Self> {
+        if disk
+            .metadata()
+            .context("getting disk metadata")?
+            .file_type()
+            .is_block_device()
+        {
+            bail!("called new_from_file() on a block device");
+        }
+        match sector_size {
+            512 | 4096 => (),
+            _ => bail!("specified unreasonable sector size {}", sector_size),
+        }
+        Self::new(disk, sector_size, filters)
+    }
+
+    fn new(disk: &mut File, sector_size: u64, filters: &[PartitionFilter]) -> Result<Self> {
+        // if there are no filters, ignore existing GPT, since we're going to
+        // overwrite it
+        if filters.is_empty() {
+            return Ok(Self {
+                sector_size,
+                partitions: Vec::new(),
+            });
+        }
+
+        // read GPT
+        let gpt = match GPT::find_from(disk) {
+            Ok(gpt) => Some(gpt),
+            Err(gptman::Error::InvalidSignature) => None,
+            Err(e) => return Err(e).context("reading partition table"),
+        };
+
+        let mut partitions = Vec::new();
+        if let Some(gpt) = gpt {
+            // cross-check GPT sector size
+            Self::verify_gpt_sector_size(&gpt, sector_size)?;
+
+            // save partitions accepted by filters
+            for (i, p) in gpt.iter() {
+                if Self::matches_filters(i, p, sector_size, filters) {
+                    partitions.push((i, p.clone()));
+                }
+            }
+        } else if filters
+            .iter()
+            .any(|f| !matches!(f, PartitionFilter::Label(_)))
+            && disk_has_mbr(disk).context("checking if disk has an MBR")?
+        {
+            // MBR partitions have no labels, so label filters can't select
+            // them.  Convert the selected partitions into GPT entries;
+            // they'll be written into the new GPT like any other saved
+            // partition.
+            let filters: Vec<PartitionFilter> = filters
+                .iter()
+                .filter(|f| !matches!(f, PartitionFilter::Label(_)))
+                .cloned()
+                .collect();
+            for (i, p) in read_mbr_partitions(disk, sector_size)? {
+                let entry = p.to_gpt_entry();
+                if Self::matches_filters(i, &entry, sector_size, &filters) {
+                    partitions.push((i, entry));
+                }
+            }
+        }
+        // otherwise there's no partition table on this disk, so no
+        // partitions to save
+
+        let result = Self {
+            sector_size,
+            partitions,
+        };
+
+        // Test restoring the saved partitions to a temporary file.  If the
+        // resulting partition table contains invalid data (e.g. duplicate
+        // partition GUIDs) we need to know now, before the caller
+        // overwrites the partition table.  Otherwise we could fail to
+        // restore, clear the table, and fail to restore _again_ to the
+        // empty table.
+        if !result.partitions.is_empty() {
+            let len = disk.seek(SeekFrom::End(0)).context("getting disk size")?;
+            let mut temp = tempfile::tempfile().context("creating dry run image")?;
+            temp.set_len(len)
+                .with_context(|| format!("setting test image size to {len}"))?;
+            result.overwrite(&mut temp).context(
+                "failed dry run restoring saved partitions; input partition table may be invalid",
+            )?;
+        }
+
+        Ok(result)
+    }
+
+    fn verify_disk_sector_size(&self, disk: &File) -> Result<()> {
+        if !disk
+            .metadata()
+            .context("getting disk metadata")?
+            .file_type()
+            .is_block_device()
+        {
+            return Ok(());
+        }
+        let disk_sector_size = get_sector_size(disk)?.get() as u64;
+        if disk_sector_size != self.sector_size {
+            bail!(
+                "disk sector size {} doesn't match expected {}",
+                disk_sector_size,
+                self.sector_size
+            );
+        }
+        Ok(())
+    }
+
+    fn verify_gpt_sector_size(gpt: &GPT, sector_size: u64) -> Result<()> {
+        if gpt.sector_size != sector_size {
+            bail!(
+                "GPT sector size {} doesn't match expected {}",
+                gpt.sector_size,
+                sector_size
+            );
+        }
+        Ok(())
+    }
+
+    fn matches_filters(
+        i: u32,
+        p: &GPTPartitionEntry,
+        sector_size: u64,
+        filters: &[PartitionFilter],
+    ) -> bool {
+        use PartitionFilter::*;
+        if !p.is_used() {
+            return false;
+        }
+        let size = p.size().unwrap_or(0).saturating_mul(sector_size);
+        filters.iter().any(|f| match f {
+            Index(Some(first), _) if first.get() > i => false,
+            Index(_, Some(last)) if last.get() < i => false,
+            Index(_, _) => true,
+            Label(glob) if glob.matches(p.partition_name.as_str()) => true,
+            Type(guid) => Uuid::from_bytes_le(p.partition_type_guid) == *guid,
+            Guid(guid) => Uuid::from_bytes_le(p.unique_partition_guid) == *guid,
+            Size(Some(min), _) if *min > size => false,
+            Size(_, Some(max)) if *max < size => false,
+            Size(_, _) => true,
+            _ => false,
+        })
+    }
+
+    /// Unconditionally write the saved partitions, and only the saved
+    /// partitions, to the disk.  Write a protective MBR and overwrite any
+    /// MBR boot code.  Updating the kernel partition table is the caller's
+    /// responsibility.
+    pub fn overwrite(&self, disk: &mut File) -> Result<()> {
+        // create GPT
+        self.verify_disk_sector_size(disk)?;
+        let mut gpt = GPT::new_from(disk, self.sector_size, *Uuid::new_v4().as_bytes())
+            .context("creating new GPT")?;
+
+        // add partitions
+        for (i, p) in &self.partitions {
+            gpt[*i] = p.clone();
+        }
+
+        // write GPT
+        gpt.write_into(disk).context("writing new GPT")?;
+
+        // Overwrite only the parts of the MBR that don't contain the
+        // partition table, then write protective MBR.  This ensures that
+        // there's no time window without an MBR, during which the kernel
+        // would refuse to read the GPT.
+        disk.rewind().context("seeking to MBR")?;
+        disk.write(&[0u8; 446])
+            .context("overwriting MBR boot code")?;
+        if self.sector_size > 512 {
+            disk.seek(SeekFrom::Start(512))
+                .context("seeking to end of MBR")?;
+            disk.write(&vec![0u8; self.sector_size as usize - 512])
+                .context("overwriting end of MBR")?;
+        }
+        GPT::write_protective_mbr_into(disk, self.sector_size).context("writing protective MBR")?;
+
+        Ok(())
+    }
+
+    /// If any partitions are saved, merge them into the GPT from source,
+    /// which must be valid, and write a protective MBR with the correct
+    /// protective partition size.  Updating the kernel partition table is
+    /// the caller's responsibility.
+    pub fn merge(&self, source: &mut (impl Read + Seek), disk: &mut File) -> Result<()> {
+        if self.partitions.is_empty() {
+            return Ok(());
+        }
+
+        // read GPT
+        self.verify_disk_sector_size(disk)?;
+        let mut gpt =
+            GPT::find_from(source).context("couldn't read partition table from source")?;
+        Self::verify_gpt_sector_size(&gpt, self.sector_size)?;
+        // The GPT thinks the disk is the size of the install image.
+        // Update sizing.
+        gpt.header
+            .update_from(disk, self.sector_size)
+            .context("updating GPT header")?;
+
+        // merge saved partitions into partition table
+        for (i, next, p) in self.renumber(&gpt) {
+            eprintln!(
+                "Saving partition {} (\"{}\") to new partition {}",
+                i, p.partition_name, next
+            );
+            gpt[next] = p.clone();
+        }
+
+        // write
+        gpt.write_into(disk).context("writing updated GPT")?;
+
+        // update protective partition size
+        GPT::write_protective_mbr_into(disk, self.sector_size).context("writing protective MBR")?;
+
+        Ok(())
+    }
+
+    /// Report where each saved partition would end up if merged into the
+    /// GPT from source, without writing anything.
+    pub fn plan_merge(&self, source: &mut (impl Read + Seek)) -> Result<Vec<SavedPartitionPlan>> {
+        if self.partitions.is_empty() {
+            return Ok(Vec::new());
+        }
+        let gpt = GPT::find_from(source).context("couldn't read partition table from source")?;
+        Self::verify_gpt_sector_size(&gpt, self.sector_size)?;
+        self.renumber(&gpt)
+            .into_iter()
+            .map(|(i, new_index, p)| {
+                Ok(SavedPartitionPlan {
+                    index: i,
+                    new_index,
+                    label: p.partition_name.as_str().to_string(),
+                    start_offset: p
+                        .starting_lba
+                        .checked_mul(self.sector_size)
+                        .context("overflow calculating partition start")?,
+                    size: p
+                        .size()
+                        .ok()
+                        .and_then(|sectors| sectors.checked_mul(self.sector_size))
+                        .context("overflow calculating partition size")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Assign a partition number in the GPT from the install image to each
+    /// saved partition.  Returns (original number, new number, entry).
+    fn renumber(&self, gpt: &GPT) -> Vec<(u32, u32, &GPTPartitionEntry)> {
+        // find partition number one larger than the largest used one
+        let mut next = gpt
+            .iter()
+            .fold(1, |prev, (i, e)| if e.is_used() { i + 1 } else { prev });
+        let mut result = Vec::new();
+        for (i, p) in &self.partitions {
+            // use the next partition number in the sequence if we have to,
+            // or the partition's original number if it's larger
+            next = next.max(*i);
+            result.push((*i, next, p));
+            next += 1;
+        }
+        result
+    }
+
+    /// Get the sector size in use for this partition table.
+    pub fn get_sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// Get the byte offset of the first byte not to be overwritten, if any,
+    /// plus a description of the partition at that offset.
+    pub fn get_offset(&self) -> Result<Option<(u64, String)>> {
+        match self.partitions.iter().min_by_key(|(_, p)| p.starting_lba) {
+            None => Ok(None),
+            Some((i, p)) => Ok(Some((
+                p.starting_lba
+                    .checked_mul(self.sector_size)
+                    .context("overflow calculating partition start")?,
+                format!("partition {} (\"{}\")", i, p.partition_name.as_str()),
+            ))),
+        }
+    }
+
+    pub fn is_saved(&self) -> bool {
+        !self.partitions.is_empty()
+    }
+
+    /// Get the saved partitions in portable form.
+    pub fn get_stashed(&self) -> Vec<StashedPartition> {
+        self.partitions
+            .iter()
+            .map(|(i, p)| StashedPartition {
+                index: *i,
+                label: p.partition_name.as_str().to_string(),
+                type_guid: Uuid::from_bytes_le(p.partition_type_guid),
+                guid: Uuid::from_bytes_le(p.unique_partition_guid),
+                start_lba: p.starting_lba,
+                end_lba: p.ending_lba,
+                attributes: p.attribute_bits,
+            })
+            .collect()
+    }
+
+    /// Write the saved partitions to a portable stash file.
+    pub fn write_stash(&self, out: impl Write) -> Result<()> {
+        let stash = PartitionStash {
+            sector_size: self.sector_size,
+            partitions: self.get_stashed(),
+        };
+        serde_json::to_writer_pretty(out, &stash).context("writing partition stash")
+    }
+
+    /// Read saved partitions from a stash file written by write_stash(),
+    /// or from a disk image containing only a GPT, as written by
+    /// `install --preserve-on-error` in older releases.
+    pub fn read_stash(input: &mut (impl Read + Seek)) -> Result<Self> {
+        let mut magic = [0u8; 1];
+        input.rewind().context("seeking to start of stash")?;
+        input
+            .read_exact(&mut magic)
+            .context("reading start of stash")?;
+        input.rewind().context("seeking to start of stash")?;
+        if magic[0] != b'{' {
+            let gpt = GPT::find_from(input).context("reading partition table from stash")?;
+            let partitions = gpt
+                .iter()
+                .filter(|(_, p)| p.is_used())
+                .map(|(i, p)| (i, p.clone()))
+                .collect();
+            return Ok(Self {
+                sector_size: gpt.sector_size,
+                partitions,
+            });
+        }
+
+        let stash: PartitionStash =
+            serde_json::from_reader(input).context("parsing partition stash")?;
+        match stash.sector_size {
+            512 | 4096 => (),
+            n => bail!("stash has unreasonable sector size {}", n),
+        }
+        let mut partitions = Vec::new();
+        for p in stash.partitions {
+            if p.index == 0 || p.start_lba > p.end_lba {
+                bail!("stash has invalid entry for partition {}", p.index);
+            }
+            partitions.push((
+                p.index,
+                GPTPartitionEntry {
+                    partition_type_guid: p.type_guid.to_bytes_le(),
+                    unique_partition_guid: p.guid.to_bytes_le(),
+                    starting_lba: p.start_lba,
+                    ending_lba: p.end_lba,
+                    attribute_bits: p.attributes,
+                    partition_name: p.label.as_str().into(),
+                },
+            ));
+        }
+        partitions.sort_unstable_by_key(|(i, _)| *i);
+        Ok(Self {
+            sector_size: stash.sector_size,
+            partitions,
+        })
+    }
+
+    /// Get the byte ranges occupied by the saved partitions, as sorted
+    /// (start, end) pairs with exclusive end.
+    pub fn get_ranges(&self) -> Result<Vec<(u64, u64)>> {
+        let mut ranges = self
+            .partitions
+            .iter()
+            .map(|(_, p)| {
+                Ok((
+                    p.starting_lba
+                        .checked_mul(self.sector_size)
+                        .context("overflow calculating partition start")?,
+                    p.ending_lba
+                        .checked_add(1)
+                        .and_then(|lba| lba.checked_mul(self.sector_size))
+                        .context("overflow calculating partition end")?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        ranges.sort_unstable();
+        Ok(ranges)
+    }
+
+    /// Get the byte ranges of a disk of the specified size which are not
+    /// occupied by saved partitions, as sorted (start, end) pairs with
+    /// exclusive end.
+    pub fn get_unsaved_ranges(&self, disk_size: u64) -> Result<Vec<(u64, u64)>> {
+        let mut result = Vec::new();
+        let mut next = 0;
+        for (start, end) in self.get_ranges()? {
+            let start = start.min(disk_size);
+            if start > next {
+                result.push((next, start));
+            }
+            next = next.max(end);
+        }
+        if disk_size > next {
+            result.push((next, disk_size));
+        }
+        Ok(result)
+    }
+
+    /// Plan moving the saved partitions that start before `image_size` to
+    /// free space after it, so the image can be written without a
+    /// collision.  Each destination is MiB-aligned and overlaps neither the
+    /// current location of any saved partition nor another destination, so
+    /// the original data stays intact until the new partition table is
+    /// written.  The last MiB of the disk is reserved for the backup GPT.
+    pub fn plan_relocation(&self, image_size: u64, disk_size: u64) -> Result<Vec<PartitionMove>> {
+        const ALIGN: u64 = 1024 * 1024;
+        let align = |offset: u64| (offset + ALIGN - 1) / ALIGN * ALIGN;
+        let limit = disk_size.saturating_sub(ALIGN);
+
+        let mut occupied = self.get_ranges()?;
+        let mut partitions: Vec<&(u32, GPTPartitionEntry)> = self.partitions.iter().collect();
+        partitions.sort_unstable_by_key(|(_, p)| p.starting_lba);
+        let mut moves = Vec::new();
+        for (i, p) in partitions {
+            let from = p.starting_lba * self.sector_size;
+            if from >= image_size {
+                continue;
+            }
+            let length = p
+                .size()
+                .context("calculating partition size")?
+                .checked_mul(self.sector_size)
+                .context("overflow calculating partition size")?;
+            let mut to = align(image_size);
+            while let Some((_, end)) = occupied
+                .iter()
+                .find(|(start, end)| to < *end && *start < to + length)
+            {
+                to = align(*end);
+            }
+            if to + length > limit {
+                bail!(
+                    "no free space after the install image to move partition {} (\"{}\")",
+                    i,
+                    p.partition_name.as_str()
+                );
+            }
+            occupied.push((to, to + length));
+            moves.push(PartitionMove {
+                index: *i,
+                label: p.partition_name.as_str().to_string(),
+                from,
+                to,
+                length,
+            });
+        }
+        Ok(moves)
+    }
+
+    /// Return a copy of the saved partitions with the specified moves
+    /// applied.
+    pub fn relocated(&self, moves: &[PartitionMove]) -> Result<Self> {
+        let mut partitions = self.partitions.clone();
+        for m in moves {
+            let (_, p) = partitions
+                .iter_mut()
+                .find(|(i, _)| *i == m.index)
+                .with_context(|| format!("couldn't find saved partition {}", m.index))?;
+            let sectors = p.size().context("calculating partition size")?;
+            p.starting_lba = m.to / self.sector_size;
+            p.ending_lba = p.starting_lba + sectors - 1;
+        }
+        Ok(Self {
+            sector_size: self.sector_size,
+            partitions,
+        })
+    }
+}
+
+fn read_sysfs_dev_block_value_u64(maj: u64, min: u64, field: &str) -> Result<u64> {
+    let s = read_sysfs_dev_block_value(maj, min, field)
+        .with_context(|| format!("reading partition {maj}:{min} {field} value from sysfs"))?;
+    s.parse().with_context(|| {
+        format!(
+            "parsing partition {}:{} {} value \"{}\" as u64",
+            maj, min, field, &s
+        )
+    })
+}
+
+fn read_sysfs_dev_block_value(maj: u64, min: u64, field: &str) -> Result<String> {
+    let path = PathBuf::from(format!("/sys/dev/block/{maj}:{min}/{field}"));
+    Ok(read_to_string(path)?.trim_end().into())
+}
+
+pub fn lsblk_single(dev: &Path) -> Result<HashMap<String, String>> {
+    BlockDevice::from_path(dev)?
+        .properties()
+        .with_context(|| format!("getting properties of {}", dev.display()))
+}
+
+/// Returns all available filesystems.
+/// rereadpt mitigates possible issue with outdated UUIDs on different
+/// paths to the same disk: after 'ignition-ostree-firstboot-uuid'
+/// '/dev/sdaX' path gets new UUID, but '/dev/sdbX/' path has an old one
+fn get_all_filesystems(rereadpt: bool) -> Result<Vec<HashMap<String, String>>> {
+    if rereadpt {
+        for dev in BlockDevice::all()? {
+            if dev.kind() != "disk" {
+                continue;
+            }
+            if let Ok(mut fd) = std::fs::File::open(dev.path()) {
+                // best-effort reread of disk that may have busy partitions; don't retry
+                let _ = reread_partition_table(&mut fd, false);
+            }
+        }
+    }
+    // The kernel updates sysfs and devtmpfs synchronously, so there's no
+    // need to wait for udev before probing the new partitions.
+    let mut result = Vec::new();
+    for dev in BlockDevice::all()? {
+        // devices can disappear while we're walking the list
+        if let Some(sb) = dev.probe().unwrap_or(None) {
+            let mut fields = HashMap::new();
+            fields.insert("NAME".to_string(), dev.path());
+            fields.insert("TYPE".to_string(), sb.fstype);
+            if let Some(label) = sb.label {
+                fields.insert("LABEL".to_string(), label);
+            }
+            if let Some(uuid) = sb.uuid {
+                fields.insert("UUID".to_string(), uuid);
+            }
+            result.push(fields);
+        }
+    }
+    Ok(result)
+}
+
+/// Returns filesystems with given label.
+/// If multiple filesystems with the label have the same UUID, we only return one of them.
+pub fn get_filesystems_with_label(label: &str, rereadpt: bool) -> Result<Vec<String>> {
+    let mut uuids = HashSet::new();
+    let result = get_all_filesystems(rereadpt)?
+        .iter()
+        .filter(|v| v.get("LABEL").map(|l| l.as_str()) == Some(label))
+        .filter(|v| match v.get("UUID") {
+            Some(uuid) if !uuid.is_empty() => uuids.insert(uuid),
+            _ => true,
+        })
+        .filter_map(|v| v.get("NAME").map(<_>::to_owned))
+        .collect();
+    Ok(result)
+}
+
+/// Get the properties of a device and, if with_deps is set, every device
+/// built on it, using lsblk's property names.
+pub fn lsblk(dev: &Path, with_deps: bool) -> Result<Vec<HashMap<String, String>>> {
+    let dev = BlockDevice::from_path(dev)?;
+    let devices = if with_deps {
+        dev.descendants()?
+    } else {
+        vec![dev]
+    };
+    devices
+        .iter()
+        .map(|dev| {
+            dev.properties()
+                .with_context(|| format!("getting properties of {}", dev.path()))
+        })
+        .collect()
+}
+
+/// Identifying properties of a whole-disk block device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskInfo {
+    pub path: String,
+    pub size: u64,
+    pub read_only: bool,
+    pub rotational: bool,
+    pub transport: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+    pub by_path: Vec<String>,
+    /// No backing hardware, e.g. zram
+    pub virtual_device: bool,
+    /// For one path of a dm-multipath map, the map's device path
+    pub multipath: Option<String>,
+}
+
+/// Get the whole-disk block devices on the system, including dm-multipath
+/// maps.  A map is identified by the hardware properties of its paths.
+pub fn get_disks() -> Result<Vec<DiskInfo>> {
+    let by_path = get_by_path_links()?;
+    let links = |dev: &BlockDevice| by_path.get(&dev.path()).cloned().unwrap_or_default();
+    let mut result = Vec::new();
+    for dev in BlockDevice::all()? {
+        let path = dev.path();
+        let (hw, by_path, multipath) = match dev.kind().as_str() {
+            "disk" => {
+                let map = dev
+                    .children()
+                    .with_context(|| format!("getting holders of {path}"))?
+                    .into_iter()
+                    .find(|holder| holder.kind() == "mpath");
+                (dev.clone(), links(&dev), map.map(|map| map.path()))
+            }
+            "mpath" => {
+                let paths = dev
+                    .parents()
+                    .with_context(|| format!("getting paths of {path}"))?;
+                let hw = match paths.first() {
+                    Some(hw) => hw.clone(),
+                    // all paths have been removed
+                    None => continue,
+                };
+                let mut by_path: Vec<String> = paths.iter().flat_map(links).collect();
+                by_path.sort();
+                (hw, by_path, None)
+            }
+            _ => continue,
+        };
+        result.push(DiskInfo {
+            by_path,
+            size: dev
+                .size()
+                .with_context(|| format!("getting size of {path}"))?,
+            read_only: dev.read_only(),
+            rotational: hw.rotational(),
+            transport: hw.transport(),
+            model: hw.model(),
+            serial: hw.serial(),
+            wwn: hw.wwn(),
+            virtual_device: hw.is_virtual(),
+            multipath,
+            path,
+        });
+    }
+    Ok(result)
+}
+
+/// Map device paths to their /dev/disk/by-path symlinks.
+fn get_by_path_links() -> Result<HashMap<String, Vec<String>>> {
+    let dir = Path::new("/dev/disk/by-path");
+    let mut result: HashMap<String, Vec<String>> = HashMap::new();
+    if !dir.exists() {
+        return Ok(result);
+    }
+    for ent in read_dir(dir).with_context(|| format!("listing {}", dir.display()))? {
+        let ent = ent.with_context(|| format!("reading {} entry", dir.display()))?;
+        let link = ent.path();
+        let target = match canonicalize(&link) {
+            Ok(target) => target,
+            // dangling symlink
+            Err(_) => continue,
+        };
+        if let (Some(link), Some(target)) = (link.to_str(), target.to_str()) {
+            result
+                .entry(target.to_string())
+                .or_default()
+                .push(link.to_string());
+        }
+    }
+    for links in result.values_mut() {
+        links.sort();
+    }
+    Ok(result)
+}
+
+/// This is a bit fuzzy, but... this function will return every block device in the parent
+/// hierarchy of `device` capable of containing other partitions. So e.g. parent devices of type
+/// "part" doesn't match, but "disk" and "mpath" does.
+pub fn find_parent_devices(device: &str) -> Result<Vec<String>> {
+    let mut parents = Vec::new();
+    // skip the device itself
+    for dev in BlockDevice::from_path(Path::new(device))?
+        .ancestors()?
+        .iter()
+        .skip(1)
+    {
+        let kind = dev.kind();
+        if kind == "disk" {
+            parents.push(dev.path());
+        } else if kind == "mpath" {
+            parents.push(dev.path());
+            // we don't need to know what disks back the multipath
+            break;
+        }
+    }
+    if parents.is_empty() {
+        bail!("no parent devices found for {}", device);
+    }
+    Ok(parents)
+}
+
+/// Find ESP partitions which sit at the same hierarchy level as `device`.
+pub fn find_colocated_esps(device: &str) -> Result<Vec<String>> {
+    const ESP_TYPE_GUID: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+    let esp_type = Uuid::parse_str(ESP_TYPE_GUID).expect("invalid partition type GUID");
+
+    // first, get the parent device
+    let parent_devices = find_parent_devices(device)
+        .with_context(|| format!("while looking for colocated ESPs of '{device}'"))?;
+
+    // now, look for all ESPs on those devices
+    let mut esps = Vec::new();
+    for parent_device in parent_devices {
+        let mut f =
+            File::open(&parent_device).with_context(|| format!("opening {}", parent_device))?;
+        let gpt = match GPT::find_from(&mut f) {
+            Ok(gpt) => gpt,
+            // not GPT-partitioned, so no ESPs
+            Err(_) => continue,
+        };
+        let esp_numbers: HashSet<u32> = gpt
+            .iter()
+            .filter(|(_, p)| p.is_used() && Uuid::from_bytes_le(p.partition_type_guid) == esp_type)
+            .map(|(n, _)| n)
+            .collect();
+        for part in BlockDevice::from_path(Path::new(&parent_device))?.children()? {
+            if part.kind() != "part" {
+                continue;
+            }
+            if let Some(n) = part.partition_number() {
+                if esp_numbers.contains(&n) {
+                    esps.push(part.path());
+                }
+            }
+        }
+    }
+    Ok(esps)
+}
+
+/// This is basically a Rust version of:
+/// https://github.com/coreos/coreos-assembler/blob/d3c7ec094a02/src/cmd-buildextend-live#L492-L495
+pub fn find_efi_vendor_dir(efi_mount: &Mount) -> Result<PathBuf> {
+    let p = efi_mount.mountpoint().join("EFI");
+    let mut vendor_dir: Vec<PathBuf> = Vec::new();
+    for ent in p.read_dir()? {
+        let ent = ent.with_context(|| format!("reading directory entry in {}", p.display()))?;
+        if !ent.file_type()?.is_dir() {
+            continue;
+        }
+        let path = ent.path();
+        if path.join("grub.cfg").is_file() {
+            vendor_dir.push(path);
+        }
+    }
+    if vendor_dir.len() != 1 {
+        bail!(
+            "Expected one vendor dir on {}, got {} ({:?})",
+            efi_mount.device(),
+            vendor_dir.len(),
+            vendor_dir,
+        );
+    }
+    Ok(vendor_dir.pop().unwrap())
+}
+
+pub fn get_blkdev_deps(device: &Path) -> Result<Vec<PathBuf>> {
+    let deps = {
+        let mut p = PathBuf::from("/sys/block");
+        p.push(
+            device
+                .canonicalize()
+                .with_context(|| format!("canonicalizing {}", device.display()))?
+                .file_name()
+                .with_context(|| format!("path {} has no filename", device.display()))?,
+        );
+        p.push("slaves");
+        p
+    };
+    let mut ret: Vec<PathBuf> = Vec::new();
+    let dir_iter = match read_dir(&deps) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret),
+        Err(e) => return Err(e).with_context(|| format!("reading dir {}", &deps.display())),
+        Ok(it) => it,
+    };
+    for ent in dir_iter {
+        let ent = ent.with_context(|| format!("reading {} entry", &deps.display()))?;
+        ret.push(Path::new("/dev").join(ent.file_name()));
+    }
+    Ok(ret)
+}
+
+pub fn get_blkdev_deps_recursing(device: &Path) -> Result<Vec<PathBuf>> {
+    let mut ret: Vec<PathBuf> = Vec::new();
+    for dep in get_blkdev_deps(device)? {
+        ret.extend(get_blkdev_deps_recursing(&dep)?);
+        ret.push(dep);
+    }
+    Ok(ret)
+}
+
+/// Get the devices stacked directly on top of a block device.
+pub fn get_blkdev_holders(device: &Path) -> Result<Vec<PathBuf>> {
+    let dev = metadata(device)
+        .with_context(|| format!("getting metadata for {}", device.display()))?
+        .st_rdev();
+    let holders = PathBuf::from(format!(
+        "/sys/dev/block/{}:{}/holders",
+        major(dev),
+        minor(dev)
+    ));
+    let mut ret: Vec<PathBuf> = Vec::new();
+    let dir_iter = match read_dir(&holders) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret),
+        Err(e) => return Err(e).with_context(|| format!("reading dir {}", &holders.display())),
+        Ok(it) => it,
+    };
+    for ent in dir_iter {
+        let ent = ent.with_context(|| format!("reading {} entry", &holders.display()))?;
+        ret.push(Path::new("/dev").join(ent.file_name()));
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Get the mountpoints of a block device in our mount namespace.
+pub fn get_mountpoints(device: &Path) -> Result<Vec<String>> {
+    let rdev = metadata(device)
+        .with_context(|| format!("getting metadata for {}", device.display()))?
+        .st_rdev();
+    let mounts = read_to_string("/proc/self/mounts").context("reading mount table")?;
+    let mut ret = Vec::new();
+    for line in mounts.lines() {
+        let mount: Vec<&str> = line.split_whitespace().collect();
+        if mount.len() != 6 {
+            bail!("invalid line in /proc/self/mounts: {}", line);
+        }
+        let source = unescape_mount_field(mount[0]);
+        if !source.starts_with("/dev/") {
+            continue;
+        }
+        match metadata(&source) {
+            Ok(meta) if meta.file_type().is_block_device() && meta.st_rdev() == rdev => {
+                ret.push(unescape_mount_field(mount[1]))
+            }
+            _ => (),
+        }
+    }
+    Ok(ret)
+}
+
+/// Check whether a block device is in use as swap.
+fn is_swap(device: &Path) -> Result<bool> {
+    let rdev = metadata(device)
+        .with_context(|| format!("getting metadata for {}", device.display()))?
+        .st_rdev();
+    let swaps = read_to_string("/proc/swaps").context("reading swap table")?;
+    // skip header
+    for line in swaps.lines().skip(1) {
+        if let Some(path) = line.split_whitespace().next() {
+            match metadata(unescape_mount_field(path)) {
+                Ok(meta) if meta.file_type().is_block_device() && meta.st_rdev() == rdev => {
+                    return Ok(true)
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Decode the octal escapes the kernel uses for whitespace and
+/// backslashes in /proc/self/mounts.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        });
+        if let (b'\\', Some(value)) = (bytes[i], octal) {
+            ret.push(value);
+            i += 4;
+        } else {
+            ret.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// Find processes with a block device open, or with open files, a working
+/// directory, or a root directory on a filesystem backed by it.  Returns
+/// PIDs and command names.
+pub fn get_processes_using(devices: &[String]) -> Result<Vec<(u32, String)>> {
+    let mut rdevs = HashSet::new();
+    for device in devices {
+        rdevs.insert(
+            metadata(device)
+                .with_context(|| format!("getting metadata for {device}"))?
+                .st_rdev(),
+        );
+    }
+    let uses_device = |path: &Path| match metadata(path) {
+        Ok(meta) => {
+            (meta.file_type().is_block_device() && rdevs.contains(&meta.st_rdev()))
+                || rdevs.contains(&meta.st_dev())
+        }
+        // process exited, or file is inaccessible
+        Err(_) => false,
+    };
+
+    let mut ret = Vec::new();
+    let self_pid = std::process::id();
+    for ent in read_dir("/proc").context("listing /proc")? {
+        let ent = ent.context("reading /proc entry")?;
+        let pid: u32 = match ent.file_name().to_string_lossy().parse() {
+            Ok(pid) if pid != self_pid => pid,
+            _ => continue,
+        };
+        let dir = ent.path();
+        let mut found = ["cwd", "root", "exe"]
+            .iter()
+            .any(|name| uses_device(&dir.join(name)));
+        if !found {
+            if let Ok(fds) = read_dir(dir.join("fd")) {
+                found = fds.flatten().any(|fd| uses_device(&fd.path()));
+            }
+        }
+        if found {
+            let comm = read_to_string(dir.join("comm")).unwrap_or_default();
+            ret.push((pid, comm.trim_end().to_string()));
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+fn reread_partition_table(file: &mut File, retry: bool) -> Result<()> {
+    let fd = file.as_raw_fd();
+    // Reread sometimes fails inexplicably.  Retry several times before
+    // giving up.
+    let max_tries = if retry { 20 } else { 1 };
+    for retries in (0..max_tries).rev() {
+        let result = unsafe { ioctl::blkrrpart(fd) };
+        match result {
+            Ok(_) => break,
+            Err(err) if retries == 0 && err == Errno::EINVAL => {
+                return Err(err)
+                    .context("couldn't reread partition table: device may not support partitions")
+            }
+            Err(err) if retries == 0 && err == Errno::EBUSY => {
+                return Err(err).context("couldn't reread partition table: device is in use")
+            }
+            Err(err) if retries == 0 => return Err(err).context("couldn't reread partition table"),
+            Err(_) => sleep(Duration::from_millis(100)),
+        }
+    }
+    Ok(())
+}
+
+/// Discard the specified byte ranges of a block device, zeroing them
+/// instead if the device doesn't support discard.  For a regular file,
+/// deallocate the ranges.
+pub fn discard_ranges(file: &File, ranges: &[(u64, u64)]) -> Result<()> {
+    let fd = file.as_raw_fd();
+    let is_file = file.metadata().context("getting metadata")?.is_file();
+    let mut zero = false;
+    for &(start, end) in ranges {
+        if is_file {
+            let ret = unsafe {
+                libc::fallocate(
+                    fd,
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    start as libc::off_t,
+                    (end - start) as libc::off_t,
+                )
+            };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("deallocating bytes {start}-{end}"));
+            }
+            continue;
+        }
+        let range = [start, end - start];
+        if !zero {
+            match unsafe { ioctl::blkdiscard(fd, &range) } {
+                Ok(_) => continue,
+                Err(Errno::EOPNOTSUPP) => {
+                    eprintln!("Device doesn't support discard; zeroing instead");
+                    zero = true;
+                }
+                Err(e) => return Err(e).with_context(|| format!("discarding bytes {start}-{end}")),
+            }
+        }
+        unsafe { ioctl::blkzeroout(fd, &range) }
+            .with_context(|| format!("zeroing bytes {start}-{end}"))?;
+    }
+    Ok(())
+}
+
+/// Get the sector size of the block device at a given path.
+pub fn get_sector_size_for_path(device: &Path) -> Result<NonZeroU32> {
+    let dev = OpenOptions::new()
+        .read(true)
+        .open(device)
+        .with_context(|| format!("opening {device:?}"))?;
+
+    if !dev
+        .metadata()
+        .with_context(|| format!("getting metadata for {device:?}"))?
+        .file_type()
+        .is_block_device()
+    {
+        bail!("{:?} is not a block device", device);
+    }
+
+    get_sector_size(&dev)
+}
+
+/// Get the logical sector size of a block device.
+pub fn get_sector_size(file: &File) -> Result<NonZeroU32> {
+    let fd = file.as_raw_fd();
+    let mut size: c_int = 0;
+    match unsafe { ioctl::blksszget(fd, &mut size) } {
+        Ok(_) => {
+            let size_u32: u32 = size
+                .try_into()
+                .with_context(|| format!("sector size {size} doesn't fit in u32"))?;
+            NonZeroU32::new(size_u32).context("found sector size of zero")
+        }
+        Err(e) => Err(anyhow!(e).context("getting sector size")),
+    }
+}
+
+/// Get the physical sector size of a block device.
+pub fn get_physical_sector_size(file: &File) -> Result<NonZeroU32> {
+    let fd = file.as_raw_fd();
+    let mut size: libc::c_uint = 0;
+    match unsafe { ioctl::blkpbszget(fd, &mut size) } {
+        Ok(_) => NonZeroU32::new(size).context("found physical sector size of zero"),
+        Err(e) => Err(anyhow!(e).context("getting physical sector size")),
+    }
+}
+
+/// Get the size of a block device.
+pub fn get_block_device_size(file: &File) -> Result<NonZeroU64> {
+    let fd = file.as_raw_fd();
+    let mut size: libc::size_t = 0;
+    match unsafe { ioctl::blkgetsize64(fd, &mut size) } {
+        // just cast using `as`: there is no platform we care about today where size_t > 64bits
+        Ok(_) => NonZeroU64::new(size as u64).context("found block size of zero"),
+        Err(e) => Err(anyhow!(e).context("getting block size")),
+    }
+}
+
+/// Get the size of the GPT metadata at the start of the disk.
+pub fn get_gpt_size(file: &mut (impl Read + Seek)) -> Result<u64> {
+    let gpt = GPT::find_from(file).context("reading GPT")?;
+    Ok(gpt.header.first_usable_lba * gpt.sector_size)
+}
+
+/// Get the size of the disk described by the GPT, based on the location
+/// of the backup header.  For an install image, this is the image size.
+pub fn get_gpt_disk_size(file: &mut (impl Read + Seek)) -> Result<u64> {
+    let gpt = GPT::find_from(file).context("reading GPT")?;
+    (gpt.header.backup_lba + 1)
+        .checked_mul(gpt.sector_size)
+        .context("overflow calculating disk size")
+}
+
+/// MBR partition types that describe a container for logical partitions
+/// rather than a partition holding data.
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Type of the single partition in a GPT protective MBR.
+const MBR_PROTECTIVE_TYPE: u8 = 0xee;
+/// Upper bound on the number of EBRs we'll follow, in case the chain loops.
+const MBR_MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+/// A data partition read from an MBR partition table.  Offsets are in
+/// sectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MbrPartition {
+    bootable: bool,
+    partition_type: u8,
+    start_lba: u64,
+    sectors: u64,
+}
+
+impl MbrPartition {
+    /// Parse one 16-byte partition table entry, returning None for unused
+    /// slots.  `base` is added to the entry's start sector.
+    fn parse(entry: &[u8], base: u64) -> Option<Self> {
+        let partition_type = entry[4];
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        if partition_type == 0 || sectors == 0 {
+            return None;
+        }
+        Some(Self {
+            bootable: entry[0] & 0x80 != 0,
+            partition_type,
+            start_lba: base + start,
+            sectors,
+        })
+    }
+
+    /// Map the MBR partition type to the closest GPT partition type GUID.
+    /// Types without a direct equivalent become Linux filesystem data.
+    fn gpt_type(&self) -> Uuid {
+        let guid = match self.partition_type {
+            // FAT and NTFS variants
+            0x01 | 0x04 | 0x06 | 0x07 | 0x0b | 0x0c | 0x0e => {
+                "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7"
+            }
+            0x82 => "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f",
+            0x8e => "e6d6d379-f507-44c2-a23c-238f2a3df928",
+            0xef => "c12a7328-f81f-11d2-ba4b-00a0c93ec93b",
+            0xfd => "a19d880f-05fc-4d3b-a006-743f0f84911e",
+            _ => "0fc63daf-8483-4772-8e79-3d69d8477de4",
+        };
+        Uuid::parse_str(guid).expect("invalid partition type GUID")
+    }
+
+    /// Convert to a GPT partition entry with a fresh unique GUID.  The
+    /// MBR active flag becomes the GPT legacy BIOS bootable attribute.
+    fn to_gpt_entry(&self) -> GPTPartitionEntry {
+        GPTPartitionEntry {
+            partition_type_guid: self.gpt_type().to_bytes_le(),
+            unique_partition_guid: Uuid::new_v4().to_bytes_le(),
+            starting_lba: self.start_lba,
+            ending_lba: self.start_lba + self.sectors - 1,
+            attribute_bits: if self.bootable { 1 << 2 } else { 0 },
+            partition_name: "".into(),
+        }
+    }
+}
+
+/// Read the four 16-byte partition table entries from the MBR or EBR at
+/// the specified sector.
+fn read_mbr_table(disk: &mut (impl Read + Seek), sector_size: u64, lba: u64) -> Result<[u8; 64]> {
+    let mut table = [0u8; 64];
+    disk.seek(SeekFrom::Start(lba * sector_size + 446))
+        .with_context(|| format!("seeking to partition table at sector {lba}"))?;
+    disk.read_exact(&mut table)
+        .with_context(|| format!("reading partition table at sector {lba}"))?;
+    Ok(table)
+}
+
+/// Read the partitions from an MBR partition table, numbered the way the
+/// kernel numbers them: primary partitions 1-4 by slot, then logical
+/// partitions from 5 in EBR chain order.  Extended partitions themselves
+/// are omitted.  The caller must already have checked for the MBR
+/// signature.
+fn read_mbr_partitions(
+    disk: &mut (impl Read + Seek),
+    sector_size: u64,
+) -> Result<Vec<(u32, MbrPartition)>> {
+    let mut result = Vec::new();
+    let mut extended = None;
+    let table = read_mbr_table(disk, sector_size, 0)?;
+    for (slot, entry) in table.chunks_exact(16).enumerate() {
+        let p = match MbrPartition::parse(entry, 0) {
+            Some(p) => p,
+            None => continue,
+        };
+        if p.partition_type == MBR_PROTECTIVE_TYPE {
+            bail!("disk has a protective MBR but no valid GPT");
+        } else if MBR_EXTENDED_TYPES.contains(&p.partition_type) {
+            if extended.is_some() {
+                bail!("MBR has more than one extended partition");
+            }
+            extended = Some(p.start_lba);
+        } else {
+            result.push((slot as u32 + 1, p));
+        }
+    }
+
+    // Walk the EBR chain.  Each EBR's first entry is a logical partition
+    // relative to the EBR; its second entry links to the next EBR,
+    // relative to the start of the extended partition.
+    if let Some(extended_start) = extended {
+        let mut ebr = extended_start;
+        let mut index = 5;
+        for ebr_count in 0.. {
+            if ebr_count >= MBR_MAX_LOGICAL_PARTITIONS {
+                bail!("too many logical partitions in MBR");
+            }
+            let table = read_mbr_table(disk, sector_size, ebr)?;
+            if let Some(p) = MbrPartition::parse(&table[0..16], ebr) {
+                result.push((index, p));
+                index += 1;
+            }
+            match MbrPartition::parse(&table[16..32], extended_start) {
+                Some(next) if MBR_EXTENDED_TYPES.contains(&next.partition_type) => {
+                    ebr = next.start_lba
+                }
+                _ => break,
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn disk_has_mbr(file: &mut (impl Read + Seek)) -> Result<bool> {
+    let mut sig = [0u8; 2];
+    file.seek(SeekFrom::Start(510))
+        .context("seeking to MBR signature")?;
+    file.read_exact(&mut sig).context("reading MBR signature")?;
+    Ok(sig == [0x55, 0xaa])
+}
+
+pub fn udev_settle() -> Result<()> {
+    // "udevadm settle" silently no-ops if the udev socket is missing, and
+    // then lsblk can't find partition labels.  Catch this early.
+    if !Path::new("/run/udev/control").exists() {
+        bail!("udevd socket missing; are we running in a container without /run/udev mounted?");
+    }
+
+    // There's a potential window after rereading the partition table where
+    // udevd hasn't yet received updates from the kernel, settle will return
+    // immediately, and lsblk won't pick up partition labels.  Try to sleep
+    // our way out of this.
+    sleep(Duration::from_millis(200));
+
+    runcmd!("udevadm", "settle")?;
+    Ok(())
+}
+
+/// Inspect a buffer from the start of a disk image and return its formatted
+/// sector size, if any can be determined.
+pub fn detect_formatted_sector_size(buf: &[u8]) -> Option<NonZeroU32> {
+    let gpt_magic: &[u8; 8] = b"EFI PART";
+
+    if buf.len() >= 520 && buf[512..520] == gpt_magic[..] {
+        // GPT at offset 512
+        NonZeroU32::new(512)
+    } else if buf.len() >= 4104 && buf[4096..4104] == gpt_magic[..] {
+        // GPT at offset 4096
+        NonZeroU32::new(4096)
+    } else {
+        // Unknown
+        None
+    }
+}
+
+/// Rewrites the partition tables of a GPT disk image for a destination
+/// with a different logical sector size.  Partitions keep their byte
+/// offsets, so their contents can be streamed unchanged; only the
+/// protective MBR and the primary and backup GPTs are rewritten.  The
+/// primary GPT must lie within the first MiB of the image and the backup
+/// GPT within the last `tail_len()` bytes.
+///
+/// Filesystems aren't rewritten, so XFS and FAT filesystems formatted for
+/// sectors smaller than the destination's can't be translated.  The
+/// headers of partitions starting after the first MiB must be passed to
+/// `check_filesystem()` before the image is written.
+#[derive(Debug)]
+pub struct SectorSizeTranslation {
+    /// Image GPT, converted to the destination sector size.
+    gpt: GPT,
+    /// Sector size of the image.
+    from: u64,
+    /// End of the image's primary GPT, in bytes.
+    from_gpt_end: u64,
+    /// Size of the image, in bytes.
+    image_size: u64,
+    /// Partition number, image offset, and length of the header of each
+    /// partition, sorted by offset.
+    headers: Vec<(u32, u64, u64)>,
+}
+
+impl SectorSizeTranslation {
+    /// Plan the translation of the image whose first MiB is `first_mb`
+    /// to the specified sector size.  Fail if any partition isn't aligned
+    /// to the new sector size or would overlap the new partition tables,
+    /// or if a filesystem in the first MiB is formatted for smaller
+    /// sectors.
+    pub fn new(first_mb: &[u8], sector_size: u64) -> Result<Self> {
+        let src = GPT::find_from(&mut Cursor::new(first_mb)).context("reading image GPT")?;
+        let from = src.sector_size;
+        let to = sector_size;
+        if !matches!((from, to), (512, 4096) | (4096, 512)) {
+            bail!("can't translate from {from}-byte to {to}-byte sectors");
+        }
+        let image_size = (src.header.backup_lba + 1)
+            .checked_mul(from)
+            .context("overflow calculating image size")?;
+        if image_size % to != 0 {
+            bail!("image size {image_size} is not a multiple of {to}-byte sectors");
+        }
+        let total = image_size / to;
+        let entry_sectors = Self::entry_sectors(&src.header, to);
+
+        let mut gpt = src.clone();
+        gpt.sector_size = to;
+        gpt.align = (src.align * from / to).max(1);
+        gpt.header.primary_lba = 1;
+        gpt.header.backup_lba = total - 1;
+        gpt.header.partition_entry_lba = 2;
+        gpt.header.first_usable_lba = 2 + entry_sectors;
+        gpt.header.last_usable_lba = total
+            .checked_sub(2 + entry_sectors)
+            .context("image too small for partition table")?;
+        for (i, p) in gpt.iter_mut() {
+            if p.is_unused() {
+                continue;
+            }
+            let start = p.starting_lba * from;
+            let end = (p.ending_lba + 1) * from;
+            if start % to != 0 || end % to != 0 {
+                bail!("partition {i} isn't aligned to {to}-byte sectors");
+            }
+            p.starting_lba = start / to;
+            p.ending_lba = end / to - 1;
+        }
+        let first_usable = gpt.header.first_usable_lba;
+        let last_usable = gpt.header.last_usable_lba;
+        for (i, p) in gpt.iter() {
+            if p.is_used() && (p.starting_lba < first_usable || p.ending_lba > last_usable) {
+                bail!("partition {i} overlaps the partition table for {to}-byte sectors");
+            }
+        }
+
+        let mut headers: Vec<(u32, u64, u64)> = gpt
+            .iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(i, p)| {
+                let start = p.starting_lba * to;
+                let len = (p.ending_lba + 1 - p.starting_lba) * to;
+                (i, start, len.min(FILESYSTEM_HEADER_SIZE))
+            })
+            .collect();
+        headers.sort_by_key(|(_, start, _)| *start);
+
+        let ret = Self {
+            from_gpt_end: src.header.first_usable_lba * from,
+            gpt,
+            from,
+            image_size,
+            headers,
+        };
+        if ret.from_gpt_end.max(first_usable * to) > first_mb.len() as u64 {
+            bail!("image partition table extends beyond the first MiB");
+        }
+        if ret.tail_len() + first_mb.len() as u64 > image_size {
+            bail!("image too small to translate partition table");
+        }
+        for (i, start, len) in ret.headers() {
+            if start < first_mb.len() as u64 {
+                let end = (start + len).min(first_mb.len() as u64);
+                ret.check_filesystem(i, &first_mb[start as usize..end as usize])?;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Partition number, image offset, and length of the header of each
+    /// partition, in order of offset.
+    pub fn headers(&self) -> impl Iterator<Item = (u32, u64, u64)> + '_ {
+        self.headers.iter().copied()
+    }
+
+    /// Fail if the header of the specified partition holds an XFS or FAT
+    /// filesystem formatted for sectors smaller than the destination's.
+    pub fn check_filesystem(&self, partition: u32, header: &[u8]) -> Result<()> {
+        if let Some((fstype, size)) = filesystem_sector_size(header) {
+            if size < self.gpt.sector_size {
+                bail!(
+                    "partition {partition} has a {fstype} filesystem with {size}-byte sectors, which can't be used on a disk with {}-byte sectors",
+                    self.gpt.sector_size
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of sectors occupied by a partition entry array.
+    fn entry_sectors(header: &GPTHeader, sector_size: u64) -> u64 {
+        let len = header.number_of_partition_entries as u64 * header.size_of_partition_entry as u64;
+        (len + sector_size - 1) / sector_size
+    }
+
+    pub fn from_sector_size(&self) -> u64 {
+        self.from
+    }
+
+    pub fn to_sector_size(&self) -> u64 {
+        self.gpt.sector_size
+    }
+
+    pub fn image_size(&self) -> u64 {
+        self.image_size
+    }
+
+    /// Length of the region at the end of the image holding the backup
+    /// GPT in either sector size.
+    pub fn tail_len(&self) -> u64 {
+        [self.from, self.gpt.sector_size]
+            .iter()
+            .map(|ss| (Self::entry_sectors(&self.gpt.header, *ss) + 1) * ss)
+            .max()
+            .unwrap()
+    }
+
+    fn entries(&self) -> Vec<GPTPartitionEntry> {
+        self.gpt.iter().map(|(_, p)| p.clone()).collect()
+    }
+
+    /// Rewrite the protective MBR and primary GPT in the first MiB of the
+    /// image.  Boot code in the MBR is preserved.
+    pub fn translate_head(&self, first_mb: &mut [u8]) -> Result<()> {
+        if first_mb[446 + 4] != MBR_PROTECTIVE_TYPE || first_mb[462..510].iter().any(|b| *b != 0) {
+            bail!("image doesn't have a protective MBR");
+        }
+        let sectors = u32::try_from(self.gpt.header.backup_lba).unwrap_or(u32::MAX);
+        first_mb[446 + 12..446 + 16].copy_from_slice(&sectors.to_le_bytes());
+
+        // clear both the old and new locations, then write the header at
+        // LBA 1 and the entries after it
+        let start = self.from.min(self.gpt.sector_size) as usize;
+        let end =
+            self.from_gpt_end
+                .max(self.gpt.header.first_usable_lba * self.gpt.sector_size) as usize;
+        first_mb[start..end].fill(0);
+        let mut header = self.gpt.header.clone();
+        header
+            .write_into(
+                &mut Cursor::new(first_mb),
+                self.gpt.sector_size,
+                &self.entries(),
+            )
+            .context("writing primary GPT")?;
+        Ok(())
+    }
+
+    /// Rewrite the backup GPT in the last `tail_len()` bytes of the image.
+    pub fn translate_tail(&self, tail: &mut [u8]) -> Result<()> {
+        if tail.len() as u64 != self.tail_len() {
+            bail!(
+                "expected {} bytes of backup GPT, found {}",
+                self.tail_len(),
+                tail.len()
+            );
+        }
+        tail.fill(0);
+        let mut header = self.gpt.header.clone();
+        header.primary_lba = self.gpt.header.backup_lba;
+        header.backup_lba = self.gpt.header.primary_lba;
+        header.partition_entry_lba = self.gpt.header.last_usable_lba + 1;
+        let mut window = WindowWriter {
+            buf: Cursor::new(tail),
+            base: self.image_size - self.tail_len(),
+        };
+        header
+            .write_into(&mut window, self.gpt.sector_size, &self.entries())
+            .context("writing backup GPT")?;
+        Ok(())
+    }
+}
+
+/// Bytes at the start of a partition needed to find its filesystem's
+/// sector size.
+const FILESYSTEM_HEADER_SIZE: u64 = 512;
+
+/// Return the filesystem type and sector size of an XFS or FAT filesystem
+/// starting at the beginning of the buffer, if any.
+fn filesystem_sector_size(header: &[u8]) -> Option<(&'static str, u64)> {
+    if header.len() >= 104 && header[0..4] == *b"XFSB" {
+        // sb_sectsize
+        Some(("xfs", u16::from_be_bytes([header[102], header[103]]).into()))
+    } else if header.len() >= 512
+        && header[510..512] == [0x55, 0xaa]
+        && (header[54..57] == *b"FAT" || header[82..85] == *b"FAT")
+    {
+        // BPB_BytsPerSec
+        Some(("vfat", u16::from_le_bytes([header[11], header[12]]).into()))
+    } else {
+        None
+    }
+}
+
+/// Writer for a buffer holding part of a larger image, addressed by
+/// offsets into the image.
+struct WindowWriter<'a> {
+    buf: Cursor<&'a mut [u8]>,
+    /// Image offset of the start of the buffer.
+    base: u64,
+}
+
+impl Write for WindowWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.buf.flush()
+    }
+}
+
+impl Seek for WindowWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) if offset >= self.base => offset - self.base,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "seek outside window",
+                ))
+            }
+        };
+        Ok(self.buf.seek(SeekFrom::Start(offset))? + self.base)
+    }
+}
+
+/// An existing CoreOS install found on a disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CoreosInstall {
+    /// The partition containing the boot filesystem.
+    pub boot_device: String,
+    /// The installed OS version, if we could determine it.
+    pub version: Option<String>,
+}
+
+/// Look for BLS entries in a mounted boot filesystem, and return None if
+/// there aren't any.  The version comes from the aleph file if the boot
+/// filesystem has one, or otherwise from the title of the default BLS
+/// entry.
+fn read_coreos_install(boot_device: &str, mountpoint: &Path) -> Result<Option<CoreosInstall>> {
+    let dir = mountpoint.join("loader/entries");
+    let entries = match read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("listing {}", dir.display())),
+    };
+
+    // ostree gives the default deployment the highest BLS version
+    let mut default: Option<(u64, Option<String>)> = None;
+    for ent in entries {
+        let ent = ent.with_context(|| format!("reading {} entry", dir.display()))?;
+        if !ent.file_name().to_string_lossy().ends_with(".conf") {
+            continue;
+        }
+        let path = ent.path();
+        let contents =
+            read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        let (version, title) = parse_bls_entry(&contents);
+        if default.as_ref().map(|(v, _)| version >= *v).unwrap_or(true) {
+            default = Some((version, title));
+        }
+    }
+    let title = match default {
+        Some((_, title)) => title,
+        None => return Ok(None),
+    };
+
+    let aleph_path = mountpoint.join(".coreos-aleph-version.json");
+    let aleph_version = match read_to_string(&aleph_path) {
+        Ok(contents) => serde_json::from_str::<serde_json::Value>(&contents)
+            .with_context(|| format!("parsing {}", aleph_path.display()))?
+            .get("build")
+            .and_then(|v| v.as_str())
+            .map(String::from),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("reading {}", aleph_path.display())),
+    };
+
+    Ok(Some(CoreosInstall {
+        boot_device: boot_device.to_string(),
+        version: aleph_version.or(title),
+    }))
+}
+
+/// Parse the version and title from a BLS entry, dropping the deployment
+/// suffix that ostree appends to the title.
+fn parse_bls_entry(contents: &str) -> (u64, Option<String>) {
+    let mut version = 0;
+    let mut title = None;
+    for line in contents.lines() {
+        match line.trim().split_once(char::is_whitespace) {
+            Some(("version", value)) => version = value.trim().parse().unwrap_or(0),
+            Some(("title", value)) => {
+                let value = value.trim();
+                let value = match value.rfind(" (ostree:") {
+                    Some(n) if value.ends_with(')') => &value[..n],
+                    _ => value,
+                };
+                title = Some(value.to_string());
+            }
+            _ => (),
+        }
+    }
+    (version, title)
+}
+
+/// Checks if underlying device is IBM DASD disk
+pub fn is_dasd(device: &str, fd: Option<&mut File>) -> Result<bool> {
+    let target =
+        canonicalize(device).with_context(|| format!("getting absolute path to {device}"))?;
+    if target.to_string_lossy().starts_with("/dev/dasd") {
+        return Ok(true);
+    }
+    let read_magic = |device: &str, disk: &mut File| -> Result<[u8; 4]> {
+        let offset = disk
+            .stream_position()
+            .with_context(|| format!("saving offset {device}"))?;
+        disk.seek(SeekFrom::Start(8194))
+            .with_context(|| format!("seeking {device}"))?;
+        let mut lbl = [0u8; 4];
+        disk.read_exact(&mut lbl)
+            .with_context(|| format!("reading label {device}"))?;
+        disk.seek(SeekFrom::Start(offset))
+            .with_context(|| format!("restoring offset {device}"))?;
+        Ok(lbl)
+    };
+    if target.to_string_lossy().starts_with("/dev/vd") {
+        let cdl_magic = [0xd3, 0xf1, 0xe5, 0xd6];
+        let lbl = if let Some(t) = fd {
+            read_magic(device, t)?
+        } else {
+            let mut disk = File::open(device).with_context(|| format!("opening {device}"))?;
+            read_magic(device, &mut disk)?
+        };
+        return Ok(cdl_magic == lbl);
+    }
+    Ok(false)
+}
+
+// create unsafe ioctl wrappers
+#[allow(clippy::missing_safety_doc)]
+mod ioctl {
+    use super::c_int;
+
+    pub const BLKPG_ADD_PARTITION: c_int = 1;
+    pub const BLKPG_DEL_PARTITION: c_int = 2;
+    pub const BLKPG_RESIZE_PARTITION: c_int = 3;
+
+    // from linux/blkpg.h
+    #[repr(C)]
+    pub struct BlkpgIoctlArg {
+        pub op: c_int,
+        pub flags: c_int,
+        pub datalen: c_int,
+        pub data: *mut libc::c_void,
+    }
+
+    #[repr(C)]
+    pub struct BlkpgPartition {
+        pub start: libc::c_longlong,
+        pub length: libc::c_longlong,
+        pub pno: c_int,
+        pub devname: [libc::c_char; 64],
+        pub volname: [libc::c_char; 64],
+    }
+
+    // from linux/loop.h
+    pub const LO_FLAGS_AUTOCLEAR: u32 = 4;
+    pub const LO_FLAGS_PARTSCAN: u32 = 8;
+
+    #[repr(C)]
+    pub struct LoopInfo64 {
+        pub lo_device: u64,
+        pub lo_inode: u64,
+        pub lo_rdevice: u64,
+        pub lo_offset: u64,
+        pub lo_sizelimit: u64,
+        pub lo_number: u32,
+        pub lo_encrypt_type: u32,
+        pub lo_encrypt_key_size: u32,
+        pub lo_flags: u32,
+        pub lo_file_name: [u8; 64],
+        pub lo_crypt_name: [u8; 64],
+        pub lo_encrypt_key: [u8; 32],
+        pub lo_init: [u64; 2],
+    }
+
+    use nix::{
+        ioctl_none, ioctl_none_bad, ioctl_read, ioctl_read_bad, ioctl_write_int_bad,
+        ioctl_write_ptr_bad, request_code_none,
+    };
+    ioctl_write_ptr_bad!(blkroset, request_code_none!(0x12, 93), c_int);
+    ioctl_read_bad!(blkroget, request_code_none!(0x12, 94), c_int);
+    ioctl_none!(blkrrpart, 0x12, 95);
+    ioctl_read_bad!(blksszget, request_code_none!(0x12, 104), c_int);
+    ioctl_read!(blkgetsize64, 0x12, 114, libc::size_t);
+    ioctl_write_ptr_bad!(blkpg, request_code_none!(0x12, 105), BlkpgIoctlArg);
+    ioctl_read_bad!(blkpbszget, request_code_none!(0x12, 123), libc::c_uint);
+    ioctl_write_ptr_bad!(blkdiscard, request_code_none!(0x12, 119), [u64; 2]);
+    ioctl_write_ptr_bad!(blkzeroout, request_code_none!(0x12, 127), [u64; 2]);
+    ioctl_none!(blkflsbuf, 0x12, 97);
+    ioctl_write_int_bad!(loop_set_fd, 0x4c00);
+    ioctl_none_bad!(loop_clr_fd, 0x4c01);
+    ioctl_write_ptr_bad!(loop_set_status64, 0x4c04, LoopInfo64);
+    ioctl_none_bad!(loop_set_capacity, 0x4c07);
+    ioctl_none_bad!(loop_ctl_get_free, 0x4c82);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{copy, Cursor};
+    use tempfile::tempfile;
+    use xz2::read::XzDecoder;
+
+    #[test]
+    fn disk_sector_size_reader() {
+        struct Test {
+            name: &'static str,
+            data: &'static [u8],
+            compressed: bool,
+            result: Option<NonZeroU32>,
+        }
+        let tests = vec![
+            Test {
+                name: "zero-length",
+                data: b"",
+                compressed: false,
+                result: None,
+            },
+            Test {
+                name: "empty-disk",
+                data: include_bytes!("../../fixtures/empty.xz"),
+                compressed: true,
+                result: None,
+            },
+            Test {
+                name: "gpt-512",
+                data: include_bytes!("../../fixtures/gpt-512.xz"),
+                compressed: true,
+                result: NonZeroU32::new(512),
+            },
+            Test {
+                name: "gpt-4096",
+                data: include_bytes!("../../fixtures/gpt-4096.xz"),
+                compressed: true,
+                result: NonZeroU32::new(4096),
+            },
+        ];
+
+        for test in tests {
+            let data = if test.compressed {
+                let mut decoder = XzDecoder::new(test.data);
+                let mut data: Vec<u8> = Vec::new();
+                decoder.read_to_end(&mut data).expect("decompress failed");
+                data
+            } else {
+                test.data.to_vec()
+            };
+            assert_eq!(
+                detect_formatted_sector_size(&data),
+                test.result,
+                "{}",
+                test.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_sector_size_translation() {
+        let len = 10 * 1024 * 1024;
+        let mb = 1024 * 1024;
+
+        // 512-byte image with MiB-aligned partitions
+        let mut image = Cursor::new(vec![0u8; len]);
+        GPT::write_protective_mbr_into(&mut image, 512).unwrap();
+        let mut gpt = GPT::new_from(&mut image, 512, make_guid("disk")).unwrap();
+        for (i, (start, end)) in [(2048, 4095), (4096, 20439)].into_iter().enumerate() {
+            gpt[i as u32 + 1] = GPTPartitionEntry {
+                partition_type_guid: make_guid("type"),
+                unique_partition_guid: make_guid(&format!("guid{i}")),
+                starting_lba: start,
+                ending_lba: end,
+                attribute_bits: 0,
+                partition_name: format!("part{i}").as_str().into(),
+            };
+        }
+        gpt.write_into(&mut image).unwrap();
+        let mut image = image.into_inner();
+        image[0..4].copy_from_slice(b"boot");
+        image[mb..mb + 5].copy_from_slice(b"hello");
+
+        let translate = |image: &[u8], sector_size: u64| {
+            let translation = SectorSizeTranslation::new(&image[..mb], sector_size).unwrap();
+            assert_eq!(translation.image_size(), len as u64);
+            let mut result = image.to_vec();
+            translation.translate_head(&mut result[..mb]).unwrap();
+            let tail_start = len - translation.tail_len() as usize;
+            translation
+                .translate_tail(&mut result[tail_start..])
+                .unwrap();
+            result
+        };
+
+        // 512 to 4096
+        let translated = translate(&image, 4096);
+        assert_eq!(
+            detect_formatted_sector_size(&translated),
+            NonZeroU32::new(4096)
+        );
+        assert_eq!(translated[0..4], *b"boot");
+        assert_eq!(translated[mb..mb + 5], *b"hello");
+        let gpt = GPT::find_from(&mut Cursor::new(&translated)).unwrap();
+        assert_eq!(gpt.sector_size, 4096);
+        assert_eq!(gpt.header.first_usable_lba, 6);
+        assert_eq!(gpt.header.last_usable_lba, 2554);
+        assert_eq!(gpt.header.backup_lba, 2559);
+        assert_eq!((gpt[1].starting_lba, gpt[1].ending_lba), (256, 511));
+        assert_eq!((gpt[2].starting_lba, gpt[2].ending_lba), (512, 2554));
+        assert_eq!(gpt[2].partition_name.as_str(), "part1");
+        let mut backup = Cursor::new(&translated);
+        backup.seek(SeekFrom::Start(2559 * 4096)).unwrap();
+        let backup = GPTHeader::read_from(&mut backup).unwrap();
+        assert_eq!(backup.primary_lba, 2559);
+        assert_eq!(backup.partition_entry_lba, 2555);
+
+        // and back again
+        assert_eq!(translate(&translated, 512), image);
+
+        // partitions must be aligned to the new sector size
+        let mut data = Vec::new();
+        XzDecoder::new(&include_bytes!("../../fixtures/gpt-512.xz")[..])
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(
+            SectorSizeTranslation::new(&data[..mb], 4096)
+                .unwrap_err()
+                .to_string(),
+            "partition 1 isn't aligned to 4096-byte sectors"
+        );
+    }
+
+    /// An XFS superblock as written by mkfs.xfs for a 512 MiB filesystem.
+    fn xfs_superblock(sector_size: u16) -> Vec<u8> {
+        let mut sb = vec![0u8; 512];
+        sb[0..4].copy_from_slice(b"XFSB");
+        sb[4..8].copy_from_slice(&4096u32.to_be_bytes()); // sb_blocksize
+        sb[8..16].copy_from_slice(&131072u64.to_be_bytes()); // sb_dblocks
+        sb[32..48].copy_from_slice(&make_guid("xfs"));
+        sb[48..56].copy_from_slice(&65541u64.to_be_bytes()); // sb_logstart
+        sb[56..64].copy_from_slice(&128u64.to_be_bytes()); // sb_rootino
+        sb[64..72].copy_from_slice(&129u64.to_be_bytes()); // sb_rbmino
+        sb[72..80].copy_from_slice(&130u64.to_be_bytes()); // sb_rsumino
+        sb[80..84].copy_from_slice(&1u32.to_be_bytes()); // sb_rextsize
+        sb[84..88].copy_from_slice(&32768u32.to_be_bytes()); // sb_agblocks
+        sb[88..92].copy_from_slice(&4u32.to_be_bytes()); // sb_agcount
+        sb[96..100].copy_from_slice(&16384u32.to_be_bytes()); // sb_logblocks
+        sb[100..102].copy_from_slice(&0xb5a5u16.to_be_bytes()); // sb_versionnum
+        sb[102..104].copy_from_slice(&sector_size.to_be_bytes()); // sb_sectsize
+        sb[104..106].copy_from_slice(&512u16.to_be_bytes()); // sb_inodesize
+        sb[106..108].copy_from_slice(&8u16.to_be_bytes()); // sb_inopblock
+        sb[108..112].copy_from_slice(b"root"); // sb_fname
+        sb[120] = 12; // sb_blocklog
+        sb[121] = sector_size.trailing_zeros() as u8; // sb_sectlog
+        sb[122] = 9; // sb_inodelog
+        sb[123] = 3; // sb_inopblog
+        sb[124] = 15; // sb_agblklog
+        sb[127] = 25; // sb_imax_pct
+        sb
+    }
+
+    /// A FAT32 boot sector as written by mkfs.fat for a 127 MiB ESP.
+    fn fat_boot_sector(sector_size: u16) -> Vec<u8> {
+        let sectors = 127 * 1024 * 1024 / sector_size as u32;
+        let mut bs = vec![0u8; 512];
+        bs[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+        bs[3..11].copy_from_slice(b"mkfs.fat");
+        bs[11..13].copy_from_slice(&sector_size.to_le_bytes()); // BPB_BytsPerSec
+        bs[13] = 1; // BPB_SecPerClus
+        bs[14..16].copy_from_slice(&32u16.to_le_bytes()); // BPB_RsvdSecCnt
+        bs[16] = 2; // BPB_NumFATs
+        bs[21] = 0xf8; // BPB_Media
+        bs[24..26].copy_from_slice(&32u16.to_le_bytes()); // BPB_SecPerTrk
+        bs[26..28].copy_from_slice(&64u16.to_le_bytes()); // BPB_NumHeads
+        bs[32..36].copy_from_slice(&sectors.to_le_bytes()); // BPB_TotSec32
+        bs[36..40].copy_from_slice(&(sectors / 128).to_le_bytes()); // BPB_FATSz32
+        bs[44..48].copy_from_slice(&2u32.to_le_bytes()); // BPB_RootClus
+        bs[48..50].copy_from_slice(&1u16.to_le_bytes()); // BPB_FSInfo
+        bs[50..52].copy_from_slice(&6u16.to_le_bytes()); // BPB_BkBootSec
+        bs[64] = 0x80; // BS_DrvNum
+        bs[66] = 0x29; // BS_BootSig
+        bs[67..71].copy_from_slice(&0x1234abcdu32.to_le_bytes()); // BS_VolID
+        bs[71..82].copy_from_slice(b"EFI-SYSTEM ");
+        bs[82..90].copy_from_slice(b"FAT32   ");
+        bs[510..512].copy_from_slice(&[0x55, 0xaa]);
+        bs
+    }
+
+    #[test]
+    fn test_sector_size_translation_filesystems() {
+        let len = 10 * 1024 * 1024;
+        let mb = 1024 * 1024;
+
+        // 512-byte image with an ESP in the first MiB and a root
+        // filesystem after it
+        let make_image = |esp_sector_size: u16| {
+            let mut image = Cursor::new(vec![0u8; len]);
+            GPT::write_protective_mbr_into(&mut image, 512).unwrap();
+            let mut gpt = GPT::new_from(&mut image, 512, make_guid("disk")).unwrap();
+            for (i, (start, end)) in [(128, 2047), (2048, 20439)].into_iter().enumerate() {
+                gpt[i as u32 + 1] = GPTPartitionEntry {
+                    partition_type_guid: make_guid("type"),
+                    unique_partition_guid: make_guid(&format!("guid{i}")),
+                    starting_lba: start,
+                    ending_lba: end,
+                    attribute_bits: 0,
+                    partition_name: format!("part{i}").as_str().into(),
+                };
+            }
+            gpt.write_into(&mut image).unwrap();
+            let mut image = image.into_inner();
+            image[64 * 1024..64 * 1024 + 512].copy_from_slice(&fat_boot_sector(esp_sector_size));
+            image
+        };
+
+        // ESP formatted for 512-byte sectors
+        assert_eq!(
+            SectorSizeTranslation::new(&make_image(512)[..mb], 4096)
+                .unwrap_err()
+                .to_string(),
+            "partition 1 has a vfat filesystem with 512-byte sectors, which can't be used on a disk with 4096-byte sectors"
+        );
+
+        // ESP formatted for 4096-byte sectors; root filesystem is checked
+        // when streamed
+        let image = make_image(4096);
+        let translation = SectorSizeTranslation::new(&image[..mb], 4096).unwrap();
+        assert_eq!(
+            translation.headers().collect::<Vec<_>>(),
+            [(1, 64 * 1024, 512), (2, mb as u64, 512)]
+        );
+        assert_eq!(
+            translation
+                .check_filesystem(2, &xfs_superblock(512))
+                .unwrap_err()
+                .to_string(),
+            "partition 2 has a xfs filesystem with 512-byte sectors, which can't be used on a disk with 4096-byte sectors"
+        );
+        translation
+            .check_filesystem(2, &xfs_superblock(4096))
+            .unwrap();
+        translation.check_filesystem(2, &[0u8; 512]).unwrap();
+        translation.check_filesystem(2, b"XFSB").unwrap();
+
+        // 4096-byte filesystems can be translated back to 512-byte sectors
+        let mut translated = image[..mb].to_vec();
+        translation.translate_head(&mut translated).unwrap();
+        let translation = SectorSizeTranslation::new(&translated, 512).unwrap();
+        translation
+            .check_filesystem(2, &xfs_superblock(4096))
+            .unwrap();
+        translation
+            .check_filesystem(2, &xfs_superblock(512))
+            .unwrap();
+
+        // only 512-byte and 4096-byte sectors are supported
+        assert_eq!(
+            SectorSizeTranslation::new(&image[..mb], 1024)
+                .unwrap_err()
+                .to_string(),
+            "can't translate from 512-byte to 1024-byte sectors"
+        );
+    }
+
+    #[test]
+    fn test_saved_partitions() {
+        use PartitionFilter::*;
+
+        let base_parts = vec![
+            make_part(1, "one", 1, 1024),
+            make_part(2, "two", 1024, 2048),
+            make_part(3, "three", 2048, 3072),
+            make_part(4, "four", 3072, 4096),
+            make_part(5, "five", 4096, 5120),
+            make_part(7, "seven", 5120, 6144),
+            make_part(8, "eight", 6144, 7168),
+            make_part(9, "nine", 7168, 8192),
+            make_part(10, "", 8192, 8193),
+            make_part(11, "", 8193, 8194),
+        ];
+        let image_parts = vec![
+            make_part(1, "boot", 1, 384),
+            make_part(2, "EFI-SYSTEM", 384, 512),
+            make_part(4, "root", 1024, 2200),
+        ];
+        let merge_base_parts = vec![make_part(2, "unused", 500, 3500)];
+
+        let index = |i| Some(NonZeroU32::new(i).unwrap());
+        let label = |l| Label(glob::Pattern::new(l).unwrap());
+        let tests = [
+            // Partition range
+            (
+                vec![Index(index(5), None)],
+                vec![
+                    make_part(5, "five", 4096, 5120),
+                    make_part(7, "seven", 5120, 6144),
+                    make_part(8, "eight", 6144, 7168),
+                    make_part(9, "nine", 7168, 8192),
+                    make_part(10, "", 8192, 8193),
+                    make_part(11, "", 8193, 8194),
+                ],
+                vec![
+                    make_part(1, "boot", 1, 384),
+                    make_part(2, "EFI-SYSTEM", 384, 512),
+                    make_part(4, "root", 1024, 2200),
+                    make_part(5, "five", 4096, 5120),
+                    make_part(7, "seven", 5120, 6144),
+                    make_part(8, "eight", 6144, 7168),
+                    make_part(9, "nine", 7168, 8192),
+                    make_part(10, "", 8192, 8193),
+                    make_part(11, "", 8193, 8194),
+                ],
+            ),
+            // Glob
+            (
+                vec![label("*i*")],
+                vec![
+                    make_part(5, "five", 4096, 5120),
+                    make_part(8, "eight", 6144, 7168),
+                    make_part(9, "nine", 7168, 8192),
+                ],
+                vec![
+                    make_part(1, "boot", 1, 384),
+                    make_part(2, "EFI-SYSTEM", 384, 512),
+                    make_part(4, "root", 1024, 2200),
+                    make_part(5, "five", 4096, 5120),
+                    make_part(8, "eight", 6144, 7168),
+                    make_part(9, "nine", 7168, 8192),
+                ],
+            ),
+            // Missing label, single partition, irrelevant range
+            (
+                vec![
+                    label("six"),
+                    Index(index(7), index(7)),
+                    Index(index(15), None),
+                ],
+                vec![make_part(7, "seven", 5120, 6144)],
+                vec![
+                    make_part(1, "boot", 1, 384),
+                    make_part(2, "EFI-SYSTEM", 384, 512),
+                    make_part(4, "root", 1024, 2200),
+                    make_part(7, "seven", 5120, 6144),
+                ],
+            ),
+            // Empty label match, multiple results
+            (
+                vec![label("")],
+                vec![make_part(10, "", 8192, 8193), make_part(11, "", 8193, 8194)],
+                vec![
+                    make_part(1, "boot", 1, 384),
+                    make_part(2, "EFI-SYSTEM", 384, 512),
+                    make_part(4, "root", 1024, 2200),
+                    make_part(10, "", 8192, 8193),
+                    make_part(11, "", 8193, 8194),
+                ],
+            ),
+            // Partition renumbering
+            (
+                vec![Index(index(4), None)],
+                vec![
+                    make_part(4, "four", 3072, 4096),
+                    make_part(5, "five", 4096, 5120),
+                    make_part(7, "seven", 5120, 6144),
+                    make_part(8, "eight", 6144, 7168),
+                    make_part(9, "nine", 7168, 8192),
+                    make_part(10, "", 8192, 8193),
+                    make_part(11, "", 8193, 8194),
+                ],
+                vec![
+                    make_part(1, "boot", 1, 384),
+                    make_part(2, "EFI-SYSTEM", 384, 512),
+                    make_part(4, "root", 1024, 2200),
+                    make_part(5, "four", 3072, 4096),
+                    make_part(6, "five", 4096, 5120),
+                    make_part(7, "seven", 5120, 6144),
+                    make_part(8, "eight", 6144, 7168),
+                    make_part(9, "nine", 7168, 8192),
+                    make_part(10, "", 8192, 8193),
+                    make_part(11, "", 8193, 8194),
+                ],
+            ),
+            // Unknown type GUID, partition GUID, size range
+            (
+                vec![
+                    Type(Uuid::from_bytes_le(make_guid("other"))),
+                    Guid(Uuid::from_bytes_le(make_guid("seven 5120 6144"))),
+                    Size(None, Some(1024 * 1024)),
+                ],
+                vec![
+                    make_part(7, "seven", 5120, 6144),
+                    make_part(10, "", 8192, 8193),
+                    make_part(11, "", 8193, 8194),
+                ],
+                vec![
+                    make_part(1, "boot", 1, 384),
+                    make_part(2, "EFI-SYSTEM", 384, 512),
+                    make_part(4, "root", 1024, 2200),
+                    make_part(7, "seven", 5120, 6144),
+                    make_part(10, "", 8192, 8193),
+                    make_part(11, "", 8193, 8194),
+                ],
+            ),
+            // No saved partitions
+            (
+                vec![Index(index(15), None)],
+                vec![],
+                merge_base_parts.clone(),
+            ),
+            // No filters
+            (vec![], vec![], merge_base_parts.clone()),
+        ];
+
+        let mut base = make_disk(512, &base_parts);
+        let mut image = make_disk(512, &image_parts);
+        assert_eq!(
+            get_gpt_disk_size(&mut image).unwrap(),
+            image_parts[2].1.ending_lba * 512 + 1024 * 1024
+        );
+        for (testnum, (filter, expected_blank, expected_image)) in tests.iter().enumerate() {
+            // try overwriting on blank disk
+            let saved = SavedPartitions::new_from_file(&mut base, 512, filter).unwrap();
+            let mut disk = make_unformatted_disk();
+            saved.overwrite(&mut disk).unwrap();
+            assert!(disk_has_mbr(&mut disk).unwrap(), "test {testnum}");
+            let result = GPT::find_from(&mut disk).unwrap();
+            assert_eq!(
+                get_gpt_size(&mut disk).unwrap(),
+                512 * result.header.first_usable_lba
+            );
+            assert_partitions_eq(expected_blank, &result, &format!("test {testnum} blank"));
+
+            // try merging with image disk onto merge_base disk
+            let mut disk = make_disk(512, &merge_base_parts);
+            saved.merge(&mut image, &mut disk).unwrap();
+            assert!(
+                disk_has_mbr(&mut disk).unwrap() != expected_blank.is_empty(),
+                "test {testnum}"
+            );
+            let result = GPT::find_from(&mut disk).unwrap();
+            assert_eq!(
+                get_gpt_size(&mut disk).unwrap(),
+                512 * result.header.first_usable_lba
+            );
+            assert_partitions_eq(expected_image, &result, &format!("test {testnum} image"));
+
+            // check that the merge plan agrees with the merge
+            let plan = saved.plan_merge(&mut image).unwrap();
+            assert_eq!(
+                plan.iter()
+                    .map(|p| (p.new_index, p.label.as_str()))
+                    .collect::<Vec<_>>(),
+                expected_image[expected_image.len() - expected_blank.len()..]
+                    .iter()
+                    .map(|(i, p)| (*i, p.partition_name.as_str()))
+                    .collect::<Vec<_>>(),
+                "test {testnum} plan"
+            );
+            assert_eq!(
+                saved.get_offset().unwrap(),
+                match expected_blank.is_empty() {
+                    true => None,
+                    false => {
+                        let (i, p) = &expected_blank[0];
+                        Some((
+                            p.starting_lba * 512,
+                            format!("partition {} (\"{}\")", i, p.partition_name.as_str()),
+                        ))
+                    }
+                },
+                "test {testnum}"
+            );
+        }
+
+        // ensure overwrite clobbers every byte of MBR
+        for sector_size in [512_usize, 4096_usize].iter() {
+            let mut disk = make_unformatted_disk();
+            disk.write_all(&vec![0xdau8; *sector_size]).unwrap();
+            let saved =
+                SavedPartitions::new_from_file(&mut disk, *sector_size as u64, &[]).unwrap();
+            saved.overwrite(&mut disk).unwrap();
+            assert!(disk_has_mbr(&mut disk).unwrap(), "{}", *sector_size);
+            disk.rewind().unwrap();
+            let mut buf = vec![0u8; *sector_size + 1];
+            disk.read_exact(&mut buf).unwrap();
+            assert_eq!(
+                buf.iter().position(|v| *v == 0xda),
+                None,
+                "{}",
+                *sector_size
+            );
+            // verify the first byte of the GPT magic number is intact
+            assert_eq!(buf[*sector_size], 0x45u8, "{}", *sector_size);
+        }
+
+        // test type GUID and size filters matching many partitions
+        let saved = SavedPartitions::new_from_file(
+            &mut base,
+            512,
+            &[Type(Uuid::from_bytes_le(make_guid("type")))],
+        )
+        .unwrap();
+        assert_eq!(saved.partitions, base_parts);
+        let saved = SavedPartitions::new_from_file(
+            &mut base,
+            512,
+            &[Size(Some(1024 * 1024 * 1024), Some(1024 * 1024 * 1024))],
+        )
+        .unwrap();
+        assert_eq!(saved.partitions, base_parts[1..8]);
+
+        // test merging with unformatted initial disk
+        let mut disk = make_unformatted_disk();
+        let saved = SavedPartitions::new_from_file(&mut disk, 512, &[label("z")]).unwrap();
+        let mut disk = make_disk(512, &merge_base_parts);
+        saved.merge(&mut image, &mut disk).unwrap();
+        let result = GPT::find_from(&mut disk).unwrap();
+        assert_partitions_eq(&merge_base_parts, &result, "unformatted disk");
+
+        // test overlapping partitions
+        let saved =
+            SavedPartitions::new_from_file(&mut base, 512, &[Index(index(1), index(1))]).unwrap();
+        let mut disk = make_disk(512, &merge_base_parts);
+        let err = saved.merge(&mut image, &mut disk).unwrap_err();
+        assert!(
+            format!("{err:#}").contains(&gptman::Error::InvalidPartitionBoundaries.to_string()),
+            "incorrect error: {err:#}"
+        );
+
+        // test trying to save partitions from a protective MBR without a GPT
+        let mut disk = make_unformatted_disk();
+        gptman::GPT::write_protective_mbr_into(&mut disk, 512).unwrap();
+        // label only
+        SavedPartitions::new(&mut disk, 512, &[label("*i*")]).unwrap();
+        // index only
+        assert_eq!(
+            SavedPartitions::new(&mut disk, 512, &[Index(index(1), index(1))])
+                .unwrap_err()
+                .to_string(),
+            "disk has a protective MBR but no valid GPT"
+        );
+        // label and index
+        assert_eq!(
+            SavedPartitions::new(&mut disk, 512, &[Index(index(1), index(1)), label("*i*")])
+                .unwrap_err()
+                .to_string(),
+            "disk has a protective MBR but no valid GPT"
+        );
+
+        // test sector size mismatch
+        let saved = SavedPartitions::new_from_file(&mut base, 512, &[label("*i*")]).unwrap();
+        let mut image_4096 = make_disk(4096, &image_parts);
+        assert_eq!(
+            get_gpt_size(&mut image_4096).unwrap(),
+            4096 * GPT::find_from(&mut image_4096)
+                .unwrap()
+                .header
+                .first_usable_lba
+        );
+        let mut disk = make_disk(4096, &merge_base_parts);
+        assert_eq!(
+            saved
+                .merge(&mut image_4096, &mut disk)
+                .unwrap_err()
+                .to_string(),
+            "GPT sector size 4096 doesn't match expected 512"
+        );
+
+        // test copying invalid partitions
+        let mut disk = make_unformatted_disk();
+        let data = include_bytes!("../../fixtures/gpt-512-duplicate-partition-guids.xz");
+        copy(&mut XzDecoder::new(&data[..]), &mut disk).unwrap();
+        assert_eq!(
+            SavedPartitions::new_from_file(&mut disk, 512, &[label("*")])
+                .unwrap_err()
+                .to_string(),
+            "failed dry run restoring saved partitions; input partition table may be invalid"
+        );
+
+        // test corrupt input partition table
+        for sector_size in &[512, 4096] {
+            let sector_size: u64 = *sector_size;
+            // backup corrupt
+            let mut disk = make_damaged_disk(sector_size, &base_parts, false, true);
+            let saved = SavedPartitions::new_from_file(&mut disk, sector_size, &[]).unwrap();
+            assert!(!saved.is_saved());
+            let saved =
+                SavedPartitions::new_from_file(&mut disk, sector_size, &[label("one")]).unwrap();
+            assert!(saved.is_saved());
+            // primary corrupt
+            let mut disk = make_damaged_disk(sector_size, &base_parts, true, false);
+            let saved = SavedPartitions::new_from_file(&mut disk, sector_size, &[]).unwrap();
+            assert!(!saved.is_saved());
+            let saved =
+                SavedPartitions::new_from_file(&mut disk, sector_size, &[label("one")]).unwrap();
+            assert!(saved.is_saved());
+            // both corrupt
+            let mut disk = make_damaged_disk(sector_size, &base_parts, true, true);
+            let saved = SavedPartitions::new_from_file(&mut disk, sector_size, &[]).unwrap();
+            assert!(!saved.is_saved());
+            let err = SavedPartitions::new_from_file(&mut disk, sector_size, &[label("one")])
+                .unwrap_err();
+            assert!(
+                format!("{err:#}").contains("could not read primary header"),
+                "incorrect error: {err:#}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_saved_mbr_partitions() {
+        use PartitionFilter::*;
+        let index = |i| Some(NonZeroU32::new(i).unwrap());
+        let mib = 1024 * 1024;
+        let image_part = |start: u64, end: u64| {
+            (
+                1,
+                GPTPartitionEntry {
+                    partition_type_guid: make_guid("type"),
+                    unique_partition_guid: make_guid("image"),
+                    starting_lba: start * 2048,
+                    ending_lba: end * 2048 - 1,
+                    attribute_bits: 0,
+                    partition_name: "image".into(),
+                },
+            )
+        };
+
+        let mut disk = make_mbr_disk();
+        assert_eq!(
+            read_mbr_partitions(&mut disk, 512)
+                .unwrap()
+                .iter()
+                .map(|(i, p)| (*i, p.partition_type, p.start_lba, p.sectors))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, 0x0c, 2048, 2048),
+                (3, 0x83, 4096, 4096),
+                (5, 0x82, 10240, 2048),
+                (6, 0x83, 14336, 2048),
+            ]
+        );
+
+        // labels can't select MBR partitions
+        let saved = SavedPartitions::new_from_file(
+            &mut disk,
+            512,
+            &[Label(glob::Pattern::new("*").unwrap())],
+        )
+        .unwrap();
+        assert!(!saved.is_saved());
+
+        // type and size filters match the converted partitions
+        let saved = SavedPartitions::new_from_file(
+            &mut disk,
+            512,
+            &[
+                Type(Uuid::parse_str("0657fd6d-a4ab-43c4-84e5-0933c84b4f4f").unwrap()),
+                Size(Some(2 * mib), None),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            saved.partitions.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![3, 5]
+        );
+
+        // save a primary and the logical partitions by index
+        let saved = SavedPartitions::new_from_file(
+            &mut disk,
+            512,
+            &[Index(index(1), index(1)), Index(index(5), None)],
+        )
+        .unwrap();
+        assert_eq!(
+            saved.partitions.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![1, 5, 6]
+        );
+        let (_, fat) = &saved.partitions[0];
+        assert_eq!(
+            Uuid::from_bytes_le(fat.partition_type_guid),
+            Uuid::parse_str("ebd0a0a2-b9e5-4433-87c0-68b6b72699c7").unwrap()
+        );
+        assert_eq!(fat.attribute_bits, 1 << 2);
+        let (_, swap) = &saved.partitions[1];
+        assert_eq!(
+            Uuid::from_bytes_le(swap.partition_type_guid),
+            Uuid::parse_str("0657fd6d-a4ab-43c4-84e5-0933c84b4f4f").unwrap()
+        );
+        assert_eq!(swap.attribute_bits, 0);
+        assert_ne!(
+            saved.partitions[1].1.unique_partition_guid,
+            saved.partitions[2].1.unique_partition_guid
+        );
+        assert_eq!(
+            saved.get_ranges().unwrap(),
+            vec![(mib, 2 * mib), (5 * mib, 6 * mib), (7 * mib, 8 * mib)]
+        );
+        assert_eq!(
+            saved.get_offset().unwrap(),
+            Some((mib, "partition 1 (\"\")".into()))
+        );
+
+        // overwrite replaces the MBR with a GPT holding the saved partitions
+        saved.overwrite(&mut disk).unwrap();
+        let gpt = GPT::find_from(&mut disk).unwrap();
+        assert_partitions_eq(&saved.partitions, &gpt, "MBR overwrite");
+
+        // merging an image that overlaps a saved partition fails
+        let mut image = make_disk(512, &vec![image_part(1, 4)]);
+        let err = saved.merge(&mut image, &mut disk).unwrap_err();
+        assert!(
+            format!("{err:#}").contains(&gptman::Error::InvalidPartitionBoundaries.to_string()),
+            "incorrect error: {err:#}"
+        );
+
+        // merge saved partitions after the image's partitions
+        let saved =
+            SavedPartitions::new_from_file(&mut make_mbr_disk(), 512, &[Index(index(3), None)])
+                .unwrap();
+        let mut image = make_disk(512, &vec![image_part(1, 2)]);
+        let mut disk = make_unformatted_disk();
+        saved.merge(&mut image, &mut disk).unwrap();
+        let gpt = GPT::find_from(&mut disk).unwrap();
+        let mut expected = vec![image_part(1, 2)];
+        expected.extend(saved.partitions.iter().cloned());
+        assert_partitions_eq(&expected, &gpt, "MBR merge");
+        assert_eq!(
+            expected.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![1, 3, 5, 6]
+        );
+
+        // MBR partition overlapping the backup GPT fails the dry run
+        let mut disk = make_unformatted_disk();
+        disk.set_len(4 * mib).unwrap();
+        let mut mbr = [0u8; 512];
+        write_mbr_entry(&mut mbr, 0, false, 0x83, 2048, 6144);
+        disk.write_all(&mbr).unwrap();
+        let err =
+            SavedPartitions::new_from_file(&mut disk, 512, &[Index(index(1), None)]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("failed dry run restoring saved partitions"),
+            "incorrect error: {err:#}"
+        );
+    }
+
+    #[test]
+    fn test_saved_ranges() {
+        let mib = 1024 * 1024;
+        let mut disk = make_disk(
+            512,
+            &vec![
+                make_part(1, "one", 1, 2),
+                make_part(2, "two", 2, 4),
+                make_part(3, "three", 4, 5),
+                make_part(4, "four", 6, 8),
+            ],
+        );
+        let filter = |l| vec![PartitionFilter::Label(glob::Pattern::new(l).unwrap())];
+
+        // adjacent saved partitions
+        let saved = SavedPartitions::new_from_file(&mut disk, 512, &filter("t*")).unwrap();
+        assert_eq!(
+            saved.get_ranges().unwrap(),
+            vec![(2 * mib, 4 * mib), (4 * mib, 5 * mib)]
+        );
+        assert_eq!(
+            saved.get_unsaved_ranges(10 * mib).unwrap(),
+            vec![(0, 2 * mib), (5 * mib, 10 * mib)]
+        );
+
+        // saved partition at the end of the disk
+        let saved = SavedPartitions::new_from_file(&mut disk, 512, &filter("four")).unwrap();
+        assert_eq!(
+            saved.get_unsaved_ranges(8 * mib).unwrap(),
+            vec![(0, 6 * mib)]
+        );
+
+        // nothing saved
+        let saved = SavedPartitions::new_from_file(&mut disk, 512, &[]).unwrap();
+        assert_eq!(saved.get_ranges().unwrap(), vec![]);
+        assert_eq!(
+            saved.get_unsaved_ranges(10 * mib).unwrap(),
+            vec![(0, 10 * mib)]
+        );
+    }
+
+    #[test]
+    fn test_partition_stash() {
+        let mut parts = vec![make_part(2, "two", 2, 4), make_part(3, "three", 4, 5)];
+        parts[1].1.attribute_bits = 1 << 60;
+        let mut disk = make_disk(512, &parts);
+        let filter = vec![PartitionFilter::Label(glob::Pattern::new("t*").unwrap())];
+        let saved = SavedPartitions::new_from_file(&mut disk, 512, &filter).unwrap();
+
+        // round trip through the portable format
+        let mut stash = Vec::new();
+        saved.write_stash(&mut stash).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&stash).unwrap();
+        assert_eq!(value["sector-size"], 512);
+        assert_eq!(value["partitions"][1]["label"], "three");
+        assert_eq!(
+            value["partitions"][1]["attributes"].as_u64().unwrap(),
+            1 << 60
+        );
+        let restored = SavedPartitions::read_stash(&mut Cursor::new(&stash)).unwrap();
+        assert_eq!(restored.sector_size, 512);
+        assert_eq!(restored.partitions, parts);
+
+        // read a GPT-only disk image stash
+        let mut image = tempfile().unwrap();
+        image.set_len(10 * 1024 * 1024).unwrap();
+        saved.overwrite(&mut image).unwrap();
+        let restored = SavedPartitions::read_stash(&mut image).unwrap();
+        assert_eq!(restored.partitions, parts);
+
+        // restore by merging into an existing table
+        let mut disk = make_disk(512, &vec![make_part(1, "one", 1, 2)]);
+        let mut source = disk.try_clone().unwrap();
+        restored.merge(&mut source, &mut disk).unwrap();
+        let mut expected = vec![make_part(1, "one", 1, 2)];
+        expected.extend(parts);
+        assert_partitions_eq(&expected, &GPT::find_from(&mut disk).unwrap(), "merge");
+
+        // bad stashes
+        for (stash, err) in [
+            (
+                r#"{"sector-size": 1024, "partitions": []}"#,
+                "stash has unreasonable sector size 1024",
+            ),
+            (
+                r#"{"sector-size": 512, "partitions": [{"index": 1, "label": "",
+                "type-guid": "0fc63daf-8483-4772-8e79-3d69d8477de4",
+                "guid": "0fc63daf-8483-4772-8e79-3d69d8477de4",
+                "start-lba": 4096, "end-lba": 2048, "attributes": 0}]}"#,
+                "stash has invalid entry for partition 1",
+            ),
+        ] {
+            assert_eq!(
+                SavedPartitions::read_stash(&mut Cursor::new(stash))
+                    .unwrap_err()
+                    .to_string(),
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_plan_relocation() {
+        let mib = 1024 * 1024;
+        let mut disk = make_disk(
+            512,
+            &vec![
+                make_part(1, "one", 1, 3),
+                make_part(2, "two", 4, 12),
+                make_part(3, "three", 14, 15),
+                make_part(4, "four", 20, 30),
+            ],
+        );
+        let filter = |l| vec![PartitionFilter::Label(glob::Pattern::new(l).unwrap())];
+
+        // nothing overlaps
+        let saved = SavedPartitions::new_from_file(&mut disk, 512, &filter("f*")).unwrap();
+        assert_eq!(saved.plan_relocation(10 * mib, 64 * mib).unwrap(), vec![]);
+
+        // partitions inside and straddling the image move past the image
+        // and around other saved partitions, without overlapping their own
+        // original data
+        let saved = SavedPartitions::new_from_file(&mut disk, 512, &filter("*")).unwrap();
+        let moves = saved.plan_relocation(10 * mib + 1, 64 * mib).unwrap();
+        let mv = |index, label: &str, from, to, length| PartitionMove {
+            index,
+            label: label.into(),
+            from: from * mib,
+            to: to * mib,
+            length: length * mib,
+        };
+        assert_eq!(moves, vec![mv(1, "one", 1, 12, 2), mv(2, "two", 4, 30, 8)]);
+        let relocated = saved.relocated(&moves).unwrap();
+        assert_eq!(
+            relocated.get_ranges().unwrap(),
+            vec![
+                (12 * mib, 14 * mib),
+                (14 * mib, 15 * mib),
+                (20 * mib, 30 * mib),
+                (30 * mib, 38 * mib)
+            ]
+        );
+        assert_eq!(relocated.get_offset().unwrap().unwrap().0, 12 * mib);
+        // relocated table is valid
+        let mut temp = tempfile().unwrap();
+        temp.set_len(64 * mib).unwrap();
+        relocated.overwrite(&mut temp).unwrap();
+
+        // no room
+        assert_eq!(
+            saved
+                .plan_relocation(10 * mib, 38 * mib)
+                .unwrap_err()
+                .to_string(),
+            "no free space after the install image to move partition 2 (\"two\")"
+        );
+    }
+
+    #[test]
+    fn test_discard_ranges() {
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0xff; 3 * 1024 * 1024]).unwrap();
+        discard_ranges(&file, &[(4096, 8192), (1024 * 1024, 2 * 1024 * 1024)]).unwrap();
+
+        let mut buf = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf.len(), 3 * 1024 * 1024);
+        for (i, b) in buf.iter().enumerate() {
+            let discarded =
+                (4096..8192).contains(&i) || (1024 * 1024..2 * 1024 * 1024).contains(&i);
+            assert_eq!(*b, if discarded { 0 } else { 0xff }, "offset {i}");
+        }
+    }
+
+    /// Partition entry spanning the specified MiB range of a 512-byte-sector
+    /// disk.
+    fn make_part(i: u32, name: &str, start: u64, end: u64) -> (u32, GPTPartitionEntry) {
+        (
+            i,
+            GPTPartitionEntry {
+                partition_type_guid: make_guid("type"),
+                unique_partition_guid: make_guid(&format!("{name} {start} {end}")),
+                starting_lba: start * 2048,
+                ending_lba: end * 2048 - 1,
+                attribute_bits: 0,
+                partition_name: name.into(),
+            },
+        )
+    }
+
+    // TODO: The partitions array assumes 512-byte sectors and we don't
+    // scale the start/end values for 4096.  This doesn't matter right now
+    // because the only use of 4096-byte sectors is in an error test.
+    fn make_disk(sector_size: u64, partitions: &Vec<(u32, GPTPartitionEntry)>) -> File {
+        let mut disk = make_unformatted_disk();
+        // Make the disk just large enough for its partitions, then resize
+        // it back up afterward.  This tests that we properly handle copying
+        // saved partitions from the larger base disk into the smaller
+        // install image.
+        let len = if partitions.is_empty() {
+            1024 * 1024
+        } else {
+            partitions[partitions.len() - 1].1.ending_lba * sector_size + 1024 * 1024
+        };
+        disk.set_len(len).unwrap();
+        let mut gpt = GPT::new_from(&mut disk, sector_size, make_guid("disk")).unwrap();
+        for (partnum, entry) in partitions {
+            gpt[*partnum] = entry.clone();
+        }
+        gpt.write_into(&mut disk).unwrap();
+        disk.set_len(10 * 1024 * 1024 * 1024).unwrap();
+        disk
+    }
+
+    /// Disk with an MBR holding two primary partitions, an empty slot,
+    /// and an extended partition containing two logical partitions:
+    ///   1: 1-2 MiB, bootable FAT32
+    ///   3: 2-4 MiB, Linux
+    ///   5: 5-6 MiB, swap
+    ///   6: 7-8 MiB, Linux
+    fn make_mbr_disk() -> File {
+        let mut disk = make_unformatted_disk();
+        let mut write_sector = |lba: u64, sector: &[u8]| {
+            disk.seek(SeekFrom::Start(lba * 512)).unwrap();
+            disk.write_all(sector).unwrap();
+        };
+        let mut mbr = [0u8; 512];
+        write_mbr_entry(&mut mbr, 0, true, 0x0c, 2048, 2048);
+        write_mbr_entry(&mut mbr, 1, false, 0x05, 8192, 10240);
+        write_mbr_entry(&mut mbr, 2, false, 0x83, 4096, 4096);
+        write_sector(0, &mbr);
+        let mut ebr = [0u8; 512];
+        write_mbr_entry(&mut ebr, 0, false, 0x82, 2048, 2048);
+        write_mbr_entry(&mut ebr, 1, false, 0x05, 4096, 6144);
+        write_sector(8192, &ebr);
+        let mut ebr = [0u8; 512];
+        write_mbr_entry(&mut ebr, 0, false, 0x83, 2048, 2048);
+        write_sector(12288, &ebr);
+        disk
+    }
+    fn write_mbr_entry(sector: &mut [u8], slot: usize, boot: bool, ty: u8, start: u32, len: u32) {
+        let entry = &mut sector[446 + slot * 16..446 + (slot + 1) * 16];
+        entry[0] = if boot { 0x80 } else { 0 };
+        entry[4] = ty;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&len.to_le_bytes());
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+    }
+    fn make_unformatted_disk() -> File {
+        let disk = tempfile().unwrap();
+        disk.set_len(10 * 1024 * 1024 * 1024).unwrap();
+        disk
+    }
+
+    fn make_damaged_disk(
+        sector_size: u64,
+        partitions: &Vec<(u32, GPTPartitionEntry)>,
+        damage_primary: bool,
+        damage_backup: bool,
+    ) -> File {
+        let mut disk = make_unformatted_disk();
+        // don't use make_disk() because it intentionally misaligns the
+        // backup GPT
+        let mut gpt = GPT::new_from(&mut disk, sector_size, make_guid("disk")).unwrap();
+        for (partnum, entry) in partitions {
+            gpt[*partnum] = entry.clone();
+            gpt[*partnum].starting_lba /= sector_size / 512;
+            gpt[*partnum].ending_lba /= sector_size / 512;
+        }
+        gpt.write_into(&mut disk).unwrap();
+        if damage_primary {
+            // write garbage to the HeaderCRC32
+            disk.seek(SeekFrom::Start(gpt.header.primary_lba * sector_size + 16))
+                .unwrap();
+            disk.write_all(&[0x15, 0xcd, 0x5b, 0x07]).unwrap();
+        }
+        if damage_backup {
+            // write garbage to the HeaderCRC32
+            disk.seek(SeekFrom::Start(gpt.header.backup_lba * sector_size + 16))
+                .unwrap();
+            disk.write_all(&[0xb1, 0x68, 0xde, 0x3a]).unwrap();
+        }
+        disk
+    }
+
+    fn make_guid(seed: &str) -> [u8; 16] {
+        let mut guid = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        for (i, b) in seed.as_bytes().iter().enumerate() {
+            guid[i % guid.len()] ^= *b;
+        }
+        guid
+    }
+
+    fn assert_partitions_eq(expected: &[(u32, GPTPartitionEntry)], found: &GPT, message: &str) {
+        assert_eq!(
+            expected
+                .iter()
+                .map(|(i, p)| (*i, p))
+                .collect::<Vec<(u32, &GPTPartitionEntry)>>(),
+            found
+                .iter()
+                .filter(|(_, p)| p.is_used())
+                .collect::<Vec<(u32, &GPTPartitionEntry)>>(),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_read_coreos_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let read = || read_coreos_install("/dev/sda3", dir.path()).unwrap();
+        assert_eq!(read(), None);
+        let entries = dir.path().join("loader/entries");
+        std::fs::create_dir_all(&entries).unwrap();
+        assert_eq!(read(), None);
+        std::fs::write(entries.join("README"), "title Not an entry\n").unwrap();
+        assert_eq!(read(), None);
+
+        std::fs::write(
+            entries.join("ostree-1.conf"),
+            "title Fedora CoreOS 39.20240112.3.0 (ostree:1)\nversion 1\nlinux /ostree/vmlinuz\n",
+        )
+        .unwrap();
+        std::fs::write(
+            entries.join("ostree-2.conf"),
+            "title Fedora CoreOS 39.20240128.3.0 (ostree:0)\nversion 2\nlinux /ostree/vmlinuz\n",
+        )
+        .unwrap();
+        assert_eq!(
+            read(),
+            Some(CoreosInstall {
+                boot_device: "/dev/sda3".into(),
+                version: Some("Fedora CoreOS 39.20240128.3.0".into()),
+            })
+        );
+
+        std::fs::write(
+            dir.path().join(".coreos-aleph-version.json"),
+            r#"{"build": "39.20240112.3.0", "ref": "fedora/x86_64/coreos/stable"}"#,
+        )
+        .unwrap();
+        assert_eq!(read().unwrap().version.as_deref(), Some("39.20240112.3.0"));
+    }
+
+    #[test]
+    fn test_parse_bls_entry() {
+        assert_eq!(parse_bls_entry(""), (0, None));
+        assert_eq!(
+            parse_bls_entry(
+                "title  Red Hat Enterprise Linux CoreOS 415.92 (ostree:0)\nversion 3\n"
+            ),
+            (3, Some("Red Hat Enterprise Linux CoreOS 415.92".into()))
+        );
+        assert_eq!(
+            parse_bls_entry("title Custom (ostree:x\nversion bogus\n"),
+            (0, Some("Custom (ostree:x".into()))
+        );
+    }
+
+    #[test]
+    fn test_unescape_mount_field() {
+        assert_eq!(unescape_mount_field("/dev/sda1"), "/dev/sda1");
+        assert_eq!(
+            unescape_mount_field("/mnt/my\\040disk\\011x"),
+            "/mnt/my disk\tx"
+        );
+        assert_eq!(unescape_mount_field("/a\\134b"), "/a\\b");
+        // not an escape
+        assert_eq!(unescape_mount_field("/a\\9b"), "/a\\9b");
+        assert_eq!(unescape_mount_field("/a\\04"), "/a\\04");
+    }
+
+    #[test]
+    fn test_holder_kind_from_dm_uuid() {
+        assert_eq!(
+            HolderKind::from_dm_uuid("LVM-Vx3Wb7DpDqPj7xXbE7ZUPbh1pFw1VQGxWBLJ5Ks"),
+            HolderKind::Lvm
+        );
+        assert_eq!(
+            HolderKind::from_dm_uuid("CRYPT-LUKS2-3f7c9d2e8a1b4c5d9e0f1a2b3c4d5e6f-luks"),
+            HolderKind::Crypt
+        );
+        assert_eq!(
+            HolderKind::from_dm_uuid("mpath-3600508b400105e210000900000490000"),
+            HolderKind::Multipath
+        );
+        assert_eq!(
+            HolderKind::from_dm_uuid("part1-mpath-3600508b400105e210000900000490000"),
+            HolderKind::DeviceMapper
+        );
+        assert_eq!(HolderKind::from_dm_uuid(""), HolderKind::DeviceMapper);
+    }
+
+    #[test]
+    fn test_ioctl_structs() {
+        // sizes from the kernel UAPI headers
+        assert_eq!(std::mem::size_of::<ioctl::LoopInfo64>(), 232);
+        assert_eq!(std::mem::size_of::<ioctl::BlkpgPartition>(), 152);
+    }
+
+    #[test]
+    fn test_blkpg_plan() {
+        use BlkpgOp::*;
+        let map = |v: &[(u32, u64, u64)]| -> BTreeMap<u32, (u64, u64)> {
+            v.iter().map(|&(n, s, l)| (n, (s, l))).collect()
+        };
+        let mb = 1 << 20;
+
+        // no change
+        let parts = map(&[(1, mb, mb), (2, 2 * mb, 10 * mb)]);
+        assert_eq!(PartTableBlkpg::plan(&parts, &parts), []);
+
+        // new install image replacing old partitions, with a saved
+        // partition renumbered and another one grown in place
+        assert_eq!(
+            PartTableBlkpg::plan(
+                &map(&[
+                    (1, mb, mb),
+                    (2, 2 * mb, 10 * mb),
+                    (3, 20 * mb, 5 * mb),
+                    (4, 40 * mb, mb)
+                ]),
+                &map(&[
+                    (1, mb, mb),
+                    (2, 2 * mb, 4 * mb),
+                    (3, 6 * mb, 8 * mb),
+                    (4, 40 * mb, 2 * mb),
+                    (5, 20 * mb, 5 * mb)
+                ]),
+            ),
+            [
+                Delete(3),
+                Resize(2, 2 * mb, 4 * mb),
+                Resize(4, 40 * mb, 2 * mb),
+                Add(3, 6 * mb, 8 * mb),
+                Add(5, 20 * mb, 5 * mb)
+            ]
+        );
+
+        // cleared partition table
+        assert_eq!(
+            PartTableBlkpg::plan(&map(&[(1, mb, mb), (2, 2 * mb, mb)]), &map(&[])),
+            [Delete(1), Delete(2)]
+        );
+    }
+}
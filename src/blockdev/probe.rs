@@ -0,0 +1,930 @@
+// Copyright 2024 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Native block device discovery and filesystem probing.
+//!
+//! Devices are enumerated from sysfs, and filesystem type, label, and UUID
+//! are read directly from on-disk superblocks.  Type names and property
+//! keys match the ones reported by lsblk and blkid, so callers can treat
+//! the results interchangeably.
+
+use anyhow::{bail, Context, Result};
+use nix::sys::stat::{major, minor};
+use std::collections::{HashMap, HashSet};
+use std::fs::{canonicalize, metadata, read_dir, read_to_string, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use super::{get_mountpoints, is_swap};
+
+/// A block device known to the kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDevice {
+    /// Kernel name, e.g. "sda1" or "dm-0".
+    pub name: String,
+    /// Canonical sysfs directory.
+    sysfs: PathBuf,
+}
+
+/// Signature found on a block device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Superblock {
+    /// Type name, as reported by blkid.
+    pub fstype: String,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+}
+
+impl BlockDevice {
+    /// Look up the block device for a device node or a symlink to one.
+    pub fn from_path(dev: &Path) -> Result<Self> {
+        let meta =
+            metadata(dev).with_context(|| format!("getting metadata for {}", dev.display()))?;
+        if !meta.file_type().is_block_device() {
+            bail!("{} is not a block device", dev.display());
+        }
+        let rdev = meta.st_rdev();
+        Self::from_sysfs(&format!("/sys/dev/block/{}:{}", major(rdev), minor(rdev)))
+    }
+
+    fn from_name(name: &str) -> Result<Self> {
+        Self::from_sysfs(&format!("/sys/class/block/{name}"))
+    }
+
+    fn from_sysfs(path: &str) -> Result<Self> {
+        let sysfs = canonicalize(path).with_context(|| format!("canonicalizing {path}"))?;
+        let name = sysfs
+            .file_name()
+            .with_context(|| format!("path {} has no filename", sysfs.display()))?
+            .to_string_lossy()
+            .into_owned();
+        Ok(Self { name, sysfs })
+    }
+
+    /// Get every block device on the system, sorted by kernel name.
+    pub fn all() -> Result<Vec<Self>> {
+        let dir = Path::new("/sys/class/block");
+        let mut names = Vec::new();
+        for ent in read_dir(dir).with_context(|| format!("listing {}", dir.display()))? {
+            let ent = ent.with_context(|| format!("reading {} entry", dir.display()))?;
+            names.push(ent.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        names.iter().map(|name| Self::from_name(name)).collect()
+    }
+
+    /// The path callers should use for the device.  Device-mapper
+    /// devices are reported by their /dev/mapper name, as lsblk does.
+    pub fn path(&self) -> String {
+        if let Some(name) = self.read_attr("dm/name") {
+            let mapper = format!("/dev/mapper/{name}");
+            if Path::new(&mapper).exists() {
+                return mapper;
+            }
+        }
+        self.node().to_string_lossy().into_owned()
+    }
+
+    /// The kernel device node, which doesn't depend on udev.
+    fn node(&self) -> PathBuf {
+        Path::new("/dev").join(&self.name)
+    }
+
+    fn read_attr(&self, attr: &str) -> Option<String> {
+        read_to_string(self.sysfs.join(attr))
+            .ok()
+            .map(|s| s.trim_end().to_string())
+            .filter(|s| !s.is_empty())
+    }
+
+    /// Size of the device in bytes.
+    pub fn size(&self) -> Result<u64> {
+        let sectors = self
+            .read_attr("size")
+            .with_context(|| format!("reading size of {}", self.name))?;
+        let sectors: u64 = sectors
+            .parse()
+            .with_context(|| format!("parsing size of {}", self.name))?;
+        Ok(sectors * 512)
+    }
+
+    /// Whether the kernel has marked the device read-only.
+    pub fn read_only(&self) -> bool {
+        self.read_attr("ro").as_deref() == Some("1")
+    }
+
+    /// Whether the device is a kernel construct, such as zram or a RAM
+    /// disk, rather than being backed by a hardware device.
+    pub fn is_virtual(&self) -> bool {
+        self.name.starts_with("zram") || !self.sysfs.join("device").exists()
+    }
+
+    /// Whether the device reports rotational media.
+    pub fn rotational(&self) -> bool {
+        self.read_attr("queue/rotational").as_deref() == Some("1")
+    }
+
+    /// Model name reported by the hardware, if any.
+    pub fn model(&self) -> Option<String> {
+        self.read_attr("device/model").and_then(|s| trimmed(&s))
+    }
+
+    /// Serial number reported by the hardware, if any.  SCSI and SATA
+    /// disks only report it in the Unit Serial Number VPD page.
+    pub fn serial(&self) -> Option<String> {
+        self.read_attr("serial")
+            .or_else(|| self.read_attr("device/serial"))
+            .and_then(|s| trimmed(&s))
+            .or_else(|| {
+                let page = std::fs::read(self.sysfs.join("device/vpd_pg80")).ok()?;
+                let len = u16::from_be_bytes(page.get(2..4)?.try_into().ok()?) as usize;
+                trimmed(&String::from_utf8_lossy(page.get(4..4 + len)?))
+            })
+    }
+
+    /// World Wide Name, formatted as lsblk reports it, if any.
+    pub fn wwn(&self) -> Option<String> {
+        let wwid = self
+            .read_attr("wwid")
+            .or_else(|| self.read_attr("device/wwid"))?;
+        if let Some(naa) = wwid.strip_prefix("naa.") {
+            Some(format!("0x{}", naa.to_lowercase()))
+        } else if wwid.starts_with("eui.") || wwid.starts_with("nvme.") {
+            Some(wwid)
+        } else {
+            // vendor-specific identifiers aren't WWNs
+            None
+        }
+    }
+
+    /// Transport used to reach the device, using lsblk's names, if known.
+    pub fn transport(&self) -> Option<String> {
+        let host = self.sysfs.components().find_map(|c| {
+            let c = c.as_os_str().to_str()?;
+            c.strip_prefix("host")
+                .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+                .map(|_| c.to_string())
+        });
+        if let Some(host) = host {
+            // SCSI disk; ask the host adapter's transport class
+            for class in ["spi", "fc", "sas", "iscsi"] {
+                if Path::new(&format!("/sys/class/{class}_host/{host}")).exists() {
+                    return Some(class.into());
+                }
+            }
+            let path = self.sysfs.to_string_lossy();
+            return if path.contains("/usb") {
+                Some("usb".into())
+            } else if path.contains("/ata") {
+                Some("sata".into())
+            } else {
+                None
+            };
+        }
+        if self.name.starts_with("nvme") {
+            Some("nvme".into())
+        } else if self.name.starts_with("vd") {
+            Some("virtio".into())
+        } else if self.name.starts_with("mmcblk") {
+            Some("mmc".into())
+        } else {
+            None
+        }
+    }
+
+    /// Device type, using lsblk's names: "disk", "part", "lvm", "crypt",
+    /// "mpath", "dm", "loop", "rom", or the RAID level for md devices.
+    pub fn kind(&self) -> String {
+        if self.sysfs.join("partition").exists() {
+            return "part".into();
+        }
+        if let Some(uuid) = self.read_attr("dm/uuid") {
+            let prefix = uuid.split('-').next().unwrap_or_default().to_lowercase();
+            return if prefix.starts_with("part") {
+                "part".into()
+            } else if ["lvm", "crypt", "mpath"].contains(&prefix.as_str()) {
+                prefix
+            } else {
+                "dm".into()
+            };
+        }
+        if self.sysfs.join("dm").exists() {
+            return "dm".into();
+        }
+        if let Some(level) = self.read_attr("md/level") {
+            return level;
+        }
+        if self.name.starts_with("loop") {
+            "loop".into()
+        } else if self.name.starts_with("sr") {
+            "rom".into()
+        } else {
+            "disk".into()
+        }
+    }
+
+    /// Partition number, for kernel partitions and for device-mapper
+    /// partitions created by kpartx.
+    pub fn partition_number(&self) -> Option<u32> {
+        if let Some(partno) = self.read_attr("partition") {
+            return partno.parse().ok();
+        }
+        self.read_attr("dm/uuid")
+            .and_then(|uuid| {
+                uuid.strip_prefix("part")
+                    .and_then(|s| s.split('-').next())
+                    .map(String::from)
+            })
+            .and_then(|partno| partno.parse().ok())
+    }
+
+    /// Devices directly built on this one: its partitions, then its
+    /// holders.
+    pub fn children(&self) -> Result<Vec<Self>> {
+        let mut partitions = Vec::new();
+        for ent in
+            read_dir(&self.sysfs).with_context(|| format!("listing {}", self.sysfs.display()))?
+        {
+            let ent = ent.with_context(|| format!("reading {} entry", self.sysfs.display()))?;
+            if ent.path().join("partition").exists() {
+                let dev = Self::from_sysfs(&ent.path().to_string_lossy())?;
+                partitions.push((dev.partition_number(), dev));
+            }
+        }
+        partitions.sort_by_key(|(partno, _)| *partno);
+        let mut ret: Vec<Self> = partitions.into_iter().map(|(_, dev)| dev).collect();
+        ret.extend(self.links("holders")?);
+        Ok(ret)
+    }
+
+    /// Devices this one is directly built on: the containing disk for a
+    /// partition, otherwise the devices it holds.
+    pub fn parents(&self) -> Result<Vec<Self>> {
+        if self.sysfs.join("partition").exists() {
+            let parent = self
+                .sysfs
+                .parent()
+                .with_context(|| format!("path {} has no parent", self.sysfs.display()))?;
+            return Ok(vec![Self::from_sysfs(&parent.to_string_lossy())?]);
+        }
+        self.links("slaves")
+    }
+
+    fn links(&self, dir: &str) -> Result<Vec<Self>> {
+        let dir = self.sysfs.join(dir);
+        let mut names = Vec::new();
+        match read_dir(&dir) {
+            Ok(ents) => {
+                for ent in ents {
+                    let ent = ent.with_context(|| format!("reading {} entry", dir.display()))?;
+                    names.push(ent.file_name().to_string_lossy().into_owned());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| format!("listing {}", dir.display())),
+        }
+        names.sort();
+        names.iter().map(|name| Self::from_name(name)).collect()
+    }
+
+    /// This device followed by everything built on it, depth-first.
+    pub fn descendants(&self) -> Result<Vec<Self>> {
+        let mut ret = Vec::new();
+        let mut seen = HashSet::new();
+        self.walk_descendants(&mut ret, &mut seen)?;
+        Ok(ret)
+    }
+
+    fn walk_descendants(&self, ret: &mut Vec<Self>, seen: &mut HashSet<String>) -> Result<()> {
+        if !seen.insert(self.name.clone()) {
+            return Ok(());
+        }
+        ret.push(self.clone());
+        for child in self.children()? {
+            child.walk_descendants(ret, seen)?;
+        }
+        Ok(())
+    }
+
+    /// This device followed by everything it's built on, breadth-first.
+    pub fn ancestors(&self) -> Result<Vec<Self>> {
+        let mut ret = vec![self.clone()];
+        let mut seen = HashSet::from([self.name.clone()]);
+        let mut i = 0;
+        while i < ret.len() {
+            for parent in ret[i].parents()? {
+                if seen.insert(parent.name.clone()) {
+                    ret.push(parent);
+                }
+            }
+            i += 1;
+        }
+        Ok(ret)
+    }
+
+    /// Read the signature on the device, if any.  Devices which can't be
+    /// opened, such as empty optical drives, have no signature.
+    pub fn probe(&self) -> Result<Option<Superblock>> {
+        let size = self.size()?;
+        if size == 0 {
+            return Ok(None);
+        }
+        let node = self.node();
+        let mut file = match File::open(&node) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        probe_superblock(&mut file, size).with_context(|| format!("probing {}", node.display()))
+    }
+
+    /// Get properties of the device with lsblk's keys: NAME, TYPE,
+    /// FSTYPE, LABEL, UUID, and MOUNTPOINT.  Missing properties are
+    /// omitted.
+    pub fn properties(&self) -> Result<HashMap<String, String>> {
+        let mut ret = HashMap::new();
+        ret.insert("NAME".into(), self.path());
+        ret.insert("TYPE".into(), self.kind());
+        if let Some(sb) = self.probe()? {
+            ret.insert("FSTYPE".into(), sb.fstype);
+            if let Some(label) = sb.label {
+                ret.insert("LABEL".into(), label);
+            }
+            if let Some(uuid) = sb.uuid {
+                ret.insert("UUID".into(), uuid);
+            }
+        }
+        let node = self.node();
+        if node.exists() {
+            if let Some(mountpoint) = get_mountpoints(&node)?.into_iter().next() {
+                ret.insert("MOUNTPOINT".into(), mountpoint);
+            } else if is_swap(&node)? {
+                ret.insert("MOUNTPOINT".into(), "[SWAP]".into());
+            }
+        }
+        Ok(ret)
+    }
+}
+
+type Prober<R> = fn(&mut R, u64) -> Result<Option<Superblock>>;
+
+/// Identify the signature on a device of the specified size.  Container
+/// formats are checked first, since RAID members and the like may also
+/// carry a valid filesystem superblock.
+pub fn probe_superblock<R: Read + Seek>(r: &mut R, size: u64) -> Result<Option<Superblock>> {
+    let probers: [Prober<R>; 9] = [
+        probe_luks,
+        probe_lvm,
+        probe_md,
+        probe_xfs,
+        probe_ext,
+        probe_btrfs,
+        probe_iso9660,
+        probe_swap,
+        probe_vfat,
+    ];
+    for prober in probers {
+        if let Some(sb) = prober(r, size)? {
+            return Ok(Some(sb));
+        }
+    }
+    Ok(None)
+}
+
+/// Read `len` bytes at `offset`, or return None if the device is too
+/// small.
+fn read_at<R: Read + Seek>(r: &mut R, offset: u64, len: usize) -> Result<Option<Vec<u8>>> {
+    r.seek(SeekFrom::Start(offset))
+        .with_context(|| format!("seeking to offset {offset}"))?;
+    let mut buf = vec![0; len];
+    match r.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(buf)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {len} bytes at offset {offset}")),
+    }
+}
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Decode a fixed-size string field padded with NULs or spaces.
+fn fixed_str(buf: &[u8]) -> Option<String> {
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let s = String::from_utf8_lossy(&buf[..end]).trim_end().to_string();
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+/// Trim whitespace and NUL padding from an identifier, treating an empty
+/// one as absent.
+fn trimmed(s: &str) -> Option<String> {
+    Some(
+        s.trim_matches(|c: char| c.is_whitespace() || c == '\0')
+            .to_string(),
+    )
+    .filter(|s| !s.is_empty())
+}
+
+/// Decode a binary UUID, treating the nil UUID as absent.
+fn binary_uuid(buf: &[u8]) -> Option<String> {
+    Uuid::from_slice(buf)
+        .ok()
+        .filter(|uuid| !uuid.is_nil())
+        .map(|uuid| uuid.to_string())
+}
+
+fn superblock(fstype: &str, label: Option<String>, uuid: Option<String>) -> Option<Superblock> {
+    Some(Superblock {
+        fstype: fstype.into(),
+        label,
+        uuid,
+    })
+}
+
+fn probe_luks<R: Read + Seek>(r: &mut R, _size: u64) -> Result<Option<Superblock>> {
+    let buf = match read_at(r, 0, 208)? {
+        Some(buf) if buf.starts_with(b"LUKS\xba\xbe") => buf,
+        _ => return Ok(None),
+    };
+    // only LUKS2 has a label
+    let label = match u16::from_be_bytes([buf[6], buf[7]]) {
+        2 => fixed_str(&buf[24..72]),
+        _ => None,
+    };
+    Ok(superblock("crypto_LUKS", label, fixed_str(&buf[168..208])))
+}
+
+fn probe_lvm<R: Read + Seek>(r: &mut R, _size: u64) -> Result<Option<Superblock>> {
+    // the label can be in any of the first four sectors
+    for sector in 0..4 {
+        let buf = match read_at(r, sector * 512, 512)? {
+            Some(buf) => buf,
+            None => return Ok(None),
+        };
+        if &buf[0..8] != b"LABELONE" || &buf[24..32] != b"LVM2 001" {
+            continue;
+        }
+        let offset = le32(&buf, 20) as usize;
+        if offset + 32 > buf.len() || !buf[offset..offset + 32].is_ascii() {
+            continue;
+        }
+        let uuid = String::from_utf8_lossy(&buf[offset..offset + 32]);
+        // LVM formats its UUIDs in groups of 6-4-4-4-4-4-6
+        let mut groups = Vec::new();
+        let mut start = 0;
+        for len in [6, 4, 4, 4, 4, 4, 6] {
+            groups.push(&uuid[start..start + len]);
+            start += len;
+        }
+        return Ok(superblock("LVM2_member", None, Some(groups.join("-"))));
+    }
+    Ok(None)
+}
+
+fn probe_md<R: Read + Seek>(r: &mut R, size: u64) -> Result<Option<Superblock>> {
+    const MD_MAGIC: u32 = 0xa92b4efc;
+    // v1.1 at the start, v1.2 4 KiB in, v1.0 near the end
+    let mut offsets = vec![0, 4096];
+    if size >= 8192 {
+        offsets.push(((size / 512 - 16) & !7) * 512);
+    }
+    for offset in offsets {
+        if let Some(buf) = read_at(r, offset, 64)? {
+            if le32(&buf, 0) == MD_MAGIC && le32(&buf, 4) == 1 {
+                return Ok(superblock(
+                    "linux_raid_member",
+                    fixed_str(&buf[32..64]),
+                    binary_uuid(&buf[16..32]),
+                ));
+            }
+        }
+    }
+    // v0.90 in the last 64 KiB-aligned block
+    if size >= 2 * 65536 {
+        let offset = (size & !65535) - 65536;
+        if let Some(buf) = read_at(r, offset, 64)? {
+            if le32(&buf, 0) == MD_MAGIC && le32(&buf, 4) == 0 {
+                let mut uuid = buf[20..24].to_vec();
+                uuid.extend_from_slice(&buf[52..64]);
+                return Ok(superblock("linux_raid_member", None, binary_uuid(&uuid)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn probe_xfs<R: Read + Seek>(r: &mut R, _size: u64) -> Result<Option<Superblock>> {
+    match read_at(r, 0, 120)? {
+        Some(buf) if buf.starts_with(b"XFSB") => Ok(superblock(
+            "xfs",
+            fixed_str(&buf[108..120]),
+            binary_uuid(&buf[32..48]),
+        )),
+        _ => Ok(None),
+    }
+}
+
+fn probe_ext<R: Read + Seek>(r: &mut R, _size: u64) -> Result<Option<Superblock>> {
+    const HAS_JOURNAL: u32 = 0x4;
+    const JOURNAL_DEV: u32 = 0x8;
+    // features understood by ext2 and ext3; anything else needs ext4
+    const EXT3_INCOMPAT: u32 = 0x2 | 0x4 | 0x10;
+    const EXT3_RO_COMPAT: u32 = 0x1 | 0x2 | 0x4;
+
+    let buf = match read_at(r, 1024, 136)? {
+        Some(buf) if le16(&buf, 56) == 0xef53 => buf,
+        _ => return Ok(None),
+    };
+    let compat = le32(&buf, 92);
+    let incompat = le32(&buf, 96);
+    let ro_compat = le32(&buf, 100);
+    let fstype = if incompat & JOURNAL_DEV != 0 {
+        "jbd"
+    } else if incompat & !EXT3_INCOMPAT != 0 || ro_compat & !EXT3_RO_COMPAT != 0 {
+        "ext4"
+    } else if compat & HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    };
+    Ok(superblock(
+        fstype,
+        fixed_str(&buf[120..136]),
+        binary_uuid(&buf[104..120]),
+    ))
+}
+
+fn probe_btrfs<R: Read + Seek>(r: &mut R, _size: u64) -> Result<Option<Superblock>> {
+    match read_at(r, 0x10000, 0x12b + 256)? {
+        Some(buf) if &buf[0x40..0x48] == b"_BHRfS_M" => Ok(superblock(
+            "btrfs",
+            fixed_str(&buf[0x12b..0x12b + 256]),
+            binary_uuid(&buf[0x20..0x30]),
+        )),
+        _ => Ok(None),
+    }
+}
+
+fn probe_iso9660<R: Read + Seek>(r: &mut R, _size: u64) -> Result<Option<Superblock>> {
+    // primary volume descriptor
+    let buf = match read_at(r, 32768, 2048)? {
+        Some(buf) if buf[0] == 1 && &buf[1..6] == b"CD001" => buf,
+        _ => return Ok(None),
+    };
+    // blkid derives the UUID from the volume creation time,
+    // YYYYMMDDHHMMSSCC
+    let date = &buf[813..829];
+    let uuid = if date.iter().all(u8::is_ascii_digit) && date.iter().any(|&b| b != b'0') {
+        let date = String::from_utf8_lossy(date);
+        Some(format!(
+            "{}-{}-{}-{}-{}-{}-{}",
+            &date[0..4],
+            &date[4..6],
+            &date[6..8],
+            &date[8..10],
+            &date[10..12],
+            &date[12..14],
+            &date[14..16]
+        ))
+    } else {
+        None
+    };
+    Ok(superblock("iso9660", fixed_str(&buf[40..72]), uuid))
+}
+
+fn probe_swap<R: Read + Seek>(r: &mut R, _size: u64) -> Result<Option<Superblock>> {
+    // the signature is at the end of the first page, whatever the page
+    // size of the system that created it
+    for page_size in [4096, 8192, 16384, 65536] {
+        let magic = match read_at(r, page_size - 10, 10)? {
+            Some(magic) => magic,
+            None => return Ok(None),
+        };
+        if magic == b"SWAP-SPACE" {
+            return Ok(superblock("swap", None, None));
+        }
+        if magic == b"SWAPSPACE2" {
+            let buf = match read_at(r, 1024, 44)? {
+                Some(buf) => buf,
+                None => return Ok(None),
+            };
+            return Ok(superblock(
+                "swap",
+                fixed_str(&buf[28..44]),
+                binary_uuid(&buf[12..28]),
+            ));
+        }
+    }
+    Ok(None)
+}
+
+fn probe_vfat<R: Read + Seek>(r: &mut R, _size: u64) -> Result<Option<Superblock>> {
+    let buf = match read_at(r, 0, 512)? {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+    let sector_size = le16(&buf, 11);
+    if buf[510..512] != [0x55, 0xaa]
+        || ![0xeb, 0xe9].contains(&buf[0])
+        || !sector_size.is_power_of_two()
+        || !(512..=4096).contains(&sector_size)
+    {
+        return Ok(None);
+    }
+    let (label, serial) = if &buf[82..90] == b"FAT32   " {
+        (&buf[71..82], &buf[67..71])
+    } else if &buf[54..57] == b"FAT" {
+        (&buf[43..54], &buf[39..43])
+    } else {
+        return Ok(None);
+    };
+    let label = fixed_str(label).filter(|l| l != "NO NAME");
+    let serial = le32(serial, 0);
+    let uuid = format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff);
+    Ok(superblock("vfat", label, Some(uuid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn image(size: usize, fields: &[(usize, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut buf = vec![0; size];
+        for (offset, data) in fields {
+            buf[*offset..*offset + data.len()].copy_from_slice(data);
+        }
+        Cursor::new(buf)
+    }
+
+    fn probe(mut img: Cursor<Vec<u8>>) -> Option<Superblock> {
+        let size = img.get_ref().len() as u64;
+        probe_superblock(&mut img, size).unwrap()
+    }
+
+    fn expected(fstype: &str, label: Option<&str>, uuid: Option<&str>) -> Option<Superblock> {
+        superblock(fstype, label.map(String::from), uuid.map(String::from))
+    }
+
+    const UUID: [u8; 16] = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+        0xef,
+    ];
+    const UUID_STR: &str = "01234567-89ab-cdef-0123-456789abcdef";
+
+    #[test]
+    fn test_probe_superblock() {
+        assert_eq!(probe(image(1 << 20, &[])), None);
+
+        // xfs
+        assert_eq!(
+            probe(image(
+                1 << 20,
+                &[(0, b"XFSB"), (32, &UUID), (108, b"root\0\0\0\0\0\0\0\0")]
+            )),
+            expected("xfs", Some("root"), Some(UUID_STR))
+        );
+
+        // ext family
+        let ext = |compat: u32, incompat: u32, ro_compat: u32| {
+            image(
+                1 << 20,
+                &[
+                    (1024 + 56, &[0x53, 0xef]),
+                    (1024 + 92, &compat.to_le_bytes()),
+                    (1024 + 96, &incompat.to_le_bytes()),
+                    (1024 + 100, &ro_compat.to_le_bytes()),
+                    (1024 + 104, &UUID),
+                    (1024 + 120, b"boot"),
+                ],
+            )
+        };
+        assert_eq!(
+            probe(ext(0, 0, 0)),
+            expected("ext2", Some("boot"), Some(UUID_STR))
+        );
+        assert_eq!(
+            probe(ext(0x4, 0x2, 0x1)),
+            expected("ext3", Some("boot"), Some(UUID_STR))
+        );
+        assert_eq!(
+            probe(ext(0x4, 0x2c2, 0x1)),
+            expected("ext4", Some("boot"), Some(UUID_STR))
+        );
+
+        // btrfs
+        assert_eq!(
+            probe(image(
+                1 << 20,
+                &[(0x10040, b"_BHRfS_M"), (0x10020, &UUID), (0x1012b, b"data")]
+            )),
+            expected("btrfs", Some("data"), Some(UUID_STR))
+        );
+
+        // iso9660
+        let mut label = [b' '; 32];
+        label[..9].copy_from_slice(b"fcos-live");
+        assert_eq!(
+            probe(image(
+                1 << 20,
+                &[
+                    (32768, b"\x01CD001"),
+                    (32768 + 40, &label),
+                    (32768 + 813, b"2024031415265300"),
+                ]
+            )),
+            expected("iso9660", Some("fcos-live"), Some("2024-03-14-15-26-53-00"))
+        );
+
+        // LUKS1 and LUKS2
+        let mut luks_uuid = [0; 40];
+        luks_uuid[..36].copy_from_slice(UUID_STR.as_bytes());
+        assert_eq!(
+            probe(image(
+                1 << 20,
+                &[
+                    (0, b"LUKS\xba\xbe\x00\x01"),
+                    (24, b"aes"),
+                    (168, &luks_uuid)
+                ]
+            )),
+            expected("crypto_LUKS", None, Some(UUID_STR))
+        );
+        assert_eq!(
+            probe(image(
+                1 << 20,
+                &[
+                    (0, b"LUKS\xba\xbe\x00\x02"),
+                    (24, b"crypt"),
+                    (168, &luks_uuid)
+                ]
+            )),
+            expected("crypto_LUKS", Some("crypt"), Some(UUID_STR))
+        );
+
+        // swap
+        assert_eq!(
+            probe(image(
+                1 << 20,
+                &[
+                    (4086, b"SWAPSPACE2"),
+                    (1024 + 12, &UUID),
+                    (1024 + 28, b"swap")
+                ]
+            )),
+            expected("swap", Some("swap"), Some(UUID_STR))
+        );
+        assert_eq!(
+            probe(image(1 << 20, &[(65526, b"SWAPSPACE2")])),
+            expected("swap", None, None)
+        );
+
+        // vfat
+        let fat_boot = [
+            (0, &[0xeb, 0x3c, 0x90][..]),
+            (11, &[0, 2]),
+            (510, &[0x55, 0xaa]),
+        ];
+        let mut fat32 = fat_boot.to_vec();
+        fat32.extend([
+            (67, &[0x78, 0x56, 0x34, 0x12][..]),
+            (71, b"EFI-SYSTEM "),
+            (82, b"FAT32   "),
+        ]);
+        assert_eq!(
+            probe(image(1 << 20, &fat32)),
+            expected("vfat", Some("EFI-SYSTEM"), Some("1234-5678"))
+        );
+        let mut fat16 = fat_boot.to_vec();
+        fat16.extend([
+            (39, &[0xef, 0xbe, 0xad, 0xde][..]),
+            (43, b"NO NAME    "),
+            (54, b"FAT16   "),
+        ]);
+        assert_eq!(
+            probe(image(1 << 20, &fat16)),
+            expected("vfat", None, Some("DEAD-BEEF"))
+        );
+        // an MBR isn't a FAT filesystem
+        assert_eq!(probe(image(1 << 20, &fat_boot)), None);
+
+        // LVM PV label in the second sector
+        assert_eq!(
+            probe(image(
+                1 << 20,
+                &[
+                    (512, b"LABELONE"),
+                    (512 + 20, &32u32.to_le_bytes()),
+                    (512 + 24, b"LVM2 001"),
+                    (512 + 32, b"abcdefghijklmnopqrstuvwxyz012345"),
+                ]
+            )),
+            expected(
+                "LVM2_member",
+                None,
+                Some("abcdef-ghij-klmn-opqr-stuv-wxyz-012345")
+            )
+        );
+
+        // md v1.0, v1.2, and v0.90; the RAID member takes precedence over
+        // the filesystem it mirrors
+        let md1 = |offset: usize| {
+            image(
+                1 << 20,
+                &[
+                    (0, b"XFSB"),
+                    (offset, &0xa92b4efcu32.to_le_bytes()),
+                    (offset + 4, &1u32.to_le_bytes()),
+                    (offset + 16, &UUID),
+                    (offset + 32, b"host:md-boot"),
+                ],
+            )
+        };
+        for offset in [4096, (1 << 20) - 8192] {
+            assert_eq!(
+                probe(md1(offset)),
+                expected("linux_raid_member", Some("host:md-boot"), Some(UUID_STR))
+            );
+        }
+        let offset = (1 << 20) - 65536;
+        assert_eq!(
+            probe(image(
+                1 << 20,
+                &[
+                    (offset, &0xa92b4efcu32.to_le_bytes()),
+                    (offset + 20, &UUID[..4]),
+                    (offset + 52, &UUID[4..]),
+                ]
+            )),
+            expected("linux_raid_member", None, Some(UUID_STR))
+        );
+
+        // tiny devices
+        assert_eq!(probe(image(512, &[])), None);
+        assert_eq!(probe(image(0, &[])), None);
+    }
+
+    #[test]
+    fn test_disk_attributes() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |attr: &str, contents: &[u8]| {
+            let path = dir.path().join(attr);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        let dev = BlockDevice {
+            name: "sdz".into(),
+            sysfs: dir.path().into(),
+        };
+        assert!(dev.is_virtual());
+        assert!(!dev.read_only());
+        assert!(!dev.rotational());
+        assert_eq!(dev.model(), None);
+        assert_eq!(dev.serial(), None);
+        assert_eq!(dev.wwn(), None);
+
+        write("ro", b"1\n");
+        write("queue/rotational", b"1\n");
+        write("device/model", b"ST4000NM0035-1V4\n");
+        write(
+            "device/vpd_pg80",
+            b"\x00\x80\x00\x14      ZC18ABCD\x00\x00\x00\x00\x00\x00",
+        );
+        write("device/wwid", b"naa.5000C500A1B2C3D4\n");
+        assert!(!dev.is_virtual());
+        assert!(dev.read_only());
+        assert!(dev.rotational());
+        assert_eq!(dev.model().as_deref(), Some("ST4000NM0035-1V4"));
+        assert_eq!(dev.serial().as_deref(), Some("ZC18ABCD"));
+        assert_eq!(dev.wwn().as_deref(), Some("0x5000c500a1b2c3d4"));
+
+        // attributes of the disk itself take precedence
+        write("serial", b"QM00001\n");
+        write("wwid", b"eui.0025388b91b2c3d4\n");
+        assert_eq!(dev.serial().as_deref(), Some("QM00001"));
+        assert_eq!(dev.wwn().as_deref(), Some("eui.0025388b91b2c3d4"));
+        write("wwid", b"t10.ATA     QEMU HARDDISK\n");
+        assert_eq!(dev.wwn(), None);
+    }
+}
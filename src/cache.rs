@@ -0,0 +1,571 @@
+// Copyright 2024 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! On-disk cache of downloaded images.
+//!
+//! Each entry is a directory named by its key, containing the image, its
+//! signature, and a JSON metadata file.  Images are keyed by the SHA-256
+//! digest published in stream metadata if there is one, or otherwise by
+//! the URL and strong ETag of the response.  Entries are assembled in a
+//! temporary directory and renamed into place, so readers never see a
+//! partial entry.  Cached images are untrusted: every use verifies them
+//! against the signature, just like a fresh download.
+
+use anyhow::{bail, Context, Result};
+use byte_unit::Byte;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, copy, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+use crate::cmdline::*;
+use crate::io::*;
+use crate::util::set_die_on_sigpipe;
+
+/// Prefix of directories holding entries being written.
+const TEMP_PREFIX: &str = ".tmp-";
+/// Age after which an abandoned temporary directory is pruned (1 day).
+const TEMP_MAX_AGE: u64 = 24 * 60 * 60;
+
+const IMAGE_FILE: &str = "image";
+const SIGNATURE_FILE: &str = "image.sig";
+const METADATA_FILE: &str = "metadata.json";
+
+#[derive(Clone, Debug)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// Metadata stored alongside a cached image.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct EntryMetadata {
+    pub url: String,
+    pub filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub length: u64,
+    /// Seconds since the epoch
+    pub created: u64,
+    /// Seconds since the epoch
+    pub last_used: u64,
+}
+
+/// A cached image, opened for reading.
+pub struct CacheEntry {
+    pub metadata: EntryMetadata,
+    pub image: File,
+    pub signature: Option<Vec<u8>>,
+}
+
+/// A cache entry being written as its source is read.  Dropping it without
+/// committing discards the partial entry.
+pub struct CacheFill {
+    cache: Cache,
+    key: String,
+    metadata: EntryMetadata,
+    dir: TempDir,
+    // taken by the reader
+    image: Option<File>,
+    state: Rc<Cell<FillState>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FillState {
+    Filling,
+    /// The source reached EOF and all of it was written
+    Complete,
+    /// Writing to the cache failed; the read continues without it
+    Failed,
+}
+
+/// Reader that copies everything read through it into a cache entry.
+struct CacheFillReader<R: Read> {
+    source: R,
+    image: File,
+    state: Rc<Cell<FillState>>,
+}
+
+impl Cache {
+    pub fn new(dir: &str) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("creating cache directory {dir}"))?;
+        Ok(Self { dir: dir.into() })
+    }
+
+    /// Key for an image with a published SHA-256 digest.
+    pub fn digest_key(digest: &Sha256Digest) -> Result<String> {
+        Ok(format!("sha256-{}", digest.to_hex_string()?))
+    }
+
+    /// Key for an image identified only by its URL and ETag.
+    pub fn url_key(url: &Url, etag: &str) -> Result<String> {
+        let digest = Sha256Digest::from_reader(&mut format!("{url}\n{etag}").as_bytes())?;
+        Ok(format!("url-{}", digest.to_hex_string()?))
+    }
+
+    /// Open the entry with the specified key, if there is one, and record
+    /// that it was used.
+    pub fn lookup(&self, key: &str) -> Result<Option<CacheEntry>> {
+        let path = self.dir.join(key);
+        let mut metadata = match read_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) if !path.exists() => return Ok(None),
+            Err(e) => {
+                eprintln!("Ignoring cache entry {key}: {e:#}");
+                return Ok(None);
+            }
+        };
+        let image = OpenOptions::new()
+            .read(true)
+            .open(path.join(IMAGE_FILE))
+            .with_context(|| format!("opening cached image {key}"))?;
+        let signature = match fs::read(path.join(SIGNATURE_FILE)) {
+            Ok(signature) => Some(signature),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("reading cached signature {key}")),
+        };
+
+        // failing to update the timestamp only affects eviction order
+        metadata.last_used = now();
+        if let Err(e) = write_metadata(&path, &metadata) {
+            eprintln!("Couldn't update cache entry {key}: {e:#}");
+        }
+
+        Ok(Some(CacheEntry {
+            metadata,
+            image,
+            signature,
+        }))
+    }
+
+    /// Start writing a new entry with the specified key.
+    pub fn fill(&self, key: &str, metadata: EntryMetadata) -> Result<CacheFill> {
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("{TEMP_PREFIX}{key}-"))
+            .tempdir_in(&self.dir)
+            .with_context(|| format!("creating temporary directory in {}", self.dir.display()))?;
+        let image = File::create(dir.path().join(IMAGE_FILE)).context("creating cached image")?;
+        Ok(CacheFill {
+            cache: self.clone(),
+            key: key.to_string(),
+            metadata,
+            dir,
+            image: Some(image),
+            state: Rc::new(Cell::new(FillState::Filling)),
+        })
+    }
+
+    /// List the keys of complete entries, and the paths of temporary
+    /// directories.
+    fn scan(&self) -> Result<(Vec<String>, Vec<PathBuf>)> {
+        let mut keys = Vec::new();
+        let mut temps = Vec::new();
+        for ent in fs::read_dir(&self.dir)
+            .with_context(|| format!("reading cache directory {}", self.dir.display()))?
+        {
+            let ent = ent.with_context(|| format!("reading {}", self.dir.display()))?;
+            let name = ent.file_name().to_string_lossy().to_string();
+            if name.starts_with(TEMP_PREFIX) {
+                temps.push(ent.path());
+            } else if !name.starts_with('.') && ent.path().is_dir() {
+                keys.push(name);
+            }
+        }
+        keys.sort();
+        Ok((keys, temps))
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        let path = self.dir.join(key);
+        fs::remove_dir_all(&path).with_context(|| format!("removing {}", path.display()))
+    }
+}
+
+impl CacheFill {
+    /// Wrap the source so everything read from it is also written to the
+    /// entry.
+    pub fn reader<'a>(&mut self, source: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+        Box::new(CacheFillReader {
+            source,
+            image: self.image.take().expect("cache fill already has a reader"),
+            state: self.state.clone(),
+        })
+    }
+
+    /// Add the entry to the cache, if the whole source was read.  The
+    /// caller should already have verified the data against the signature.
+    pub fn commit(mut self, signature: &[u8]) -> Result<()> {
+        if self.state.get() != FillState::Complete {
+            bail!("image wasn't completely read");
+        }
+        let image_path = self.dir.path().join(IMAGE_FILE);
+        let image = OpenOptions::new()
+            .read(true)
+            .open(&image_path)
+            .context("opening cached image")?;
+        image.sync_all().context("syncing cached image")?;
+        self.metadata.length = image
+            .metadata()
+            .context("getting cached image metadata")?
+            .len();
+        let mut sig = File::create(self.dir.path().join(SIGNATURE_FILE))
+            .context("creating cached signature")?;
+        sig.write_all(signature)
+            .and_then(|_| sig.sync_all())
+            .context("writing cached signature")?;
+        self.metadata.created = now();
+        self.metadata.last_used = self.metadata.created;
+        write_metadata(self.dir.path(), &self.metadata)?;
+
+        // If someone else added the same entry in the meantime, keep theirs.
+        let path = self.cache.dir.join(&self.key);
+        if path.exists() {
+            return Ok(());
+        }
+        let dir = self.dir.into_path();
+        if let Err(e) = fs::rename(&dir, &path) {
+            let _ = fs::remove_dir_all(&dir);
+            if !path.exists() {
+                return Err(e).with_context(|| format!("renaming cache entry to {}", self.key));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for CacheFillReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.source.read(buf)?;
+        if self.state.get() == FillState::Filling {
+            if count == 0 {
+                self.state.set(FillState::Complete);
+            } else if let Err(e) = self.image.write_all(&buf[..count]) {
+                eprintln!("Couldn't write image to cache: {e}");
+                self.state.set(FillState::Failed);
+            }
+        }
+        Ok(count)
+    }
+}
+
+fn read_metadata(dir: &Path) -> Result<EntryMetadata> {
+    let path = dir.join(METADATA_FILE);
+    let data = fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Write entry metadata, atomically replacing any existing copy.
+fn write_metadata(dir: &Path, metadata: &EntryMetadata) -> Result<()> {
+    let path = dir.join(METADATA_FILE);
+    let mut file = tempfile::Builder::new()
+        .prefix(TEMP_PREFIX)
+        .tempfile_in(dir)
+        .with_context(|| format!("creating temporary file in {}", dir.display()))?;
+    serde_json::to_writer_pretty(&mut file, metadata).context("serializing cache metadata")?;
+    file.as_file()
+        .sync_all()
+        .context("syncing cache metadata")?;
+    file.persist(&path)
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Format the time since the specified timestamp.
+fn format_age(timestamp: u64) -> String {
+    let age = now().saturating_sub(timestamp);
+    match age {
+        0..=59 => format!("{age}s ago"),
+        60..=3599 => format!("{}m ago", age / 60),
+        3600..=86399 => format!("{}h ago", age / 3600),
+        _ => format!("{}d ago", age / 86400),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    Byte::from_bytes(bytes.into())
+        .get_appropriate_unit(true)
+        .format(1)
+}
+
+/// Subcommand to list cache entries.
+pub fn cache_list(config: CacheListConfig) -> Result<()> {
+    let cache = Cache::new(&config.cache_dir)?;
+    let mut rows = vec![[
+        "Key".to_string(),
+        "Size".to_string(),
+        "Last used".to_string(),
+        "Filename".to_string(),
+    ]];
+    for key in cache.scan()?.0 {
+        match read_metadata(&cache.dir.join(&key)) {
+            Ok(metadata) => rows.push([
+                key,
+                format_size(metadata.length),
+                format_age(metadata.last_used),
+                metadata.filename,
+            ]),
+            Err(e) => eprintln!("Skipping cache entry {key}: {e:#}"),
+        }
+    }
+
+    // calculate field widths
+    let mut widths: [usize; 3] = [0; 3];
+    for row in &rows {
+        for (width, field) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(field.len());
+        }
+    }
+
+    set_die_on_sigpipe()?;
+    for row in &rows {
+        println!(
+            "{:4$}  {:>5$}  {:6$}  {}",
+            row[0], row[1], row[2], row[3], widths[0], widths[1], widths[2]
+        );
+    }
+    Ok(())
+}
+
+/// Subcommand to verify cache entries against their signatures.
+pub fn cache_verify(config: CacheVerifyConfig) -> Result<()> {
+    let cache = Cache::new(&config.cache_dir)?;
+    let keys = VerifyKeys::new(&config.signing_key, config.signing_keys_only)?;
+    let mut failed = 0;
+    for key in cache.scan()?.0 {
+        eprintln!("Verifying {key}");
+        if let Err(e) = verify_entry(&cache.dir.join(&key), keys.clone()) {
+            eprintln!("Cache entry {key} is invalid: {e:#}");
+            failed += 1;
+            if config.remove {
+                cache.remove(&key)?;
+                eprintln!("Removed {key}");
+            }
+        }
+    }
+    match (failed, config.remove) {
+        (0, _) => Ok(()),
+        (n, true) => {
+            eprintln!("Removed {n} invalid cache entries");
+            Ok(())
+        }
+        (n, false) => bail!("{} cache entries failed verification", n),
+    }
+}
+
+fn verify_entry(dir: &Path, keys: VerifyKeys) -> Result<()> {
+    let metadata = read_metadata(dir)?;
+    let signature = fs::read(dir.join(SIGNATURE_FILE)).context("reading signature")?;
+    let sha256 = metadata
+        .sha256
+        .as_deref()
+        .map(Sha256Digest::from_hex)
+        .transpose()
+        .context("parsing SHA-256 digest")?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(dir.join(IMAGE_FILE))
+        .context("opening image")?;
+    let mut hash_reader =
+        Sha256VerifyReader::new(BufReader::with_capacity(BUFFER_SIZE, &mut file), sha256);
+    let mut reader = VerifyReader::new(&mut hash_reader, Some(&signature), keys)?;
+    copy(&mut reader, &mut io::sink()).context("reading image")?;
+    reader.verify()?;
+    drop(reader);
+    hash_reader.verify()?;
+    Ok(())
+}
+
+/// Subcommand to evict cache entries.
+pub fn cache_prune(config: CachePruneConfig) -> Result<()> {
+    if !config.all && config.max_size.is_none() && config.max_age.is_none() {
+        bail!("specify --max-size, --max-age, or --all");
+    }
+    let cache = Cache::new(&config.cache_dir)?;
+    let (keys, temps) = cache.scan()?;
+    let now = now();
+
+    // clean up after writers that didn't finish
+    for path in temps {
+        let modified = path
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if config.all || now.saturating_sub(modified) > TEMP_MAX_AGE {
+            fs::remove_dir_all(&path).with_context(|| format!("removing {}", path.display()))?;
+        }
+    }
+
+    // read entries, discarding broken ones, most recently used first
+    let mut entries = Vec::new();
+    for key in keys {
+        match read_metadata(&cache.dir.join(&key)) {
+            Ok(metadata) => entries.push((key, metadata)),
+            Err(e) => {
+                eprintln!("Removing unreadable cache entry {key}: {e:#}");
+                cache.remove(&key)?;
+            }
+        }
+    }
+    entries.sort_by_key(|(_, metadata)| std::cmp::Reverse(metadata.last_used));
+
+    let mut kept = 0;
+    for (key, metadata) in entries {
+        let expired = config
+            .max_age
+            .map(|days| now.saturating_sub(metadata.last_used) > days * 86400)
+            .unwrap_or(false);
+        let oversize = config
+            .max_size
+            .as_ref()
+            .map(|max| kept + metadata.length > max.0)
+            .unwrap_or(false);
+        if config.all || expired || oversize {
+            cache.remove(&key)?;
+            eprintln!("Removed {} ({})", metadata.filename, key);
+        } else {
+            kept += metadata.length;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(cache: &Cache, key: &str, data: &[u8]) -> CacheFill {
+        let mut fill = cache
+            .fill(
+                key,
+                EntryMetadata {
+                    url: "https://example.com/image.raw.xz".into(),
+                    filename: "image.raw.xz".into(),
+                    etag: None,
+                    sha256: None,
+                    length: 0,
+                    created: 0,
+                    last_used: 0,
+                },
+            )
+            .unwrap();
+        let mut reader = fill.reader(Box::new(data));
+        copy(&mut reader, &mut io::sink()).unwrap();
+        fill
+    }
+
+    #[test]
+    fn test_cache_fill() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().to_str().unwrap()).unwrap();
+        assert!(cache.lookup("a").unwrap().is_none());
+
+        // complete fill
+        fill(&cache, "a", b"hello").commit(b"sig").unwrap();
+        let mut entry = cache.lookup("a").unwrap().unwrap();
+        let mut data = Vec::new();
+        entry.image.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+        assert_eq!(entry.signature.unwrap(), b"sig");
+        assert_eq!(entry.metadata.length, 5);
+        assert_eq!(entry.metadata.filename, "image.raw.xz");
+        assert!(entry.metadata.last_used > 0);
+
+        // abandoned fill leaves nothing behind
+        drop(fill(&cache, "b", b"hello"));
+        assert!(cache.lookup("b").unwrap().is_none());
+        assert_eq!(cache.scan().unwrap(), (vec!["a".to_string()], Vec::new()));
+
+        // incomplete fill can't be committed
+        let mut partial = cache.fill("c", entry.metadata.clone()).unwrap();
+        let mut reader = partial.reader(Box::new(&b"hello"[..]));
+        reader.read_exact(&mut [0; 2]).unwrap();
+        drop(reader);
+        partial.commit(b"sig").unwrap_err();
+        assert!(cache.lookup("c").unwrap().is_none());
+
+        // concurrent fill of the same key keeps the first entry
+        fill(&cache, "a", b"world").commit(b"sig2").unwrap();
+        let entry = cache.lookup("a").unwrap().unwrap();
+        assert_eq!(entry.signature.unwrap(), b"sig");
+    }
+
+    #[test]
+    fn test_cache_keys() {
+        let digest = Sha256Digest::from_hex(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        )
+        .unwrap();
+        assert_eq!(
+            Cache::digest_key(&digest).unwrap(),
+            "sha256-e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        let url = Url::parse("https://example.com/a").unwrap();
+        let key = Cache::url_key(&url, "\"1\"").unwrap();
+        assert!(key.starts_with("url-"));
+        assert_eq!(key, Cache::url_key(&url, "\"1\"").unwrap());
+        assert_ne!(key, Cache::url_key(&url, "\"2\"").unwrap());
+    }
+
+    #[test]
+    fn test_cache_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().to_str().unwrap()).unwrap();
+        let prune = |max_size: Option<u64>, max_age: Option<u64>| {
+            cache_prune(CachePruneConfig {
+                cache_dir: dir.path().to_str().unwrap().into(),
+                max_size: max_size.map(ByteSize),
+                max_age,
+                all: false,
+            })
+            .unwrap();
+            cache.scan().unwrap().0
+        };
+        for (key, age) in [("a", 0), ("b", 2 * 86400), ("c", 4 * 86400)] {
+            fill(&cache, key, b"0123456789").commit(b"sig").unwrap();
+            let path = cache.dir.join(key);
+            let mut metadata = read_metadata(&path).unwrap();
+            metadata.last_used -= age;
+            write_metadata(&path, &metadata).unwrap();
+        }
+
+        assert_eq!(prune(None, Some(5)), vec!["a", "b", "c"]);
+        assert_eq!(prune(None, Some(3)), vec!["a", "b"]);
+        assert_eq!(prune(Some(15), None), vec!["a"]);
+        cache_prune(CachePruneConfig {
+            cache_dir: dir.path().to_str().unwrap().into(),
+            max_size: None,
+            max_age: None,
+            all: true,
+        })
+        .unwrap();
+        assert!(cache.scan().unwrap().0.is_empty());
+    }
+}
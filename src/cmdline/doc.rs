@@ -118,6 +118,7 @@ pub fn pack_example_config(_config: PackExampleConfigConfig) -> Result<()> {
                         // more verbose than 80 columns will allow
                         "save-partlabel" => "glob",
                         "save-partindex" => "id-or-range",
+                        "save-partsize" => "size-or-range",
                         _ => value_names.expect("missing value name"),
                     };
                     format!("[{0}, {0}]", value_name)
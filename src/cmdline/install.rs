@@ -34,6 +34,7 @@ use super::Cmd;
 // Please keep the entire help text to 80 columns.
 
 const ADVANCED: &str = "Advanced Options";
+const DEST_SELECTION: &str = "Destination Selection Options";
 
 // As a special case, this struct supports Serialize and Deserialize for
 // config file parsing.  Here are the rules.  Build or test should fail if
@@ -63,7 +64,8 @@ pub struct InstallConfig {
     ///
     /// Load additional config options from the specified YAML config file.
     /// Later config files override earlier ones, and command-line options
-    /// override config files.
+    /// override config files.  A destination device overrides earlier
+    /// destination selection options, and vice versa.
     ///
     /// Config file keys are long option names without the leading "--".
     /// Values are strings for non-repeatable options, arrays of strings for
@@ -202,6 +204,9 @@ pub struct InstallConfig {
     /// Multiple numbers or ranges can be specified in multiple options, or
     /// in a single option separated by commas.
     ///
+    /// On a disk with an MBR partition table, the selected partitions are
+    /// converted to GPT partitions with an equivalent type.
+    ///
     /// Saved partitions will be renumbered if necessary.  If partitions
     /// overlap with the install image, or installation fails for any other
     /// reason, the specified partitions will still be preserved.
@@ -213,6 +218,73 @@ pub struct InstallConfig {
     // Allow ranges like "-2".
     #[arg(allow_hyphen_values = true)]
     pub save_partindex: Vec<String>,
+    /// Save partitions with this type GUID
+    ///
+    /// Preserve any existing partitions on the destination device whose
+    /// partition type GUID matches the specified value.  Multiple GUIDs can
+    /// be specified in multiple options, or in a single option separated by
+    /// commas.
+    ///
+    /// Saved partitions will be renumbered if necessary.  If partitions
+    /// overlap with the install image, or installation fails for any other
+    /// reason, the specified partitions will still be preserved.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, value_name = "guid")]
+    #[arg(value_delimiter = ',')]
+    pub save_parttype: Vec<String>,
+    /// Save partitions with this partition GUID
+    ///
+    /// Preserve any existing partitions on the destination device whose
+    /// unique partition GUID (PARTUUID) matches the specified value.
+    /// Multiple GUIDs can be specified in multiple options, or in a single
+    /// option separated by commas.
+    ///
+    /// Saved partitions will be renumbered if necessary.  If partitions
+    /// overlap with the install image, or installation fails for any other
+    /// reason, the specified partitions will still be preserved.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, value_name = "guid")]
+    #[arg(value_delimiter = ',')]
+    pub save_partuuid: Vec<String>,
+    /// Save partitions with this size or size range
+    ///
+    /// Preserve any existing partitions on the destination device whose
+    /// size matches the specified value or range.  Sizes are in bytes and
+    /// accept a unit suffix such as "500GB" or "100GiB".  Ranges can be
+    /// bounded on both ends ("1GiB-2GiB", inclusive) or one end ("100GiB-"
+    /// or "-1GiB").  Multiple sizes or ranges can be specified in multiple
+    /// options, or in a single option separated by commas.
+    ///
+    /// Saved partitions will be renumbered if necessary.  If partitions
+    /// overlap with the install image, or installation fails for any other
+    /// reason, the specified partitions will still be preserved.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, value_name = "size")]
+    #[arg(value_delimiter = ',')]
+    #[arg(allow_hyphen_values = true)]
+    pub save_partsize: Vec<String>,
+    /// Move saved partitions overlapping the image
+    ///
+    /// If a saved partition starts inside the region needed by the install
+    /// image, copy its contents to free space after the image and update
+    /// the partition table to point to the copy, rather than failing the
+    /// install.  The partition table is only updated after all copies are
+    /// complete, so if the move is interrupted, the original partitions and
+    /// partition table are left untouched.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long)]
+    pub relocate_saved_partitions: bool,
+    /// Action if destination has a CoreOS install
+    ///
+    /// Before installing, mount the destination's boot filesystem
+    /// read-only, without replaying its journal, and look for an existing
+    /// CoreOS install.  With "overwrite" (the default), report the
+    /// installed version and continue.  With "refuse", fail if an install
+    /// is found.  With "require-blank", fail if the destination has any
+    /// partitions at all.  With --dry-run, the destination is only checked
+    /// if this option is specified.
+    #[arg(long, value_name = "action")]
+    pub existing_install: Option<ExistingInstall>,
 
     // obscure options without short names
     /// Force offline installation
@@ -226,6 +298,21 @@ pub struct InstallConfig {
     #[serde(skip_serializing_if = "is_default")]
     #[arg(long, help_heading = ADVANCED)]
     pub insecure: bool,
+    /// Trust additional GPG signing keys
+    ///
+    /// Trust the OpenPGP public keys in the specified keyring file, or in
+    /// every file in the specified directory, in addition to the built-in
+    /// keys.  Use this to install self-built CoreOS images.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, value_name = "path", help_heading = ADVANCED)]
+    pub signing_key: Vec<String>,
+    /// Trust only custom GPG signing keys
+    ///
+    /// Don't trust the built-in signing keys, only the keys specified with
+    /// --signing-key.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, help_heading = ADVANCED)]
+    pub signing_keys_only: bool,
     /// Allow Ignition URL without HTTPS or hash
     #[serde(skip_serializing_if = "is_default")]
     #[arg(long, help_heading = ADVANCED)]
@@ -242,9 +329,76 @@ pub struct InstallConfig {
     /// If installation fails, coreos-installer normally clears the
     /// destination's partition table to prevent booting from invalid
     /// boot media.  Skip clearing the partition table as a debugging aid.
+    /// Any saved partitions are written to a stash file in /tmp, which can
+    /// be restored with "coreos-installer dev partitions restore".
     #[serde(skip_serializing_if = "is_default")]
     #[arg(long, help_heading = ADVANCED)]
     pub preserve_on_error: bool,
+    /// Unmount and deactivate users of destination
+    ///
+    /// If partitions on the destination are mounted, used as swap, or held
+    /// by LVM logical volumes, md arrays, dm-crypt mappings, or other
+    /// device-mapper devices, unmount and deactivate them before installing
+    /// rather than failing.  Devices that also use other disks, such as
+    /// logical volumes with physical volumes elsewhere, are not released.
+    /// Processes using the destination are reported but not stopped.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, help_heading = ADVANCED)]
+    pub teardown_holders: bool,
+    /// Print install plan without writing to disk
+    ///
+    /// Resolve the image source, read the destination's partition table,
+    /// and evaluate partition saving and post-processing options, but don't
+    /// write to the destination device.  Print a JSON description of the
+    /// planned installation to stdout.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, help_heading = ADVANCED)]
+    pub dry_run: bool,
+    /// Read back and verify written image
+    ///
+    /// After writing the install image, drop cached data and read the
+    /// image back from the destination, failing the install if it doesn't
+    /// match what was written.  The offset of the first mismatching 4 KiB
+    /// block is reported.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, help_heading = ADVANCED)]
+    pub verify_write: bool,
+    /// Discard all data on destination before install
+    ///
+    /// Before writing the install image, discard the contents of the
+    /// destination device, excluding any saved partitions.  If the device
+    /// doesn't support discard, zero it instead, which may be slow.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, help_heading = ADVANCED)]
+    pub discard: bool,
+    /// Wipe old signatures from destination
+    ///
+    /// Before writing the install image, erase filesystem, RAID, LVM, and
+    /// partition table signatures from the destination device and its
+    /// partitions, excluding any saved partitions.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, help_heading = ADVANCED)]
+    pub wipe_signatures: bool,
+    /// Write JSON-lines events to fd:N or socket path
+    ///
+    /// Report progress and results as a stream of JSON objects, one per
+    /// line, written to an inherited file descriptor (specified as "fd:N")
+    /// or to a Unix stream socket at the specified path.
+    #[arg(long, value_name = "target", help_heading = ADVANCED)]
+    pub event_output: Option<EventOutput>,
+    /// POST install status events to URL
+    ///
+    /// Report the start, progress, and result of the install by POSTing a
+    /// JSON object for each event to the specified HTTP or HTTPS URL.  The
+    /// objects have the same format as --event-output.  Reports are sent
+    /// in the background, and failure to deliver them doesn't affect the
+    /// install.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[arg(long, value_name = "URL", help_heading = ADVANCED)]
+    pub status_url: Option<Url>,
+    /// PEM CA bundle to trust for status URL
+    #[arg(long, value_name = "path", requires = "status_url", help_heading = ADVANCED)]
+    pub status_ca: Option<String>,
     /// Fetch retries, or "infinite"
     ///
     /// Number of times to retry network fetches, or the string "infinite"
@@ -252,17 +406,105 @@ pub struct InstallConfig {
     #[serde(skip_serializing_if = "is_default")]
     #[arg(long, value_name = "N", default_value_t, help_heading = ADVANCED)]
     pub fetch_retries: FetchRetries,
+    /// Parallel range requests per image
+    ///
+    /// Fetch large images as a series of byte ranges over the specified
+    /// number of concurrent connections, which can be faster on
+    /// high-latency links.  If the server doesn't support range requests,
+    /// fall back to a single connection.
+    #[serde(skip_serializing_if = "is_default")]
+    #[arg(long, value_name = "N", default_value_t, help_heading = ADVANCED)]
+    pub fetch_segments: FetchSegments,
+    /// Cache downloaded images in directory
+    ///
+    /// Keep downloaded images in the specified directory and reuse them in
+    /// later installs.  Cached images are verified against their signatures
+    /// each time they're used.
+    #[arg(long, value_name = "path", help_heading = ADVANCED)]
+    pub cache_dir: Option<String>,
     /// Enable IBM Secure IPL
     #[serde(skip_serializing_if = "is_default")]
     #[arg(long, help_heading = ADVANCED)]
     pub secure_ipl: bool,
 
+    // destination selection
+    /// Select disk of at least this size
+    ///
+    /// Instead of specifying a destination device, select a disk of at
+    /// least the specified size, such as "100GB" or "128GiB".  The
+    /// installer fails if no disks or multiple disks match the selection
+    /// options, unless --dest-select is specified.
+    #[arg(long, value_name = "size", help_heading = DEST_SELECTION)]
+    pub dest_min_size: Option<ByteSize>,
+    /// Select disk of at most this size
+    #[arg(long, value_name = "size", help_heading = DEST_SELECTION)]
+    pub dest_max_size: Option<ByteSize>,
+    /// Select disk by media type (hdd, ssd)
+    #[arg(long, value_name = "type", help_heading = DEST_SELECTION)]
+    pub dest_media: Option<DiskMedia>,
+    /// Select disk by transport (nvme, sata, usb)
+    #[arg(long, value_name = "name", help_heading = DEST_SELECTION)]
+    pub dest_transport: Option<String>,
+    /// Select disk by model glob
+    #[arg(long, value_name = "glob", help_heading = DEST_SELECTION)]
+    pub dest_model: Option<String>,
+    /// Select disk by serial number glob
+    #[arg(long, value_name = "glob", help_heading = DEST_SELECTION)]
+    pub dest_serial: Option<String>,
+    /// Select disk by WWN glob
+    #[arg(long, value_name = "glob", help_heading = DEST_SELECTION)]
+    pub dest_wwn: Option<String>,
+    /// Select disk by /dev/disk/by-path glob
+    ///
+    /// Select a disk with a /dev/disk/by-path symlink matching the
+    /// specified glob, such as "/dev/disk/by-path/pci-0000:00:1f.2-ata-*".
+    #[arg(long, value_name = "glob", help_heading = DEST_SELECTION)]
+    pub dest_by_path: Option<String>,
+    /// Pick largest or smallest matching disk
+    ///
+    /// If multiple disks match the selection options, pick the largest or
+    /// smallest one rather than failing.
+    #[arg(long, value_name = "which", help_heading = DEST_SELECTION)]
+    pub dest_select: Option<DiskSelect>,
+
     // positional args
     /// Destination device
     ///
     /// Path to the device node for the destination disk.  The beginning of
     /// the device will be overwritten without further confirmation.
-    #[arg(required_unless_present = "config_file")]
+    ///
+    /// Alternatively, path to an existing regular file, which will be
+    /// overwritten with a disk image using 512-byte sectors.  Customizing
+    /// the installed system (Ignition config, network config, console,
+    /// platform, or kernel arguments) attaches the image to a loop device
+    /// and mounts it, which requires root.
+    ///
+    /// When installing to a dm-multipath map, the kernel arguments needed
+    /// to boot from it are added automatically.  Installing to a single
+    /// path of a multipath map is refused.
+    #[arg(required_unless_present_any = [
+        "config_file",
+        "dest_min_size",
+        "dest_max_size",
+        "dest_media",
+        "dest_transport",
+        "dest_model",
+        "dest_serial",
+        "dest_wwn",
+        "dest_by_path",
+        "dest_select",
+    ])]
+    #[arg(conflicts_with_all = [
+        "dest_min_size",
+        "dest_max_size",
+        "dest_media",
+        "dest_transport",
+        "dest_model",
+        "dest_serial",
+        "dest_wwn",
+        "dest_by_path",
+        "dest_select",
+    ])]
     pub dest_device: Option<String>,
 }
 
@@ -272,33 +514,79 @@ impl InstallConfig {
             return Ok(self);
         }
 
-        let args = self
+        let mut configs = self
             .config_file
             .iter()
             .map(|path| {
-                serde_yaml::from_reader::<_, InstallConfig>(
+                let config = serde_yaml::from_reader::<_, InstallConfig>(
                     OpenOptions::new()
                         .read(true)
                         .open(path)
                         .with_context(|| format!("opening config file {path}"))?,
                 )
-                .with_context(|| format!("parsing config file {path}"))?
-                .to_args()
-                .with_context(|| format!("serializing config file {path}"))
+                .with_context(|| format!("parsing config file {path}"))?;
+                Ok((config, format!("config file {path}")))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        configs.push((self, "command-line arguments".into()));
+
+        // A destination device replaces destination selection options
+        // from earlier config files, and vice versa, since they can't be
+        // combined.
+        let (mut have_device, mut have_selection) = (false, false);
+        for (config, _) in configs.iter_mut().rev() {
+            let (device, selection) = (config.dest_device.is_some(), config.has_dest_selection());
+            if have_device {
+                config.clear_dest_selection();
+            }
+            if have_selection {
+                config.dest_device = None;
+            }
+            have_device |= device;
+            have_selection |= selection;
+        }
+
+        let args = configs
+            .iter()
+            .map(|(config, source)| {
+                config
+                    .to_args()
+                    .with_context(|| format!("serializing {source}"))
             })
             .collect::<Result<Vec<Vec<_>>>>()?
             .into_iter()
             .flatten()
-            .chain(
-                self.to_args()
-                    .context("serializing command-line arguments")?,
-            )
             .collect::<Vec<_>>();
 
-        println!("Running with arguments: {}", args.join(" "));
+        eprintln!("Running with arguments: {}", args.join(" "));
         Self::from_args(&args)
     }
 
+    /// Whether any destination selection options are specified.
+    pub fn has_dest_selection(&self) -> bool {
+        self.dest_min_size.is_some()
+            || self.dest_max_size.is_some()
+            || self.dest_media.is_some()
+            || self.dest_transport.is_some()
+            || self.dest_model.is_some()
+            || self.dest_serial.is_some()
+            || self.dest_wwn.is_some()
+            || self.dest_by_path.is_some()
+            || self.dest_select.is_some()
+    }
+
+    fn clear_dest_selection(&mut self) {
+        self.dest_min_size = None;
+        self.dest_max_size = None;
+        self.dest_media = None;
+        self.dest_transport = None;
+        self.dest_model = None;
+        self.dest_serial = None;
+        self.dest_wwn = None;
+        self.dest_by_path = None;
+        self.dest_select = None;
+    }
+
     fn from_args<T: AsRef<OsStr>>(args: &[T]) -> Result<Self> {
         match Cmd::try_parse_from(
             vec![
@@ -359,13 +647,39 @@ mod test {
             network_dir: DefaultedString::<NetworkDir>::from_str("o").unwrap(),
             save_partlabel: vec!["p".into(), "q".into()],
             save_partindex: vec!["r".into(), "s".into()],
+            save_parttype: vec!["0fc63daf-8483-4772-8e79-3d69d8477de4".into()],
+            save_partuuid: vec!["a0b1c2d3-0000-4000-8000-123456789abc".into()],
+            save_partsize: vec!["100GiB-".into(), "-1MiB".into()],
+            relocate_saved_partitions: true,
+            existing_install: Some(ExistingInstall::Refuse),
             offline: true,
             insecure: true,
+            signing_key: vec!["keys.asc".into(), "keys.d".into()],
+            signing_keys_only: true,
             insecure_ignition: true,
             stream_base_url: Some(Url::parse("http://example.com/t").unwrap()),
             preserve_on_error: true,
+            teardown_holders: true,
+            dry_run: true,
+            verify_write: true,
+            discard: true,
+            wipe_signatures: true,
+            event_output: Some(EventOutput::Fd(3)),
+            status_url: Some(Url::parse("http://example.com/status").unwrap()),
+            status_ca: Some("ca.pem".into()),
             fetch_retries: FetchRetries::from_str("3").unwrap(),
+            fetch_segments: FetchSegments::from_str("4").unwrap(),
+            cache_dir: Some("cache".into()),
             secure_ipl: true,
+            dest_min_size: Some(ByteSize::from_str("1GiB").unwrap()),
+            dest_max_size: Some(ByteSize::from_str("2GB").unwrap()),
+            dest_media: Some(DiskMedia::SolidState),
+            dest_transport: Some("v".into()),
+            dest_model: Some("w".into()),
+            dest_serial: Some("x".into()),
+            dest_wwn: Some("y".into()),
+            dest_by_path: Some("z".into()),
+            dest_select: Some(DiskSelect::Largest),
             dest_device: Some("u".into()),
         };
         let expected = vec![
@@ -409,15 +723,64 @@ mod test {
             "r",
             "--save-partindex",
             "s",
+            "--save-parttype",
+            "0fc63daf-8483-4772-8e79-3d69d8477de4",
+            "--save-partuuid",
+            "a0b1c2d3-0000-4000-8000-123456789abc",
+            "--save-partsize",
+            "100GiB-",
+            "--save-partsize",
+            "-1MiB",
+            "--relocate-saved-partitions",
+            "--existing-install",
+            "refuse",
             "--offline",
             "--insecure",
+            "--signing-key",
+            "keys.asc",
+            "--signing-key",
+            "keys.d",
+            "--signing-keys-only",
             "--insecure-ignition",
             "--stream-base-url",
             "http://example.com/t",
             "--preserve-on-error",
+            "--teardown-holders",
+            "--dry-run",
+            "--verify-write",
+            "--discard",
+            "--wipe-signatures",
+            "--event-output",
+            "fd:3",
+            "--status-url",
+            "http://example.com/status",
+            "--status-ca",
+            "ca.pem",
             "--fetch-retries",
             "3",
+            "--fetch-segments",
+            "4",
+            "--cache-dir",
+            "cache",
             "--secure-ipl",
+            "--dest-min-size",
+            "1073741824",
+            "--dest-max-size",
+            "2000000000",
+            "--dest-media",
+            "ssd",
+            "--dest-transport",
+            "v",
+            "--dest-model",
+            "w",
+            "--dest-serial",
+            "x",
+            "--dest-wwn",
+            "y",
+            "--dest-by-path",
+            "z",
+            "--dest-select",
+            "largest",
             "u",
         ];
         assert_eq!(config.to_args().unwrap(), expected);
@@ -442,12 +805,29 @@ copy-network: true
 network-dir: o
 save-partlabel: [p, q]
 save-partindex: [r, s]
+save-parttype: [0fc63daf-8483-4772-8e79-3d69d8477de4]
+save-partuuid: [a0b1c2d3-0000-4000-8000-123456789abc]
+save-partsize: [100GiB-, -1MiB]
+relocate-saved-partitions: true
+existing-install: require-blank
 offline: true
 insecure: true
+signing-key: [keys.asc, keys.d]
+signing-keys-only: true
 insecure-ignition: true
 stream-base-url: http://example.com/t
 preserve-on-error: true
+teardown-holders: true
+dry-run: true
+verify-write: true
+discard: true
+wipe-signatures: true
+event-output: /run/events.sock
+status-url: http://example.com/status
+status-ca: ca.pem
 fetch-retries: 3
+fetch-segments: 4
+cache-dir: cache
 dest-device: u
 "#
                 .as_bytes(),
@@ -484,13 +864,40 @@ dest-device: u
             network_dir: DefaultedString::<NetworkDir>::from_str("o").unwrap(),
             save_partlabel: vec!["p".into(), "q".into()],
             save_partindex: vec!["r".into(), "s".into()],
+            save_parttype: vec!["0fc63daf-8483-4772-8e79-3d69d8477de4".into()],
+            save_partuuid: vec!["a0b1c2d3-0000-4000-8000-123456789abc".into()],
+            save_partsize: vec!["100GiB-".into(), "-1MiB".into()],
+            relocate_saved_partitions: true,
+            existing_install: Some(ExistingInstall::RequireBlank),
             offline: true,
             insecure: true,
+            signing_key: vec!["keys.asc".into(), "keys.d".into()],
+            signing_keys_only: true,
             insecure_ignition: true,
             stream_base_url: Some(Url::parse("http://example.com/t").unwrap()),
             preserve_on_error: true,
+            teardown_holders: true,
+            dry_run: true,
+            verify_write: true,
+            discard: true,
+            wipe_signatures: true,
+            event_output: Some(EventOutput::Socket("/run/events.sock".into())),
+            status_url: Some(Url::parse("http://example.com/status").unwrap()),
+            status_ca: Some("ca.pem".into()),
             fetch_retries: FetchRetries::from_str("3").unwrap(),
+            fetch_segments: FetchSegments::from_str("4").unwrap(),
+            cache_dir: Some("cache".into()),
             secure_ipl: false,
+            // conflict
+            dest_min_size: None,
+            dest_max_size: None,
+            dest_media: None,
+            dest_transport: None,
+            dest_model: None,
+            dest_serial: None,
+            dest_wwn: None,
+            dest_by_path: None,
+            dest_select: None,
             dest_device: Some("u".into()),
         };
         let config = InstallConfig::from_args(&["--config-file", f.path().to_str().unwrap()])
@@ -500,6 +907,49 @@ dest-device: u
         assert_eq!(expected, config);
     }
 
+    /// Test that destination selection options deserialize as expected
+    #[test]
+    fn parse_dest_selection_config_file() {
+        let mut f = NamedTempFile::new().unwrap();
+        f.as_file_mut()
+            .write_all(
+                r#"
+dest-min-size: 100GB
+dest-max-size: 2TiB
+dest-media: hdd
+dest-transport: sata
+dest-model: "ST*"
+dest-serial: "Z1*"
+dest-wwn: "0x5000*"
+dest-by-path: "*-ata-1"
+dest-select: smallest
+"#
+                .as_bytes(),
+            )
+            .unwrap();
+        let expected = InstallConfig {
+            dest_min_size: Some(ByteSize(100_000_000_000)),
+            dest_max_size: Some(ByteSize(2 << 40)),
+            dest_media: Some(DiskMedia::Rotational),
+            dest_transport: Some("sata".into()),
+            dest_model: Some("ST*".into()),
+            dest_serial: Some("Z1*".into()),
+            dest_wwn: Some("0x5000*".into()),
+            dest_by_path: Some("*-ata-1".into()),
+            dest_select: Some(DiskSelect::Smallest),
+            ..Default::default()
+        };
+        let config = InstallConfig::from_args(&["--config-file", f.path().to_str().unwrap()])
+            .unwrap()
+            .expand_config_files()
+            .unwrap();
+        assert_eq!(expected, config);
+
+        // can't combine with a destination device
+        InstallConfig::from_args(&["--dest-media", "ssd", "/dev/sda"]).unwrap_err();
+        InstallConfig::from_args(&["--dest-media", "floppy"]).unwrap_err();
+    }
+
     /// Check that default InstallConfig serializes to empty arg list
     #[test]
     fn serialize_default_install_config_args() {
@@ -578,4 +1028,60 @@ dest-device: u
         .expand_config_files()
         .unwrap_err();
     }
+
+    /// Test that a destination device and destination selection options
+    /// override each other when merging config files
+    #[test]
+    fn install_config_file_dest_override() {
+        let mut device = NamedTempFile::new().unwrap();
+        device
+            .as_file_mut()
+            .write_all(b"dest-device: /dev/missing\nfetch-retries: 1")
+            .unwrap();
+        let mut selection = NamedTempFile::new().unwrap();
+        selection
+            .as_file_mut()
+            .write_all(b"dest-min-size: 1GiB\ndest-model: \"ST*\"")
+            .unwrap();
+        let expand = |args: &[&str]| {
+            InstallConfig::from_args(args)
+                .unwrap()
+                .expand_config_files()
+        };
+        let device = device.path().to_str().unwrap();
+        let selection = selection.path().to_str().unwrap();
+
+        // selection options replace an earlier device
+        let config = expand(&["--config-file", device, "--config-file", selection]).unwrap();
+        assert_eq!(config.dest_device, None);
+        assert_eq!(config.dest_min_size, Some(ByteSize(1 << 30)));
+        assert_eq!(config.dest_model.as_deref(), Some("ST*"));
+        assert_eq!(
+            config.fetch_retries,
+            FetchRetries::Finite(NonZeroU32::new(1).unwrap())
+        );
+        let config = expand(&["--config-file", device, "--dest-serial", "Z1*"]).unwrap();
+        assert_eq!(config.dest_device, None);
+        assert_eq!(config.dest_serial.as_deref(), Some("Z1*"));
+
+        // a device replaces earlier selection options
+        let config = expand(&["--config-file", selection, "--config-file", device]).unwrap();
+        assert_eq!(config.dest_device.as_deref(), Some("/dev/missing"));
+        assert!(!config.has_dest_selection());
+        let config = expand(&["--config-file", selection, "/dev/other"]).unwrap();
+        assert_eq!(config.dest_device.as_deref(), Some("/dev/other"));
+        assert!(!config.has_dest_selection());
+
+        // the latest one wins
+        let config = expand(&[
+            "--config-file",
+            device,
+            "--config-file",
+            selection,
+            "/dev/other",
+        ])
+        .unwrap();
+        assert_eq!(config.dest_device.as_deref(), Some("/dev/other"));
+        assert!(!config.has_dest_selection());
+    }
 }
@@ -45,8 +45,19 @@ pub enum Cmd {
     Install(InstallConfig),
     /// Download a CoreOS image
     Download(DownloadConfig),
+    /// Mirror a Fedora CoreOS stream to a directory
+    Mirror(MirrorConfig),
     /// List available images in a Fedora CoreOS stream
     ListStream(ListStreamConfig),
+    /// List disks that could be install targets
+    ///
+    /// Unmounted boot filesystems are inspected for an existing CoreOS
+    /// install by mounting them read-only, without replaying their
+    /// journals.
+    ListDisks(ListDisksConfig),
+    /// Commands to manage the image cache
+    #[command(subcommand)]
+    Cache(CacheCmd),
     /// Commands to manage a CoreOS live ISO image
     #[command(subcommand)]
     Iso(IsoCmd),
@@ -61,6 +72,16 @@ pub enum Cmd {
     Dev(DevCmd),
 }
 
+#[derive(Debug, Parser)]
+pub enum CacheCmd {
+    /// List cached images
+    List(CacheListConfig),
+    /// Verify cached images against their signatures
+    Verify(CacheVerifyConfig),
+    /// Remove cached images
+    Prune(CachePruneConfig),
+}
+
 #[derive(Debug, Parser)]
 pub enum IsoCmd {
     /// Embed an Ignition config in an ISO image
@@ -185,6 +206,9 @@ pub enum DevCmd {
     /// Commands to extract data
     #[command(subcommand)]
     Extract(DevExtractCmd),
+    /// Commands to stash and restore saved partitions
+    #[command(subcommand)]
+    Partitions(DevPartitionsCmd),
 }
 
 #[derive(Debug, Parser)]
@@ -205,6 +229,16 @@ pub enum DevExtractCmd {
     Initrd(DevExtractInitrdConfig),
 }
 
+#[derive(Debug, Parser)]
+pub enum DevPartitionsCmd {
+    /// Save partition entries from a disk to a stash file
+    Stash(DevPartitionsStashConfig),
+    /// Merge stashed partition entries into a disk's partition table
+    Restore(DevPartitionsRestoreConfig),
+    /// Show the contents of a partition stash file
+    Show(DevPartitionsShowConfig),
+}
+
 #[derive(Debug, Parser)]
 pub struct DownloadConfig {
     /// Fedora CoreOS stream
@@ -231,12 +265,71 @@ pub struct DownloadConfig {
     /// Allow unsigned image
     #[arg(long)]
     pub insecure: bool,
+    /// Trust additional GPG keyring file or dir
+    #[arg(long, value_name = "path")]
+    pub signing_key: Vec<String>,
+    /// Trust only keys from --signing-key
+    #[arg(long)]
+    pub signing_keys_only: bool,
     /// Base URL for Fedora CoreOS stream metadata
     #[arg(long, value_name = "URL")]
     pub stream_base_url: Option<Url>,
     /// Fetch retries, or "infinite"
     #[arg(long, value_name = "N", default_value_t)]
     pub fetch_retries: FetchRetries,
+    /// Parallel range requests per image
+    #[arg(long, value_name = "N", default_value_t)]
+    pub fetch_segments: FetchSegments,
+    /// Cache downloaded images in directory
+    #[arg(long, value_name = "path")]
+    pub cache_dir: Option<String>,
+    /// Write JSON-lines events to fd:N or socket path
+    #[arg(long, value_name = "target")]
+    pub event_output: Option<EventOutput>,
+}
+
+#[derive(Debug, Parser)]
+pub struct MirrorConfig {
+    /// Fedora CoreOS stream
+    #[arg(short, long, value_name = "name", default_value = "stable")]
+    pub stream: String,
+    /// Architecture to mirror [default: current]
+    #[arg(short, long, value_name = "name")]
+    pub architecture: Vec<String>,
+    /// Fedora CoreOS platform name
+    #[arg(short, long, value_name = "name", default_value = "metal")]
+    pub platform: Vec<String>,
+    /// Image format to mirror [default: all]
+    #[arg(short, long, value_name = "name")]
+    pub format: Vec<String>,
+    /// Destination directory
+    #[arg(short = 'C', long, value_name = "path", default_value = ".")]
+    pub directory: String,
+    /// Base URL of the mirror in the rewritten stream
+    ///
+    /// Point artifacts in the rewritten stream metadata at this URL, where
+    /// the destination directory will be served.  By default, artifact
+    /// locations are relative to the stream metadata.
+    #[arg(long, value_name = "URL")]
+    pub base_url: Option<Url>,
+    /// Allow unsigned images
+    #[arg(long)]
+    pub insecure: bool,
+    /// Trust additional GPG keyring file or dir
+    #[arg(long, value_name = "path")]
+    pub signing_key: Vec<String>,
+    /// Trust only keys from --signing-key
+    #[arg(long)]
+    pub signing_keys_only: bool,
+    /// Base URL for Fedora CoreOS stream metadata
+    #[arg(long, value_name = "URL")]
+    pub stream_base_url: Option<Url>,
+    /// Fetch retries, or "infinite"
+    #[arg(long, value_name = "N", default_value_t)]
+    pub fetch_retries: FetchRetries,
+    /// Parallel range requests per image
+    #[arg(long, value_name = "N", default_value_t)]
+    pub fetch_segments: FetchSegments,
 }
 
 #[derive(Debug, Parser)]
@@ -249,6 +342,52 @@ pub struct ListStreamConfig {
     pub stream_base_url: Option<Url>,
 }
 
+#[derive(Debug, Parser)]
+pub struct ListDisksConfig {
+    /// Print the disk inventory as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct CacheListConfig {
+    /// Cache directory
+    #[arg(long, value_name = "path")]
+    pub cache_dir: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct CacheVerifyConfig {
+    /// Cache directory
+    #[arg(long, value_name = "path")]
+    pub cache_dir: String,
+    /// Remove images that fail verification
+    #[arg(long)]
+    pub remove: bool,
+    /// Trust additional GPG keyring file or dir
+    #[arg(long, value_name = "path")]
+    pub signing_key: Vec<String>,
+    /// Trust only keys from --signing-key
+    #[arg(long)]
+    pub signing_keys_only: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct CachePruneConfig {
+    /// Cache directory
+    #[arg(long, value_name = "path")]
+    pub cache_dir: String,
+    /// Remove least recently used images above size
+    #[arg(long, value_name = "size")]
+    pub max_size: Option<ByteSize>,
+    /// Remove images not used for this many days
+    #[arg(long, value_name = "days")]
+    pub max_age: Option<u64>,
+    /// Remove all images
+    #[arg(long)]
+    pub all: bool,
+}
+
 #[derive(Debug, Parser)]
 pub struct CommonCustomizeConfig {
     /// Ignition config fragment for dest sys
@@ -330,6 +469,19 @@ pub struct CommonCustomizeConfig {
     /// specified.
     #[arg(long, value_name = "path")]
     pub installer_config: Vec<String>,
+    /// Installer status URL
+    ///
+    /// If installer is run at boot, POST a JSON object to the specified
+    /// URL when the install starts, periodically while it progresses, and
+    /// when it succeeds or fails.
+    #[arg(long, value_name = "URL")]
+    pub installer_status_url: Option<Url>,
+    /// PEM CA bundle for installer status URL
+    ///
+    /// Trust the certificate authorities in the specified PEM bundle when
+    /// connecting to the installer status URL.
+    #[arg(long, value_name = "path", requires = "installer_status_url")]
+    pub installer_status_ca: Option<String>,
     /// Ignition config fragment for live env
     ///
     /// Merge the specified Ignition config into the config for the live
@@ -703,6 +855,53 @@ pub struct DevExtractInitrdConfig {
     pub filter: Vec<String>,
 }
 
+#[derive(Debug, Parser)]
+pub struct DevPartitionsStashConfig {
+    /// Save partitions with this label glob
+    #[arg(long, value_name = "lx", value_delimiter = ',')]
+    pub save_partlabel: Vec<String>,
+    /// Save partitions with this number or range
+    #[arg(long, value_name = "id", value_delimiter = ',')]
+    #[arg(allow_hyphen_values = true)]
+    pub save_partindex: Vec<String>,
+    /// Save partitions with this type GUID
+    #[arg(long, value_name = "guid", value_delimiter = ',')]
+    pub save_parttype: Vec<String>,
+    /// Save partitions with this partition GUID
+    #[arg(long, value_name = "guid", value_delimiter = ',')]
+    pub save_partuuid: Vec<String>,
+    /// Save partitions with this size or size range
+    #[arg(long, value_name = "size", value_delimiter = ',')]
+    #[arg(allow_hyphen_values = true)]
+    pub save_partsize: Vec<String>,
+    /// Write to a file instead of stdout
+    #[arg(short, long, value_name = "path")]
+    pub output: Option<String>,
+    /// Source device or disk image file
+    #[arg(value_name = "DEV")]
+    pub device: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct DevPartitionsRestoreConfig {
+    /// Replace the partition table rather than merging into it
+    #[arg(long)]
+    pub overwrite: bool,
+    /// Partition stash file
+    #[arg(value_name = "STASH")]
+    pub stash: String,
+    /// Destination device or disk image file
+    #[arg(value_name = "DEV")]
+    pub device: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct DevPartitionsShowConfig {
+    /// Partition stash file
+    #[arg(value_name = "STASH")]
+    pub stash: String,
+}
+
 #[cfg(feature = "docgen")]
 #[derive(Debug, Parser)]
 pub struct PackManConfig {
@@ -15,6 +15,7 @@
 //! Miscellaneous helper types.
 
 use anyhow::{anyhow, Error, Result};
+use byte_unit::Byte;
 use serde::{Deserialize, Serialize};
 use serde_with::{DeserializeFromStr, SerializeDisplay};
 use std::default::Default;
@@ -22,17 +23,25 @@ use std::fmt;
 use std::marker::PhantomData;
 use std::num::NonZeroU32;
 use std::str::FromStr;
+use uuid::Uuid;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PartitionFilter {
     Label(glob::Pattern),
     Index(Option<NonZeroU32>, Option<NonZeroU32>),
+    /// Partition type GUID
+    Type(Uuid),
+    /// Unique partition GUID
+    Guid(Uuid),
+    /// Partition size in bytes, inclusive
+    Size(Option<u64>, Option<u64>),
 }
 
-#[derive(Debug, DeserializeFromStr, SerializeDisplay, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, DeserializeFromStr, SerializeDisplay, Clone, Copy, PartialEq, Eq)]
 pub enum FetchRetries {
     Infinite,
     Finite(NonZeroU32),
+    #[default]
     None,
 }
 
@@ -59,9 +68,185 @@ impl fmt::Display for FetchRetries {
     }
 }
 
-impl Default for FetchRetries {
+/// Number of concurrent range requests used to fetch an image.
+#[derive(Debug, DeserializeFromStr, SerializeDisplay, Clone, Copy, PartialEq, Eq)]
+pub struct FetchSegments(NonZeroU32);
+
+impl FetchSegments {
+    pub fn get(&self) -> u32 {
+        self.0.get()
+    }
+}
+
+impl Default for FetchSegments {
     fn default() -> Self {
-        Self::None
+        Self(NonZeroU32::new(1).unwrap())
+    }
+}
+
+impl FromStr for FetchSegments {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let num = s.parse::<u32>().map_err(|e| anyhow!(e))?;
+        NonZeroU32::new(num)
+            .map(Self)
+            .ok_or_else(|| anyhow!("segment count must be at least 1"))
+    }
+}
+
+impl fmt::Display for FetchSegments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A byte count, optionally with a unit suffix such as "500GB" or "32GiB".
+#[derive(Debug, DeserializeFromStr, SerializeDisplay, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = Byte::from_str(s)
+            .map_err(|e| anyhow!("couldn't parse size '{}': {}", s, e))?
+            .get_bytes();
+        Ok(Self(
+            bytes
+                .try_into()
+                .map_err(|_| anyhow!("size '{}' is too large", s))?,
+        ))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Storage media type of a disk.
+#[derive(Debug, DeserializeFromStr, SerializeDisplay, Clone, Copy, PartialEq, Eq)]
+pub enum DiskMedia {
+    Rotational,
+    SolidState,
+}
+
+impl FromStr for DiskMedia {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hdd" => Ok(Self::Rotational),
+            "ssd" => Ok(Self::SolidState),
+            _ => Err(anyhow!("unknown media type '{}'; expected hdd or ssd", s)),
+        }
+    }
+}
+
+impl fmt::Display for DiskMedia {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rotational => write!(f, "hdd"),
+            Self::SolidState => write!(f, "ssd"),
+        }
+    }
+}
+
+/// Which disk to pick when several match the selection criteria.
+#[derive(Debug, DeserializeFromStr, SerializeDisplay, Clone, Copy, PartialEq, Eq)]
+pub enum DiskSelect {
+    Largest,
+    Smallest,
+}
+
+impl FromStr for DiskSelect {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest" => Ok(Self::Largest),
+            "smallest" => Ok(Self::Smallest),
+            _ => Err(anyhow!(
+                "unknown selection '{}'; expected largest or smallest",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DiskSelect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Largest => write!(f, "largest"),
+            Self::Smallest => write!(f, "smallest"),
+        }
+    }
+}
+
+/// What to do if the destination already contains an OS.
+#[derive(Debug, DeserializeFromStr, SerializeDisplay, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingInstall {
+    Overwrite,
+    Refuse,
+    RequireBlank,
+}
+
+impl FromStr for ExistingInstall {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(Self::Overwrite),
+            "refuse" => Ok(Self::Refuse),
+            "require-blank" => Ok(Self::RequireBlank),
+            _ => Err(anyhow!(
+                "unknown action '{}'; expected overwrite, refuse, or require-blank",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ExistingInstall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overwrite => write!(f, "overwrite"),
+            Self::Refuse => write!(f, "refuse"),
+            Self::RequireBlank => write!(f, "require-blank"),
+        }
+    }
+}
+
+/// Where to send machine-readable events: an inherited file descriptor
+/// ("fd:N") or the path to a Unix stream socket.
+#[derive(Debug, DeserializeFromStr, SerializeDisplay, Clone, PartialEq, Eq)]
+pub enum EventOutput {
+    Fd(i32),
+    Socket(String),
+}
+
+impl FromStr for EventOutput {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(fd) = s.strip_prefix("fd:") {
+            let fd: i32 = fd
+                .parse()
+                .map_err(|e| anyhow!("couldn't parse file descriptor '{}': {}", fd, e))?;
+            if fd < 0 {
+                return Err(anyhow!("invalid file descriptor {}", fd));
+            }
+            Ok(Self::Fd(fd))
+        } else if s.is_empty() {
+            Err(anyhow!("empty event output"))
+        } else {
+            Ok(Self::Socket(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for EventOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fd(fd) => write!(f, "fd:{fd}"),
+            Self::Socket(path) => write!(f, "{path}"),
+        }
     }
 }
 
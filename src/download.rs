@@ -23,18 +23,45 @@ use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
-use crate::blockdev::{detect_formatted_sector_size, get_gpt_size, SavedPartitions};
+use crate::blockdev::{
+    detect_formatted_sector_size, get_gpt_size, SavedPartitions, SectorSizeTranslation,
+};
+use crate::cache::Cache;
 use crate::cmdline::*;
+use crate::events::*;
 use crate::io::*;
 use crate::source::*;
 
 // Download all artifacts for an image and verify their signatures.
 pub fn download(config: DownloadConfig) -> Result<()> {
+    // start reporting events, if requested
+    if let Some(output) = &config.event_output {
+        open_event_output(output)?;
+    }
+    emit(Event::Started {
+        command: "download",
+    });
+    let result = run_download(config);
+    emit_result(&result);
+    result
+}
+
+fn run_download(config: DownloadConfig) -> Result<()> {
+    // load custom signing keys, if any
+    let keys = VerifyKeys::new(&config.signing_key, config.signing_keys_only)?;
+
+    let cache = config.cache_dir.as_deref().map(Cache::new).transpose()?;
+
     // Build image location.  Ideally the parser would use conflicts_with
     // (and an ArgGroup for streams), but that doesn't play well with
     // default arguments, so we manually prioritize modes.
     let location: Box<dyn ImageLocation> = if let Some(image_url) = &config.image_url {
-        Box::new(UrlLocation::new(image_url, config.fetch_retries))
+        Box::new(UrlLocation::new(
+            image_url,
+            config.fetch_retries,
+            config.fetch_segments,
+            cache,
+        ))
     } else {
         Box::new(StreamLocation::new(
             &config.stream,
@@ -43,6 +70,8 @@ pub fn download(config: DownloadConfig) -> Result<()> {
             &config.format,
             config.stream_base_url.as_ref(),
             config.fetch_retries,
+            config.fetch_segments,
+            cache,
         )?)
     };
     eprintln!("{location}");
@@ -53,6 +82,13 @@ pub fn download(config: DownloadConfig) -> Result<()> {
         bail!("no artifacts found");
     }
     for source in sources.iter_mut() {
+        emit(Event::SourceSelected {
+            filename: &source.filename,
+            artifact_type: &source.artifact_type,
+            length: source.length_hint,
+            signed: source.signature.is_some(),
+        });
+
         // set up image source
         if source.signature.is_none() {
             if config.insecure {
@@ -68,15 +104,14 @@ pub fn download(config: DownloadConfig) -> Result<()> {
         path.push(&config.directory);
         path.push(filename);
         let sig_path = path.with_file_name(format!("{filename}.sig"));
+        let partial_path = path.with_file_name(format!("{filename}.partial"));
 
         // check existing image and signature; don't redownload if OK
         // If we decompressed last time, the call will fail because we can't
         // check the old signature.  If we didn't decompress last time but are
         // decompressing this time, we're not smart enough to decompress the
         // existing file.
-        if !decompress
-            && check_image_and_sig(source, &path, &sig_path, VerifyKeys::Production).is_ok()
-        {
+        if !decompress && check_image_and_sig(source, &path, &sig_path, keys.clone()).is_ok() {
             // report the output file path and keep going
             println!("{}", path.display());
             continue;
@@ -85,26 +120,130 @@ pub fn download(config: DownloadConfig) -> Result<()> {
         // Write the image and signature.  Only write the signature if we
         // weren't asked to decompress, regardless of whether we actually
         // did.
-        if let Err(err) = write_image_and_sig(
+        fetch_image_and_sig(
             source,
             &path,
+            &partial_path,
             &sig_path,
             decompress,
             !config.decompress,
-            VerifyKeys::Production,
-        ) {
-            // delete output files, which may not have been created yet
-            let _ = remove_file(&path);
-            let _ = remove_file(&sig_path);
+            keys.clone(),
+        )?;
+
+        // report the output file path
+        println!("{}", path.display());
+    }
+
+    Ok(())
+}
 
-            // fail
-            return Err(err);
+/// Mirror artifacts from a stream, and write stream metadata pointing to
+/// the mirrored copies.
+pub fn mirror(config: MirrorConfig) -> Result<()> {
+    // load custom signing keys, if any
+    let keys = VerifyKeys::new(&config.signing_key, config.signing_keys_only)?;
+
+    // fetch stream metadata and select artifacts
+    let architectures = if config.architecture.is_empty() {
+        vec![DefaultedString::<Architecture>::default().to_string()]
+    } else {
+        config.architecture.clone()
+    };
+    let mut metadata = StreamMetadata::fetch(
+        &config.stream,
+        config.stream_base_url.as_ref(),
+        config.fetch_retries,
+        config.fetch_segments,
+    )?;
+    let artifacts = metadata.select(&architectures, &config.platform, &config.format)?;
+    if artifacts.is_empty() {
+        bail!("no artifacts found");
+    }
+    // make sure joining relative locations doesn't drop the last path
+    // component of the base URL
+    let base_url = config.base_url.clone().map(|mut url| {
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
         }
+        url
+    });
 
-        // report the output file path
+    for artifact in &artifacts {
+        eprintln!(
+            "Mirroring {} {} {} {} artifact",
+            artifact.architecture, artifact.platform, artifact.format, artifact.artifact_type
+        );
+        // calculate paths
+        // Filenames include the release and architecture, but group them
+        // by stream and architecture to keep directories manageable.
+        let mirror_path = |filename: &str| {
+            let location = format!("{}/{}/{}", config.stream, artifact.architecture, filename);
+            let path = Path::new(&config.directory).join(&location);
+            let sig_path = path.with_file_name(format!("{filename}.sig"));
+            (location, path, sig_path)
+        };
+        let info = metadata.info(artifact)?;
+        let (mut location, mut path, mut sig_path) = mirror_path(&info.filename);
+
+        // Don't refetch artifacts we already have.  If the stream metadata
+        // has a digest, we can check them without contacting the server.
+        let signed = match check_mirrored_image(
+            artifact,
+            &info,
+            &path,
+            &sig_path,
+            config.insecure,
+            keys.clone(),
+        ) {
+            Ok(signed) => signed,
+            Err(_) => {
+                let mut source = metadata.source(artifact)?;
+                if source.signature.is_none() {
+                    if config.insecure {
+                        eprintln!("Signature not found; skipping verification as requested");
+                    } else {
+                        bail!("--insecure not specified and signature not found");
+                    }
+                }
+                // we may have been redirected to a different filename
+                (location, path, sig_path) = mirror_path(&source.filename);
+                let partial_path = path.with_file_name(format!("{}.partial", source.filename));
+                let dir = path.parent().expect("artifact path has no parent");
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("creating directory {}", dir.display()))?;
+                if check_image_and_sig(&source, &path, &sig_path, keys.clone()).is_err() {
+                    fetch_image_and_sig(
+                        &mut source,
+                        &path,
+                        &partial_path,
+                        &sig_path,
+                        false,
+                        true,
+                        keys.clone(),
+                    )?;
+                }
+                source.signature.is_some()
+            }
+        };
         println!("{}", path.display());
+
+        // point stream metadata at the mirrored copy
+        let location = match &base_url {
+            Some(base_url) => base_url
+                .join(&location)
+                .context("building artifact URL")?
+                .to_string(),
+            None => location,
+        };
+        metadata.relocate(artifact, &location, signed)?;
     }
 
+    // Write stream metadata last, so it never refers to artifacts we don't
+    // have.
+    let path = Path::new(&config.directory).join(format!("{}.json", config.stream));
+    metadata.write(&path)?;
+    println!("{}", path.display());
+
     Ok(())
 }
 
@@ -146,46 +285,139 @@ fn check_image_and_sig(
     keys: VerifyKeys,
 ) -> Result<()> {
     // ensure we have something to check
-    if source.signature.is_none() {
-        bail!("no signature available; can't check existing file");
+    if source.signature.is_none() && source.sha256.is_none() {
+        bail!("no signature or digest available; can't check existing file");
     }
-    let signature = source.signature.as_ref().unwrap();
 
     // compare signature to expectation
-    let mut sig_file = OpenOptions::new()
-        .read(true)
-        .open(sig_path)
-        .with_context(|| format!("opening {}", sig_path.display()))?;
-    let mut buf = Vec::new();
-    sig_file
-        .read_to_end(&mut buf)
-        .with_context(|| format!("reading {}", sig_path.display()))?;
-    if &buf != signature {
-        bail!("signature file doesn't match source");
+    if let Some(signature) = source.signature.as_ref() {
+        if &read_signature(sig_path)? != signature {
+            bail!("signature file doesn't match source");
+        }
+    }
+
+    check_image(
+        path,
+        source.signature.as_deref(),
+        source.sha256.clone(),
+        &source.artifact_type,
+        keys,
+    )
+}
+
+// Check an already-mirrored image against the digest from stream metadata
+// and the signature saved next to it, if any.  Return whether there was a
+// signature, or an error if the image can't be reused.
+fn check_mirrored_image(
+    artifact: &StreamArtifact,
+    info: &ArtifactInfo,
+    path: &Path,
+    sig_path: &Path,
+    insecure: bool,
+    keys: VerifyKeys,
+) -> Result<bool> {
+    // without a digest, a stale image could still carry a valid signature
+    if info.sha256.is_none() {
+        bail!("no digest available; can't check existing file");
     }
+    let signature = match read_signature(sig_path) {
+        Ok(signature) => Some(signature),
+        Err(_) if insecure => None,
+        Err(e) => return Err(e),
+    };
+    check_image(
+        path,
+        signature.as_deref(),
+        info.sha256.clone(),
+        &artifact.artifact_type,
+        keys,
+    )?;
+    Ok(signature.is_some())
+}
+
+fn read_signature(sig_path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(sig_path).with_context(|| format!("reading {}", sig_path.display()))
+}
 
+// Verify an image file against a signature and digest.
+fn check_image(
+    path: &Path,
+    signature: Option<&[u8]>,
+    sha256: Option<Sha256Digest>,
+    artifact_type: &str,
+    keys: VerifyKeys,
+) -> Result<()> {
     // open image file
     let mut file = OpenOptions::new()
         .read(true)
         .open(path)
         .with_context(|| format!("opening {}", path.display()))?;
 
-    // perform GPG verification
-    let mut reader = VerifyReader::new(
-        BufReader::with_capacity(BUFFER_SIZE, &mut file),
-        Some(signature),
-        keys,
-    )?;
+    // perform GPG and digest verification
+    let mut hash_reader =
+        Sha256VerifyReader::new(BufReader::with_capacity(BUFFER_SIZE, &mut file), sha256);
+    let mut reader = VerifyReader::new(&mut hash_reader, signature, keys)?;
     copy(&mut reader, &mut io::sink())?;
     reader.verify_without_logging_failure()?;
+    drop(reader);
+    if hash_reader.verify()? {
+        report_digest_verified(artifact_type, "sha256");
+    }
 
     Ok(())
 }
 
-/// Copy the image to disk, and also the signature if requested.
+// Return true if the partially-downloaded image at the specified path is
+// a prefix of the source image that we could resume downloading from.  We
+// can't resume if we're decompressing.
+fn is_resumable(source: &ImageSource, partial_path: &Path, decompress: bool) -> bool {
+    let length = match partial_path.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return false,
+    };
+    !decompress && length > 0 && source.length_hint.map(|l| length < l).unwrap_or(false)
+}
+
+/// Copy the image and signature with write_image_and_sig().  On failure,
+/// delete the output files, but keep an incomplete download around so we
+/// can resume it next time.
+fn fetch_image_and_sig(
+    source: &mut ImageSource,
+    path: &Path,
+    partial_path: &Path,
+    sig_path: &Path,
+    decompress: bool,
+    save_sig: bool,
+    keys: VerifyKeys,
+) -> Result<()> {
+    let result = write_image_and_sig(
+        source,
+        path,
+        partial_path,
+        sig_path,
+        decompress,
+        save_sig,
+        keys,
+    );
+    if result.is_err() {
+        // the output files may not have been created yet
+        if !is_resumable(source, partial_path, decompress) {
+            let _ = remove_file(partial_path);
+        }
+        let _ = remove_file(path);
+        let _ = remove_file(sig_path);
+    }
+    result
+}
+
+/// Copy the image to disk, and also the signature if requested.  The image
+/// is downloaded to partial_path and then renamed into place.  If
+/// partial_path already contains the beginning of the image, resume the
+/// download where it left off.
 fn write_image_and_sig(
     source: &mut ImageSource,
     path: &Path,
+    partial_path: &Path,
     sig_path: &Path,
     decompress: bool,
     save_sig: bool,
@@ -195,22 +427,53 @@ fn write_image_and_sig(
     let mut dest = OpenOptions::new()
         .write(true)
         .create(true)
-        .truncate(true)
-        .open(path)
-        .with_context(|| format!("opening {}", path.display()))?;
+        .truncate(false)
+        .open(partial_path)
+        .with_context(|| format!("opening {}", partial_path.display()))?;
+
+    // If we have a partial download, fetch the rest of the image and feed
+    // the existing data plus the rest through the signature verifier.
+    let mut resume_offset = 0;
+    if is_resumable(source, partial_path, decompress) {
+        let offset = dest
+            .metadata()
+            .with_context(|| format!("getting metadata for {}", partial_path.display()))?
+            .len();
+        match source.resume_at(offset) {
+            Ok(true) => {
+                eprintln!("Resuming download at offset {offset}");
+                let existing = OpenOptions::new()
+                    .read(true)
+                    .open(partial_path)
+                    .with_context(|| format!("opening {}", partial_path.display()))?;
+                let rest = std::mem::replace(&mut source.reader, Box::new(io::empty()));
+                source.reader = Box::new(existing.take(offset).chain(rest));
+                resume_offset = offset;
+            }
+            Ok(false) => eprintln!("Server doesn't support resuming; restarting download"),
+            Err(e) => eprintln!("Couldn't resume download: {e:#}"),
+        }
+    }
+    if resume_offset == 0 {
+        dest.set_len(0)
+            .with_context(|| format!("truncating {}", partial_path.display()))?;
+    }
 
     // download and verify image
     // don't check sector size
     write_image(
         source,
         &mut dest,
-        path,
-        image_copy_default,
+        partial_path,
+        |first_mb, source, dest, _, _| image_copy_resume(first_mb, source, dest, resume_offset),
         decompress,
         None,
         None,
         keys,
     )?;
+    drop(dest);
+    std::fs::rename(partial_path, path)
+        .with_context(|| format!("renaming {} to {}", partial_path.display(), path.display()))?;
 
     // write signature, if requested
     if let (true, Some(signature)) = (save_sig, source.signature.as_ref()) {
@@ -248,9 +511,12 @@ where
     let mut verify_reader =
         VerifyReader::new(&mut source.reader, source.signature.as_deref(), keys)?;
 
+    // wrap again for digest verification, if available
+    let mut hash_reader = Sha256VerifyReader::new(&mut verify_reader, source.sha256.clone());
+
     // wrap again for progress reporting
-    let mut reader: Box<dyn Read> = Box::new(ProgressReader::new(
-        &mut verify_reader,
+    let reader: Box<dyn Read> = Box::new(ProgressReader::new(
+        &mut hash_reader,
         source.length_hint,
         &source.artifact_type,
     ));
@@ -261,11 +527,17 @@ where
     // correctly, or the file might be local.  Then wrap in a
     // DecompressReader for decompression.
     let peek_reader = PeekReader::with_capacity(BUFFER_SIZE, reader);
-    if decompress {
-        reader = Box::new(DecompressReader::new(peek_reader)?);
+    let reader: Box<dyn Read> = if decompress {
+        Box::new(DecompressReader::new(peek_reader)?)
     } else {
-        reader = Box::new(peek_reader);
-    }
+        Box::new(peek_reader)
+    };
+
+    // Wrap again for verification of the decompressed digest, if
+    // available.
+    let uncompressed_sha256 = source.uncompressed_sha256.clone().filter(|_| decompress);
+    let mut uncompressed_hash_reader = Sha256VerifyReader::new(reader, uncompressed_sha256);
+    let mut reader: Box<dyn Read> = Box::new(&mut uncompressed_hash_reader);
 
     // Wrap again for limit checking.
     let byte_limit = saved.map(|saved| saved.get_offset()).transpose()?.flatten();
@@ -283,13 +555,30 @@ where
     if let Some(expected) = expected_sector_size {
         // Can we derive one from source data?
         if let Some(actual) = detect_formatted_sector_size(&first_mb) {
-            // Do they match?
+            // If they don't match, rewrite the partition tables for the
+            // destination as we go
             if expected != actual {
-                bail!(
-                    "source has sector size {} but destination has sector size {}",
+                let translation = SectorSizeTranslation::new(&first_mb, expected.get().into())
+                    .with_context(|| {
+                        format!(
+                            "source has sector size {} but destination has sector size {}",
+                            actual.get(),
+                            expected.get()
+                        )
+                    })?;
+                eprintln!(
+                    "Translating partition table from {}-byte to {}-byte sectors",
                     actual.get(),
                     expected.get()
                 );
+                translation.translate_head(&mut first_mb)?;
+                reader = Box::new(TranslatingReader {
+                    source: reader,
+                    translation,
+                    offset: first_mb.len() as u64,
+                    buffer: Cursor::new(Vec::new()),
+                    done: false,
+                });
             }
         }
     }
@@ -297,16 +586,221 @@ where
     // call the callback to copy the image
     image_copy(&first_mb, &mut reader, dest, dest_path, saved)?;
 
-    // check signature
+    // check digests and signature
     drop(reader);
+    let uncompressed_verified = uncompressed_hash_reader
+        .verify()
+        .context("verifying uncompressed image")?;
+    drop(uncompressed_hash_reader);
+    if hash_reader.verify().context("verifying image")? {
+        report_digest_verified(&source.artifact_type, "sha256");
+    }
+    if uncompressed_verified {
+        report_digest_verified(&source.artifact_type, "uncompressed-sha256");
+    }
     verify_reader.verify()?;
+    if source.signature.is_some() {
+        emit(Event::SignatureVerified {
+            artifact_type: &source.artifact_type,
+        });
+    }
 
     // finish I/O before closing the progress bar
     dest.sync_all().context("syncing data to disk")?;
 
+    // the image checked out, so it's safe to reuse
+    source.commit_to_cache();
+
     Ok(())
 }
 
+/// Report that an image digest from stream metadata matched.
+fn report_digest_verified(artifact_type: &str, digest: &str) {
+    eprintln!("Verified {digest} digest from stream metadata");
+    emit(Event::DigestVerified {
+        artifact_type,
+        digest,
+    });
+}
+
+/// Copy the image to a file, skipping the first resume_offset bytes of the
+/// source, which the file already contains.  Unlike image_copy_default(),
+/// write the first MiB immediately, since the partial download is not
+/// usable until it's renamed into place and we need it to be a verbatim
+/// prefix of the image if we resume again.
+fn image_copy_resume(
+    first_mb: &[u8],
+    source: &mut dyn Read,
+    dest: &mut File,
+    resume_offset: u64,
+) -> Result<()> {
+    let first_mb_len = first_mb.len() as u64;
+    if resume_offset < first_mb_len {
+        dest.rewind().context("seeking to start of file")?;
+        dest.write_all(first_mb)
+            .context("writing first MiB of image")?;
+    } else {
+        // the source has already read and verified these bytes from the
+        // existing file
+        copy(
+            &mut source.take(resume_offset - first_mb_len),
+            &mut io::sink(),
+        )
+        .context("reading existing data")?;
+        dest.seek(SeekFrom::Start(resume_offset))
+            .context("seeking to end of existing data")?;
+    }
+
+    let mut buf_dest = BufWriter::with_capacity(BUFFER_SIZE, dest);
+    copy(source, &mut buf_dest).context("writing image")?;
+    buf_dest.flush().context("flushing data to disk")?;
+    Ok(())
+}
+
+/// Decompress and return the first MiB of the image without consuming it:
+/// the compressed bytes read along the way are replayed to later readers
+/// of the source, so signature verification still sees the whole stream.
+pub fn peek_first_mb(source: &mut ImageSource) -> Result<Vec<u8>> {
+    peek_image(source, |reader| {
+        let mut first_mb = vec![0u8; 1024 * 1024];
+        reader
+            .read_exact(&mut first_mb)
+            .context("decoding first MiB of image")?;
+        Ok(first_mb)
+    })
+}
+
+/// Check that the image can be written to a destination with the
+/// specified sector size, before anything is written to it.  If the image
+/// has a different sector size, decompress it far enough to check the
+/// filesystem at the start of each partition.  Like peek_first_mb(), this
+/// doesn't consume the source.
+pub fn check_image_sector_size(source: &mut ImageSource, sector_size: u32) -> Result<()> {
+    peek_image(source, |reader| {
+        let mut first_mb = vec![0u8; 1024 * 1024];
+        reader
+            .read_exact(&mut first_mb)
+            .context("decoding first MiB of image")?;
+        let actual = match detect_formatted_sector_size(&first_mb) {
+            Some(actual) if actual.get() != sector_size => actual.get(),
+            _ => return Ok(()),
+        };
+        let context = || {
+            format!("source has sector size {actual} but destination has sector size {sector_size}")
+        };
+        let translation =
+            SectorSizeTranslation::new(&first_mb, sector_size.into()).with_context(context)?;
+        let mut offset = first_mb.len() as u64;
+        for (partition, start, len) in translation.headers() {
+            if start < offset {
+                // already checked
+                continue;
+            }
+            copy(&mut reader.take(start - offset), &mut io::sink()).context("decoding image")?;
+            let mut header = vec![0; len as usize];
+            reader
+                .read_exact(&mut header)
+                .context("decoding partition header")?;
+            translation
+                .check_filesystem(partition, &header)
+                .with_context(context)?;
+            offset = start + len;
+        }
+        Ok(())
+    })
+}
+
+/// Run a function against the decompressed image without consuming the
+/// source.
+fn peek_image<T>(
+    source: &mut ImageSource,
+    f: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<T> {
+    let mut recorder = RecordingReader {
+        source: &mut source.reader,
+        record: Vec::new(),
+    };
+    let result = DecompressReader::new(PeekReader::with_capacity(BUFFER_SIZE, &mut recorder))
+        .and_then(|mut reader| f(&mut reader));
+    let record = recorder.record;
+    let rest = std::mem::replace(&mut source.reader, Box::new(io::empty()));
+    source.reader = Box::new(Cursor::new(record).chain(rest));
+    result
+}
+
+/// Reader that keeps a copy of everything read through it.
+struct RecordingReader<R: Read> {
+    source: R,
+    record: Vec<u8>,
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.source.read(buf)?;
+        self.record.extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Reader that passes through an image after its first MiB, checking the
+/// filesystem in each partition and rewriting the backup GPT at the end
+/// for a different sector size.
+struct TranslatingReader<R: Read> {
+    source: R,
+    translation: SectorSizeTranslation,
+    /// Image offset of the next byte to be read from source.
+    offset: u64,
+    /// Data already read from source, to be returned before reading more.
+    buffer: Cursor<Vec<u8>>,
+    /// Whether we've translated the end of the image.
+    done: bool,
+}
+
+impl<R: Read> Read for TranslatingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.buffer.read(buf)?;
+        if count > 0 || buf.is_empty() {
+            return Ok(count);
+        }
+        if self.done {
+            // pass through anything after the end of the GPT image
+            return self.source.read(buf);
+        }
+        let tail_start = self.translation.image_size() - self.translation.tail_len();
+        let header = self
+            .translation
+            .headers()
+            .find(|(_, start, _)| *start >= self.offset && *start < tail_start);
+        let next = header.map(|(_, start, _)| start).unwrap_or(tail_start);
+        if self.offset < next {
+            let len = buf.len().min((next - self.offset) as usize);
+            let count = self.source.read(&mut buf[..len])?;
+            self.offset += count as u64;
+            return Ok(count);
+        }
+        let data = match header {
+            Some((partition, _, len)) => {
+                let len = len.min(tail_start - self.offset);
+                let mut data = vec![0; len as usize];
+                self.source.read_exact(&mut data)?;
+                self.translation
+                    .check_filesystem(partition, &data)
+                    .map(|_| data)
+            }
+            None => {
+                let mut tail = vec![0; self.translation.tail_len() as usize];
+                self.source.read_exact(&mut tail)?;
+                self.done = true;
+                self.translation.translate_tail(&mut tail).map(|_| tail)
+            }
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{e:#}")))?;
+        self.offset += data.len() as u64;
+        self.buffer = Cursor::new(data);
+        self.read(buf)
+    }
+}
+
 pub fn image_copy_default(
     first_mb: &[u8],
     source: &mut dyn Read,
@@ -387,6 +881,41 @@ pub fn image_copy_default(
     Ok(())
 }
 
+/// Read back image data written to dest starting at offset, bypassing the
+/// page cache, and check it against the digests recorded while copying.
+pub fn verify_written_image(dest: &mut File, offset: u64, digests: &BlockDigests) -> Result<()> {
+    eprintln!("Verifying written image");
+
+    // make sure we read from the device, not from cache
+    dest.sync_all().context("syncing data to disk")?;
+    let ret = unsafe { libc::posix_fadvise(dest.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if ret != 0 {
+        bail!(
+            "dropping cached data: {}",
+            io::Error::from_raw_os_error(ret)
+        );
+    }
+
+    dest.seek(SeekFrom::Start(offset))
+        .with_context(|| format!("seeking disk to offset {offset}"))?;
+    let mut reader = ProgressReader::new(
+        BufReader::with_capacity(BUFFER_SIZE, dest),
+        Some(digests.length()),
+        "written image",
+    );
+    if let Some(mismatch) = digests
+        .first_mismatch(&mut reader)
+        .context("reading back written image")?
+    {
+        bail!(
+            "written image doesn't match source; first mismatch in {}-byte block at offset {}",
+            digests.block_size(),
+            offset + mismatch
+        );
+    }
+    Ok(())
+}
+
 pub fn download_to_tempfile(url: &Url, retries: FetchRetries) -> Result<File> {
     let mut f = tempfile::tempfile()?;
 
@@ -409,12 +938,13 @@ pub fn download_to_tempfile(url: &Url, retries: FetchRetries) -> Result<File> {
     Ok(f)
 }
 
-struct ProgressReader<'a, R: Read> {
+pub struct ProgressReader<'a, R: Read> {
     source: R,
     length: Option<(NonZeroU64, String)>,
     artifact_type: &'a str,
 
     position: u64,
+    start: Instant,
     last_report: Instant,
 
     tty: bool,
@@ -423,7 +953,7 @@ struct ProgressReader<'a, R: Read> {
 }
 
 impl<'a, R: Read> ProgressReader<'a, R> {
-    fn new(source: R, length: Option<u64>, artifact_type: &'a str) -> Self {
+    pub fn new(source: R, length: Option<u64>, artifact_type: &'a str) -> Self {
         let tty = isatty(stderr().as_raw_fd()).unwrap_or_else(|e| {
             eprintln!("checking if stderr is a TTY: {e}");
             false
@@ -437,6 +967,7 @@ impl<'a, R: Read> ProgressReader<'a, R> {
             artifact_type,
 
             position: 0,
+            start: Instant::now(),
             last_report: Instant::now(),
 
             tty,
@@ -489,6 +1020,18 @@ impl<'a, R: Read> Read for ProgressReader<'a, R> {
                 ),
             }
             let _ = std::io::stdout().flush();
+
+            let elapsed = self.start.elapsed().as_secs_f64();
+            emit(Event::Progress {
+                artifact_type: self.artifact_type,
+                bytes: self.position,
+                total: self.length.as_ref().map(|(l, _)| l.get()),
+                rate: if elapsed > 0.0 {
+                    (self.position as f64 / elapsed) as u64
+                } else {
+                    0
+                },
+            });
         }
         Ok(count)
     }
@@ -506,7 +1049,7 @@ impl<'a, R: Read> Drop for ProgressReader<'a, R> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use gptman::{GPTPartitionEntry, GPT};
+    use gptman::{GPTHeader, GPTPartitionEntry, GPT};
     use std::fs::{read, write};
     use std::io::{Seek, SeekFrom};
     use tempfile::TempDir;
@@ -681,6 +1224,273 @@ mod tests {
         assert_eq!(buf, precious.as_bytes());
     }
 
+    #[test]
+    fn test_write_image_sector_size_translation() {
+        let len = 4 * 1024 * 1024;
+        let (mut source, source_path) = tempfile::Builder::new()
+            .prefix("coreos-installer-")
+            .tempfile()
+            .unwrap()
+            .into_parts();
+        source.set_len(len).unwrap();
+        GPT::write_protective_mbr_into(&mut source, 512).unwrap();
+        let mut gpt = GPT::new_from(&mut source, 512, *Uuid::new_v4().as_bytes()).unwrap();
+        gpt[1] = GPTPartitionEntry {
+            partition_type_guid: [1u8; 16],
+            unique_partition_guid: [1u8; 16],
+            starting_lba: 2048,
+            ending_lba: 6143,
+            attribute_bits: 0,
+            partition_name: "bovik".into(),
+        };
+        gpt.write_into(&mut source).unwrap();
+        let precious = "hello world";
+        source.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        source.write_all(precious.as_bytes()).unwrap();
+
+        let (mut dest, dest_path) = tempfile::Builder::new()
+            .prefix("coreos-installer-")
+            .tempfile()
+            .unwrap()
+            .into_parts();
+        write_image(
+            &mut FileLocation::new(source_path.to_str().unwrap())
+                .sources()
+                .unwrap()
+                .remove(0),
+            &mut dest,
+            &dest_path,
+            image_copy_default,
+            false,
+            None,
+            NonZeroU32::new(4096),
+            VerifyKeys::InsecureTest,
+        )
+        .unwrap();
+
+        assert_eq!(dest.metadata().unwrap().len(), len);
+        let gpt = GPT::find_from(&mut dest).unwrap();
+        assert_eq!(gpt.sector_size, 4096);
+        assert_eq!((gpt[1].starting_lba, gpt[1].ending_lba), (256, 767));
+        assert_eq!(gpt[1].partition_name.as_str(), "bovik");
+        // backup header is valid
+        dest.seek(SeekFrom::Start(len - 4096)).unwrap();
+        GPTHeader::read_from(&mut dest).unwrap();
+        dest.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        let mut buf = vec![0u8; precious.len()];
+        dest.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, precious.as_bytes());
+
+        // filesystems formatted for 512-byte sectors can't be translated
+        let mut superblock = [0u8; 512];
+        superblock[0..4].copy_from_slice(b"XFSB");
+        superblock[102..104].copy_from_slice(&512u16.to_be_bytes());
+        source.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        source.write_all(&superblock).unwrap();
+        dest.rewind().unwrap();
+        let err = write_image(
+            &mut FileLocation::new(source_path.to_str().unwrap())
+                .sources()
+                .unwrap()
+                .remove(0),
+            &mut dest,
+            &dest_path,
+            image_copy_default,
+            false,
+            None,
+            NonZeroU32::new(4096),
+            VerifyKeys::InsecureTest,
+        )
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("partition 1 has a xfs filesystem with 512-byte sectors"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn test_write_image_digest() {
+        let (mut source, source_path) = tempfile::Builder::new()
+            .prefix("coreos-installer-")
+            .tempfile()
+            .unwrap()
+            .into_parts();
+        source.set_len(2 * 1024 * 1024).unwrap();
+        partition(&mut source, None);
+        source.rewind().unwrap();
+        let mut data = Vec::new();
+        source.read_to_end(&mut data).unwrap();
+        let digest = Sha256Digest::from_hex(&hex::encode(openssl::sha::sha256(&data))).unwrap();
+        let wrong = Sha256Digest::from_hex(&"0".repeat(64)).unwrap();
+
+        for (sha256, ok) in [(digest, true), (wrong, false)] {
+            let mut image = FileLocation::new(source_path.to_str().unwrap())
+                .sources()
+                .unwrap()
+                .remove(0);
+            image.sha256 = Some(sha256);
+            let mut dest = tempfile::tempfile().unwrap();
+            let result = write_image(
+                &mut image,
+                &mut dest,
+                Path::new("/z"),
+                image_copy_default,
+                false,
+                None,
+                None,
+                VerifyKeys::InsecureTest,
+            );
+            assert_eq!(result.is_ok(), ok, "{result:?}");
+        }
+    }
+
+    #[test]
+    fn test_image_copy_resume() {
+        let len: usize = 3 * 1024 * 1024 + 1000;
+        let mb: usize = 1024 * 1024;
+
+        let mut data = vec![0u8; len];
+        for (i, el) in data.iter_mut().enumerate() {
+            *el = (i % 251) as u8;
+        }
+
+        for offset in [0, 1000, mb, 2 * mb + 17] {
+            // existing partial download
+            let mut dest = tempfile::tempfile().unwrap();
+            dest.write_all(&data[0..offset]).unwrap();
+            // the source has already read the existing data
+            let mut source = Cursor::new(&data[mb..]);
+            image_copy_resume(&data[0..mb], &mut source, &mut dest, offset as u64).unwrap();
+
+            dest.rewind().unwrap();
+            let mut result = Vec::new();
+            dest.read_to_end(&mut result).unwrap();
+            assert!(result == data, "offset {offset}");
+        }
+    }
+
+    #[test]
+    fn test_check_image_sector_size() {
+        let len = 6 * 1024 * 1024;
+        let make_image = |root_sector_size: u16| {
+            let mut image = Cursor::new(vec![0u8; len]);
+            GPT::write_protective_mbr_into(&mut image, 512).unwrap();
+            let mut gpt = GPT::new_from(&mut image, 512, *Uuid::new_v4().as_bytes()).unwrap();
+            for (i, (start, end)) in [(2048, 4095), (6144, 10239)].into_iter().enumerate() {
+                gpt[i as u32 + 1] = GPTPartitionEntry {
+                    partition_type_guid: [1u8; 16],
+                    unique_partition_guid: [i as u8 + 1; 16],
+                    starting_lba: start,
+                    ending_lba: end,
+                    attribute_bits: 0,
+                    partition_name: "bovik".into(),
+                };
+            }
+            gpt.write_into(&mut image).unwrap();
+            let mut image = image.into_inner();
+            for (offset, sector_size) in
+                [(1024 * 1024, 4096u16), (3 * 1024 * 1024, root_sector_size)]
+            {
+                image[offset..offset + 4].copy_from_slice(b"XFSB");
+                image[offset + 102..offset + 104].copy_from_slice(&sector_size.to_be_bytes());
+            }
+            image
+        };
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image");
+        let check = |image: &[u8], sector_size: u32| {
+            write(&path, image).unwrap();
+            let mut source = FileLocation::new(path.to_str().unwrap())
+                .sources()
+                .unwrap()
+                .pop()
+                .unwrap();
+            let result = check_image_sector_size(&mut source, sector_size);
+            // the source can still be read from the beginning
+            let mut data = Vec::new();
+            source.reader.read_to_end(&mut data).unwrap();
+            assert!(data == image);
+            result
+        };
+
+        let image = make_image(4096);
+        check(&image, 512).unwrap();
+        check(&image, 4096).unwrap();
+        let image = make_image(512);
+        check(&image, 512).unwrap();
+        assert_eq!(
+            format!("{:#}", check(&image, 4096).unwrap_err()),
+            "source has sector size 512 but destination has sector size 4096: partition 2 has a xfs filesystem with 512-byte sectors, which can't be used on a disk with 4096-byte sectors"
+        );
+    }
+
+    #[test]
+    fn test_peek_first_mb() {
+        use flate2::write::GzEncoder;
+
+        let mut data = vec![0u8; 3 * 1024 * 1024];
+        for (i, el) in data.iter_mut().enumerate() {
+            *el = (i % 251) as u8;
+        }
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("image.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        write(&path, &compressed).unwrap();
+
+        let mut source = FileLocation::new(path.to_str().unwrap())
+            .sources()
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(peek_first_mb(&mut source).unwrap(), &data[..1024 * 1024]);
+        // the whole compressed stream is still available
+        let mut result = Vec::new();
+        source.reader.read_to_end(&mut result).unwrap();
+        assert!(result == compressed);
+    }
+
+    #[test]
+    fn test_verify_written_image() {
+        let len: usize = 3 * 1024 * 1024 + 1000;
+        let mb: usize = 1024 * 1024;
+
+        let mut data = vec![0u8; len];
+        for (i, el) in data.iter_mut().enumerate() {
+            *el = (i % 251) as u8;
+        }
+
+        // copy while recording digests
+        let mut source = BlockHashReader::new(Cursor::new(&data[mb..]), 4096);
+        let mut dest = tempfile::tempfile().unwrap();
+        image_copy_default(&data[0..mb], &mut source, &mut dest, Path::new("/z"), None).unwrap();
+        let digests = source.into_digests();
+        assert_eq!(digests.length(), (len - mb) as u64);
+        verify_written_image(&mut dest, mb as u64, &digests).unwrap();
+
+        // the first MiB isn't checked
+        dest.rewind().unwrap();
+        dest.write_all(&[0xff; 512]).unwrap();
+        verify_written_image(&mut dest, mb as u64, &digests).unwrap();
+
+        // corruption is reported at the start of its block
+        dest.seek(SeekFrom::Start(2 * mb as u64 + 5000)).unwrap();
+        dest.write_all(&[0xff; 512]).unwrap();
+        let err = verify_written_image(&mut dest, mb as u64, &digests).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "written image doesn't match source; first mismatch in 4096-byte block at offset {}",
+                2 * mb + 4096
+            )
+        );
+
+        // truncation
+        dest.set_len(len as u64 - 10).unwrap();
+        verify_written_image(&mut dest, mb as u64, &digests).unwrap_err();
+    }
+
     #[test]
     fn test_image_copy_default_first_mb() {
         let len: usize = 2 * 1024 * 1024;
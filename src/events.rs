@@ -0,0 +1,442 @@
+// Copyright 2024 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Machine-readable event stream for tools driving the installer.
+//!
+//! Each event is written as a single-line JSON object, followed by a
+//! newline.  The "event" field identifies the event type; the remaining
+//! fields depend on the type.  Consumers should ignore unknown event types
+//! and fields, so new ones can be added compatibly.
+
+use anyhow::{bail, Context, Result};
+use reqwest::Url;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cmdline::{EventOutput, FetchRetries};
+use crate::install::PostprocessStep;
+use crate::source::{http_post_json, new_http_client, new_http_client_with_ca};
+
+/// Timeout for each status report.
+const STATUS_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Minimum interval between progress reports to the status URL.
+const STATUS_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait for pending status reports after the final event.
+const STATUS_FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// The command is starting.  This is the first event.
+    Started { command: &'a str },
+    /// An image artifact was selected for download or install.
+    #[serde(rename_all = "kebab-case")]
+    SourceSelected {
+        filename: &'a str,
+        artifact_type: &'a str,
+        length: Option<u64>,
+        signed: bool,
+    },
+    /// The artifact's signature was successfully verified.
+    #[serde(rename_all = "kebab-case")]
+    SignatureVerified { artifact_type: &'a str },
+    /// The artifact's SHA-256 digest from stream metadata was verified.
+    /// The digest is "sha256" for the data as fetched or
+    /// "uncompressed-sha256" for the decompressed data.
+    #[serde(rename_all = "kebab-case")]
+    DigestVerified {
+        artifact_type: &'a str,
+        digest: &'a str,
+    },
+    /// Bytes read so far, the total if known, and the average rate in
+    /// bytes per second.
+    #[serde(rename_all = "kebab-case")]
+    Progress {
+        artifact_type: &'a str,
+        bytes: u64,
+        total: Option<u64>,
+        rate: u64,
+    },
+    /// A post-processing step is starting.
+    PostprocessStep { step: PostprocessStep },
+    /// The install failed and the partition table is being reset.
+    PartitionTableReset { device: &'a str },
+    /// The command completed successfully.  This is the last event.
+    Succeeded,
+    /// The command failed.  This is the last event.  The error chain
+    /// starts with the outermost context.
+    Failed { error: Vec<String> },
+}
+
+/// Writer for a single event output.
+struct EventWriter {
+    out: Box<dyn Write + Send>,
+    finished: bool,
+}
+
+impl EventWriter {
+    fn open(output: &EventOutput) -> Result<Self> {
+        let out: Box<dyn Write + Send> = match output {
+            EventOutput::Fd(fd) => {
+                // make sure the fd is open before taking ownership of it,
+                // and don't leak it to subprocesses
+                let flags = unsafe { libc::fcntl(*fd, libc::F_GETFD) };
+                if flags < 0 {
+                    bail!(
+                        "checking event output fd {}: {}",
+                        fd,
+                        io::Error::last_os_error()
+                    );
+                }
+                if unsafe { libc::fcntl(*fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+                    bail!(
+                        "setting close-on-exec on event output fd {}: {}",
+                        fd,
+                        io::Error::last_os_error()
+                    );
+                }
+                Box::new(unsafe { File::from_raw_fd(*fd) })
+            }
+            EventOutput::Socket(path) => Box::new(
+                UnixStream::connect(path)
+                    .with_context(|| format!("connecting to event socket {path}"))?,
+            ),
+        };
+        Ok(Self {
+            out,
+            finished: false,
+        })
+    }
+
+    fn emit(&mut self, event: &Event) -> Result<()> {
+        // nothing follows the final event
+        if self.finished {
+            return Ok(());
+        }
+        if matches!(event, Event::Succeeded | Event::Failed { .. }) {
+            self.finished = true;
+        }
+        let mut buf = serde_json::to_vec(event).context("serializing event")?;
+        buf.push(b'\n');
+        self.out.write_all(&buf).context("writing event")?;
+        self.out.flush().context("flushing event")
+    }
+}
+
+/// Reporter that POSTs each event to an HTTP endpoint.  Requests are sent
+/// from a background thread so a slow or unreachable endpoint can't stall
+/// the caller.
+struct StatusReporter {
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    done: mpsc::Receiver<()>,
+    last_progress: Option<Instant>,
+}
+
+impl StatusReporter {
+    fn new(url: &Url, ca: Option<&str>, retries: FetchRetries) -> Result<Self> {
+        let client = match ca {
+            Some(path) => new_http_client_with_ca(path)?,
+            None => new_http_client()?,
+        };
+        let url = url.clone();
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let (done_sender, done) = mpsc::channel();
+        thread::Builder::new()
+            .name("status-reporter".into())
+            .spawn(move || {
+                // only complain once, but keep trying
+                let mut warned = false;
+                for body in receiver {
+                    if let Err(e) =
+                        http_post_json(client.clone(), &url, retries, body, STATUS_REQUEST_TIMEOUT)
+                    {
+                        if !warned {
+                            eprintln!("Couldn't report status: {e:#}");
+                            warned = true;
+                        }
+                    }
+                }
+                let _ = done_sender.send(());
+            })
+            .context("starting status reporter thread")?;
+        Ok(Self {
+            sender: Some(sender),
+            done,
+            last_progress: None,
+        })
+    }
+
+    fn emit(&mut self, event: &Event) -> Result<()> {
+        // nothing follows the final event
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return Ok(()),
+        };
+        // rate-limit progress reports
+        if let Event::Progress { .. } = event {
+            if let Some(last) = self.last_progress {
+                if last.elapsed() < STATUS_PROGRESS_INTERVAL {
+                    return Ok(());
+                }
+            }
+            self.last_progress = Some(Instant::now());
+        }
+        let body = serde_json::to_vec(event).context("serializing event")?;
+        // if the thread has exited, there's nothing more we can do
+        let _ = sender.send(body);
+        if matches!(event, Event::Succeeded | Event::Failed { .. }) {
+            self.finish();
+        }
+        Ok(())
+    }
+
+    /// Give pending reports a bounded amount of time to be delivered.
+    fn finish(&mut self) {
+        if self.sender.take().is_some() && self.done.recv_timeout(STATUS_FINISH_TIMEOUT).is_err() {
+            eprintln!("Timed out reporting status");
+        }
+    }
+}
+
+struct Sinks {
+    writer: Option<EventWriter>,
+    reporter: Option<StatusReporter>,
+}
+
+static EVENTS: Mutex<Sinks> = Mutex::new(Sinks {
+    writer: None,
+    reporter: None,
+});
+
+/// Start sending events to the specified output.
+pub fn open_event_output(output: &EventOutput) -> Result<()> {
+    let writer = EventWriter::open(output)?;
+    EVENTS.lock().expect("event lock poisoned").writer = Some(writer);
+    Ok(())
+}
+
+/// Start POSTing events to the specified URL, optionally trusting
+/// additional certificate authorities from a PEM bundle.
+pub fn open_status_url(url: &Url, ca: Option<&str>, retries: FetchRetries) -> Result<()> {
+    let reporter = StatusReporter::new(url, ca, retries)?;
+    EVENTS.lock().expect("event lock poisoned").reporter = Some(reporter);
+    Ok(())
+}
+
+/// Send an event to the configured event output and status URL, if any.
+/// Failing to send an event isn't fatal; we report the error and stop
+/// sending events.
+pub fn emit(event: Event) {
+    let mut events = EVENTS.lock().expect("event lock poisoned");
+    if let Some(writer) = events.writer.as_mut() {
+        if let Err(e) = writer.emit(&event) {
+            eprintln!("Couldn't send event; disabling event output: {e:#}");
+            events.writer = None;
+        }
+    }
+    if let Some(reporter) = events.reporter.as_mut() {
+        if let Err(e) = reporter.emit(&event) {
+            eprintln!("Couldn't report status; disabling status reports: {e:#}");
+            events.reporter = None;
+        }
+    }
+}
+
+/// Send the final event for the specified command result.
+pub fn emit_result<T>(result: &Result<T>) {
+    match result {
+        Ok(_) => emit(Event::Succeeded),
+        Err(err) => emit_failure(err),
+    }
+}
+
+/// Send a failure event with the chain of the specified error.
+pub fn emit_failure(err: &anyhow::Error) {
+    emit(Event::Failed {
+        error: err.chain().map(|e| e.to_string()).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_event_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut writer =
+            EventWriter::open(&EventOutput::Socket(path.to_str().unwrap().into())).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        writer
+            .emit(&Event::SourceSelected {
+                filename: "image.raw.xz",
+                artifact_type: "disk",
+                length: Some(100),
+                signed: true,
+            })
+            .unwrap();
+        writer
+            .emit(&Event::Progress {
+                artifact_type: "disk",
+                bytes: 50,
+                total: None,
+                rate: 25,
+            })
+            .unwrap();
+        writer
+            .emit(&Event::PostprocessStep {
+                step: PostprocessStep::FirstbootKargs,
+            })
+            .unwrap();
+        writer
+            .emit(&Event::Failed {
+                error: vec!["install failed".into(), "no space".into()],
+            })
+            .unwrap();
+        // dropped after the final event
+        writer.emit(&Event::Succeeded).unwrap();
+        drop(writer);
+
+        let lines: Vec<String> = BufReader::new(stream)
+            .lines()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"event":"source-selected","filename":"image.raw.xz","artifact-type":"disk","length":100,"signed":true}"#,
+                r#"{"event":"progress","artifact-type":"disk","bytes":50,"total":null,"rate":25}"#,
+                r#"{"event":"postprocess-step","step":"firstboot-kargs"}"#,
+                r#"{"event":"failed","error":["install failed","no space"]}"#,
+            ]
+        );
+
+        // nonexistent socket
+        assert!(EventWriter::open(&EventOutput::Socket(
+            dir.path().join("missing").to_str().unwrap().into(),
+        ))
+        .is_err());
+    }
+
+    /// Accept the specified number of HTTP requests, replying with the
+    /// specified status code.  Returns the URL and the request bodies.
+    fn serve_status(status: u16, count: usize) -> (Url, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/status", listener.local_addr().unwrap())).unwrap();
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for _ in 0..count {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(": ") {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+            bodies
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_status_reporter() {
+        let progress = Event::Progress {
+            artifact_type: "disk",
+            bytes: 50,
+            total: Some(100),
+            rate: 25,
+        };
+
+        // events are delivered in order, with progress rate-limited
+        let (url, handle) = serve_status(200, 3);
+        let mut reporter = StatusReporter::new(&url, None, FetchRetries::None).unwrap();
+        reporter
+            .emit(&Event::Started { command: "install" })
+            .unwrap();
+        reporter.emit(&progress).unwrap();
+        reporter.emit(&progress).unwrap();
+        reporter
+            .emit(&Event::Failed {
+                error: vec!["install failed".into()],
+            })
+            .unwrap();
+        reporter.emit(&Event::Succeeded).unwrap();
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                r#"{"event":"started","command":"install"}"#,
+                r#"{"event":"progress","artifact-type":"disk","bytes":50,"total":100,"rate":25}"#,
+                r#"{"event":"failed","error":["install failed"]}"#,
+            ]
+        );
+
+        // server errors aren't fatal
+        let (url, handle) = serve_status(500, 2);
+        let mut reporter = StatusReporter::new(&url, None, FetchRetries::None).unwrap();
+        reporter
+            .emit(&Event::Started { command: "install" })
+            .unwrap();
+        reporter.emit(&Event::Succeeded).unwrap();
+        assert_eq!(handle.join().unwrap().len(), 2);
+
+        // neither is an unreachable endpoint
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/status", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+        let start = Instant::now();
+        let mut reporter = StatusReporter::new(&url, None, FetchRetries::None).unwrap();
+        reporter
+            .emit(&Event::Started { command: "install" })
+            .unwrap();
+        reporter.emit(&Event::Succeeded).unwrap();
+        assert!(start.elapsed() < STATUS_FINISH_TIMEOUT);
+
+        // bad CA bundle
+        let ca = tempfile::NamedTempFile::new().unwrap();
+        assert!(
+            StatusReporter::new(&url, Some(ca.path().to_str().unwrap()), FetchRetries::None)
+                .is_err()
+        );
+    }
+}
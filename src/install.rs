@@ -15,30 +15,81 @@
 use anyhow::{bail, Context, Result};
 use nix::mount;
 use regex::{Captures, Regex};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions, Permissions};
-use std::io::{self, BufReader, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
 use std::num::NonZeroU32;
 use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use uuid::Uuid;
 
 use crate::blockdev::*;
+use crate::cache::Cache;
 use crate::cmdline::*;
 use crate::download::*;
+use crate::events::*;
 use crate::io::*;
 #[cfg(target_arch = "s390x")]
 use crate::s390x;
 use crate::source::*;
+use crate::util::set_die_on_sigpipe;
 
 // Match the grub.cfg console settings commands in
 // https://github.com/coreos/coreos-assembler/blob/main/src/grub.cfg
 const GRUB_CFG_CONSOLE_SETTINGS_RE: &str = r"(?P<prefix>\n# CONSOLE-SETTINGS-START\n)(?P<commands>([^\n]*\n)*)(?P<suffix># CONSOLE-SETTINGS-END\n)";
 
+// Granularity of --verify-write digests, and so of the mismatch offset it
+// reports.  32 bytes of digest per block, so 8 MiB per GiB of image.
+const VERIFY_BLOCK_SIZE: usize = 4096;
+
 pub fn install(config: InstallConfig) -> Result<()> {
     // evaluate config files
     let config = config.expand_config_files()?;
 
+    // start reporting events, if requested
+    if let Some(output) = &config.event_output {
+        open_event_output(output)?;
+    }
+    if let Some(url) = &config.status_url {
+        // never fail the install because we can't report status
+        if let Err(e) = open_status_url(url, config.status_ca.as_deref(), config.fetch_retries) {
+            eprintln!("Couldn't set up status reporting: {e:#}");
+        }
+    }
+    emit(Event::Started { command: "install" });
+    let result = run_install(config);
+    emit_result(&result);
+    result
+}
+
+fn run_install(mut config: InstallConfig) -> Result<()> {
+    // select destination device by criteria, if requested
+    if let Some(device) = select_dest_device(&config)? {
+        eprintln!("Selected destination device {device}");
+        config.dest_device = Some(device);
+    }
+
+    // refuse to install to one path of a multipath device, and add the
+    // kargs needed to boot from a multipath map
+    if let Some(device) = config.dest_device.as_deref() {
+        let disk = Disk::new(device)?;
+        if let Some(map) = disk
+            .get_multipath_map()
+            .with_context(|| format!("checking whether {device} is a multipath path"))?
+        {
+            bail!("{device} is a path of multipath device {map}; install to {map} instead");
+        }
+        if disk.is_multipath()? {
+            let kargs = get_multipath_kargs(&config);
+            if !kargs.is_empty() {
+                eprintln!("Adding multipath kernel arguments: {}", kargs.join(" "));
+                config.append_karg.extend(kargs);
+            }
+        }
+    }
+
     // make sure we have a device path
     let device = config
         .dest_device
@@ -91,23 +142,29 @@ pub fn install(config: InstallConfig) -> Result<()> {
     };
 
     // parse partition saving filters
+    fn strs(v: &[String]) -> Vec<&str> {
+        v.iter().map(|s| s.as_str()).collect()
+    }
     let save_partitions = parse_partition_filters(
-        &config
-            .save_partlabel
-            .iter()
-            .map(|s| s.as_str())
-            .collect::<Vec<&str>>(),
-        &config
-            .save_partindex
-            .iter()
-            .map(|s| s.as_str())
-            .collect::<Vec<&str>>(),
+        &strs(&config.save_partlabel),
+        &strs(&config.save_partindex),
+        &strs(&config.save_parttype),
+        &strs(&config.save_partuuid),
+        &strs(&config.save_partsize),
     )?;
 
+    // check whether we're writing a disk image file rather than a disk
+    let dest_is_file = Disk::new(device)?.is_image_file()?;
+    #[cfg(target_arch = "s390x")]
+    if dest_is_file {
+        bail!("installing to a disk image file is not supported on s390x");
+    }
+
     // compute sector size
     // Uninitialized ECKD DASD's blocksize is 512, but after formatting
     // it changes to the recommended 4096
     // https://bugzilla.redhat.com/show_bug.cgi?id=1905159
+    // Disk image files use 512-byte sectors.
     #[allow(clippy::match_bool, clippy::match_single_binding)]
     let sector_size = match is_dasd(device, None)
         .with_context(|| format!("checking whether {device} is an IBM DASD disk"))?
@@ -116,10 +173,32 @@ pub fn install(config: InstallConfig) -> Result<()> {
         true => s390x::dasd_try_get_sector_size(device).transpose(),
         _ => None,
     };
-    let sector_size = sector_size
-        .unwrap_or_else(|| get_sector_size_for_path(Path::new(device)))
-        .with_context(|| format!("getting sector size of {device}"))?
-        .get();
+    let sector_size = if dest_is_file {
+        512
+    } else {
+        sector_size
+            .unwrap_or_else(|| get_sector_size_for_path(Path::new(device)))
+            .with_context(|| format!("getting sector size of {device}"))?
+            .get()
+    };
+
+    // look for an existing install before modifying the destination
+    let existing_install = if dest_is_file {
+        if config.existing_install.is_some() {
+            bail!("--existing-install is not supported when installing to a disk image file");
+        }
+        None
+    } else if config.dry_run && config.existing_install.is_none() {
+        // only inspect the destination if asked to
+        None
+    } else {
+        check_existing_install(
+            device,
+            config
+                .existing_install
+                .unwrap_or(ExistingInstall::Overwrite),
+        )?
+    };
 
     // Set up DASD.  We need to do this before initiating the download
     // because otherwise the download might time out while we're low-level
@@ -133,16 +212,28 @@ pub fn install(config: InstallConfig) -> Result<()> {
                 // to save.
                 bail!("saving DASD partitions is not supported");
             }
-            s390x::prepare_dasd(device)?;
+            if config.verify_write {
+                // image_copy_s390x doesn't write the image contiguously
+                bail!("verifying the written image is not supported on DASD");
+            }
+            if !config.dry_run {
+                s390x::prepare_dasd(device)?;
+            }
         }
     }
 
     // set up image source
+    let cache = config.cache_dir.as_deref().map(Cache::new).transpose()?;
     // create location
     let location: Box<dyn ImageLocation> = if let Some(image_file) = &config.image_file {
         Box::new(FileLocation::new(image_file))
     } else if let Some(image_url) = &config.image_url {
-        Box::new(UrlLocation::new(image_url, config.fetch_retries))
+        Box::new(UrlLocation::new(
+            image_url,
+            config.fetch_retries,
+            config.fetch_segments,
+            cache,
+        ))
     } else if config.offline {
         match OsmetLocation::new(config.architecture.as_str(), sector_size)? {
             Some(osmet) => Box::new(osmet),
@@ -179,6 +270,8 @@ pub fn install(config: InstallConfig) -> Result<()> {
                 format,
                 config.stream_base_url.as_ref(),
                 config.fetch_retries,
+                config.fetch_segments,
+                cache,
             )?)
         }
     };
@@ -190,6 +283,12 @@ pub fn install(config: InstallConfig) -> Result<()> {
     if !sources.is_empty() {
         bail!("found multiple artifacts");
     }
+    emit(Event::SourceSelected {
+        filename: &source.filename,
+        artifact_type: &source.artifact_type,
+        length: source.length_hint,
+        signed: source.signature.is_some(),
+    });
     if source.signature.is_none() && location.require_signature() {
         if config.insecure {
             eprintln!("Signature not found; skipping verification as requested");
@@ -198,25 +297,43 @@ pub fn install(config: InstallConfig) -> Result<()> {
         }
     }
 
-    // open output; ensure it's a block device and we have exclusive access
+    // load custom signing keys, if any
+    let keys = VerifyKeys::new(&config.signing_key, config.signing_keys_only)?;
+
+    // make sure the image can be translated to the destination's sector
+    // size, if necessary, before we modify the destination
+    check_image_sector_size(&mut source, sector_size)?;
+
+    // if we're only planning, report and stop before opening the device
+    // for writing
+    if config.dry_run {
+        return print_install_plan(
+            &config,
+            &mut source,
+            &save_partitions,
+            sector_size,
+            dest_is_file,
+            ignition.is_some(),
+            network_config,
+            existing_install,
+        );
+    }
+
+    // open output; ensure it's a block device or disk image file, and that
+    // we have exclusive access to a block device
     let mut dest = OpenOptions::new()
         .read(true)
         .write(true)
         .open(device)
         .with_context(|| format!("opening {device}"))?;
-    if !dest
-        .metadata()
-        .with_context(|| format!("getting metadata for {device}"))?
-        .file_type()
-        .is_block_device()
-    {
-        bail!("{} is not a block device", device);
+    if !dest_is_file {
+        check_block_device(&dest, device)?;
+        ensure_exclusive_access(device, config.teardown_holders)
+            .with_context(|| format!("checking for exclusive access to {device}"))?;
     }
-    ensure_exclusive_access(device)
-        .with_context(|| format!("checking for exclusive access to {device}"))?;
 
     // save partitions that we plan to keep
-    let saved = SavedPartitions::new_from_disk(&mut dest, &save_partitions)
+    let saved = save_partitions_from(&mut dest, dest_is_file, sector_size, &save_partitions)
         .with_context(|| format!("saving partitions from {device}"))?;
 
     // get reference to partition table
@@ -226,6 +343,15 @@ pub fn install(config: InstallConfig) -> Result<()> {
         .get_partition_table()
         .with_context(|| format!("getting partition table for {device}"))?;
 
+    // move saved partitions out of the image's way, if requested
+    // On failure the original partition table is still intact, so don't
+    // reset it.
+    let saved = if config.relocate_saved_partitions && saved.is_saved() {
+        relocate_saved_partitions(&config, &mut source, &mut dest, &mut *table, saved)?
+    } else {
+        saved
+    };
+
     // copy and postprocess disk image
     // On failure, clear and reread the partition table to prevent the disk
     // from accidentally being used.
@@ -238,6 +364,7 @@ pub fn install(config: InstallConfig) -> Result<()> {
         &saved,
         ignition,
         network_config,
+        keys,
     ) {
         // log the error so the details aren't dropped if we encounter
         // another error during cleanup
@@ -253,16 +380,25 @@ pub fn install(config: InstallConfig) -> Result<()> {
                 // failed.  Preserve the saved partitions by writing them to
                 // a file in /tmp and telling the user about it.  Hey, it's
                 // a debug flag.
-                stash_saved_partitions(&mut dest, &saved)?;
+                stash_saved_partitions(device, &saved)?;
             }
         } else {
             reset_partition_table(&config, &mut dest, &mut *table, &saved)?;
         }
 
-        // return a generic error so our exit status is right
+        // report the underlying error, then return a generic error so our
+        // exit status is right
+        emit_failure(&err);
         bail!("install failed");
     }
 
+    // A disk image file won't share a machine with the disks attached to
+    // this one, so there's no point checking for conflicting filesystems.
+    if dest_is_file {
+        eprintln!("Install complete.");
+        return Ok(());
+    }
+
     // Because grub picks /boot by label and the OS picks /boot, we can end up racing/flapping
     // between picking a /boot partition on startup. So check amount of filesystems labeled 'boot'
     // and warn user if it's not only one
@@ -294,7 +430,13 @@ wiping them with `wipefs -a`.\n"
     Ok(())
 }
 
-fn parse_partition_filters(labels: &[&str], indexes: &[&str]) -> Result<Vec<PartitionFilter>> {
+fn parse_partition_filters(
+    labels: &[&str],
+    indexes: &[&str],
+    types: &[&str],
+    guids: &[&str],
+    sizes: &[&str],
+) -> Result<Vec<PartitionFilter>> {
     use PartitionFilter::*;
     let mut filters: Vec<PartitionFilter> = Vec::new();
 
@@ -339,33 +481,742 @@ fn parse_partition_filters(labels: &[&str], indexes: &[&str]) -> Result<Vec<Part
             _ => filters.push(filter),
         };
     }
+
+    // partition type and unique GUIDs
+    for guid in types {
+        filters.push(Type(Uuid::parse_str(guid).with_context(|| {
+            format!("couldn't parse partition type GUID '{guid}'")
+        })?));
+    }
+    for guid in guids {
+        filters.push(Guid(Uuid::parse_str(guid).with_context(|| {
+            format!("couldn't parse partition GUID '{guid}'")
+        })?));
+    }
+
+    // partition size ranges
+    let parse_size = |s: &str| -> Result<Option<u64>> {
+        match s {
+            "" => Ok(None), // open end of range
+            _ => Ok(Some(
+                ByteSize::from_str(s)
+                    .with_context(|| format!("couldn't parse partition size '{s}'"))?
+                    .0,
+            )),
+        }
+    };
+    for range in sizes {
+        let parts: Vec<&str> = range.split('-').collect();
+        let filter = match parts.len() {
+            1 => Size(parse_size(parts[0])?, parse_size(parts[0])?),
+            2 => Size(parse_size(parts[0])?, parse_size(parts[1])?),
+            _ => bail!("couldn't parse partition size range '{}'", range),
+        };
+        match filter {
+            Size(None, None) => bail!(
+                "both ends of partition size range '{}' cannot be open",
+                range
+            ),
+            Size(Some(x), Some(y)) if x > y => bail!(
+                "start of partition size range '{}' cannot be greater than end",
+                range
+            ),
+            _ => filters.push(filter),
+        };
+    }
     Ok(filters)
 }
 
-fn ensure_exclusive_access(device: &str) -> Result<()> {
+/// If the config selects the destination by criteria rather than by path,
+/// find the matching disk.
+fn select_dest_device(config: &InstallConfig) -> Result<Option<String>> {
+    if !config.has_dest_selection() {
+        return Ok(None);
+    }
+    let disks = get_disks().context("listing disks")?;
+    Ok(Some(filter_disks(config, disks)?.path))
+}
+
+/// Kernel arguments needed to boot from a dm-multipath map.  Once
+/// rd.multipath is set, the OS finds /boot and the root filesystem via
+/// the dm-mpath-boot and dm-mpath-root labels rather than the underlying
+/// paths.
+const MULTIPATH_KARGS: [&str; 3] = [
+    "rd.multipath=default",
+    "root=/dev/disk/by-label/dm-mpath-root",
+    "rw",
+];
+
+/// Return the multipath kargs that the user hasn't already appended or
+/// explicitly deleted.
+fn get_multipath_kargs(config: &InstallConfig) -> Vec<String> {
+    let key = |karg: &str| karg.split('=').next().unwrap_or(karg).to_string();
+    MULTIPATH_KARGS
+        .iter()
+        .filter(|karg| {
+            !config
+                .append_karg
+                .iter()
+                .chain(&config.delete_karg)
+                .any(|k| key(k) == key(karg))
+        })
+        .map(|karg| karg.to_string())
+        .collect()
+}
+
+/// Pick the single disk matching the destination selection criteria.
+fn filter_disks(config: &InstallConfig, disks: Vec<DiskInfo>) -> Result<DiskInfo> {
+    let parse_glob = |glob: &Option<String>, what: &str| -> Result<Option<glob::Pattern>> {
+        glob.as_deref()
+            .map(|glob| {
+                glob::Pattern::new(glob)
+                    .with_context(|| format!("couldn't parse {what} glob '{glob}'"))
+            })
+            .transpose()
+    };
+    let model = parse_glob(&config.dest_model, "model")?;
+    let serial = parse_glob(&config.dest_serial, "serial")?;
+    let wwn = parse_glob(&config.dest_wwn, "WWN")?;
+    let by_path = parse_glob(&config.dest_by_path, "by-path")?;
+    let matches = |glob: &Option<glob::Pattern>, value: &Option<String>| match glob {
+        Some(glob) => value.as_deref().map(|v| glob.matches(v)).unwrap_or(false),
+        None => true,
+    };
+
+    let mut candidates: Vec<DiskInfo> = disks
+        .into_iter()
+        .filter(|d| {
+            !d.read_only
+                && !d.virtual_device
+                // select the multipath map instead
+                && d.multipath.is_none()
+                && d.size > 0
+                && config.dest_min_size.map(|s| d.size >= s.0).unwrap_or(true)
+                && config.dest_max_size.map(|s| d.size <= s.0).unwrap_or(true)
+                && config
+                    .dest_media
+                    .map(|m| d.rotational == (m == DiskMedia::Rotational))
+                    .unwrap_or(true)
+                && config
+                    .dest_transport
+                    .as_ref()
+                    .map(|t| d.transport.as_ref() == Some(t))
+                    .unwrap_or(true)
+                && matches(&model, &d.model)
+                && matches(&serial, &d.serial)
+                && matches(&wwn, &d.wwn)
+                && by_path
+                    .as_ref()
+                    .map(|glob| d.by_path.iter().any(|link| glob.matches(link)))
+                    .unwrap_or(true)
+        })
+        .collect();
+
+    match (candidates.len(), config.dest_select) {
+        (0, _) => bail!("no disks match the destination selection options"),
+        (1, _) => Ok(candidates.remove(0)),
+        (_, Some(DiskSelect::Largest)) => Ok(candidates
+            .into_iter()
+            .max_by_key(|d| d.size)
+            .expect("no candidates")),
+        (_, Some(DiskSelect::Smallest)) => Ok(candidates
+            .into_iter()
+            .min_by_key(|d| d.size)
+            .expect("no candidates")),
+        (_, None) => bail!(
+            "multiple disks match the destination selection options: {}",
+            candidates
+                .iter()
+                .map(|d| d.path.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Inventory entry for a whole disk, as reported by `list-disks`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct DiskReport {
+    path: String,
+    size: u64,
+    logical_sector_size: u32,
+    physical_sector_size: u32,
+    model: Option<String>,
+    serial: Option<String>,
+    wwn: Option<String>,
+    transport: Option<String>,
+    rotational: bool,
+    read_only: bool,
+    busy: bool,
+    holders: Vec<String>,
+    dasd: bool,
+    coreos_install: Option<CoreosInstall>,
+    partitions: Vec<PartitionReport>,
+}
+
+/// Inventory entry for a partition, as reported by `list-disks`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PartitionReport {
+    path: String,
+    size: Option<u64>,
+    fstype: Option<String>,
+    label: Option<String>,
+    mountpoint: Option<String>,
+    swap: bool,
+    holders: Vec<String>,
+}
+
+impl PartitionReport {
+    fn is_busy(&self) -> bool {
+        self.mountpoint.is_some() || self.swap || !self.holders.is_empty()
+    }
+}
+
+pub fn list_disks(config: ListDisksConfig) -> Result<()> {
+    let mut reports = Vec::new();
+    for info in get_disks().context("listing disks")? {
+        // skip empty removable-media drives, devices such as zram which
+        // aren't install targets, and paths of multipath maps, which are
+        // reported via the map
+        if info.size == 0 || info.virtual_device || info.multipath.is_some() {
+            continue;
+        }
+        reports.push(get_disk_report(info)?);
+    }
+
+    set_die_on_sigpipe()?;
+    if config.json {
+        let mut out = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut out, &reports)
+            .context("failed to serialize disk inventory")?;
+        out.write_all(b"\n").context("failed to write newline")?;
+    } else {
+        for line in format_disk_table(&reports) {
+            println!("{line}");
+        }
+    }
+    Ok(())
+}
+
+fn get_disk_report(info: DiskInfo) -> Result<DiskReport> {
+    let path = info.path;
+    let mut file = File::open(&path).with_context(|| format!("opening {path}"))?;
+    let logical_sector_size = get_sector_size(&file)
+        .with_context(|| format!("getting sector size of {path}"))?
+        .get();
+    let physical_sector_size = get_physical_sector_size(&file)
+        .with_context(|| format!("getting physical sector size of {path}"))?
+        .get();
+    let size = get_block_device_size(&file)
+        .with_context(|| format!("getting size of {path}"))?
+        .get();
+    let dasd = is_dasd(&path, Some(&mut file))?;
+
+    let disk = Disk::new(&path)?;
+    let holders = disk.get_holders()?;
+    let mut partitions = Vec::new();
+    for part in disk
+        .get_partitions()
+        .with_context(|| format!("getting partitions of {path}"))?
+    {
+        partitions.push(PartitionReport {
+            size: Partition::get_offsets(&part.path)
+                .ok()
+                .map(|(start, end)| end - start),
+            holders: part.get_holders().unwrap_or_default(),
+            path: part.path,
+            fstype: part.fstype,
+            label: part.label,
+            mountpoint: part.mountpoint,
+            swap: part.swap,
+        });
+    }
+    let coreos_install = disk.get_coreos_install().unwrap_or_else(|e| {
+        eprintln!("Couldn't check {path} for a CoreOS install: {e:#}");
+        None
+    });
+
+    Ok(DiskReport {
+        busy: !holders.is_empty() || partitions.iter().any(|p| p.is_busy()),
+        path,
+        size,
+        logical_sector_size,
+        physical_sector_size,
+        model: info.model,
+        serial: info.serial,
+        wwn: info.wwn,
+        transport: info.transport,
+        rotational: info.rotational,
+        read_only: info.read_only,
+        holders,
+        dasd,
+        coreos_install,
+        partitions,
+    })
+}
+
+/// Format the disk inventory as a table, with each disk followed by its
+/// partitions.
+fn format_disk_table(disks: &[DiskReport]) -> Vec<String> {
+    let format_bytes = |count: u64| {
+        byte_unit::Byte::from_bytes(count.into())
+            .get_appropriate_unit(true)
+            .format(1)
+    };
+    let opt = |value: &Option<String>| value.clone().unwrap_or_default();
+
+    let mut rows: Vec<[String; 10]> = vec![[
+        "Device",
+        "Size",
+        "Sectors",
+        "Media",
+        "Transport",
+        "Model",
+        "Serial",
+        "Filesystem",
+        "Label",
+        "Flags",
+    ]
+    .map(String::from)];
+    for disk in disks {
+        let flags = [
+            (disk.read_only, "read-only"),
+            (disk.busy, "busy"),
+            (disk.dasd, "dasd"),
+            (disk.coreos_install.is_some(), "coreos"),
+        ];
+        rows.push([
+            disk.path.clone(),
+            format_bytes(disk.size),
+            format!("{}/{}", disk.logical_sector_size, disk.physical_sector_size),
+            if disk.rotational { "hdd" } else { "ssd" }.into(),
+            opt(&disk.transport),
+            opt(&disk.model),
+            opt(&disk.serial),
+            String::new(),
+            String::new(),
+            join_flags(&flags),
+        ]);
+        for part in &disk.partitions {
+            let flags = [
+                (part.mountpoint.is_some(), "mounted"),
+                (part.swap, "swap"),
+                (!part.holders.is_empty(), "held"),
+            ];
+            rows.push([
+                format!("  {}", part.path),
+                part.size.map(format_bytes).unwrap_or_default(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                opt(&part.fstype),
+                opt(&part.label),
+                join_flags(&flags),
+            ]);
+        }
+    }
+
+    // calculate field widths
+    let mut widths = [0; 10];
+    for row in &rows {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(widths)
+                .map(|(field, width)| format!("{field:width$}"))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+fn join_flags(flags: &[(bool, &str)]) -> String {
+    flags
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Look for an existing install on the destination and apply the
+/// --existing-install policy.  Return the install that will be
+/// overwritten, if any.
+fn check_existing_install(device: &str, action: ExistingInstall) -> Result<Option<CoreosInstall>> {
+    let disk = Disk::new(device)?;
+    if action == ExistingInstall::RequireBlank {
+        let partitions = disk
+            .get_partitions()
+            .with_context(|| format!("getting partitions of {device}"))?;
+        if !partitions.is_empty() {
+            bail!(
+                "{} is not blank; found existing partitions: {}",
+                device,
+                partitions
+                    .iter()
+                    .map(|p| p.path.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        return Ok(None);
+    }
+
+    let found = match disk.get_coreos_install() {
+        Ok(found) => found,
+        // don't break installs that don't care
+        Err(e) if action == ExistingInstall::Overwrite => {
+            eprintln!("Couldn't check {device} for an existing CoreOS install: {e:#}");
+            None
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("checking {device} for an existing install"))
+        }
+    };
+    if let Some(install) = &found {
+        let version = install.version.as_deref().unwrap_or("unknown version");
+        if action == ExistingInstall::Refuse {
+            bail!(
+                "{device} already contains a CoreOS install ({version}); refusing to overwrite it"
+            );
+        }
+        eprintln!("Found existing CoreOS install on {device} ({version})");
+    }
+    Ok(found)
+}
+
+fn check_block_device(dest: &File, device: &str) -> Result<()> {
+    if !dest
+        .metadata()
+        .with_context(|| format!("getting metadata for {device}"))?
+        .file_type()
+        .is_block_device()
+    {
+        bail!("{} is not a block device or regular file", device);
+    }
+    Ok(())
+}
+
+fn save_partitions_from(
+    dest: &mut File,
+    dest_is_file: bool,
+    sector_size: u32,
+    filters: &[PartitionFilter],
+) -> Result<SavedPartitions> {
+    if dest_is_file {
+        SavedPartitions::new_from_file(dest, sector_size as u64, filters)
+    } else {
+        SavedPartitions::new_from_disk(dest, filters)
+    }
+}
+
+fn ensure_exclusive_access(device: &str, teardown: bool) -> Result<()> {
     let mut parts = Disk::new(device)?.get_busy_partitions()?;
     if parts.is_empty() {
         return Ok(());
     }
     parts.sort_unstable_by_key(|p| p.path.to_string());
+    let holders = report_busy_partitions(device, &parts)?;
+    if !teardown {
+        bail!("found busy partitions");
+    }
+    check_teardown(device, &get_partition_paths(device)?, &holders)?;
+
+    eprintln!("Releasing partitions in use on {device}");
+    for (part, holders) in parts.iter().zip(&holders) {
+        for holder in holders {
+            holder.teardown()?;
+        }
+        let mountpoints = get_mountpoints(Path::new(&part.path))?;
+        release_device(Path::new(&part.path), &mountpoints, part.swap)
+            .with_context(|| format!("releasing {}", part.path))?;
+    }
+
+    let parts = Disk::new(device)?.get_busy_partitions()?;
+    if !parts.is_empty() {
+        report_busy_partitions(device, &parts)?;
+        bail!("partitions still busy after releasing them");
+    }
+    Ok(())
+}
+
+/// Print the busy partitions, the chain of devices holding each of them,
+/// and the processes using any of those devices.  Return the holders of
+/// each partition.
+fn report_busy_partitions(device: &str, parts: &[Partition]) -> Result<Vec<Vec<Holder>>> {
+    fn report_holder(holder: &Holder, disk_parts: &[String], depth: usize) -> Result<()> {
+        let indent = "    ".repeat(depth);
+        for mountpoint in &holder.mountpoints {
+            eprintln!("{indent}{} mounted on {}", holder.path, mountpoint);
+        }
+        if holder.swap {
+            eprintln!("{indent}{} is swap device", holder.path);
+        }
+        // e.g. an md array or LVM LV spanning multiple disks
+        let others = holder.other_devices(disk_parts);
+        if !others.is_empty() {
+            eprintln!("{indent}{} also uses {}", holder.path, others.join(", "));
+        }
+        for child in &holder.holders {
+            eprintln!("{indent}{} in use by {}", holder.path, child.describe());
+            report_holder(child, disk_parts, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    let disk_parts = get_partition_paths(device)?;
+    let mut all_holders = Vec::new();
+    let mut devices = vec![device.to_string()];
     eprintln!("Partitions in use on {device}:");
     for part in parts {
+        devices.push(part.path.clone());
         if let Some(mountpoint) = part.mountpoint.as_ref() {
             eprintln!("    {} mounted on {}", part.path, mountpoint);
         }
         if part.swap {
             eprintln!("    {} is swap device", part.path);
         }
-        for holder in part.get_holders()? {
-            eprintln!("    {} in use by {}", part.path, holder);
+        let holders = Holder::find_for_partition(part)
+            .with_context(|| format!("finding holders of {}", part.path))?;
+        for holder in &holders {
+            eprintln!("    {} in use by {}", part.path, holder.describe());
+            report_holder(holder, &disk_parts, 2)?;
+            devices.extend(holder.all_paths());
+        }
+        all_holders.push(holders);
+    }
+
+    let processes = get_processes_using(&devices)?;
+    if !processes.is_empty() {
+        eprintln!("Processes using {device}:");
+        for (pid, comm) in processes {
+            eprintln!("    {pid} ({comm})");
+        }
+    }
+    Ok(all_holders)
+}
+
+/// Paths of the partitions of the device.
+fn get_partition_paths(device: &str) -> Result<Vec<String>> {
+    Ok(Disk::new(device)?
+        .get_partitions()?
+        .into_iter()
+        .map(|p| p.path)
+        .collect())
+}
+
+/// Refuse to tear down holders that are also built on devices outside the
+/// destination, since releasing them would affect those devices too.
+fn check_teardown(device: &str, disk_parts: &[String], holders: &[Vec<Holder>]) -> Result<()> {
+    let spanning: Vec<String> = holders
+        .iter()
+        .flatten()
+        .flat_map(|holder| holder.find_spanning(disk_parts))
+        .map(|holder| holder.describe())
+        .collect();
+    if !spanning.is_empty() {
+        bail!(
+            "refusing to release devices that also use disks other than {}: {}",
+            device,
+            spanning.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Description of an install, printed as JSON by --dry-run.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct InstallPlan {
+    dest_device: String,
+    sector_size: u32,
+    source: SourcePlan,
+    saved_partitions: Vec<SavedPartitionPlan>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    relocations: Vec<PartitionMove>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    existing_install: Option<CoreosInstall>,
+    verify_write: bool,
+    discard: bool,
+    wipe_signatures: bool,
+    postprocess: Vec<PostprocessStep>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct SourcePlan {
+    filename: String,
+    artifact_type: String,
+    length_hint: Option<u64>,
+    signed: bool,
+    sector_size: Option<u32>,
+    image_size: u64,
+}
+
+/// Post-processing steps that write_disk() may perform after copying the
+/// image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostprocessStep {
+    Ignition,
+    Platform,
+    Console,
+    FirstbootKargs,
+    Kargs,
+    NetworkConfig,
+    Zipl,
+}
+
+fn emit_step(step: PostprocessStep) {
+    emit(Event::PostprocessStep { step });
+}
+
+/// Decide which post-processing steps write_disk() will perform.
+fn postprocess_steps(
+    config: &InstallConfig,
+    have_ignition: bool,
+    network_config: Option<&str>,
+) -> Vec<PostprocessStep> {
+    use PostprocessStep::*;
+    let mut steps = Vec::new();
+    if have_ignition {
+        steps.push(Ignition);
+    }
+    if config.platform.is_some() {
+        steps.push(Platform);
+    }
+    if config.platform.is_some() || !config.console.is_empty() {
+        steps.push(Console);
+    }
+    if config.firstboot_args.is_some() {
+        steps.push(FirstbootKargs);
+    }
+    if !config.append_karg.is_empty() || !config.delete_karg.is_empty() {
+        steps.push(Kargs);
+    }
+    if network_config.is_some() {
+        steps.push(NetworkConfig);
+    }
+    if cfg!(target_arch = "s390x") {
+        steps.push(Zipl);
+    }
+    steps
+}
+
+/// Read the destination's partition table and the start of the image,
+/// check them against each other, and print the resulting install plan.
+/// Don't open the destination for writing.
+#[allow(clippy::too_many_arguments)]
+fn print_install_plan(
+    config: &InstallConfig,
+    source: &mut ImageSource,
+    save_partitions: &[PartitionFilter],
+    sector_size: u32,
+    dest_is_file: bool,
+    have_ignition: bool,
+    network_config: Option<&str>,
+    existing_install: Option<CoreosInstall>,
+) -> Result<()> {
+    let device = config.dest_device.as_deref().expect("device missing");
+
+    // open output read-only; ensure it's a block device or disk image file
+    let mut dest = OpenOptions::new()
+        .read(true)
+        .open(device)
+        .with_context(|| format!("opening {device}"))?;
+    if !dest_is_file {
+        check_block_device(&dest, device)?;
+    }
+
+    // find partitions that we would save
+    let saved = save_partitions_from(&mut dest, dest_is_file, sector_size, save_partitions)
+        .with_context(|| format!("saving partitions from {device}"))?;
+
+    // read the first MiB of the image, as write_image() would
+    let peek_reader = PeekReader::with_capacity(BUFFER_SIZE, &mut source.reader);
+    let mut reader = DecompressReader::new(peek_reader)?;
+    let mut first_mb = vec![0u8; 1024 * 1024];
+    reader
+        .read_exact(&mut first_mb)
+        .context("decoding first MiB of image")?;
+    drop(reader);
+
+    // check image sector size against the destination, and translate the
+    // partition table as write_image() would
+    let image_sector_size = detect_formatted_sector_size(&first_mb).map(|s| s.get());
+    if let Some(actual) = image_sector_size {
+        if actual != sector_size {
+            SectorSizeTranslation::new(&first_mb, sector_size.into())
+                .with_context(|| {
+                    format!(
+                        "source has sector size {actual} but destination has sector size {sector_size}"
+                    )
+                })?
+                .translate_head(&mut first_mb)?;
+        }
+    }
+
+    // check that the image doesn't overlap any saved partitions, after
+    // moving them if requested
+    let mut image = io::Cursor::new(&first_mb);
+    let image_size = get_gpt_disk_size(&mut image).context("getting image size")?;
+    let relocations = if config.relocate_saved_partitions {
+        saved
+            .plan_relocation(image_size, get_dest_size(&dest, device)?)
+            .context("planning saved partition relocation")?
+    } else {
+        Vec::new()
+    };
+    let saved = saved.relocated(&relocations)?;
+    if let Some((offset, conflict)) = saved.get_offset()? {
+        if image_size > offset {
+            bail!("collision with {} at offset {}", conflict, offset);
         }
     }
-    bail!("found busy partitions");
+    let saved_partitions = saved
+        .plan_merge(&mut image)
+        .context("planning partition saving")?;
+
+    let plan = InstallPlan {
+        dest_device: device.to_string(),
+        sector_size,
+        source: SourcePlan {
+            filename: source.filename.clone(),
+            artifact_type: source.artifact_type.clone(),
+            length_hint: source.length_hint,
+            signed: source.signature.is_some(),
+            sector_size: image_sector_size,
+            image_size,
+        },
+        saved_partitions,
+        relocations,
+        existing_install,
+        verify_write: config.verify_write,
+        discard: config.discard,
+        wipe_signatures: config.wipe_signatures,
+        postprocess: postprocess_steps(config, have_ignition, network_config),
+    };
+    let mut out = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut out, &plan).context("failed to serialize install plan")?;
+    out.write_all(b"\n").context("failed to write newline")?;
+    Ok(())
 }
 
 /// Copy the image source to the target disk and do all post-processing.
 /// If this function fails, the caller should wipe the partition table
 /// to ensure the user doesn't boot from a partially-written disk.
+#[allow(clippy::too_many_arguments)]
 fn write_disk(
     config: &InstallConfig,
     source: &mut ImageSource,
@@ -374,11 +1225,32 @@ fn write_disk(
     saved: &SavedPartitions,
     ignition: Option<File>,
     network_config: Option<&str>,
+    keys: VerifyKeys,
 ) -> Result<()> {
     let device = config.dest_device.as_deref().expect("device missing");
 
-    // Get sector size of destination, for comparing with image
-    let sector_size = get_sector_size(dest)?;
+    // clear out old data, if requested
+    if config.wipe_signatures || config.discard {
+        clear_disk(config, dest, saved)?;
+    }
+
+    // Get sector size of destination, for comparing with image.  A disk
+    // image file uses 512-byte sectors, since that's what a loop device
+    // will give it.  If we're not saving any partitions in an image file,
+    // don't write an empty partition table, since the file may be too
+    // small to hold one.
+    let (sector_size, saved) = if dest
+        .metadata()
+        .with_context(|| format!("getting metadata for {device}"))?
+        .is_file()
+    {
+        (
+            NonZeroU32::new(512),
+            Some(saved).filter(|saved| saved.is_saved()),
+        )
+    } else {
+        (Some(get_sector_size(dest)?), Some(saved))
+    };
 
     // copy the image
     #[allow(clippy::match_bool, clippy::match_single_binding)]
@@ -387,37 +1259,60 @@ fn write_disk(
         true => s390x::image_copy_s390x,
         _ => image_copy_default,
     };
-    write_image(
-        source,
-        dest,
-        Path::new(device),
-        image_copy,
-        true,
-        Some(saved),
-        Some(sector_size),
-        VerifyKeys::Production,
-    )?;
+    if config.verify_write {
+        // record digests of everything after the first MiB, which
+        // image_copy writes contiguously starting at that offset
+        let mut digests = None;
+        write_image(
+            source,
+            dest,
+            Path::new(device),
+            |first_mb, source, dest, dest_path, saved| {
+                let mut source = BlockHashReader::new(source, VERIFY_BLOCK_SIZE);
+                image_copy(first_mb, &mut source, dest, dest_path, saved)?;
+                digests = Some(source.into_digests());
+                Ok(())
+            },
+            true,
+            saved,
+            sector_size,
+            keys,
+        )?;
+        verify_written_image(
+            dest,
+            1024 * 1024,
+            &digests.expect("image copy didn't record digests"),
+        )?;
+    } else {
+        write_image(
+            source,
+            dest,
+            Path::new(device),
+            image_copy,
+            true,
+            saved,
+            sector_size,
+            keys,
+        )?;
+    }
     table.reread()?;
 
     // postprocess
-    if ignition.is_some()
-        || config.firstboot_args.is_some()
-        || !config.append_karg.is_empty()
-        || !config.delete_karg.is_empty()
-        || config.platform.is_some()
-        || !config.console.is_empty()
-        || network_config.is_some()
-        || cfg!(target_arch = "s390x")
-    {
-        let mount = Disk::new(device)?.mount_partition_by_label("boot", mount::MsFlags::empty())?;
+    if !postprocess_steps(config, ignition.is_some(), network_config).is_empty() {
+        let mount = table
+            .disk()?
+            .mount_partition_by_label("boot", mount::MsFlags::empty())?;
         if let Some(ignition) = ignition.as_ref() {
+            emit_step(PostprocessStep::Ignition);
             write_ignition(mount.mountpoint(), &config.ignition_hash, ignition)
                 .context("writing Ignition configuration")?;
         }
         if let Some(platform) = config.platform.as_ref() {
+            emit_step(PostprocessStep::Platform);
             write_platform(mount.mountpoint(), platform).context("writing platform ID")?;
         }
         if config.platform.is_some() || !config.console.is_empty() {
+            emit_step(PostprocessStep::Console);
             write_console(
                 mount.mountpoint(),
                 config.platform.as_deref(),
@@ -426,10 +1321,12 @@ fn write_disk(
             .context("configuring console")?;
         }
         if let Some(firstboot_args) = config.firstboot_args.as_ref() {
+            emit_step(PostprocessStep::FirstbootKargs);
             write_firstboot_kargs(mount.mountpoint(), firstboot_args)
                 .context("writing firstboot kargs")?;
         }
         if !config.append_karg.is_empty() || !config.delete_karg.is_empty() {
+            emit_step(PostprocessStep::Kargs);
             eprintln!("Modifying kernel arguments");
 
             Console::maybe_warn_on_kargs(&config.append_karg, "--append-karg", "--console");
@@ -442,10 +1339,12 @@ fn write_disk(
             .context("deleting and appending kargs")?;
         }
         if let Some(network_config) = network_config.as_ref() {
+            emit_step(PostprocessStep::NetworkConfig);
             copy_network_config(mount.mountpoint(), network_config)?;
         }
         #[cfg(target_arch = "s390x")]
         {
+            emit_step(PostprocessStep::Zipl);
             s390x::zipl(
                 mount.mountpoint(),
                 None,
@@ -466,6 +1365,118 @@ fn write_disk(
     Ok(())
 }
 
+/// Wipe signatures from and/or discard the contents of the destination,
+/// leaving saved partitions untouched.
+fn clear_disk(config: &InstallConfig, dest: &mut File, saved: &SavedPartitions) -> Result<()> {
+    let device = config.dest_device.as_deref().expect("device missing");
+
+    if config.wipe_signatures {
+        Disk::new(device)?
+            .wipe_signatures(saved)
+            .with_context(|| format!("wiping signatures from {device}"))?;
+    }
+    if config.discard {
+        eprintln!("Discarding data on {device}");
+        let size = get_dest_size(dest, device)?;
+        discard_ranges(dest, &saved.get_unsaved_ranges(size)?)
+            .with_context(|| format!("discarding data on {device}"))?;
+    }
+
+    // The old partition table is gone.  Write out the saved partitions
+    // immediately, so they aren't lost if we crash before the image copy
+    // does so.
+    if saved.is_saved() {
+        saved
+            .overwrite(dest)
+            .context("restoring saved partitions")?;
+    }
+    dest.sync_all().context("syncing data to disk")?;
+    dest.rewind().with_context(|| format!("seeking {device}"))?;
+    Ok(())
+}
+
+/// Get the size of the destination block device or disk image file.
+fn get_dest_size(dest: &File, device: &str) -> Result<u64> {
+    let metadata = dest
+        .metadata()
+        .with_context(|| format!("getting metadata for {device}"))?;
+    if metadata.is_file() {
+        Ok(metadata.len())
+    } else {
+        Ok(get_block_device_size(dest)
+            .with_context(|| format!("getting size of {device}"))?
+            .get())
+    }
+}
+
+/// Move the data of saved partitions that overlap the install image to
+/// free space after it, then write a partition table pointing to the new
+/// locations.  The destinations never overlap a saved partition's current
+/// data, so until the partition table is written the disk is unchanged
+/// from the point of view of its original table, and an interrupted move
+/// needs no recovery.  Returns the relocated saved partitions.
+fn relocate_saved_partitions(
+    config: &InstallConfig,
+    source: &mut ImageSource,
+    dest: &mut File,
+    table: &mut dyn PartTable,
+    saved: SavedPartitions,
+) -> Result<SavedPartitions> {
+    let device = config.dest_device.as_deref().expect("device missing");
+
+    // find out how much space the image needs
+    let first_mb = peek_first_mb(source)?;
+    let image_size =
+        get_gpt_disk_size(&mut io::Cursor::new(&first_mb)).context("getting image size")?;
+    let moves = saved
+        .plan_relocation(image_size, get_dest_size(dest, device)?)
+        .context("planning saved partition relocation")?;
+    if moves.is_empty() {
+        return Ok(saved);
+    }
+
+    // copy data
+    let mut source_disk = File::open(device).with_context(|| format!("opening {device}"))?;
+    for m in &moves {
+        eprintln!(
+            "Moving partition {} (\"{}\") from offset {} to offset {}",
+            m.index, m.label, m.from, m.to
+        );
+        source_disk
+            .seek(SeekFrom::Start(m.from))
+            .with_context(|| format!("seeking {device} to offset {}", m.from))?;
+        dest.seek(SeekFrom::Start(m.to))
+            .with_context(|| format!("seeking {device} to offset {}", m.to))?;
+        let artifact_type = format!("partition {}", m.index);
+        let mut reader = ProgressReader::new(
+            BufReader::with_capacity(BUFFER_SIZE, (&mut source_disk).take(m.length)),
+            Some(m.length),
+            &artifact_type,
+        );
+        let copied = io::copy(&mut reader, dest)
+            .with_context(|| format!("copying partition {}", m.index))?;
+        drop(reader);
+        if copied != m.length {
+            bail!(
+                "copied {} bytes of partition {} but expected {}",
+                copied,
+                m.index,
+                m.length
+            );
+        }
+        dest.sync_all().context("syncing data to disk")?;
+    }
+
+    // switch the partition table to the new locations
+    let relocated = saved.relocated(&moves)?;
+    relocated
+        .overwrite(dest)
+        .context("writing relocated partition table")?;
+    dest.sync_all().context("syncing partition table to disk")?;
+    table.reread()?;
+    Ok(relocated)
+}
+
 /// Write the Ignition config.
 fn write_ignition(
     mountpoint: &Path,
@@ -715,6 +1726,7 @@ fn reset_partition_table(
 ) -> Result<()> {
     eprintln!("Resetting partition table");
     let device = config.dest_device.as_deref().expect("device missing");
+    emit(Event::PartitionTableReset { device });
 
     if is_dasd(device, Some(dest))? {
         // Don't write out a GPT, since the backup GPT may overwrite
@@ -740,27 +1752,141 @@ fn reset_partition_table(
 
 // Preserve saved partitions by writing them to a file in /tmp and reporting
 // the path.
-fn stash_saved_partitions(disk: &mut File, saved: &SavedPartitions) -> Result<()> {
+fn stash_saved_partitions(device: &str, saved: &SavedPartitions) -> Result<PathBuf> {
     let mut stash = tempfile::Builder::new()
         .prefix("coreos-installer-partitions.")
+        .suffix(".json")
         .tempfile()
         .context("creating partition stash file")?;
     let path = stash.path().to_owned();
     eprintln!("Storing saved partition entries to {}", path.display());
-    let len = disk.seek(SeekFrom::End(0)).context("seeking disk")?;
-    stash
-        .as_file()
-        .set_len(len)
-        .with_context(|| format!("extending partition stash file {}", path.display()))?;
     saved
-        .overwrite(stash.as_file_mut())
+        .write_stash(stash.as_file_mut())
         .with_context(|| format!("stashing saved partitions to {}", path.display()))?;
     stash
         .keep()
         .with_context(|| format!("retaining saved partition stash in {}", path.display()))?;
+    eprintln!(
+        "Restore them with: coreos-installer dev partitions restore {} {}",
+        path.display(),
+        device
+    );
+    Ok(path)
+}
+
+pub fn dev_partitions_stash(config: DevPartitionsStashConfig) -> Result<()> {
+    fn strs(v: &[String]) -> Vec<&str> {
+        v.iter().map(|s| s.as_str()).collect()
+    }
+    let filters = parse_partition_filters(
+        &strs(&config.save_partlabel),
+        &strs(&config.save_partindex),
+        &strs(&config.save_parttype),
+        &strs(&config.save_partuuid),
+        &strs(&config.save_partsize),
+    )?;
+    if filters.is_empty() {
+        bail!("no partitions selected; specify at least one --save-* option");
+    }
+
+    let device = config.device.as_str();
+    let is_file = Disk::new(device)?.is_image_file()?;
+    let mut disk = File::open(device).with_context(|| format!("opening {device}"))?;
+    if !is_file {
+        check_block_device(&disk, device)?;
+    }
+    // disk image files use 512-byte sectors, as in install
+    let saved = save_partitions_from(&mut disk, is_file, 512, &filters)
+        .with_context(|| format!("saving partitions from {device}"))?;
+    if !saved.is_saved() {
+        bail!("no partitions on {} matched", device);
+    }
+
+    match &config.output {
+        Some(path) => {
+            let mut out = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .with_context(|| format!("opening {path}"))?;
+            saved.write_stash(&mut out)?;
+            out.write_all(b"\n").context("writing newline")?;
+        }
+        None => {
+            let mut out = io::stdout().lock();
+            saved.write_stash(&mut out)?;
+            out.write_all(b"\n").context("writing newline")?;
+        }
+    }
     Ok(())
 }
 
+pub fn dev_partitions_restore(config: DevPartitionsRestoreConfig) -> Result<()> {
+    let saved = read_partition_stash(&config.stash)?;
+    if !saved.is_saved() {
+        eprintln!("No partitions in {}", config.stash);
+        return Ok(());
+    }
+
+    let device = config.device.as_str();
+    let is_file = Disk::new(device)?.is_image_file()?;
+    let mut dest = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .with_context(|| format!("opening {device}"))?;
+    if !is_file {
+        check_block_device(&dest, device)?;
+        ensure_exclusive_access(device, false)
+            .with_context(|| format!("checking for exclusive access to {device}"))?;
+    }
+
+    if config.overwrite {
+        eprintln!("Replacing partition table on {device}");
+        saved
+            .overwrite(&mut dest)
+            .context("writing stashed partitions")?;
+    } else {
+        let mut source = dest
+            .try_clone()
+            .with_context(|| format!("duplicating handle for {device}"))?;
+        saved
+            .merge(&mut source, &mut dest)
+            .with_context(|| format!("merging stashed partitions into {device}"))?;
+    }
+    dest.sync_all().context("syncing partition table to disk")?;
+    if !is_file {
+        Disk::new(device)?
+            .get_partition_table()
+            .with_context(|| format!("getting partition table for {device}"))?
+            .reread()?;
+    }
+    Ok(())
+}
+
+pub fn dev_partitions_show(config: DevPartitionsShowConfig) -> Result<()> {
+    let saved = read_partition_stash(&config.stash)?;
+    let sector_size = saved.get_sector_size();
+    println!("Sector size: {sector_size}");
+    for p in saved.get_stashed() {
+        println!("Partition {} (\"{}\"):", p.index, p.label);
+        println!("  Start: {}", p.start_lba * sector_size);
+        println!("  Size: {}", (p.end_lba - p.start_lba + 1) * sector_size);
+        println!("  Type GUID: {}", p.type_guid);
+        println!("  Partition GUID: {}", p.guid);
+        if p.attributes != 0 {
+            println!("  Attributes: {:#x}", p.attributes);
+        }
+    }
+    Ok(())
+}
+
+fn read_partition_stash(path: &str) -> Result<SavedPartitions> {
+    let mut stash = File::open(path).with_context(|| format!("opening {path}"))?;
+    SavedPartitions::read_stash(&mut BufReader::new(&mut stash))
+        .with_context(|| format!("reading partition stash {path}"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -773,8 +1899,14 @@ mod tests {
         let i = |v| Some(NonZeroU32::new(v).unwrap());
 
         assert_eq!(
-            parse_partition_filters(&["foo", "z*b?", ""], &["1", "7-7", "2-4", "-3", "4-"])
-                .unwrap(),
+            parse_partition_filters(
+                &["foo", "z*b?", ""],
+                &["1", "7-7", "2-4", "-3", "4-"],
+                &["0FC63DAF-8483-4772-8E79-3D69D8477DE4"],
+                &["a0b1c2d3-0000-4000-8000-123456789abc"],
+                &["100GiB-", "-1MB", "512-4KiB", "5"]
+            )
+            .unwrap(),
             vec![
                 g("foo"),
                 g("z*b?"),
@@ -783,14 +1915,20 @@ mod tests {
                 Index(i(7), i(7)),
                 Index(i(2), i(4)),
                 Index(None, i(3)),
-                Index(i(4), None)
+                Index(i(4), None),
+                Type(Uuid::parse_str("0fc63daf-8483-4772-8e79-3d69d8477de4").unwrap()),
+                Guid(Uuid::parse_str("a0b1c2d3-0000-4000-8000-123456789abc").unwrap()),
+                Size(Some(100 << 30), None),
+                Size(None, Some(1_000_000)),
+                Size(Some(512), Some(4096)),
+                Size(Some(5), Some(5)),
             ]
         );
 
         let bad_globs = vec![("***", "couldn't parse label glob '***'")];
         for (glob, err) in bad_globs {
             assert_eq!(
-                &parse_partition_filters(&["f", glob, "z*"], &["7-", "34"])
+                &parse_partition_filters(&["f", glob, "z*"], &["7-", "34"], &[], &[], &[])
                     .unwrap_err()
                     .to_string(),
                 err
@@ -813,12 +1951,532 @@ mod tests {
         ];
         for (range, err) in bad_ranges {
             assert_eq!(
-                &parse_partition_filters(&["f", "z*"], &["7-", range, "34"])
+                &parse_partition_filters(&["f", "z*"], &["7-", range, "34"], &[], &[], &[])
+                    .unwrap_err()
+                    .to_string(),
+                err
+            );
+        }
+
+        let bad_guids = vec![
+            (
+                &["x"][..],
+                &[][..],
+                "couldn't parse partition type GUID 'x'",
+            ),
+            (
+                &[],
+                &["0fc63daf"],
+                "couldn't parse partition GUID '0fc63daf'",
+            ),
+        ];
+        for (types, guids, err) in bad_guids {
+            assert_eq!(
+                &parse_partition_filters(&[], &[], types, guids, &[])
                     .unwrap_err()
                     .to_string(),
                 err
             );
         }
+
+        let bad_sizes = vec![
+            ("-", "both ends of partition size range '-' cannot be open"),
+            ("1G-2G-3G", "couldn't parse partition size range '1G-2G-3G'"),
+            ("12q", "couldn't parse partition size '12q'"),
+            (
+                "2GiB-1GiB",
+                "start of partition size range '2GiB-1GiB' cannot be greater than end",
+            ),
+        ];
+        for (range, err) in bad_sizes {
+            assert_eq!(
+                &parse_partition_filters(&[], &[], &[], &[], &["1G-", range])
+                    .unwrap_err()
+                    .to_string(),
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_filter_disks() {
+        let disk = |path: &str, size: u64, rotational: bool, transport: &str, model: &str| {
+            DiskInfo {
+                path: path.into(),
+                size,
+                read_only: false,
+                rotational,
+                transport: Some(transport.into()),
+                model: Some(model.into()),
+                serial: Some(format!("SN-{model}")),
+                wwn: None,
+                by_path: vec![format!("/dev/disk/by-path/{transport}-{path}")],
+                virtual_device: false,
+                multipath: None,
+            }
+        };
+        let disks = vec![
+            disk("/dev/sda", 4_000_000_000_000, true, "sata", "Big Spinner"),
+            disk("/dev/sdb", 32_000_000_000, false, "usb", "Thumb Drive"),
+            disk("/dev/nvme0n1", 500_000_000_000, false, "nvme", "Fast SSD"),
+            disk("/dev/nvme1n1", 1_000_000_000_000, false, "nvme", "Fast SSD"),
+            DiskInfo {
+                read_only: true,
+                ..disk("/dev/sr0", 1_000_000_000, false, "sata", "DVD")
+            },
+            // zram swap is never a candidate, even though it's smallest
+            DiskInfo {
+                transport: None,
+                virtual_device: true,
+                ..disk("/dev/zram0", 8_000_000_000, false, "", "")
+            },
+        ];
+        let select = |config: InstallConfig| {
+            filter_disks(&config, disks.clone())
+                .map(|d| d.path)
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(
+            select(InstallConfig {
+                dest_media: Some(DiskMedia::Rotational),
+                ..Default::default()
+            }),
+            Ok("/dev/sda".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_transport: Some("usb".into()),
+                ..Default::default()
+            }),
+            Ok("/dev/sdb".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_min_size: Some(ByteSize(100_000_000_000)),
+                dest_max_size: Some(ByteSize(600_000_000_000)),
+                ..Default::default()
+            }),
+            Ok("/dev/nvme0n1".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_model: Some("Fast*".into()),
+                ..Default::default()
+            }),
+            Err("multiple disks match the destination selection options: /dev/nvme0n1, /dev/nvme1n1".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_model: Some("Fast*".into()),
+                dest_select: Some(DiskSelect::Largest),
+                ..Default::default()
+            }),
+            Ok("/dev/nvme1n1".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_media: Some(DiskMedia::SolidState),
+                dest_select: Some(DiskSelect::Smallest),
+                ..Default::default()
+            }),
+            Ok("/dev/sdb".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_serial: Some("SN-Thumb*".into()),
+                dest_by_path: Some("/dev/disk/by-path/usb-*".into()),
+                ..Default::default()
+            }),
+            Ok("/dev/sdb".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_wwn: Some("*".into()),
+                ..Default::default()
+            }),
+            Err("no disks match the destination selection options".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_model: Some("DVD".into()),
+                ..Default::default()
+            }),
+            Err("no disks match the destination selection options".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_model: Some("[".into()),
+                ..Default::default()
+            }),
+            Err("couldn't parse model glob '['".into())
+        );
+
+        // the paths of a multipath map aren't candidates, but the map is
+        let lun = |path: &str, multipath: Option<&str>| DiskInfo {
+            wwn: Some("0x600508b400105e210000900000490000".into()),
+            multipath: multipath.map(String::from),
+            ..disk(path, 100_000_000_000, true, "fc", "SAN LUN")
+        };
+        let disks = vec![
+            disk("/dev/sda", 4_000_000_000_000, true, "sata", "Big Spinner"),
+            lun("/dev/sdb", Some("/dev/mapper/mpatha")),
+            lun("/dev/sdc", Some("/dev/mapper/mpatha")),
+            lun("/dev/mapper/mpatha", None),
+        ];
+        let select = |config: InstallConfig| {
+            filter_disks(&config, disks.clone())
+                .map(|d| d.path)
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            select(InstallConfig {
+                dest_wwn: Some("0x600508b4*".into()),
+                ..Default::default()
+            }),
+            Ok("/dev/mapper/mpatha".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_serial: Some("SN-SAN LUN".into()),
+                ..Default::default()
+            }),
+            Ok("/dev/mapper/mpatha".into())
+        );
+        assert_eq!(
+            select(InstallConfig {
+                dest_media: Some(DiskMedia::Rotational),
+                dest_select: Some(DiskSelect::Smallest),
+                ..Default::default()
+            }),
+            Ok("/dev/mapper/mpatha".into())
+        );
+    }
+
+    #[test]
+    fn test_format_disk_table() {
+        let disks = vec![
+            DiskReport {
+                path: "/dev/sda".into(),
+                size: 256 << 30,
+                logical_sector_size: 512,
+                physical_sector_size: 4096,
+                model: Some("Example SSD".into()),
+                serial: Some("S123".into()),
+                wwn: None,
+                transport: Some("sata".into()),
+                rotational: false,
+                read_only: false,
+                busy: true,
+                holders: Vec::new(),
+                dasd: false,
+                coreos_install: Some(CoreosInstall {
+                    boot_device: "/dev/sda3".into(),
+                    version: Some("Fedora CoreOS 39.20240128.3.0".into()),
+                }),
+                partitions: vec![
+                    PartitionReport {
+                        path: "/dev/sda3".into(),
+                        size: Some(384 << 20),
+                        fstype: Some("ext4".into()),
+                        label: Some("boot".into()),
+                        mountpoint: Some("/boot".into()),
+                        swap: false,
+                        holders: Vec::new(),
+                    },
+                    PartitionReport {
+                        path: "/dev/sda4".into(),
+                        size: None,
+                        fstype: None,
+                        label: None,
+                        mountpoint: None,
+                        swap: false,
+                        holders: vec!["/dev/dm-0".into()],
+                    },
+                ],
+            },
+            DiskReport {
+                path: "/dev/vdb".into(),
+                size: 10 << 30,
+                logical_sector_size: 4096,
+                physical_sector_size: 4096,
+                model: None,
+                serial: None,
+                wwn: None,
+                transport: None,
+                rotational: true,
+                read_only: true,
+                busy: false,
+                holders: Vec::new(),
+                dasd: true,
+                coreos_install: None,
+                partitions: Vec::new(),
+            },
+        ];
+        assert_eq!(
+            format_disk_table(&disks),
+            [
+                "Device       Size       Sectors    Media  Transport  Model        Serial  Filesystem  Label  Flags",
+                "/dev/sda     256.0 GiB  512/4096   ssd    sata       Example SSD  S123                       busy,coreos",
+                "  /dev/sda3  384.0 MiB                                                    ext4        boot   mounted",
+                "  /dev/sda4                                                                                  held",
+                "/dev/vdb     10.0 GiB   4096/4096  hdd                                                       read-only,dasd",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_multipath_kargs() {
+        let config = InstallConfig::default();
+        assert_eq!(get_multipath_kargs(&config), MULTIPATH_KARGS);
+
+        let config = InstallConfig {
+            append_karg: vec!["root=/dev/disk/by-label/custom".into(), "quiet".into()],
+            delete_karg: vec!["rw".into()],
+            ..Default::default()
+        };
+        assert_eq!(get_multipath_kargs(&config), ["rd.multipath=default"]);
+    }
+
+    #[test]
+    fn test_check_teardown() {
+        let holder = |path: &str, kind, name: Option<&str>, deps: &[&str], holders| Holder {
+            path: path.into(),
+            kind,
+            name: name.map(String::from),
+            mountpoints: Vec::new(),
+            swap: false,
+            deps: deps.iter().map(|dep| dep.to_string()).collect(),
+            holders,
+        };
+        let disk_parts = vec!["/dev/vda1".to_string(), "/dev/vda2".to_string()];
+
+        // LV inside a LUKS volume on the destination, plus an md array
+        // across two destination partitions
+        let local = vec![
+            vec![holder(
+                "/dev/dm-0",
+                HolderKind::Crypt,
+                Some("luks-root"),
+                &["/dev/vda1"],
+                vec![holder(
+                    "/dev/dm-1",
+                    HolderKind::Lvm,
+                    Some("vg-root"),
+                    &["/dev/vda1", "/dev/dm-0"],
+                    Vec::new(),
+                )],
+            )],
+            vec![holder(
+                "/dev/md127",
+                HolderKind::Md,
+                None,
+                &["/dev/vda1", "/dev/vda2"],
+                Vec::new(),
+            )],
+        ];
+        check_teardown("/dev/vda", &disk_parts, &local).unwrap();
+
+        // LV spanning a PV on another disk
+        let spanning = vec![vec![holder(
+            "/dev/dm-0",
+            HolderKind::Crypt,
+            Some("luks-data"),
+            &["/dev/vda2"],
+            vec![holder(
+                "/dev/dm-2",
+                HolderKind::Lvm,
+                Some("vg-data"),
+                &["/dev/vda2", "/dev/dm-0", "/dev/vdb1"],
+                Vec::new(),
+            )],
+        )]];
+        assert_eq!(
+            holder(
+                "/dev/dm-2",
+                HolderKind::Lvm,
+                None,
+                &["/dev/vda2", "/dev/dm-0", "/dev/vdb1"],
+                Vec::new()
+            )
+            .other_devices(&disk_parts),
+            ["/dev/vdb1"]
+        );
+        assert_eq!(
+            check_teardown("/dev/vda", &disk_parts, &spanning)
+                .unwrap_err()
+                .to_string(),
+            "refusing to release devices that also use disks other than /dev/vda: /dev/dm-2 (LVM volume vg-data)"
+        );
+    }
+
+    #[test]
+    fn test_postprocess_steps() {
+        use PostprocessStep::*;
+
+        let zipl = |mut steps: Vec<PostprocessStep>| {
+            if cfg!(target_arch = "s390x") {
+                steps.push(Zipl);
+            }
+            steps
+        };
+
+        let config = InstallConfig::default();
+        assert_eq!(postprocess_steps(&config, false, None), zipl(vec![]));
+        assert_eq!(
+            postprocess_steps(&config, true, Some("/etc")),
+            zipl(vec![Ignition, NetworkConfig])
+        );
+
+        let config = InstallConfig {
+            platform: Some("metal".into()),
+            delete_karg: vec!["foo".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            postprocess_steps(&config, false, None),
+            zipl(vec![Platform, Console, Kargs])
+        );
+
+        let config = InstallConfig {
+            console: vec!["ttyS0".parse().unwrap()],
+            firstboot_args: Some("ip=dhcp".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            postprocess_steps(&config, false, None),
+            zipl(vec![Console, FirstbootKargs])
+        );
+    }
+
+    // s390x always postprocesses, which needs root
+    #[cfg(not(target_arch = "s390x"))]
+    #[test]
+    fn test_write_disk_image_file() {
+        use gptman::{GPTPartitionEntry, GPT};
+
+        let part = |label: &str, start: u64, end: u64| GPTPartitionEntry {
+            partition_type_guid: [1u8; 16],
+            unique_partition_guid: *Uuid::new_v4().as_bytes(),
+            starting_lba: start,
+            ending_lba: end,
+            attribute_bits: 0,
+            partition_name: label.into(),
+        };
+        let make_gpt = |file: &mut File, parts: &[(u32, GPTPartitionEntry)]| {
+            GPT::write_protective_mbr_into(file, 512).unwrap();
+            let mut gpt = GPT::new_from(file, 512, *Uuid::new_v4().as_bytes()).unwrap();
+            for (partnum, entry) in parts {
+                gpt[*partnum] = entry.clone();
+            }
+            gpt.write_into(file).unwrap();
+        };
+        let fill = |file: &mut File, start: u64, len: usize, byte: u8| {
+            file.seek(SeekFrom::Start(start)).unwrap();
+            file.write_all(&vec![byte; len]).unwrap();
+        };
+        let dir = tempfile::TempDir::new().unwrap();
+
+        // 4 MiB image with a 1 MiB boot partition
+        let image_path = dir.path().join("image");
+        let mut image = File::create(&image_path).unwrap();
+        image.set_len(4 << 20).unwrap();
+        make_gpt(&mut image, &[(1, part("boot", 2048, 4095))]);
+        fill(&mut image, 1 << 20, 1 << 20, 0xb0);
+        drop(image);
+
+        // 16 MiB disk image with a partition to save and one to discard
+        let dest_path = dir.path().join("disk");
+        let mut dest = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&dest_path)
+            .unwrap();
+        dest.set_len(16 << 20).unwrap();
+        make_gpt(
+            &mut dest,
+            &[
+                (1, part("junk", 2048, 4095)),
+                (5, part("data", 20480, 24575)),
+            ],
+        );
+        fill(&mut dest, 1 << 20, 1 << 20, 0xee);
+        fill(&mut dest, 10 << 20, 2 << 20, 0xda);
+
+        // no filters, nothing saved
+        assert!(!save_partitions_from(&mut dest, true, 512, &[])
+            .unwrap()
+            .is_saved());
+        let saved = save_partitions_from(
+            &mut dest,
+            true,
+            512,
+            &[PartitionFilter::Label(glob::Pattern::new("data").unwrap())],
+        )
+        .unwrap();
+        assert!(saved.is_saved());
+
+        // write the image without any postprocessing, which doesn't need
+        // a loop device
+        let dest_device = dest_path.to_str().unwrap();
+        let config = InstallConfig {
+            dest_device: Some(dest_device.into()),
+            ..Default::default()
+        };
+        let mut source = FileLocation::new(image_path.to_str().unwrap())
+            .sources()
+            .unwrap()
+            .pop()
+            .unwrap();
+        let mut table = Disk::new(dest_device)
+            .unwrap()
+            .get_partition_table()
+            .unwrap();
+        write_disk(
+            &config,
+            &mut source,
+            &mut dest,
+            &mut *table,
+            &saved,
+            None,
+            None,
+            VerifyKeys::InsecureTest,
+        )
+        .unwrap();
+
+        // the image's partition replaced the discarded one, and the saved
+        // partition and its contents are intact
+        assert_eq!(dest.metadata().unwrap().len(), 16 << 20);
+        let gpt = GPT::find_from(&mut dest).unwrap();
+        let parts: Vec<(u32, String, u64, u64)> = gpt
+            .iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(i, p)| {
+                (
+                    i,
+                    p.partition_name.as_str().to_string(),
+                    p.starting_lba,
+                    p.ending_lba,
+                )
+            })
+            .collect();
+        assert_eq!(
+            parts,
+            vec![
+                (1, "boot".into(), 2048, 4095),
+                (5, "data".into(), 20480, 24575),
+            ]
+        );
+        let read = |file: &mut File, start: u64, len: usize| {
+            let mut buf = vec![0u8; len];
+            file.seek(SeekFrom::Start(start)).unwrap();
+            file.read_exact(&mut buf).unwrap();
+            buf
+        };
+        assert!(read(&mut dest, 1 << 20, 1 << 20) == vec![0xb0; 1 << 20]);
+        assert!(read(&mut dest, 10 << 20, 2 << 20) == vec![0xda; 2 << 20]);
     }
 
     #[test]
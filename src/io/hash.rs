@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::{bail, ensure, Context, Error, Result};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
 use openssl::hash::{Hasher, MessageDigest};
 use openssl::sha;
 use serde::{Deserialize, Serialize};
@@ -179,6 +179,62 @@ impl Sha256Digest {
         }
         Ok(String::from_utf8(buf)?)
     }
+
+    /// Parses a hex-encoded SHA256 digest.
+    pub fn from_hex(hex_digest: &str) -> Result<Self> {
+        let digest = hex::decode(hex_digest).context("decoding hex digest")?;
+        let len = digest.len().saturating_mul(8);
+        Ok(Sha256Digest(
+            digest
+                .try_into()
+                .map_err(|_| anyhow!("wrong digest length ({})", len))?,
+        ))
+    }
+}
+
+/// Reader that computes the SHA256 of the data read through it and checks
+/// it against an expected digest, if one was provided.
+pub struct Sha256VerifyReader<R: Read> {
+    source: R,
+    state: Option<(sha::Sha256, Sha256Digest)>,
+}
+
+impl<R: Read> Sha256VerifyReader<R> {
+    pub fn new(source: R, expected: Option<Sha256Digest>) -> Self {
+        Self {
+            source,
+            state: expected.map(|digest| (sha::Sha256::new(), digest)),
+        }
+    }
+
+    /// Check the digest of the data read so far.  Return an error on
+    /// mismatch, false if there was nothing to check, or true if the
+    /// digest matched.  Only the first call performs the check.
+    pub fn verify(&mut self) -> Result<bool> {
+        let (hasher, expected) = match self.state.take() {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        let computed = hasher.finish();
+        if computed != expected.0 {
+            bail!(
+                "SHA-256 mismatch, computed '{}' but expected '{}'",
+                hex::encode(computed),
+                hex::encode(expected.0),
+            );
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Sha256VerifyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.source.read(buf)?;
+        if let Some((hasher, _)) = self.state.as_mut() {
+            hasher.update(&buf[..count]);
+        }
+        Ok(count)
+    }
 }
 
 pub struct WriteHasher<W: Write> {
@@ -224,6 +280,102 @@ impl<W: Write> TryFrom<WriteHasher<W>> for Sha256Digest {
     }
 }
 
+/// SHA-256 digests of consecutive fixed-size blocks of a stream, used to
+/// locate corruption when the stream is later read back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDigests {
+    block_size: usize,
+    digests: Vec<Sha256Digest>,
+    length: u64,
+}
+
+impl BlockDigests {
+    /// Total number of bytes covered by the digests.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Number of bytes covered by each digest.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Read back length() bytes and return the offset of the first block
+    /// that doesn't match its digest, if any.
+    pub fn first_mismatch(&self, reader: &mut impl Read) -> Result<Option<u64>> {
+        let mut buf = vec![0u8; self.block_size];
+        let mut offset = 0u64;
+        for digest in &self.digests {
+            let count = (self.length - offset).min(self.block_size as u64) as usize;
+            reader
+                .read_exact(&mut buf[..count])
+                .with_context(|| format!("reading data at offset {offset}"))?;
+            if sha::sha256(&buf[..count]) != digest.0 {
+                return Ok(Some(offset));
+            }
+            offset += count as u64;
+        }
+        Ok(None)
+    }
+}
+
+/// A reader that records BlockDigests of the data passing through it.
+pub struct BlockHashReader<R: Read> {
+    reader: R,
+    digests: BlockDigests,
+    hasher: sha::Sha256,
+    block_remaining: usize,
+}
+
+impl<R: Read> BlockHashReader<R> {
+    pub fn new(reader: R, block_size: usize) -> Self {
+        assert!(block_size > 0);
+        Self {
+            reader,
+            digests: BlockDigests {
+                block_size,
+                digests: Vec::new(),
+                length: 0,
+            },
+            hasher: sha::Sha256::new(),
+            block_remaining: block_size,
+        }
+    }
+
+    fn finish_block(&mut self) {
+        let hasher = std::mem::replace(&mut self.hasher, sha::Sha256::new());
+        self.digests.digests.push(Sha256Digest(hasher.finish()));
+        self.block_remaining = self.digests.block_size;
+    }
+
+    /// Return the digests of all data read so far, including any final
+    /// partial block.
+    pub fn into_digests(mut self) -> BlockDigests {
+        if self.block_remaining < self.digests.block_size {
+            self.finish_block();
+        }
+        self.digests
+    }
+}
+
+impl<R: Read> Read for BlockHashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.reader.read(buf)?;
+        let mut data = &buf[..count];
+        while !data.is_empty() {
+            let n = data.len().min(self.block_remaining);
+            self.hasher.update(&data[..n]);
+            self.block_remaining -= n;
+            if self.block_remaining == 0 {
+                self.finish_block();
+            }
+            data = &data[n..];
+        }
+        self.digests.length += count as u64;
+        Ok(count)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -239,6 +391,33 @@ mod tests {
         IgnitionHash::from_str(null_digest).unwrap();
     }
 
+    #[test]
+    fn test_sha256_verify_reader() {
+        let input = b"abc";
+        let good = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let bad = "aa7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        Sha256Digest::from_hex("ba7816").unwrap_err();
+        Sha256Digest::from_hex("zz").unwrap_err();
+
+        let mut reader =
+            Sha256VerifyReader::new(&input[..], Some(Sha256Digest::from_hex(good).unwrap()));
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert!(reader.verify().unwrap());
+        assert!(!reader.verify().unwrap());
+
+        let mut reader =
+            Sha256VerifyReader::new(&input[..], Some(Sha256Digest::from_hex(bad).unwrap()));
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(
+            reader.verify().unwrap_err().to_string(),
+            format!("SHA-256 mismatch, computed '{good}' but expected '{bad}'")
+        );
+
+        let mut reader = Sha256VerifyReader::new(&input[..], None);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert!(!reader.verify().unwrap());
+    }
+
     #[test]
     fn test_ignition_hash_validate() {
         let input = vec![b'a', b'b', b'c'];
@@ -254,4 +433,33 @@ mod tests {
             assert!(hasher.validate(&mut rd).is_ok() == *valid);
         }
     }
+
+    #[test]
+    fn test_block_digests() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        for block_size in [1, 100, 4096, 10000, 20000] {
+            let mut reader = BlockHashReader::new(&data[..], block_size);
+            let mut out = Vec::new();
+            io::copy(&mut reader, &mut out).unwrap();
+            assert_eq!(out, data);
+            let digests = reader.into_digests();
+            assert_eq!(digests.length(), 10000);
+            assert_eq!(
+                digests.first_mismatch(&mut &data[..]).unwrap(),
+                None,
+                "block size {block_size}"
+            );
+
+            let mut corrupt = data.clone();
+            corrupt[5000] ^= 1;
+            corrupt[9999] ^= 1;
+            assert_eq!(
+                digests.first_mismatch(&mut &corrupt[..]).unwrap(),
+                Some(5000 / block_size as u64 * block_size as u64),
+                "block size {block_size}"
+            );
+
+            digests.first_mismatch(&mut &data[..9000]).unwrap_err();
+        }
+    }
 }
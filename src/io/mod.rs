@@ -22,6 +22,7 @@ mod hash;
 mod ignition;
 mod initrd;
 mod limit;
+mod openpgp;
 mod peek;
 mod tee;
 mod verify;
@@ -34,6 +35,7 @@ pub use self::hash::*;
 pub use self::ignition::*;
 pub use self::initrd::*;
 pub use self::limit::*;
+pub use self::openpgp::*;
 pub use self::peek::*;
 pub use self::tee::*;
 pub use self::verify::*;
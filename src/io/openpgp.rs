@@ -0,0 +1,687 @@
+// Copyright 2024 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal OpenPGP support for checking detached signatures.
+//!
+//! Only the subset needed to verify version 4 binary-document signatures
+//! made by RSA and Ed25519 keys is implemented.  Keys are trusted because
+//! they're in a keyring we were given, so certifications, subkey bindings,
+//! and key expiration are not checked.  Keys with a revocation signature in
+//! the keyring are never trusted, even if the revocation can't be verified,
+//! and expired signatures and those with unknown critical subpackets are
+//! rejected.
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use openssl::bn::BigNum;
+use openssl::hash::{Hasher, MessageDigest};
+use openssl::md::{Md, MdRef};
+use openssl::pkey::{Id, PKey, Public};
+use openssl::pkey_ctx::PkeyCtx;
+use openssl::rsa::{Padding, Rsa};
+use openssl::sha::sha1;
+use openssl::sign::Verifier;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TAG_SIGNATURE: u8 = 2;
+const TAG_PUBLIC_KEY: u8 = 6;
+const TAG_USER_ID: u8 = 13;
+const TAG_PUBLIC_SUBKEY: u8 = 14;
+
+const ALGO_RSA: u8 = 1;
+const ALGO_RSA_SIGN: u8 = 3;
+const ALGO_EDDSA_LEGACY: u8 = 22;
+const ALGO_ED25519: u8 = 27;
+
+const SIG_BINARY: u8 = 0x00;
+const SIG_KEY_REVOCATION: u8 = 0x20;
+const SIG_SUBKEY_REVOCATION: u8 = 0x28;
+
+const SUBPACKET_CREATION_TIME: u8 = 2;
+const SUBPACKET_EXPIRATION_TIME: u8 = 3;
+const SUBPACKET_ISSUER: u8 = 16;
+const SUBPACKET_ISSUER_FINGERPRINT: u8 = 33;
+
+/// Curve OID of Ed25519 in legacy EdDSA keys.
+const OID_ED25519: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xda, 0x47, 0x0f, 0x01];
+
+/// An OpenPGP public key or subkey.
+pub struct PublicKey {
+    /// Fingerprint of this key, in uppercase hex.
+    pub fingerprint: String,
+    /// Fingerprint of the primary key this key belongs to.
+    pub primary_fingerprint: String,
+    /// First User ID of the primary key.
+    pub user_id: Option<String>,
+    algorithm: u8,
+    /// None if the algorithm isn't supported.
+    key: Option<PKey<Public>>,
+    /// Whether the keyring has a revocation signature for this key or
+    /// its primary key.
+    revoked: bool,
+}
+
+impl PublicKey {
+    pub fn key_id(&self) -> &str {
+        &self.fingerprint[self.fingerprint.len() - 16..]
+    }
+}
+
+/// Parse the version 4 public keys and subkeys in an armored or binary
+/// keyring.  Keys of other versions are skipped.
+pub fn parse_keyring(data: &[u8]) -> Result<Vec<PublicKey>> {
+    let mut keys: Vec<PublicKey> = Vec::new();
+    // index of the current primary key, if we're using it
+    let mut primary: Option<usize> = None;
+    // index of the current subkey, if we're using it
+    let mut subkey: Option<usize> = None;
+    for (tag, body) in packets(&dearmor(data)?)? {
+        match tag {
+            TAG_PUBLIC_KEY => {
+                primary = None;
+                subkey = None;
+                if let Some(key) = parse_key(&body).context("parsing OpenPGP public key")? {
+                    primary = Some(keys.len());
+                    keys.push(key);
+                }
+            }
+            TAG_PUBLIC_SUBKEY => {
+                subkey = None;
+                if let (Some(i), Some(key)) = (
+                    primary,
+                    parse_key(&body).context("parsing OpenPGP public subkey")?,
+                ) {
+                    subkey = Some(keys.len());
+                    keys.push(PublicKey {
+                        primary_fingerprint: keys[i].fingerprint.clone(),
+                        user_id: keys[i].user_id.clone(),
+                        revoked: keys[i].revoked,
+                        ..key
+                    });
+                }
+            }
+            TAG_SIGNATURE => {
+                // version 4 and version 3 signature types
+                let sig_type = match body.as_slice() {
+                    [4, sig_type, ..] | [3, 5, sig_type, ..] => *sig_type,
+                    _ => continue,
+                };
+                // revoking a primary key revokes its subkeys too
+                match (sig_type, primary, subkey) {
+                    (SIG_KEY_REVOCATION, Some(i), _) => {
+                        keys[i..].iter_mut().for_each(|key| key.revoked = true)
+                    }
+                    (SIG_SUBKEY_REVOCATION, _, Some(i)) => keys[i].revoked = true,
+                    _ => (),
+                }
+            }
+            TAG_USER_ID => {
+                if let Some(key) = primary.map(|i| &mut keys[i]) {
+                    if key.user_id.is_none() {
+                        key.user_id = Some(String::from_utf8_lossy(&body).into_owned());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    if keys.is_empty() {
+        bail!("no OpenPGP public keys found");
+    }
+    Ok(keys)
+}
+
+/// Parse a key packet as a primary key without a User ID, or return None
+/// if it isn't a version 4 key.
+fn parse_key(body: &[u8]) -> Result<Option<PublicKey>> {
+    let mut p = Parser::new(body);
+    if p.u8()? != 4 {
+        return Ok(None);
+    }
+    let _created = p.u32()?;
+    let algorithm = p.u8()?;
+    let key = match algorithm {
+        ALGO_RSA | ALGO_RSA_SIGN => {
+            let n = BigNum::from_slice(p.mpi()?)?;
+            let e = BigNum::from_slice(p.mpi()?)?;
+            Some(PKey::from_rsa(Rsa::from_public_components(n, e)?)?)
+        }
+        ALGO_EDDSA_LEGACY => {
+            let oid_len = p.u8()?;
+            let oid = p.take(oid_len as usize)?;
+            let point = p.mpi()?;
+            // native point format, prefixed with 0x40
+            if oid == OID_ED25519 && point.len() == 33 && point[0] == 0x40 {
+                Some(PKey::public_key_from_raw_bytes(&point[1..], Id::ED25519)?)
+            } else {
+                None
+            }
+        }
+        ALGO_ED25519 => Some(PKey::public_key_from_raw_bytes(p.take(32)?, Id::ED25519)?),
+        _ => None,
+    };
+
+    let mut hashed = vec![0x99];
+    hashed.extend((body.len() as u16).to_be_bytes());
+    hashed.extend(body);
+    let fingerprint = hex::encode_upper(sha1(&hashed));
+    Ok(Some(PublicKey {
+        primary_fingerprint: fingerprint.clone(),
+        fingerprint,
+        user_id: None,
+        algorithm,
+        key,
+        revoked: false,
+    }))
+}
+
+/// A detached signature over binary data.
+pub struct Signature {
+    /// Key ID of the issuer, if specified.
+    pub issuer_key_id: Option<String>,
+    /// Fingerprint of the issuer, if specified.
+    pub issuer_fingerprint: Option<String>,
+    algorithm: u8,
+    digest: MessageDigest,
+    md: &'static MdRef,
+    /// Signature fields covered by the hash, which follow the data.
+    trailer: Vec<u8>,
+    /// Leftmost 16 bits of the signed hash.
+    hash_prefix: [u8; 2],
+    /// Signature MPIs, or the raw Ed25519 signature.
+    values: Vec<Vec<u8>>,
+}
+
+impl Signature {
+    /// Parse an armored or binary signature.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let packets = packets(&dearmor(data)?)?;
+        let body = match packets.as_slice() {
+            [(TAG_SIGNATURE, body)] => body,
+            _ => bail!("expected a single OpenPGP signature packet"),
+        };
+        let mut p = Parser::new(body);
+        let version = p.u8()?;
+        if version != 4 {
+            bail!("unsupported OpenPGP signature version {version}");
+        }
+        let sig_type = p.u8()?;
+        if sig_type != SIG_BINARY {
+            bail!("unsupported OpenPGP signature type {sig_type:#04x}");
+        }
+        let algorithm = p.u8()?;
+        let hash_algorithm = p.u8()?;
+        let (digest, md) = match hash_algorithm {
+            8 => (MessageDigest::sha256(), Md::sha256()),
+            9 => (MessageDigest::sha384(), Md::sha384()),
+            10 => (MessageDigest::sha512(), Md::sha512()),
+            11 => (MessageDigest::sha224(), Md::sha224()),
+            _ => bail!("unsupported OpenPGP hash algorithm {hash_algorithm}"),
+        };
+        let hashed_len = p.u16()? as usize;
+        let hashed = p.take(hashed_len)?;
+        let unhashed_len = p.u16()? as usize;
+        let unhashed = p.take(unhashed_len)?;
+        let hash_prefix = [p.u8()?, p.u8()?];
+        let values = match algorithm {
+            ALGO_RSA | ALGO_RSA_SIGN => vec![p.mpi()?.to_vec()],
+            ALGO_EDDSA_LEGACY => vec![p.mpi()?.to_vec(), p.mpi()?.to_vec()],
+            ALGO_ED25519 => vec![p.take(64)?.to_vec()],
+            _ => bail!("unsupported OpenPGP public key algorithm {algorithm}"),
+        };
+
+        // the hash covers the version through the hashed subpackets,
+        // followed by a final trailer with their length
+        let mut trailer = body[..6 + hashed_len].to_vec();
+        trailer.extend([4, 0xff]);
+        trailer.extend(((6 + hashed_len) as u32).to_be_bytes());
+
+        let mut issuer_key_id = None;
+        let mut issuer_fingerprint = None;
+        let mut created = None;
+        let mut expires = None;
+        let hashed = subpackets(hashed)?.into_iter().map(|s| (true, s));
+        let unhashed = subpackets(unhashed)?.into_iter().map(|s| (false, s));
+        for (is_hashed, (typ, critical, data)) in hashed.chain(unhashed) {
+            match typ {
+                SUBPACKET_ISSUER if data.len() == 8 => {
+                    issuer_key_id.get_or_insert_with(|| hex::encode_upper(data));
+                }
+                SUBPACKET_ISSUER_FINGERPRINT if data.len() == 21 && data[0] == 4 => {
+                    issuer_fingerprint.get_or_insert_with(|| hex::encode_upper(&data[1..]));
+                }
+                // times are only meaningful if they're signed
+                SUBPACKET_CREATION_TIME if is_hashed => created = Some(Parser::new(data).u32()?),
+                SUBPACKET_EXPIRATION_TIME if is_hashed => expires = Some(Parser::new(data).u32()?),
+                SUBPACKET_ISSUER
+                | SUBPACKET_ISSUER_FINGERPRINT
+                | SUBPACKET_CREATION_TIME
+                | SUBPACKET_EXPIRATION_TIME => (),
+                _ if critical => bail!("unsupported critical OpenPGP signature subpacket {typ}"),
+                _ => (),
+            }
+        }
+        // an expiration time of zero means the signature doesn't expire
+        if let (Some(created), Some(expires @ 1..)) = (created, expires) {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .context("getting current time")?
+                .as_secs();
+            if now >= created as u64 + expires as u64 {
+                bail!("OpenPGP signature has expired");
+            }
+        }
+
+        Ok(Self {
+            issuer_key_id,
+            issuer_fingerprint,
+            algorithm,
+            digest,
+            md,
+            trailer,
+            hash_prefix,
+            values,
+        })
+    }
+
+    /// Return a hasher for the signed data.
+    pub fn hasher(&self) -> Result<Hasher> {
+        Ok(Hasher::new(self.digest)?)
+    }
+
+    /// Finish hashing the signed data.
+    pub fn digest(&self, mut hasher: Hasher) -> Result<Vec<u8>> {
+        hasher.update(&self.trailer)?;
+        Ok(hasher.finish()?.to_vec())
+    }
+
+    /// Whether the signature names the key as its issuer.  A signature
+    /// that doesn't name an issuer could be from any key.
+    pub fn is_issued_by(&self, key: &PublicKey) -> bool {
+        match (&self.issuer_fingerprint, &self.issuer_key_id) {
+            (Some(fingerprint), _) => *fingerprint == key.fingerprint,
+            (None, Some(key_id)) => key_id == key.key_id(),
+            (None, None) => true,
+        }
+    }
+
+    /// Check the signature over a digest from digest() against the key.
+    /// Fail if the key has been revoked.
+    pub fn verify(&self, digest: &[u8], key: &PublicKey) -> Result<bool> {
+        if key.revoked {
+            bail!("key {} has been revoked", key.fingerprint);
+        }
+        let pkey = match &key.key {
+            Some(pkey) => pkey,
+            None => bail!(
+                "key {} uses unsupported algorithm {}",
+                key.fingerprint,
+                key.algorithm
+            ),
+        };
+        if digest[..2] != self.hash_prefix {
+            return Ok(false);
+        }
+        match (self.algorithm, key.algorithm) {
+            (ALGO_RSA | ALGO_RSA_SIGN, ALGO_RSA | ALGO_RSA_SIGN) => {
+                // MPIs drop leading zeroes, but OpenSSL wants the
+                // signature to be as long as the modulus
+                let value = &self.values[0];
+                let size = pkey.size();
+                if value.len() > size {
+                    return Ok(false);
+                }
+                let mut sig = vec![0; size - value.len()];
+                sig.extend(value);
+                let mut ctx = PkeyCtx::new(pkey)?;
+                ctx.verify_init()?;
+                ctx.set_rsa_padding(Padding::PKCS1)?;
+                ctx.set_signature_md(self.md)?;
+                // OpenSSL may report a bad signature as an error
+                Ok(ctx.verify(digest, &sig).unwrap_or(false))
+            }
+            (ALGO_EDDSA_LEGACY, ALGO_EDDSA_LEGACY) => {
+                let mut sig = Vec::new();
+                for value in &self.values {
+                    if value.len() > 32 {
+                        return Ok(false);
+                    }
+                    sig.resize(sig.len() + 32 - value.len(), 0);
+                    sig.extend(value);
+                }
+                Ok(Verifier::new_without_digest(pkey)?.verify_oneshot(&sig, digest)?)
+            }
+            (ALGO_ED25519, ALGO_ED25519) => {
+                Ok(Verifier::new_without_digest(pkey)?.verify_oneshot(&self.values[0], digest)?)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Decode ASCII armor, if present, concatenating the contents of all the
+/// armored blocks.  Return binary data unchanged.
+fn dearmor(data: &[u8]) -> Result<Vec<u8>> {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) if text.trim_start().starts_with("-----BEGIN PGP ") => text,
+        _ => return Ok(data.to_vec()),
+    };
+    let mut ret = Vec::new();
+    let mut lines = text.lines().map(str::trim_end);
+    while let Some(line) = lines.next() {
+        if !line.starts_with("-----BEGIN PGP ") {
+            continue;
+        }
+        // skip armor headers
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+        }
+        // collect base64 up to the checksum or the end line
+        let mut encoded = String::new();
+        for line in lines.by_ref() {
+            if line.starts_with('=') || line.starts_with("-----END PGP ") {
+                break;
+            }
+            encoded.push_str(line);
+        }
+        ret.extend(
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .context("decoding OpenPGP ASCII armor")?,
+        );
+    }
+    Ok(ret)
+}
+
+/// Split OpenPGP data into tags and packet bodies.
+fn packets(data: &[u8]) -> Result<Vec<(u8, Vec<u8>)>> {
+    let mut p = Parser::new(data);
+    let mut ret = Vec::new();
+    while !p.is_empty() {
+        let ctb = p.u8()?;
+        if ctb & 0x80 == 0 {
+            bail!("invalid OpenPGP packet header {ctb:#04x}");
+        }
+        if ctb & 0x40 != 0 {
+            // new format
+            let mut body = Vec::new();
+            loop {
+                let first = p.u8()?;
+                let len = match first {
+                    0..=191 => first as usize,
+                    192..=223 => ((first as usize - 192) << 8) + p.u8()? as usize + 192,
+                    255 => p.u32()? as usize,
+                    // partial body length; another length follows
+                    _ => {
+                        body.extend(p.take(1 << (first & 0x1f))?);
+                        continue;
+                    }
+                };
+                body.extend(p.take(len)?);
+                break;
+            }
+            ret.push((ctb & 0x3f, body));
+        } else {
+            // old format
+            let len = match ctb & 0x03 {
+                0 => p.u8()? as usize,
+                1 => p.u16()? as usize,
+                2 => p.u32()? as usize,
+                _ => p.remaining(),
+            };
+            ret.push(((ctb >> 2) & 0x0f, p.take(len)?.to_vec()));
+        }
+    }
+    Ok(ret)
+}
+
+/// Split signature subpacket data into types, critical bits, and subpacket
+/// bodies.
+fn subpackets(data: &[u8]) -> Result<Vec<(u8, bool, &[u8])>> {
+    let mut p = Parser::new(data);
+    let mut ret = Vec::new();
+    while !p.is_empty() {
+        let first = p.u8()?;
+        let len = match first {
+            0..=191 => first as usize,
+            192..=254 => ((first as usize - 192) << 8) + p.u8()? as usize + 192,
+            255 => p.u32()? as usize,
+        };
+        let (typ, body) = p
+            .take(len)?
+            .split_first()
+            .context("empty OpenPGP signature subpacket")?;
+        ret.push((typ & 0x7f, typ & 0x80 != 0, body));
+    }
+    Ok(ret)
+}
+
+/// Reader for big-endian OpenPGP fields.
+struct Parser<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Parser<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.buf.len() {
+            bail!("truncated OpenPGP data");
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Read a multiprecision integer, returning its big-endian bytes.
+    fn mpi(&mut self) -> Result<&'a [u8]> {
+        let bits = self.u16()? as usize;
+        self.take((bits + 7) / 8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keyring() {
+        let keys = parse_keyring(include_bytes!("../../fixtures/verify/test-key.pub.asc")).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            keys[0].fingerprint,
+            "1A304569BFADFB3E3F96F0C3AA2A69A46EC352FC"
+        );
+        assert_eq!(keys[0].key_id(), "AA2A69A46EC352FC");
+        assert_eq!(
+            keys[1].fingerprint,
+            "FC51AC9FED914ECAD67EDB2A5FBDA8071BF24B78"
+        );
+        for key in &keys {
+            assert_eq!(
+                key.primary_fingerprint,
+                "1A304569BFADFB3E3F96F0C3AA2A69A46EC352FC"
+            );
+            assert_eq!(
+                key.user_id.as_deref(),
+                Some("coreos-installer snake oil key <coreos-installer@example.com>")
+            );
+        }
+
+        let keys = parse_keyring(include_bytes!("../signing-keys.asc")).unwrap();
+        assert_eq!(keys.len(), 4);
+        assert!(keys.iter().all(|key| key.key.is_some() && !key.revoked));
+        assert_eq!(
+            keys[3].user_id.as_deref(),
+            Some("Fedora (40) <fedora-40-primary@fedoraproject.org>")
+        );
+
+        let keys = parse_keyring(include_bytes!(
+            "../../fixtures/verify/test-key-ed25519.pub.asc"
+        ))
+        .unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            keys[0].fingerprint,
+            "D3B3F3F036683F5AC8DA18E7EB1B2D4A3777251C"
+        );
+        assert!(keys[0].key.is_some());
+
+        assert!(parse_keyring(b"").is_err());
+        assert!(parse_keyring(include_bytes!(
+            "../../fixtures/verify/test-key.priv.asc.sig"
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let data = include_bytes!("../../fixtures/verify/test-key.priv.asc");
+        let rsa = parse_keyring(include_bytes!("../../fixtures/verify/test-key.pub.asc")).unwrap();
+        let ed25519 = parse_keyring(include_bytes!(
+            "../../fixtures/verify/test-key-ed25519.pub.asc"
+        ))
+        .unwrap();
+        for (sig, keys, other_keys) in [
+            (
+                &include_bytes!("../../fixtures/verify/test-key.priv.asc.sig")[..],
+                &rsa,
+                &ed25519,
+            ),
+            (
+                &include_bytes!("../../fixtures/verify/test-key.priv.asc.ed25519.sig")[..],
+                &ed25519,
+                &rsa,
+            ),
+        ] {
+            let sig = Signature::parse(sig).unwrap();
+            let key = keys.iter().find(|key| sig.is_issued_by(key)).unwrap();
+            assert!(!other_keys.iter().any(|key| sig.is_issued_by(key)));
+
+            let mut hasher = sig.hasher().unwrap();
+            hasher.update(data).unwrap();
+            let digest = sig.digest(hasher).unwrap();
+            assert!(sig.verify(&digest, key).unwrap());
+
+            let mut hasher = sig.hasher().unwrap();
+            hasher.update(&data[..data.len() - 1]).unwrap();
+            let digest = sig.digest(hasher).unwrap();
+            assert!(!sig.verify(&digest, key).unwrap());
+        }
+
+        assert!(Signature::parse(b"").is_err());
+        assert!(
+            Signature::parse(include_bytes!("../../fixtures/verify/test-key.pub.asc")).is_err()
+        );
+    }
+
+    #[test]
+    fn test_signature_subpackets() {
+        let data = include_bytes!("../../fixtures/verify/test-key.priv.asc");
+        let keys = parse_keyring(include_bytes!("../../fixtures/verify/test-key.pub.asc")).unwrap();
+
+        // critical expiration time in the future
+        let sig = Signature::parse(include_bytes!(
+            "../../fixtures/verify/test-key.priv.asc.expiring.sig"
+        ))
+        .unwrap();
+        let mut hasher = sig.hasher().unwrap();
+        hasher.update(data).unwrap();
+        let digest = sig.digest(hasher).unwrap();
+        assert!(sig.verify(&digest, &keys[1]).unwrap());
+
+        // expired
+        assert_eq!(
+            Signature::parse(include_bytes!(
+                "../../fixtures/verify/test-key.priv.asc.expired.sig"
+            ))
+            .err()
+            .unwrap()
+            .to_string(),
+            "OpenPGP signature has expired"
+        );
+
+        // critical notation
+        assert_eq!(
+            Signature::parse(include_bytes!(
+                "../../fixtures/verify/test-key.priv.asc.critical.sig"
+            ))
+            .err()
+            .unwrap()
+            .to_string(),
+            "unsupported critical OpenPGP signature subpacket 20"
+        );
+    }
+
+    #[test]
+    fn test_revocation() {
+        let data = include_bytes!("../../fixtures/verify/test-key.priv.asc");
+        let sig = Signature::parse(include_bytes!(
+            "../../fixtures/verify/test-key.priv.asc.sig"
+        ))
+        .unwrap();
+        let mut hasher = sig.hasher().unwrap();
+        hasher.update(data).unwrap();
+        let digest = sig.digest(hasher).unwrap();
+
+        let keys = parse_keyring(include_bytes!("../../fixtures/verify/test-key.pub.asc")).unwrap();
+        assert!(keys.iter().all(|key| !key.revoked));
+
+        // revoking the primary key revokes the subkey
+        let keys = parse_keyring(include_bytes!(
+            "../../fixtures/verify/test-key-revoked.pub.asc"
+        ))
+        .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|key| key.revoked));
+        assert_eq!(
+            sig.verify(&digest, &keys[1]).unwrap_err().to_string(),
+            "key FC51AC9FED914ECAD67EDB2A5FBDA8071BF24B78 has been revoked"
+        );
+
+        // revoking the subkey doesn't revoke the primary key
+        let keys = parse_keyring(include_bytes!(
+            "../../fixtures/verify/test-key-subkey-revoked.pub.asc"
+        ))
+        .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(!keys[0].revoked);
+        assert!(keys[1].revoked);
+        assert!(sig.verify(&digest, &keys[1]).is_err());
+    }
+}
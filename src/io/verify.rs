@@ -13,30 +13,112 @@
 // limitations under the License.
 
 use anyhow::{bail, Context, Result};
-use std::fs::{metadata, set_permissions, OpenOptions};
-use std::io::{self, Read, Write};
-use std::os::unix::fs::PermissionsExt;
-use std::process::{Child, Command, Stdio};
-use std::thread::{self, JoinHandle};
-use tempfile::{self, TempDir};
+use openssl::hash::Hasher;
+use std::fs::{metadata, read, read_dir};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
+use super::{parse_keyring, PublicKey, Signature};
+
+const PRODUCTION_KEYS: &[u8] = include_bytes!("../signing-keys.asc");
+
+#[derive(Clone, Debug)]
 pub enum VerifyKeys {
     /// Production keys
     Production,
+    /// Production keys plus custom keyrings
+    ProductionAndCustom(Vec<Keyring>),
+    /// Custom keyrings only
+    Custom(Vec<Keyring>),
     /// Snake oil key
     #[cfg(test)]
     InsecureTest,
 }
 
+impl VerifyKeys {
+    /// Trust the production keys plus the keyrings at the specified paths,
+    /// or only the specified keyrings if custom_only is set.  A path can
+    /// be a keyring file or a directory of keyring files.
+    pub fn new(paths: &[String], custom_only: bool) -> Result<Self> {
+        let mut keyrings = Vec::new();
+        for path in paths.iter().map(Path::new) {
+            let meta = metadata(path)
+                .with_context(|| format!("getting metadata for {}", path.display()))?;
+            if !meta.is_dir() {
+                keyrings.push(Keyring::load(path)?);
+                continue;
+            }
+            let mut entries: Vec<PathBuf> = read_dir(path)
+                .and_then(|dir| dir.map(|ent| ent.map(|ent| ent.path())).collect())
+                .with_context(|| format!("reading directory {}", path.display()))?;
+            entries.sort();
+            for entry in entries {
+                let hidden = entry
+                    .file_name()
+                    .map(|name| name.to_string_lossy().starts_with('.'))
+                    .unwrap_or(true);
+                if !hidden && entry.is_file() {
+                    keyrings.push(Keyring::load(&entry)?);
+                }
+            }
+        }
+        match (custom_only, keyrings.is_empty()) {
+            (true, true) => bail!("no custom signing keys found"),
+            (true, false) => Ok(Self::Custom(keyrings)),
+            (false, true) => Ok(Self::Production),
+            (false, false) => Ok(Self::ProductionAndCustom(keyrings)),
+        }
+    }
+
+    /// Return the keyrings to import, with a description of each.
+    fn keyrings(&self) -> Vec<(String, &[u8])> {
+        let (production, custom): (bool, &[Keyring]) = match self {
+            Self::Production => (true, &[]),
+            Self::ProductionAndCustom(keyrings) => (true, keyrings),
+            Self::Custom(keyrings) => (false, keyrings),
+            #[cfg(test)]
+            Self::InsecureTest => {
+                return vec![(
+                    "test keyring".to_string(),
+                    &include_bytes!("../../fixtures/verify/test-key.pub.asc")[..],
+                )]
+            }
+        };
+        let mut ret = Vec::new();
+        if production {
+            ret.push(("built-in keyring".to_string(), PRODUCTION_KEYS));
+        }
+        ret.extend(
+            custom
+                .iter()
+                .map(|keyring| (keyring.path.display().to_string(), &keyring.data[..])),
+        );
+        ret
+    }
+}
+
+/// OpenPGP public keys read from a file, in armored or binary format.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    path: PathBuf,
+    data: Vec<u8>,
+}
+
+impl Keyring {
+    fn load(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            data: read(path).with_context(|| format!("reading {}", path.display()))?,
+        })
+    }
+}
+
 #[derive(Debug)]
 enum VerifyReport {
     /// Report verification result to stderr
     Stderr,
     /// Report verification result to stderr only if successful
     StderrOnSuccess,
-    /// Verify silently
-    Ignore,
 }
 
 pub struct VerifyReader<R: Read> {
@@ -45,13 +127,13 @@ pub struct VerifyReader<R: Read> {
 
 enum VerifyType<R: Read> {
     None(R),
-    Gpg(GpgReader<R>),
+    OpenPgp(Box<OpenPgpReader<R>>),
 }
 
 impl<R: Read> VerifyReader<R> {
     pub fn new(source: R, gpg_signature: Option<&[u8]>, keys: VerifyKeys) -> Result<Self> {
         let typ = if let Some(signature) = gpg_signature {
-            VerifyType::Gpg(GpgReader::new(source, signature, keys)?)
+            VerifyType::OpenPgp(Box::new(OpenPgpReader::new(source, signature, keys)?))
         } else {
             VerifyType::None(source)
         };
@@ -63,7 +145,7 @@ impl<R: Read> VerifyReader<R> {
     pub fn verify(&mut self) -> Result<()> {
         match &mut self.typ {
             VerifyType::None(_) => (),
-            VerifyType::Gpg(reader) => reader.finish(VerifyReport::Stderr)?,
+            VerifyType::OpenPgp(reader) => reader.finish(VerifyReport::Stderr)?,
         }
         Ok(())
     }
@@ -73,7 +155,7 @@ impl<R: Read> VerifyReader<R> {
     pub fn verify_without_logging_failure(&mut self) -> Result<()> {
         match &mut self.typ {
             VerifyType::None(_) => (),
-            VerifyType::Gpg(reader) => reader.finish(VerifyReport::StderrOnSuccess)?,
+            VerifyType::OpenPgp(reader) => reader.finish(VerifyReport::StderrOnSuccess)?,
         }
         Ok(())
     }
@@ -83,187 +165,77 @@ impl<R: Read> Read for VerifyReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match &mut self.typ {
             VerifyType::None(reader) => reader.read(buf),
-            VerifyType::Gpg(reader) => reader.read(buf),
+            VerifyType::OpenPgp(reader) => reader.read(buf),
         }
     }
 }
 
-struct GpgReader<R: Read> {
-    _gpgdir: TempDir,
+/// Result of checking a signature.
+enum Outcome {
+    /// Valid signature from the key at this index
+    Good(usize),
+    /// Signature from the key at this index doesn't match the data
+    Bad(usize),
+    /// None of the trusted keys issued the signature
+    NoPublicKey,
+    /// The signature couldn't be checked
+    Error(String),
+}
+
+struct OpenPgpReader<R: Read> {
     source: R,
-    child: Child,
-    stderr_thread: Option<JoinHandle<io::Result<Vec<u8>>>>,
+    signature: Signature,
+    /// Trusted keys, with a description of the keyring each came from
+    keys: Vec<(PublicKey, String)>,
+    /// Taken when the signature is checked
+    hasher: Option<Hasher>,
+    outcome: Option<Outcome>,
+    reported: bool,
 }
 
-impl<R: Read> GpgReader<R> {
+impl<R: Read> OpenPgpReader<R> {
     fn new(source: R, signature: &[u8], keys: VerifyKeys) -> Result<Self> {
-        // create GPG home directory with restrictive mode
-        let gpgdir = tempfile::Builder::new()
-            .prefix("coreos-installer-")
-            .tempdir()
-            .context("creating temporary directory")?;
-        let meta = metadata(gpgdir.path()).context("getting metadata for temporary directory")?;
-        let mut permissions = meta.permissions();
-        permissions.set_mode(0o700);
-        set_permissions(gpgdir.path(), permissions)
-            .context("setting mode for temporary directory")?;
-
-        // import public keys
-        let keys = match keys {
-            VerifyKeys::Production => &include_bytes!("../signing-keys.asc")[..],
-            #[cfg(test)]
-            VerifyKeys::InsecureTest => {
-                &include_bytes!("../../fixtures/verify/test-key.pub.asc")[..]
-            }
-        };
-        let mut import = Command::new("gpg")
-            .arg("--homedir")
-            .arg(gpgdir.path())
-            .arg("--batch")
-            .arg("--quiet")
-            .arg("--import")
-            .stdin(Stdio::piped())
-            .spawn()
-            .context("running gpg --import")?;
-        import
-            .stdin
-            .as_mut()
-            .unwrap()
-            .write_all(keys)
-            .context("importing GPG keys")?;
-        if !import.wait().context("waiting for gpg --import")?.success() {
-            bail!("gpg --import failed");
-        }
-
-        // list the public keys we just imported
-        let mut list = Command::new("gpg")
-            .arg("--homedir")
-            .arg(gpgdir.path())
-            .arg("--batch")
-            .arg("--list-keys")
-            .arg("--with-colons")
-            .stdout(Stdio::piped())
-            .spawn()
-            .context("running gpg --list-keys")?;
-        let mut list_output = String::new();
-        list.stdout
-            .as_mut()
-            .unwrap()
-            .read_to_string(&mut list_output)
-            .context("listing GPG keys")?;
-        if !list
-            .wait()
-            .context("waiting for gpg --list-keys")?
-            .success()
-        {
-            bail!("gpg --list-keys failed");
-        }
-
-        // accumulate key IDs into trust arguments
-        let mut trust: Vec<&str> = Vec::new();
-        for line in list_output.lines() {
-            let fields: Vec<&str> = line.split(':').collect();
-            // only look at public keys
-            if fields[0] != "pub" {
-                continue;
-            }
-            // extract key ID
-            if fields.len() >= 5 {
-                trust.append(&mut vec!["--trusted-key", fields[4]]);
-            }
+        let signature = Signature::parse(signature).context("parsing signature")?;
+        let mut trusted = Vec::new();
+        for (description, keyring) in keys.keyrings() {
+            let parsed = parse_keyring(keyring)
+                .with_context(|| format!("reading signing keys from {description}"))?;
+            trusted.extend(parsed.into_iter().map(|key| (key, description.clone())));
         }
+        let hasher = signature.hasher()?;
 
-        // mark keys trusted in trustdb
-        // We do this as a separate pass to keep the resulting log lines
-        // out of the verify output.
-        let trustdb = Command::new("gpg")
-            .arg("--homedir")
-            .arg(gpgdir.path())
-            .arg("--batch")
-            .arg("--check-trustdb")
-            .args(trust)
-            .output()
-            .context("running gpg --check-trustdb")?;
-        if !trustdb.status.success() {
-            // copy out its stderr
-            eprint!("{}", String::from_utf8_lossy(&trustdb.stderr));
-            bail!("gpg --check-trustdb failed");
-        }
-
-        // write signature to file
-        let mut signature_path = gpgdir.path().to_path_buf();
-        signature_path.push("signature");
-        let mut signature_file = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(&signature_path)
-            .context("creating signature file")?;
-        signature_file
-            .write_all(signature)
-            .context("writing signature file")?;
-
-        // start verification
-        let mut verify = Command::new("gpg")
-            .arg("--homedir")
-            .arg(gpgdir.path())
-            .arg("--batch")
-            .arg("--verify")
-            .arg(&signature_path)
-            .arg("-")
-            .stdin(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .context("running gpg --verify")?;
-
-        // spawn stderr reader
-        let mut stderr = verify.stderr.take().unwrap();
-        let stderr_thread = thread::Builder::new()
-            .name("gpg-stderr".into())
-            .spawn(move || -> io::Result<Vec<u8>> {
-                let mut buf = Vec::new();
-                stderr.read_to_end(&mut buf)?;
-                Ok(buf)
-            })
-            .context("spawning GPG stderr reader")?;
-
-        Ok(GpgReader {
-            _gpgdir: gpgdir,
+        Ok(OpenPgpReader {
             source,
-            child: verify,
-            stderr_thread: Some(stderr_thread),
+            signature,
+            keys: trusted,
+            hasher: Some(hasher),
+            outcome: None,
+            reported: false,
         })
     }
 
-    /// Stop GPG, forward its stderr if requested, and check its exit status.
-    /// The exit status check happens on every call, but stderr forwarding
-    /// only happens on the first call.
+    /// Check the signature, report the result if requested, and return an
+    /// error if verification failed.  The check happens on the first call
+    /// and its result is reused afterward, but reporting only happens on
+    /// the first call.
     fn finish(&mut self, report: VerifyReport) -> io::Result<()> {
-        // do cleanup first: wait for child process and join on thread
-        let wait_result = self.child.wait();
-        let join_result = self.stderr_thread.take().map(|t| t.join());
-
-        // possibly copy GPG's stderr to ours
-        let success = wait_result?.success();
-        match join_result {
-            // thread returned GPG's stderr
-            Some(Ok(Ok(stderr))) => match report {
+        if self.outcome.is_none() {
+            self.outcome = Some(
+                self.check()
+                    .unwrap_or_else(|e| Outcome::Error(format!("{e:#}"))),
+            );
+        }
+        let outcome = self.outcome.as_ref().unwrap();
+        let success = matches!(outcome, Outcome::Good(_));
+
+        if !self.reported {
+            self.reported = true;
+            match report {
                 VerifyReport::StderrOnSuccess if !success => (),
-                // use eprint rather than io::stderr() so the output is
-                // captured when running tests
-                VerifyReport::Stderr | VerifyReport::StderrOnSuccess => {
-                    eprint!("{}", String::from_utf8_lossy(&stderr))
-                }
-                VerifyReport::Ignore => (),
-            },
-            // thread returned error
-            Some(Ok(Err(e))) => return Err(e),
-            // thread panicked; propagate the panic
-            Some(Err(e)) => std::panic::resume_unwind(e),
-            // already joined the thread on a previous call
-            None => (),
+                VerifyReport::Stderr | VerifyReport::StderrOnSuccess => self.report(outcome),
+            }
         }
 
-        // check GPG exit status
         if !success {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -273,33 +245,65 @@ impl<R: Read> GpgReader<R> {
 
         Ok(())
     }
-}
 
-impl<R: Read> Read for GpgReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if buf.is_empty() {
-            return Ok(0);
+    /// Finish hashing and try each trusted key that could have issued the
+    /// signature.
+    fn check(&mut self) -> Result<Outcome> {
+        let hasher = self.hasher.take().context("signature already checked")?;
+        let digest = self.signature.digest(hasher)?;
+        let mut outcome = Outcome::NoPublicKey;
+        for (i, (key, _)) in self.keys.iter().enumerate() {
+            if self.signature.is_issued_by(key) {
+                if self.signature.verify(&digest, key)? {
+                    return Ok(Outcome::Good(i));
+                }
+                outcome = Outcome::Bad(i);
+            }
         }
-        let count = self.source.read(buf)?;
-        if count > 0 {
-            // On a partial write we return an error in violation of the
-            // API contract.  This should be okay, since it's a fatal error
-            // for us anyway.
-            self.child
-                .stdin
-                .as_mut()
-                .unwrap()
-                .write_all(&buf[0..count])?;
+        Ok(outcome)
+    }
+
+    // use eprintln rather than io::stderr() so the output is captured when
+    // running tests
+    fn report(&self, outcome: &Outcome) {
+        let describe = |key: &PublicKey| match &key.user_id {
+            Some(user_id) => format!("\"{user_id}\""),
+            None => format!("key {}", key.fingerprint),
+        };
+        match outcome {
+            Outcome::Good(i) => {
+                let (key, source) = &self.keys[*i];
+                eprintln!("Good signature from {}", describe(key));
+                eprintln!("Signed by key {} from {source}", key.primary_fingerprint);
+            }
+            Outcome::Bad(i) => eprintln!("BAD signature from {}", describe(&self.keys[*i].0)),
+            Outcome::NoPublicKey => eprintln!(
+                "Can't check signature: no public key {}",
+                self.signature
+                    .issuer_fingerprint
+                    .as_ref()
+                    .or(self.signature.issuer_key_id.as_ref())
+                    .map(String::as_str)
+                    .unwrap_or("(unknown)")
+            ),
+            Outcome::Error(e) => eprintln!("Can't check signature: {e}"),
         }
-        Ok(count)
     }
 }
 
-impl<R: Read> Drop for GpgReader<R> {
-    fn drop(&mut self) {
-        // if we haven't already forwarded GPG's stderr, avoid doing it now,
-        // so we don't imply that we're checking the result
-        self.finish(VerifyReport::Ignore).ok();
+impl<R: Read> Read for OpenPgpReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.source.read(buf)?;
+        match self.hasher.as_mut() {
+            Some(hasher) => hasher.update(&buf[..count])?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "read after signature was checked",
+                ))
+            }
+        }
+        Ok(count)
     }
 }
 
@@ -371,4 +375,50 @@ mod tests {
         reader.verify_without_logging_failure().unwrap_err();
         assert_eq!(&buf[..], &data[..]);
     }
+
+    /// Load custom keyrings from files and directories
+    #[test]
+    fn test_custom_keys() {
+        let data = include_bytes!("../../fixtures/verify/test-key.priv.asc");
+        let sig = include_bytes!("../../fixtures/verify/test-key.priv.asc.sig");
+        let dir = tempfile::tempdir().unwrap();
+        let keydir = dir.path().join("keys.d");
+        std::fs::create_dir(&keydir).unwrap();
+        std::fs::write(
+            keydir.join("test.asc"),
+            include_bytes!("../../fixtures/verify/test-key.pub.asc"),
+        )
+        .unwrap();
+        std::fs::write(keydir.join(".hidden"), b"not a key").unwrap();
+        let keydir = keydir.to_str().unwrap().to_string();
+
+        // no custom keys
+        assert!(matches!(
+            VerifyKeys::new(&[], false).unwrap(),
+            VerifyKeys::Production
+        ));
+        VerifyKeys::new(&[], true).unwrap_err();
+        VerifyKeys::new(
+            &[dir.path().join("missing").to_str().unwrap().into()],
+            false,
+        )
+        .unwrap_err();
+
+        // custom keys alongside production keys, or alone
+        for custom_only in [false, true] {
+            let keys = VerifyKeys::new(std::slice::from_ref(&keydir), custom_only).unwrap();
+            assert_eq!(keys.keyrings().len(), if custom_only { 1 } else { 2 });
+            let mut reader = VerifyReader::new(&data[..], Some(&sig[..]), keys).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            reader.verify().unwrap();
+        }
+
+        // production keys don't validate the test key's signature
+        let mut reader =
+            VerifyReader::new(&data[..], Some(&sig[..]), VerifyKeys::Production).unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        reader.verify().unwrap_err();
+    }
 }
@@ -13,8 +13,10 @@
 // limitations under the License.
 
 pub mod blockdev;
+pub mod cache;
 pub mod cmdline;
 pub mod download;
+pub mod events;
 pub mod install;
 pub mod io;
 pub mod iso9660;
@@ -16,6 +16,7 @@
 
 use anyhow::{bail, Context, Result};
 use nmstate::NetworkState;
+use reqwest::Url;
 use serde::Deserialize;
 use serde_json;
 use std::fs::read;
@@ -50,6 +51,7 @@ pub(super) struct OsFeatures {
 #[serde(default, rename_all = "kebab-case")]
 pub(super) struct InstallerDirectives {
     pub console: bool,
+    pub status_url: bool,
 }
 
 impl OsFeatures {
@@ -85,6 +87,9 @@ pub(super) struct LiveInitrd {
     installer: Option<InstallConfig>,
     /// Have the installer copy network configs, if we are running it
     installer_copy_network: bool,
+    /// URL and optional CA bundle for installer status reports, if we are
+    /// running it
+    installer_status: Option<(Url, Option<Vec<u8>>)>,
     /// Ignition CAs for the dest system, if it has an Ignition config
     dest_ca: Vec<Vec<u8>>,
 
@@ -137,6 +142,9 @@ impl LiveInitrd {
         for path in &common.installer_config {
             conf.installer_config(path)?;
         }
+        if let Some(url) = &common.installer_status_url {
+            conf.installer_status(url, common.installer_status_ca.as_deref())?;
+        }
         for path in &common.live_ignition {
             conf.live_config(path)?;
         }
@@ -307,6 +315,17 @@ RequiredBy={install_target}",
         self.installer_config_bytes(&filename(path)?, &data)
     }
 
+    pub fn installer_status(&mut self, url: &Url, ca_path: Option<&str>) -> Result<()> {
+        if !self.features.installer_config_directives.status_url {
+            bail!("This OS image does not support reporting installer status.");
+        }
+        let ca = ca_path
+            .map(|path| read(path).with_context(|| format!("reading {path}")))
+            .transpose()?;
+        self.installer_status = Some((url.clone(), ca));
+        Ok(())
+    }
+
     fn installer_config_bytes(&mut self, filename: &str, data: &[u8]) -> Result<()> {
         if !self.features.installer_config {
             bail!("This OS image does not support customizing installer configuration.");
@@ -387,6 +406,19 @@ RequiredBy={install_target}",
                     .get_or_insert_with(Default::default)
                     .copy_network = true;
             }
+            if let Some((url, ca)) = self.installer_status.take() {
+                let conf = self.installer.get_or_insert_with(Default::default);
+                conf.status_url = Some(url);
+                if let Some(ca) = ca {
+                    let ca_path = "/etc/coreos/installer-status-ca.pem";
+                    self.live.get_or_insert_with(Default::default).add_file(
+                        ca_path.into(),
+                        &ca,
+                        0o644,
+                    )?;
+                    conf.status_ca = Some(ca_path.into());
+                }
+            }
         }
 
         if let Some(conf) = self.installer.take() {
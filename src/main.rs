@@ -15,15 +15,22 @@
 use anyhow::Result;
 use clap::Parser;
 
-use libcoreinst::{cmdline, download, install, live, osmet, source};
+use libcoreinst::{cache, cmdline, download, install, live, osmet, source};
 
 use cmdline::*;
 
 fn main() -> Result<()> {
     match Cmd::parse() {
         Cmd::Download(c) => download::download(c),
+        Cmd::Mirror(c) => download::mirror(c),
         Cmd::Install(c) => install::install(c),
         Cmd::ListStream(c) => source::list_stream(c),
+        Cmd::ListDisks(c) => install::list_disks(c),
+        Cmd::Cache(c) => match c {
+            CacheCmd::List(c) => cache::cache_list(c),
+            CacheCmd::Verify(c) => cache::cache_verify(c),
+            CacheCmd::Prune(c) => cache::cache_prune(c),
+        },
         Cmd::Iso(c) => match c {
             IsoCmd::Customize(c) => live::iso_customize(c),
             IsoCmd::Embed(c) => live::iso_embed(c),
@@ -79,6 +86,11 @@ fn main() -> Result<()> {
                 DevExtractCmd::Osmet(c) => osmet::dev_extract_osmet(c),
                 DevExtractCmd::Initrd(c) => live::dev_extract_initrd(c),
             },
+            DevCmd::Partitions(c) => match c {
+                DevPartitionsCmd::Stash(c) => install::dev_partitions_stash(c),
+                DevPartitionsCmd::Restore(c) => install::dev_partitions_restore(c),
+                DevPartitionsCmd::Show(c) => install::dev_partitions_show(c),
+            },
         },
     }
 }
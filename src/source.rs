@@ -13,23 +13,35 @@
 // limitations under the License.
 
 use anyhow::{anyhow, bail, Context, Result};
-use reqwest::{blocking, StatusCode, Url};
+use openssl::x509::X509;
+use reqwest::header::{
+    HeaderMap, HeaderValue, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use reqwest::{blocking, Certificate, StatusCode, Url};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fmt::{Display, Formatter};
 use std::fs::OpenOptions;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::thread::sleep;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, sleep};
 use std::time::Duration;
 
+use crate::cache::{Cache, CacheFill, EntryMetadata};
 use crate::cmdline::*;
+use crate::io::Sha256Digest;
 use crate::osmet::*;
 use crate::util::set_die_on_sigpipe;
 
 /// Completion timeout for HTTP requests (4 hours).
 const HTTP_COMPLETION_TIMEOUT: Duration = Duration::from_secs(4 * 60 * 60);
 
+/// Size of each range request when fetching an image in parallel segments.
+const SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
 /// Default base URL to Fedora CoreOS streams metadata.
 const DEFAULT_STREAM_BASE_URL: &str = "https://builds.coreos.fedoraproject.org/streams/";
 
@@ -67,7 +79,10 @@ pub struct UrlLocation {
     image_url: Url,
     sig_url: Url,
     artifact_type: String,
+    sha256: Option<Sha256Digest>,
     retries: FetchRetries,
+    segments: FetchSegments,
+    cache: Option<Cache>,
 }
 
 // Remote image source specified by Fedora CoreOS stream metadata
@@ -80,14 +95,60 @@ pub struct StreamLocation {
     platform: String,
     format: String,
     retries: FetchRetries,
+    segments: FetchSegments,
+    cache: Option<Cache>,
 }
 
 pub struct ImageSource {
     pub reader: Box<dyn Read>,
     pub length_hint: Option<u64>,
     pub signature: Option<Vec<u8>>,
+    /// Expected digest of the data as fetched, from stream metadata
+    pub sha256: Option<Sha256Digest>,
+    /// Expected digest of the decompressed data, from stream metadata
+    pub uncompressed_sha256: Option<Sha256Digest>,
     pub filename: String,
     pub artifact_type: String,
+    // for sources that can be restarted partway through
+    request: Option<HttpRequest>,
+    // for sources being copied into the cache as they're read
+    cache_fill: Option<CacheFill>,
+}
+
+impl ImageSource {
+    /// Replace the reader with one that starts at the specified byte offset
+    /// into the image.  Returns false if the source doesn't support this.
+    pub fn resume_at(&mut self, offset: u64) -> Result<bool> {
+        let request = match &self.request {
+            Some(request) => request,
+            None => return Ok(false),
+        };
+        match request.get_range(offset, None, request.retries)? {
+            Some(resp) => {
+                // the cache would miss the skipped data
+                self.cache_fill = None;
+                self.reader = Box::new(HttpReader::new(
+                    request.clone(),
+                    resp,
+                    offset,
+                    self.length_hint,
+                ));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Add the image to the cache, if we were filling a cache entry.  Call
+    /// only after the image has been read and verified.  Failures are
+    /// reported but not fatal.
+    pub fn commit_to_cache(&mut self) {
+        if let (Some(fill), Some(signature)) = (self.cache_fill.take(), &self.signature) {
+            if let Err(e) = fill.commit(signature) {
+                eprintln!("Couldn't add image to cache: {e:#}");
+            }
+        }
+    }
 }
 
 impl FileLocation {
@@ -146,25 +207,45 @@ impl ImageLocation for FileLocation {
             reader: Box::new(out),
             length_hint: Some(length),
             signature,
+            sha256: None,
+            uncompressed_sha256: None,
             filename,
             artifact_type: "disk".to_string(),
+            request: None,
+            cache_fill: None,
         }])
     }
 }
 
 impl UrlLocation {
-    pub fn new(url: &Url, retries: FetchRetries) -> Self {
+    pub fn new(
+        url: &Url,
+        retries: FetchRetries,
+        segments: FetchSegments,
+        cache: Option<Cache>,
+    ) -> Self {
         let mut sig_url = url.clone();
         sig_url.set_path(&format!("{}.sig", sig_url.path()));
-        Self::new_full(url, &sig_url, "disk", retries)
+        Self::new_full(url, &sig_url, "disk", None, retries, segments, cache)
     }
 
-    fn new_full(url: &Url, sig_url: &Url, artifact_type: &str, retries: FetchRetries) -> Self {
+    fn new_full(
+        url: &Url,
+        sig_url: &Url,
+        artifact_type: &str,
+        sha256: Option<Sha256Digest>,
+        retries: FetchRetries,
+        segments: FetchSegments,
+        cache: Option<Cache>,
+    ) -> Self {
         Self {
             image_url: url.clone(),
             sig_url: sig_url.clone(),
             artifact_type: artifact_type.to_string(),
+            sha256,
             retries,
+            segments,
+            cache,
         }
     }
 
@@ -179,6 +260,43 @@ impl UrlLocation {
             .context("reading signature content")?;
         Ok(sig_bytes)
     }
+
+    /// Build a source reading from a cache entry, if the cache has one
+    /// with the specified key.  Prefer the current signature, so a
+    /// revoked signature isn't resurrected from the cache, but use the
+    /// cached one if we couldn't fetch it.
+    fn cached_source(
+        &self,
+        cache: &Cache,
+        key: &str,
+        signature: &Option<Vec<u8>>,
+    ) -> Result<Option<ImageSource>> {
+        let entry = match cache.lookup(key)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        eprintln!("Reading image from cache entry {key}");
+        let signature = match signature {
+            Some(signature) => Some(signature.clone()),
+            None => {
+                if entry.signature.is_some() {
+                    eprintln!("Using cached signature");
+                }
+                entry.signature
+            }
+        };
+        Ok(Some(ImageSource {
+            reader: Box::new(entry.image),
+            length_hint: Some(entry.metadata.length),
+            signature,
+            sha256: self.sha256.clone(),
+            uncompressed_sha256: None,
+            filename: entry.metadata.filename,
+            artifact_type: self.artifact_type.clone(),
+            request: None,
+            cache_fill: None,
+        }))
+    }
 }
 
 impl Display for UrlLocation {
@@ -198,14 +316,31 @@ impl ImageLocation for UrlLocation {
             .map_err(|e| eprintln!("Failed to fetch signature: {e}"))
             .ok();
 
+        // if the image has a published digest, we can check the cache
+        // without fetching anything
+        let digest_key = self.sha256.as_ref().map(Cache::digest_key).transpose()?;
+        if let (Some(cache), Some(key)) = (&self.cache, &digest_key) {
+            if let Some(source) = self.cached_source(cache, key, &signature)? {
+                return Ok(vec![source]);
+            }
+        }
+
         // start fetch, get length
         let client = new_http_client()?;
-        let resp = http_get(client, &self.image_url, self.retries).context("fetching image URL")?;
+        let resp = http_get(client.clone(), &self.image_url, self.retries)
+            .context("fetching image URL")?;
         match resp.status() {
             StatusCode::OK => (),
             s => bail!("image fetch failed: {}", s),
         };
         let length_hint = resp.content_length();
+        let request = HttpRequest::new(client, self.retries, &resp);
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
         // ignores the Content-Disposition filename
         let filename = resp
             .url()
@@ -215,17 +350,77 @@ impl ImageLocation for UrlLocation {
             .context("walking image URL")?
             .to_string();
 
+        // Otherwise, key the image by its URL and strong ETag.  Only cache
+        // images we'll be able to verify when they're reused.
+        let key = match (&self.cache, &signature, digest_key, &etag) {
+            (Some(_), Some(_), Some(key), _) => Some(key),
+            (Some(cache), Some(_), None, Some(etag)) => {
+                let key = Cache::url_key(&request.url, etag)?;
+                if let Some(source) = self.cached_source(cache, &key, &signature)? {
+                    return Ok(vec![source]);
+                }
+                Some(key)
+            }
+            _ => None,
+        };
+
+        // fetch large images in parallel segments, if requested
+        let mut reader: Box<dyn Read> = match length_hint {
+            Some(length) if self.segments.get() > 1 && length > SEGMENT_SIZE => {
+                match SegmentedReader::new(&request, length, self.segments.get(), SEGMENT_SIZE)? {
+                    Some(reader) => Box::new(reader),
+                    None => {
+                        eprintln!(
+                            "Server doesn't support range requests; fetching in a single stream"
+                        );
+                        Box::new(HttpReader::new(request.clone(), resp, 0, length_hint))
+                    }
+                }
+            }
+            _ => Box::new(HttpReader::new(request.clone(), resp, 0, length_hint)),
+        };
+
+        let mut cache_fill = None;
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            let metadata = EntryMetadata {
+                url: request.url.to_string(),
+                filename: filename.clone(),
+                etag,
+                sha256: self
+                    .sha256
+                    .as_ref()
+                    .map(Sha256Digest::to_hex_string)
+                    .transpose()?,
+                length: 0,
+                created: 0,
+                last_used: 0,
+            };
+            // a broken cache shouldn't break the fetch
+            match cache.fill(&key, metadata) {
+                Ok(mut fill) => {
+                    reader = fill.reader(reader);
+                    cache_fill = Some(fill);
+                }
+                Err(e) => eprintln!("Couldn't create cache entry: {e:#}"),
+            }
+        }
+
         Ok(vec![ImageSource {
-            reader: Box::new(resp),
+            reader,
             length_hint,
             signature,
+            sha256: self.sha256.clone(),
+            uncompressed_sha256: None,
             filename,
             artifact_type: self.artifact_type.clone(),
+            request: Some(request),
+            cache_fill,
         }])
     }
 }
 
 impl StreamLocation {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         stream: &str,
         architecture: &str,
@@ -233,6 +428,8 @@ impl StreamLocation {
         format: &str,
         base_url: Option<&Url>,
         retries: FetchRetries,
+        segments: FetchSegments,
+        cache: Option<Cache>,
     ) -> Result<Self> {
         Ok(Self {
             stream_base_url: base_url.cloned(),
@@ -242,6 +439,8 @@ impl StreamLocation {
             platform: platform.to_string(),
             format: format.to_string(),
             retries,
+            segments,
+            cache,
         })
     }
 }
@@ -268,7 +467,7 @@ impl ImageLocation for StreamLocation {
     fn sources(&self) -> Result<Vec<ImageSource>> {
         // fetch and parse stream metadata
         let client = new_http_client()?;
-        let stream = fetch_stream(client, &self.stream_url, self.retries)?;
+        let stream: Stream = fetch_stream(client, &self.stream_url, self.retries)?;
 
         // descend it
         let artifacts = stream
@@ -285,17 +484,16 @@ impl ImageLocation for StreamLocation {
                 )
             })?;
 
-        // build sources, letting UrlLocation handle the details
+        // build sources
         let mut sources: Vec<ImageSource> = Vec::new();
         for (artifact_type, artifact) in artifacts.iter() {
-            let artifact_url = Url::parse(&artifact.location)
-                .context("parsing artifact URL from stream metadata")?;
-            let signature_url = Url::parse(&artifact.signature)
-                .context("parsing signature URL from stream metadata")?;
-            let mut artifact_sources =
-                UrlLocation::new_full(&artifact_url, &signature_url, artifact_type, self.retries)
-                    .sources()?;
-            sources.append(&mut artifact_sources);
+            sources.append(&mut artifact.sources(
+                &self.stream_url,
+                artifact_type,
+                self.retries,
+                self.segments,
+                self.cache.clone(),
+            )?);
         }
         sources.sort_by_key(|k| k.artifact_type.to_string());
         Ok(sources)
@@ -361,8 +559,12 @@ impl ImageLocation for OsmetLocation {
             reader: Box::new(unpacker),
             length_hint: Some(length),
             signature: None,
+            sha256: None,
+            uncompressed_sha256: None,
             filename,
             artifact_type: "disk".to_string(),
+            request: None,
+            cache_fill: None,
         }])
     }
 
@@ -385,7 +587,7 @@ pub fn list_stream(config: ListStreamConfig) -> Result<()> {
     // fetch stream metadata
     let client = new_http_client()?;
     let stream_url = build_stream_url(&config.stream, config.stream_base_url.as_ref())?;
-    let stream = fetch_stream(client, &stream_url, FetchRetries::None)?;
+    let stream: Stream = fetch_stream(client, &stream_url, FetchRetries::None)?;
 
     // walk formats
     let mut rows: Vec<Row> = Vec::new();
@@ -430,6 +632,218 @@ pub fn list_stream(config: ListStreamConfig) -> Result<()> {
     Ok(())
 }
 
+/// Stream metadata fetched for mirroring.  Fields we don't otherwise parse
+/// are preserved when the metadata is written back out.
+pub struct StreamMetadata {
+    url: Url,
+    retries: FetchRetries,
+    segments: FetchSegments,
+    json: serde_json::Value,
+}
+
+/// An artifact listed in stream metadata.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamArtifact {
+    pub architecture: String,
+    pub platform: String,
+    pub format: String,
+    pub artifact_type: String,
+}
+
+/// What stream metadata says about an artifact.
+pub struct ArtifactInfo {
+    pub filename: String,
+    pub sha256: Option<Sha256Digest>,
+}
+
+impl StreamMetadata {
+    pub fn fetch(
+        stream: &str,
+        base_url: Option<&Url>,
+        retries: FetchRetries,
+        segments: FetchSegments,
+    ) -> Result<Self> {
+        let url = build_stream_url(stream, base_url)?;
+        let json = fetch_stream(new_http_client()?, &url, retries)?;
+        Ok(Self {
+            url,
+            retries,
+            segments,
+            json,
+        })
+    }
+
+    fn parse(&self) -> Result<Stream> {
+        Stream::deserialize(&self.json).context("decoding stream metadata")
+    }
+
+    /// Drop all architectures, platforms, and formats except the specified
+    /// ones.  If none are specified at some level, keep all of them.
+    /// Return the remaining artifacts.
+    pub fn select(
+        &mut self,
+        architectures: &[String],
+        platforms: &[String],
+        formats: &[String],
+    ) -> Result<Vec<StreamArtifact>> {
+        // check that everything requested exists
+        let stream = self.parse()?;
+        for architecture_name in architectures {
+            let architecture = stream
+                .architectures
+                .get(architecture_name)
+                .with_context(|| {
+                    format!("couldn't find architecture {architecture_name} in stream metadata")
+                })?;
+            for platform_name in platforms {
+                let platform = architecture.artifacts.get(platform_name).with_context(|| {
+                    format!(
+                        "couldn't find architecture {architecture_name}, platform {platform_name} in stream metadata"
+                    )
+                })?;
+                if !formats.is_empty()
+                    && !formats
+                        .iter()
+                        .any(|format| platform.formats.contains_key(format))
+                {
+                    bail!("couldn't find architecture {architecture_name}, platform {platform_name}, formats {} in stream metadata", formats.join(", "));
+                }
+            }
+        }
+
+        // prune the rest
+        for architecture in
+            prune_object(self.json.get_mut("architectures"), architectures)?.values_mut()
+        {
+            for platform in prune_object(architecture.get_mut("artifacts"), platforms)?.values_mut()
+            {
+                prune_object(platform.get_mut("formats"), formats)?;
+            }
+        }
+
+        // list what's left
+        let mut artifacts = Vec::new();
+        for (architecture_name, architecture) in self.parse()?.architectures {
+            for (platform_name, platform) in architecture.artifacts {
+                for (format_name, format) in platform.formats {
+                    for artifact_type in format.into_keys() {
+                        artifacts.push(StreamArtifact {
+                            architecture: architecture_name.clone(),
+                            platform: platform_name.clone(),
+                            format: format_name.clone(),
+                            artifact_type,
+                        });
+                    }
+                }
+            }
+        }
+        artifacts.sort();
+        Ok(artifacts)
+    }
+
+    /// Get the JSON pointer to an artifact.
+    fn pointer(artifact: &StreamArtifact) -> String {
+        let escape = |s: &str| s.replace('~', "~0").replace('/', "~1");
+        format!(
+            "/architectures/{}/artifacts/{}/formats/{}/{}",
+            escape(&artifact.architecture),
+            escape(&artifact.platform),
+            escape(&artifact.format),
+            escape(&artifact.artifact_type)
+        )
+    }
+
+    fn artifact(&self, artifact: &StreamArtifact) -> Result<Artifact> {
+        let value = self
+            .json
+            .pointer(&Self::pointer(artifact))
+            .with_context(|| format!("couldn't find {artifact:?} in stream metadata"))?;
+        Artifact::deserialize(value).context("decoding stream metadata")
+    }
+
+    /// Get the filename and expected digest of an artifact without
+    /// fetching it.
+    pub fn info(&self, artifact: &StreamArtifact) -> Result<ArtifactInfo> {
+        let value = self.artifact(artifact)?;
+        let url = self
+            .url
+            .join(&value.location)
+            .context("parsing artifact URL from stream metadata")?;
+        let filename = url
+            .path_segments()
+            .context("splitting artifact URL")?
+            .next_back()
+            .context("walking artifact URL")?
+            .to_string();
+        Ok(ArtifactInfo {
+            filename,
+            sha256: value.sha256()?,
+        })
+    }
+
+    /// Open an artifact for reading.
+    pub fn source(&self, artifact: &StreamArtifact) -> Result<ImageSource> {
+        self.artifact(artifact)?
+            .sources(
+                &self.url,
+                &artifact.artifact_type,
+                self.retries,
+                self.segments,
+                None,
+            )?
+            .pop()
+            .context("no artifact found")
+    }
+
+    /// Point an artifact at a new location, and its signature too if we
+    /// have one there.
+    pub fn relocate(
+        &mut self,
+        artifact: &StreamArtifact,
+        location: &str,
+        signed: bool,
+    ) -> Result<()> {
+        let value = self
+            .json
+            .pointer_mut(&Self::pointer(artifact))
+            .and_then(|value| value.as_object_mut())
+            .with_context(|| format!("couldn't find {artifact:?} in stream metadata"))?;
+        value.insert("location".into(), location.into());
+        if signed {
+            value.insert("signature".into(), format!("{location}.sig").into());
+        }
+        Ok(())
+    }
+
+    /// Write the metadata to a file, replacing it atomically.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".partial");
+        let temp_path = PathBuf::from(temp_path);
+        let mut buf = serde_json::to_vec_pretty(&self.json).context("encoding stream metadata")?;
+        buf.push(b'\n');
+        std::fs::write(&temp_path, buf)
+            .with_context(|| format!("writing {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, path)
+            .with_context(|| format!("renaming {} to {}", temp_path.display(), path.display()))
+    }
+}
+
+/// Keep only the specified keys of a JSON object in stream metadata, or all
+/// of them if none are specified.
+fn prune_object<'a>(
+    value: Option<&'a mut serde_json::Value>,
+    keep: &[String],
+) -> Result<&'a mut serde_json::Map<String, serde_json::Value>> {
+    let map = value
+        .and_then(|value| value.as_object_mut())
+        .context("unexpected structure in stream metadata")?;
+    if !keep.is_empty() {
+        map.retain(|key, _| keep.contains(key));
+    }
+    Ok(map)
+}
+
 /// Generate a stream URL from a stream name and base URL, or the default
 /// base URL if none is specified.
 fn build_stream_url(stream: &str, base_url: Option<&Url>) -> Result<Url> {
@@ -440,7 +854,11 @@ fn build_stream_url(stream: &str, base_url: Option<&Url>) -> Result<Url> {
 }
 
 /// Fetch and parse stream metadata.
-fn fetch_stream(client: blocking::Client, url: &Url, retries: FetchRetries) -> Result<Stream> {
+fn fetch_stream<T: DeserializeOwned>(
+    client: blocking::Client,
+    url: &Url,
+    retries: FetchRetries,
+) -> Result<T> {
     // fetch stream metadata
     let resp = http_get(client, url, retries).context("fetching stream metadata")?;
     match resp.status() {
@@ -449,24 +867,86 @@ fn fetch_stream(client: blocking::Client, url: &Url, retries: FetchRetries) -> R
     };
 
     // parse it
-    let stream: Stream = serde_json::from_reader(resp).context("decoding stream metadata")?;
-    Ok(stream)
+    serde_json::from_reader(resp).context("decoding stream metadata")
 }
 
 /// Customize and build a new HTTP client.
 pub fn new_http_client() -> Result<blocking::Client> {
-    blocking::ClientBuilder::new()
-        .timeout(HTTP_COMPLETION_TIMEOUT)
+    http_client_builder()
         .build()
         .context("building HTTP client")
 }
 
+/// Build a new HTTP client which additionally trusts the certificate
+/// authorities in the specified PEM bundle.
+pub fn new_http_client_with_ca(ca_path: &str) -> Result<blocking::Client> {
+    let data = std::fs::read(ca_path).with_context(|| format!("reading {ca_path}"))?;
+    let certs = X509::stack_from_pem(&data)
+        .with_context(|| format!("parsing certificates from {ca_path}"))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", ca_path);
+    }
+    let mut builder = http_client_builder();
+    for cert in certs {
+        let der = cert.to_der().context("encoding certificate")?;
+        builder = builder
+            .add_root_certificate(Certificate::from_der(&der).context("loading certificate")?);
+    }
+    builder.build().context("building HTTP client")
+}
+
+/// Common configuration for all of our HTTP clients.
+fn http_client_builder() -> blocking::ClientBuilder {
+    blocking::ClientBuilder::new().timeout(HTTP_COMPLETION_TIMEOUT)
+}
+
 /// Wrapper around Client::get() with error handling based on HTTP return code and optionally basic
 /// exponential backoff retries for transient errors.
 pub fn http_get(
     client: blocking::Client,
     url: &Url,
     retries: FetchRetries,
+) -> Result<blocking::Response> {
+    http_get_with_headers(client, url, retries, HeaderMap::new())
+}
+
+/// http_get() with additional request headers.
+fn http_get_with_headers(
+    client: blocking::Client,
+    url: &Url,
+    retries: FetchRetries,
+    headers: HeaderMap,
+) -> Result<blocking::Response> {
+    http_send(url, retries, "fetching", || {
+        client.get(url.clone()).headers(headers.clone())
+    })
+}
+
+/// POST a JSON document to the specified URL, with the same error handling
+/// and retry behavior as http_get().
+pub fn http_post_json(
+    client: blocking::Client,
+    url: &Url,
+    retries: FetchRetries,
+    body: Vec<u8>,
+    timeout: Duration,
+) -> Result<blocking::Response> {
+    http_send(url, retries, "posting to", || {
+        client
+            .post(url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .timeout(timeout)
+    })
+}
+
+/// Send the request produced by build(), retrying transient errors.  verb
+/// describes the operation in error messages.
+fn http_send(
+    url: &Url,
+    retries: FetchRetries,
+    verb: &str,
+    build: impl Fn() -> blocking::RequestBuilder,
 ) -> Result<blocking::Response> {
     // this matches `curl --retry` semantics -- see list in `curl(1)`
     const RETRY_STATUS_CODES: [u16; 6] = [408, 429, 500, 502, 503, 504];
@@ -479,7 +959,7 @@ pub fn http_get(
     };
 
     loop {
-        let err: anyhow::Error = match client.get(url.clone()).send() {
+        let err: anyhow::Error = match build().send() {
             Err(err) => err.into(),
             Ok(resp) => match resp.status().as_u16() {
                 code if RETRY_STATUS_CODES.contains(&code) => anyhow!(
@@ -490,7 +970,7 @@ pub fn http_get(
                 _ => {
                     return resp
                         .error_for_status()
-                        .with_context(|| format!("fetching '{url}'"));
+                        .with_context(|| format!("{verb} '{url}'"));
                 }
             },
         };
@@ -498,11 +978,11 @@ pub fn http_get(
         if !infinite {
             tries -= 1;
             if tries == 0 {
-                return Err(err).with_context(|| format!("fetching '{url}'"));
+                return Err(err).with_context(|| format!("{verb} '{url}'"));
             }
         }
 
-        eprintln!("Error fetching '{url}': {err}");
+        eprintln!("Error {verb} '{url}': {err}");
         eprintln!("Sleeping {delay}s and retrying...");
         sleep(Duration::from_secs(delay));
         delay = std::cmp::min(delay * 2, 10 * 60); // cap to 10 mins; matches curl
@@ -528,14 +1008,823 @@ struct Platform {
 struct Artifact {
     location: String,
     signature: String,
+    sha256: Option<String>,
+    #[serde(rename = "uncompressed-sha256")]
+    uncompressed_sha256: Option<String>,
+}
+
+impl Artifact {
+    /// Build sources for the artifact, letting UrlLocation handle the
+    /// details.  Relative URLs are resolved against the stream metadata
+    /// URL.
+    fn sources(
+        &self,
+        stream_url: &Url,
+        artifact_type: &str,
+        retries: FetchRetries,
+        segments: FetchSegments,
+        cache: Option<Cache>,
+    ) -> Result<Vec<ImageSource>> {
+        let artifact_url = stream_url
+            .join(&self.location)
+            .context("parsing artifact URL from stream metadata")?;
+        let signature_url = stream_url
+            .join(&self.signature)
+            .context("parsing signature URL from stream metadata")?;
+        let mut sources = UrlLocation::new_full(
+            &artifact_url,
+            &signature_url,
+            artifact_type,
+            self.sha256()?,
+            retries,
+            segments,
+            cache,
+        )
+        .sources()?;
+        for source in sources.iter_mut() {
+            source.uncompressed_sha256 = self
+                .uncompressed_sha256
+                .as_deref()
+                .map(Sha256Digest::from_hex)
+                .transpose()
+                .context("parsing artifact uncompressed SHA-256 from stream metadata")?;
+        }
+        Ok(sources)
+    }
+
+    fn sha256(&self) -> Result<Option<Sha256Digest>> {
+        self.sha256
+            .as_deref()
+            .map(Sha256Digest::from_hex)
+            .transpose()
+            .context("parsing artifact SHA-256 from stream metadata")
+    }
+}
+
+/// An HTTP GET request that can be reissued for a byte range of the same
+/// resource.
+#[derive(Clone, Debug)]
+struct HttpRequest {
+    client: blocking::Client,
+    url: Url,
+    retries: FetchRetries,
+    // strong ETag or Last-Modified date of the original response, so we
+    // don't splice together two versions of the resource
+    validator: Option<HeaderValue>,
+}
+
+impl HttpRequest {
+    fn new(client: blocking::Client, retries: FetchRetries, resp: &blocking::Response) -> Self {
+        let headers = resp.headers();
+        let validator = headers
+            .get(ETAG)
+            .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+            .or_else(|| headers.get(LAST_MODIFIED))
+            .cloned();
+        Self {
+            client,
+            // after any redirects
+            url: resp.url().clone(),
+            retries,
+            validator,
+        }
+    }
+
+    /// Fetch the resource starting from the specified byte offset, and
+    /// stopping before the end offset if one is specified.  Returns None if
+    /// the server doesn't support range requests or the resource has
+    /// changed.
+    fn get_range(
+        &self,
+        offset: u64,
+        end: Option<u64>,
+        retries: FetchRetries,
+    ) -> Result<Option<blocking::Response>> {
+        let last = end.map(|end| (end - 1).to_string()).unwrap_or_default();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            RANGE,
+            HeaderValue::from_str(&format!("bytes={offset}-{last}"))
+                .context("building Range header")?,
+        );
+        if let Some(validator) = &self.validator {
+            headers.insert(IF_RANGE, validator.clone());
+        }
+        let resp = http_get_with_headers(self.client.clone(), &self.url, retries, headers)
+            .with_context(|| format!("fetching from offset {offset}"))?;
+        if resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Ok(None);
+        }
+        // make sure we got the range we asked for
+        let expected = match end {
+            Some(_) => format!("bytes {offset}-{last}/"),
+            None => format!("bytes {offset}-"),
+        };
+        match resp
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(range) if range.starts_with(&expected) => Ok(Some(resp)),
+            range => bail!(
+                "requested range starting at {} but received {}",
+                offset,
+                range.unwrap_or("none")
+            ),
+        }
+    }
+}
+
+/// A reader for an HTTP response body that reconnects with a Range request
+/// if the transfer fails partway through, up to the configured number of
+/// retries.
+struct HttpReader {
+    request: HttpRequest,
+    resp: blocking::Response,
+    position: u64,
+    length: Option<u64>,
+    // end of the requested range, if we only asked for part of the resource
+    end: Option<u64>,
+    // None for infinite
+    retries_left: Option<u32>,
+}
+
+impl HttpReader {
+    fn new(
+        request: HttpRequest,
+        resp: blocking::Response,
+        position: u64,
+        length: Option<u64>,
+    ) -> Self {
+        let retries_left = match request.retries {
+            FetchRetries::Infinite => None,
+            FetchRetries::Finite(n) => Some(n.get()),
+            FetchRetries::None => Some(0),
+        };
+        Self {
+            request,
+            resp,
+            position,
+            length,
+            end: None,
+            retries_left,
+        }
+    }
+
+    /// Read a response to a request for the range [start, end).
+    fn new_range(request: HttpRequest, resp: blocking::Response, start: u64, end: u64) -> Self {
+        Self {
+            end: Some(end),
+            ..Self::new(request, resp, start, Some(end))
+        }
+    }
+
+    /// Reconnect at the current position.  The request itself isn't
+    /// retried; failed reconnects count against our own retry budget.
+    fn resume(&mut self) -> io::Result<()> {
+        match self
+            .request
+            .get_range(self.position, self.end, FetchRetries::None)
+        {
+            Ok(Some(resp)) => {
+                self.resp = resp;
+                Ok(())
+            }
+            Ok(None) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "server didn't honor request to resume fetch",
+            )),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, format!("{e:#}"))),
+        }
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let mut err = match self.resp.read(buf) {
+                Ok(0) if self.length.map(|l| self.position < l).unwrap_or(false) => io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before end of response",
+                ),
+                Ok(count) => {
+                    self.position += count as u64;
+                    return Ok(count);
+                }
+                Err(e) => e,
+            };
+            let mut delay = 0;
+            loop {
+                match self.retries_left.as_mut() {
+                    Some(0) => return Err(err),
+                    Some(n) => *n -= 1,
+                    None => (),
+                }
+                eprintln!(
+                    "Error fetching '{}' at offset {}: {}",
+                    self.request.url, self.position, err
+                );
+                if delay > 0 {
+                    eprintln!("Sleeping {delay}s and resuming fetch...");
+                    sleep(Duration::from_secs(delay));
+                } else {
+                    eprintln!("Resuming fetch...");
+                }
+                match self.resume() {
+                    Ok(()) => break,
+                    Err(e) if e.kind() == io::ErrorKind::Unsupported => return Err(e),
+                    Err(e) => err = e,
+                }
+                delay = (delay * 2).clamp(1, 10 * 60);
+            }
+        }
+    }
+}
+
+/// A segment of a resource to be fetched by a SegmentedReader worker.
+struct Segment {
+    start: u64,
+    end: u64,
+    result: mpsc::SyncSender<io::Result<Vec<u8>>>,
+}
+
+/// A reader that fetches a resource as a series of fixed-size range
+/// requests spread across several connections, and returns the data in
+/// order.  Segments are buffered in memory, so we only fetch as many
+/// segments ahead of the reader as there are connections.
+struct SegmentedReader {
+    current: Cursor<Vec<u8>>,
+    // results of in-flight segments, in order
+    pending: VecDeque<mpsc::Receiver<io::Result<Vec<u8>>>>,
+    jobs: mpsc::Sender<Segment>,
+    next_offset: u64,
+    length: u64,
+    segment_size: u64,
+}
+
+impl SegmentedReader {
+    /// Start fetching the resource with the specified number of
+    /// connections.  Returns None if the server doesn't honor range
+    /// requests.
+    fn new(
+        request: &HttpRequest,
+        length: u64,
+        connections: u32,
+        segment_size: u64,
+    ) -> Result<Option<Self>> {
+        // the first request tells us whether ranges work
+        let first_end = segment_size.min(length);
+        let resp = match request.get_range(0, Some(first_end), request.retries)? {
+            Some(resp) => resp,
+            None => return Ok(None),
+        };
+
+        let (jobs, queue) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let (result, receiver) = mpsc::sync_channel(1);
+        let mut first = Some((
+            resp,
+            Segment {
+                start: 0,
+                end: first_end,
+                result,
+            },
+        ));
+        for _ in 0..connections {
+            let request = request.clone();
+            let queue = queue.clone();
+            let first = first.take();
+            thread::Builder::new()
+                .name("fetch".into())
+                .spawn(move || Self::worker(request, queue, first))
+                .context("spawning fetch thread")?;
+        }
+
+        let mut reader = Self {
+            current: Cursor::new(Vec::new()),
+            pending: VecDeque::from([receiver]),
+            jobs,
+            next_offset: first_end,
+            length,
+            segment_size,
+        };
+        for _ in 1..connections {
+            reader.queue_next();
+        }
+        Ok(Some(reader))
+    }
+
+    /// Queue the next segment for fetching, if there is one.
+    fn queue_next(&mut self) {
+        if self.next_offset >= self.length {
+            return;
+        }
+        let end = (self.next_offset + self.segment_size).min(self.length);
+        let (result, receiver) = mpsc::sync_channel(1);
+        // if the workers have exited, receiving the result will fail
+        let _ = self.jobs.send(Segment {
+            start: self.next_offset,
+            end,
+            result,
+        });
+        self.pending.push_back(receiver);
+        self.next_offset = end;
+    }
+
+    /// Fetch segments until the reader goes away.
+    fn worker(
+        request: HttpRequest,
+        queue: Arc<Mutex<mpsc::Receiver<Segment>>>,
+        first: Option<(blocking::Response, Segment)>,
+    ) {
+        if let Some((resp, segment)) = first {
+            let reader = HttpReader::new_range(request.clone(), resp, segment.start, segment.end);
+            let _ = segment.result.send(Self::read_segment(reader, &segment));
+        }
+        loop {
+            let segment = match queue.lock().expect("lock poisoned").recv() {
+                Ok(segment) => segment,
+                Err(_) => return,
+            };
+            let result = match request.get_range(segment.start, Some(segment.end), request.retries)
+            {
+                Ok(Some(resp)) => {
+                    let reader =
+                        HttpReader::new_range(request.clone(), resp, segment.start, segment.end);
+                    Self::read_segment(reader, &segment)
+                }
+                Ok(None) => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "server didn't honor range request",
+                )),
+                Err(e) => Err(io::Error::new(io::ErrorKind::Other, format!("{e:#}"))),
+            };
+            // the reader may have gone away
+            let _ = segment.result.send(result);
+        }
+    }
+
+    fn read_segment(mut reader: HttpReader, segment: &Segment) -> io::Result<Vec<u8>> {
+        let expected = segment.end - segment.start;
+        let mut data = Vec::with_capacity(expected as usize);
+        reader.read_to_end(&mut data)?;
+        if data.len() as u64 != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expected {} bytes at offset {} but received {}",
+                    expected,
+                    segment.start,
+                    data.len()
+                ),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+impl Read for SegmentedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let count = self.current.read(buf)?;
+            if count > 0 || buf.is_empty() {
+                return Ok(count);
+            }
+            let receiver = match self.pending.pop_front() {
+                Some(receiver) => receiver,
+                None => return Ok(0),
+            };
+            let data = receiver.recv().map_err(|_| {
+                io::Error::new(io::ErrorKind::Other, "fetch thread exited unexpectedly")
+            })??;
+            self.current = Cursor::new(data);
+            self.queue_next();
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[test]
     fn test_new_http_client() {
         let _ = new_http_client().unwrap();
     }
+
+    /// Serve a single fixed body over HTTP.  The first GET is cut off
+    /// halfway through the body; requests with a Range header are answered
+    /// with the rest of it.  Returns the URL and the received Range headers.
+    fn serve_truncated(
+        body: &'static [u8],
+        connections: usize,
+    ) -> (Url, std::thread::JoinHandle<Vec<Option<String>>>) {
+        use std::io::{BufRead, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/image.raw",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut ranges = Vec::new();
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let (name, value) = line.split_once(": ").unwrap();
+                    if name.eq_ignore_ascii_case("range") {
+                        range = Some(value.to_string());
+                    }
+                }
+                let mut stream = stream;
+                if request_line.contains(".sig ") {
+                    write!(
+                        stream,
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    )
+                    .unwrap();
+                    continue;
+                }
+                match &range {
+                    None => {
+                        write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"abc\"\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+                        stream.write_all(&body[..body.len() / 2]).unwrap();
+                    }
+                    Some(range) => {
+                        let start: usize = range
+                            .strip_prefix("bytes=")
+                            .and_then(|r| r.strip_suffix('-'))
+                            .unwrap()
+                            .parse()
+                            .unwrap();
+                        write!(stream, "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n", body.len() - start, start, body.len() - 1, body.len()).unwrap();
+                        stream.write_all(&body[start..]).unwrap();
+                    }
+                }
+                ranges.push(range);
+            }
+            ranges
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_http_resume() {
+        static BODY: [u8; 100_000] = [7; 100_000];
+
+        // reconnect after a truncated response
+        let (url, handle) = serve_truncated(&BODY, 3);
+        let location = UrlLocation::new(
+            &url,
+            FetchRetries::Finite(1.try_into().unwrap()),
+            FetchSegments::default(),
+            None,
+        );
+        let mut sources = location.sources().unwrap();
+        let mut buf = Vec::new();
+        sources[0].reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], &BODY[..]);
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![None, Some(format!("bytes={}-", BODY.len() / 2))]
+        );
+
+        // no retries
+        let (url, handle) = serve_truncated(&BODY, 2);
+        let location = UrlLocation::new(&url, FetchRetries::None, FetchSegments::default(), None);
+        let mut sources = location.sources().unwrap();
+        let mut buf = Vec::new();
+        sources[0].reader.read_to_end(&mut buf).unwrap_err();
+        handle.join().unwrap();
+
+        // explicit resume
+        let (url, handle) = serve_truncated(&BODY, 3);
+        let location = UrlLocation::new(&url, FetchRetries::None, FetchSegments::default(), None);
+        let mut sources = location.sources().unwrap();
+        assert!(sources[0].resume_at(1000).unwrap());
+        let mut buf = Vec::new();
+        sources[0].reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(&buf[..], &BODY[1000..]);
+        assert_eq!(handle.join().unwrap()[1], Some("bytes=1000-".to_string()));
+
+        // failed reconnects share the read retry budget
+        let (url, requests) = serve_unavailable(&BODY);
+        let location = UrlLocation::new(
+            &url,
+            FetchRetries::Finite(2.try_into().unwrap()),
+            FetchSegments::default(),
+            None,
+        );
+        let mut sources = location.sources().unwrap();
+        let mut buf = Vec::new();
+        sources[0].reader.read_to_end(&mut buf).unwrap_err();
+        // signature, initial GET, two reconnects
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    /// Serve a fixed body over HTTP, cutting off the first GET halfway
+    /// through and answering every later request with 503.  Returns the URL
+    /// and a count of requests received.
+    fn serve_unavailable(body: &'static [u8]) -> (Url, Arc<AtomicUsize>) {
+        use std::io::{BufRead, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/image.raw",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim_end().is_empty() {
+                        break;
+                    }
+                }
+                // the image GET follows the signature GET
+                let truncate = count.fetch_add(1, Ordering::SeqCst) == 1;
+                let status = if request_line.contains(".sig ") {
+                    "404 Not Found"
+                } else if truncate {
+                    write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"abc\"\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+                    stream.write_all(&body[..body.len() / 2]).unwrap();
+                    continue;
+                } else {
+                    "503 Service Unavailable"
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+            }
+        });
+        (url, requests)
+    }
+
+    /// Serve a fixed body over HTTP, handling each connection on its own
+    /// thread.  Range requests are honored only if honor_ranges is set.
+    /// Returns the URL and the received Range headers.
+    fn serve_ranges(
+        body: &'static [u8],
+        honor_ranges: bool,
+        connections: usize,
+    ) -> (Url, std::thread::JoinHandle<Vec<Option<String>>>) {
+        use std::io::{BufRead, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/image.raw",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut handlers = Vec::new();
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                handlers.push(std::thread::spawn(move || {
+                    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                    let mut range = None;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        let line = line.trim_end();
+                        if line.is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(": ") {
+                            if name.eq_ignore_ascii_case("range") {
+                                range = Some(value.to_string());
+                            }
+                        }
+                    }
+                    let mut stream = stream;
+                    match range.as_deref().filter(|_| honor_ranges) {
+                        None => {
+                            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+                            // the client may hang up early
+                            let _ = stream.write_all(body);
+                        }
+                        Some(range) => {
+                            let (start, end) = range
+                                .strip_prefix("bytes=")
+                                .and_then(|r| r.split_once('-'))
+                                .unwrap();
+                            let start: usize = start.parse().unwrap();
+                            let end: usize = end.parse().unwrap();
+                            write!(stream, "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n", end + 1 - start, start, end, body.len()).unwrap();
+                            stream.write_all(&body[start..=end]).unwrap();
+                        }
+                    }
+                    range
+                }));
+            }
+            handlers.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_http_segments() {
+        let body: &'static [u8] = Box::leak(
+            (0..100_000u32)
+                .map(|i| (i % 251) as u8)
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+        );
+        let request = |url| HttpRequest {
+            client: new_http_client().unwrap(),
+            url,
+            retries: FetchRetries::None,
+            validator: None,
+        };
+
+        // segments are reassembled in order
+        let (url, handle) = serve_ranges(body, true, 7);
+        let mut reader = SegmentedReader::new(&request(url), body.len() as u64, 3, 16384)
+            .unwrap()
+            .unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, body);
+        let mut ranges = handle.join().unwrap();
+        ranges.sort();
+        let mut expected: Vec<_> = (0..7)
+            .map(|i| {
+                let end = ((i + 1) * 16384).min(body.len()) - 1;
+                Some(format!("bytes={}-{}", i * 16384, end))
+            })
+            .collect();
+        expected.sort();
+        assert_eq!(ranges, expected);
+
+        // fall back if the server ignores ranges
+        let (url, handle) = serve_ranges(body, false, 1);
+        assert!(
+            SegmentedReader::new(&request(url), body.len() as u64, 3, 16384)
+                .unwrap()
+                .is_none()
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_stream_metadata_mirror() {
+        let artifact = |name: &str| {
+            serde_json::json!({
+                "location": format!("https://example.com/builds/{name}"),
+                "signature": format!("https://example.com/builds/{name}.sig"),
+                "sha256": "0000000000000000000000000000000000000000000000000000000000000000",
+            })
+        };
+        let json = serde_json::json!({
+            "stream": "stable",
+            "metadata": {"last-modified": "2024-01-01T00:00:00Z"},
+            "architectures": {
+                "x86_64": {
+                    "artifacts": {
+                        "metal": {
+                            "release": "39.1",
+                            "formats": {
+                                "raw.xz": {"disk": artifact("a.raw.xz")},
+                                "pxe": {
+                                    "kernel": artifact("a-kernel"),
+                                    "initramfs": artifact("a-initramfs.img"),
+                                },
+                            },
+                        },
+                        "qemu": {"formats": {"qcow2.xz": {"disk": artifact("a.qcow2.xz")}}},
+                    },
+                    "images": {"aws": {}},
+                },
+                "aarch64": {
+                    "artifacts": {"metal": {"formats": {"raw.xz": {"disk": artifact("b.raw.xz")}}}},
+                },
+            },
+        });
+        let mut metadata = StreamMetadata {
+            url: Url::parse("https://example.com/streams/stable.json").unwrap(),
+            retries: FetchRetries::None,
+            segments: FetchSegments::default(),
+            json,
+        };
+
+        // missing architecture, platform, or format
+        let s = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        metadata
+            .select(&s(&["s390x"]), &s(&["metal"]), &[])
+            .unwrap_err();
+        metadata
+            .select(&s(&["x86_64"]), &s(&["azure"]), &[])
+            .unwrap_err();
+        metadata
+            .select(&s(&["x86_64"]), &s(&["metal"]), &s(&["iso"]))
+            .unwrap_err();
+
+        let artifacts = metadata
+            .select(&s(&["x86_64"]), &s(&["metal"]), &[])
+            .unwrap();
+        let names: Vec<_> = artifacts
+            .iter()
+            .map(|a| {
+                format!(
+                    "{}/{}/{}/{}",
+                    a.architecture, a.platform, a.format, a.artifact_type
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "x86_64/metal/pxe/initramfs",
+                "x86_64/metal/pxe/kernel",
+                "x86_64/metal/raw.xz/disk"
+            ]
+        );
+        // unselected entries are dropped and other fields are kept
+        assert!(metadata.json["architectures"].get("aarch64").is_none());
+        assert!(metadata.json["architectures"]["x86_64"]["artifacts"]
+            .get("qemu")
+            .is_none());
+        assert_eq!(
+            metadata.json["metadata"]["last-modified"],
+            "2024-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            metadata.json["architectures"]["x86_64"]["artifacts"]["metal"]["release"],
+            "39.1"
+        );
+
+        let info = metadata.info(&artifacts[2]).unwrap();
+        assert_eq!(info.filename, "a.raw.xz");
+        assert_eq!(
+            info.sha256.unwrap().to_hex_string().unwrap(),
+            "0000000000000000000000000000000000000000000000000000000000000000"
+        );
+
+        // unsigned artifacts keep the upstream signature URL
+        metadata
+            .relocate(&artifacts[1], "stable/x86_64/a-kernel", false)
+            .unwrap();
+        let kernel = &metadata.json["architectures"]["x86_64"]["artifacts"]["metal"]["formats"]
+            ["pxe"]["kernel"];
+        assert_eq!(kernel["location"], "stable/x86_64/a-kernel");
+        assert_eq!(
+            kernel["signature"],
+            "https://example.com/builds/a-kernel.sig"
+        );
+
+        metadata
+            .relocate(&artifacts[2], "stable/x86_64/a.raw.xz", true)
+            .unwrap();
+        let disk = &metadata.json["architectures"]["x86_64"]["artifacts"]["metal"]["formats"]
+            ["raw.xz"]["disk"];
+        assert_eq!(disk["location"], "stable/x86_64/a.raw.xz");
+        assert_eq!(disk["signature"], "stable/x86_64/a.raw.xz.sig");
+        assert_eq!(
+            disk["sha256"],
+            "0000000000000000000000000000000000000000000000000000000000000000"
+        );
+
+        // relative locations resolve against the stream URL
+        let artifact = Artifact::deserialize(disk).unwrap();
+        assert_eq!(
+            metadata.url.join(&artifact.location).unwrap().as_str(),
+            "https://example.com/streams/stable/x86_64/a.raw.xz"
+        );
+
+        // write and reread
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stable.json");
+        metadata.write(&path).unwrap();
+        let reread: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(reread, metadata.json);
+        assert!(!dir.path().join("stable.json.partial").exists());
+    }
 }
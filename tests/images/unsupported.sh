@@ -71,5 +71,10 @@ try iso extract pxe synthetic.iso |
 try iso customize --dest-console ttyS0 embed-areas-2022-02.iso |
     grepq "does not support customizing the destination console"
 
+# no install --status-url support
+try iso customize --installer-status-url https://example.com/status \
+    embed-areas-2022-02.iso |
+    grepq "does not support reporting installer status"
+
 # Done
 echo "Success."